@@ -0,0 +1,273 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+use crate::chunks::{InstallOptions, chunk_filename, install_chunk};
+use crate::error::Error;
+use crate::manifest::{POINTER, generation_paths, parse_manifest};
+use crate::root::StatePaths;
+use crate::source::FileSource;
+use crate::update::{HeaderPolicy, read_headers_assuming};
+
+/// Directory of the state dir that `import` leaves the archive's manifests in.
+pub const IMPORTED_DIR: &str = "imported";
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// What `export` wrote.
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    /// Blake3 hash of the current manifest, which the archive's `manifest` pointer names
+    pub manifest_hash: String,
+    pub manifests: usize,
+    pub chunks: usize,
+    pub bytes: u64,
+}
+
+/// What `import` installed.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Hash of the manifest the archive's pointer names, saved under `<state>/imported`
+    pub manifest_hash: String,
+    pub manifest_path: PathBuf,
+    pub chunks: usize,
+    pub bytes: u64,
+}
+
+fn append_file(
+    builder: &mut tar::Builder<impl Write>,
+    name: &str,
+    data: &[u8],
+) -> Result<(), io::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, name, data)
+}
+
+/// Bundles every stored generation's manifest and the chunks they reference into a tar archive,
+/// zstd compressed with `compress`. It's laid out like an uncompressed repo, with a `manifest`
+/// pointer to the current generation, so it can also be unpacked and served or used as an
+/// additional cache.
+pub fn export(
+    state: &StatePaths,
+    writer: impl Write,
+    compress: bool,
+) -> Result<ExportReport, Error> {
+    if compress {
+        let (encoder, report) = write_archive(state, zstd::Encoder::new(writer, 0)?)?;
+        encoder.finish()?.flush()?;
+        Ok(report)
+    } else {
+        let (mut writer, report) = write_archive(state, writer)?;
+        writer.flush()?;
+        Ok(report)
+    }
+}
+
+fn write_archive<W: Write>(state: &StatePaths, writer: W) -> Result<(W, ExportReport), Error> {
+    let generations = generation_paths(&state.manifests);
+    if generations.is_empty() {
+        return Err("nothing is installed, so there's nothing to export".into());
+    }
+
+    let mut builder = tar::Builder::new(writer);
+    let mut report = ExportReport::default();
+    let mut seen = HashSet::new();
+    let mut chunklists = Vec::new();
+
+    // Manifests first, so imports know every chunk's hasher before reaching it
+    for (generation, path) in generations.iter().enumerate() {
+        let manifest = fs::read_to_string(path)?;
+        let hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        if generation == 0 {
+            append_file(&mut builder, POINTER, hash.as_bytes())?;
+            report.manifest_hash = hash.clone();
+        }
+        if seen.insert(hash.clone()) {
+            append_file(&mut builder, &hash, manifest.as_bytes())?;
+            report.manifests += 1;
+        }
+        chunklists.push(parse_manifest(&manifest)?.1);
+    }
+
+    for chunk in chunklists.iter().flatten() {
+        let filename = chunk_filename(chunk);
+        if !seen.insert(filename.clone()) {
+            continue;
+        }
+
+        let path = state.chunkstore.join(&filename);
+        let mut file = fs::File::open(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("could not read chunk {}: {e}", path.display()),
+            )
+        })?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(file.metadata()?.len());
+        header.set_mode(0o644);
+        builder.append_data(&mut header, format!("chunks/{filename}"), &mut file)?;
+        report.chunks += 1;
+        report.bytes += header.size()?;
+    }
+
+    Ok((builder.into_inner()?, report))
+}
+
+/// Unpacks an archive made by `export` into the chunkstore, checking each chunk against the
+/// manifests listing it. The manifests are saved under `<state>/imported`, for an offline update
+/// to install with `UpdateOptions::manifest_file`.
+pub async fn import(state: &StatePaths, reader: impl Read) -> Result<ImportReport, Error> {
+    let mut reader = BufReader::new(reader);
+    let reader: Box<dyn Read> = match reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        true => Box::new(zstd::Decoder::with_buffer(reader)?),
+        false => Box::new(reader),
+    };
+
+    // Unpacked within the state dir first, as chunks can only be checked once their manifest
+    // has been read
+    let unpacked = &state.state.join("import.tmp");
+    match fs::remove_dir_all(unpacked) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    fs::create_dir_all(unpacked)?;
+    let result = install_unpacked(state, reader, unpacked).await;
+    fs::remove_dir_all(unpacked)?;
+
+    result
+}
+
+async fn install_unpacked(
+    state: &StatePaths,
+    reader: impl Read,
+    unpacked: &Path,
+) -> Result<ImportReport, Error> {
+    tar::Archive::new(reader).unpack(unpacked)?;
+    let manifest_hash = fs::read_to_string(unpacked.join(POINTER))
+        .map_err(|e| Error::Parse(format!("archive has no manifest pointer: {e}")))?
+        .trim()
+        .to_string();
+
+    fs::create_dir_all(&state.chunkstore)?;
+    let imported = state.state.join(IMPORTED_DIR);
+    fs::create_dir_all(&imported)?;
+    let source = FileSource::new(unpacked);
+    let mut report = ImportReport::default();
+    let mut installed = HashSet::new();
+
+    for entry in fs::read_dir(unpacked)? {
+        let path = entry?.path();
+        if !path.is_file() || path.file_name().is_some_and(|name| name == POINTER) {
+            continue;
+        }
+
+        let manifest = fs::read_to_string(&path)?;
+        let (headers, chunklist) = parse_manifest(&manifest)?;
+        // Already installed once, so a MinVersion it doesn't meet was already accepted
+        let policy = HeaderPolicy {
+            ignore_min_version: true,
+            ..Default::default()
+        };
+        let (_, hasher) = read_headers_assuming(&headers, &policy)?;
+        for chunk in &chunklist {
+            if !installed.insert(chunk_filename(chunk)) {
+                continue;
+            }
+
+            // Exported chunks are stored uncompressed under their bare hash
+            report.bytes += install_chunk(
+                &source,
+                chunk,
+                &state.chunkstore,
+                &InstallOptions {
+                    hash_method: hasher,
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| e.context(format_args!("could not import {}", chunk.path)))?;
+            report.chunks += 1;
+        }
+
+        let hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        fs::write(imported.join(&hash), &manifest)?;
+        info!(manifest = %hash, "Imported manifest {hash}");
+    }
+
+    report.manifest_path = imported.join(&manifest_hash);
+    if !report.manifest_path.exists() {
+        return Err(Error::Parse(format!(
+            "archive's pointer names manifest {manifest_hash}, which it doesn't hold"
+        )));
+    }
+    report.manifest_hash = manifest_hash;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MemorySource;
+    use crate::update::{UpdateOptions, update};
+
+    #[tokio::test]
+    async fn test_export_import_roundtrip() {
+        let seeded = temp_dir::TempDir::new().unwrap();
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "v1")]);
+        update(&source, seeded.path(), &UpdateOptions::default())
+            .await
+            .unwrap();
+        let manifest_hash = source.publish(&[("bin/tool", "v2"), ("share/doc", "docs")]);
+        update(&source, seeded.path(), &UpdateOptions::default())
+            .await
+            .unwrap();
+
+        let seeded_state = StatePaths::new(seeded.path(), None);
+        let mut archive = Vec::new();
+        let report = export(&seeded_state, &mut archive, true).unwrap();
+        assert_eq!(report.manifest_hash, manifest_hash);
+        assert_eq!((report.manifests, report.chunks), (2, 3));
+        assert!(archive.starts_with(&ZSTD_MAGIC));
+
+        // A fresh root installs from the archive alone
+        let fresh = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(fresh.path(), None);
+        let imported = import(&state, archive.as_slice()).await.unwrap();
+        assert_eq!(imported.manifest_hash, manifest_hash);
+        assert_eq!(imported.chunks, 3);
+        assert!(!state.state.join("import.tmp").exists());
+
+        let options = UpdateOptions {
+            offline: true,
+            manifest_file: Some(imported.manifest_path),
+            ..Default::default()
+        };
+        let summary = update(&MemorySource::default(), fresh.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.manifest_hash, manifest_hash);
+        assert_eq!(summary.chunks_downloaded, 0);
+        assert_eq!(
+            fs::read_to_string(fresh.child("usr/share/doc")).unwrap(),
+            "docs"
+        );
+
+        // Chunks are checked against their hash on the way in
+        let mut archive = Vec::new();
+        export(&seeded_state, &mut archive, false).unwrap();
+        let at = archive.windows(4).position(|w| w == b"docs").unwrap();
+        archive[at..at + 4].copy_from_slice(b"dogs");
+        let fresh = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(fresh.path(), None);
+        let error = import(&state, archive.as_slice()).await.unwrap_err();
+        assert!(matches!(error, Error::Mismatch(_)), "{error:?}");
+    }
+}
@@ -0,0 +1,65 @@
+use clap::Parser;
+use std::fs;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use tracing::info;
+
+use pkgsmgr::archive::export;
+use pkgsmgr::logging::{LogOptions, init_logging};
+use pkgsmgr::root::StatePaths;
+
+/// Bundles a root's manifests and their chunks into one tar archive, for seeding other machines
+/// with pkgsmgr-import.
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(long)]
+    root_path: Option<PathBuf>,
+    /// Directory holding pkgsmgr's state, absolute or relative to the root [default: .pkgsmgr]
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    #[command(flatten)]
+    log: LogOptions,
+
+    /// Archive to write, zstd compressed if it ends in `.zst`
+    output: PathBuf,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    init_logging(&args.log);
+
+    let root_path = &args.root_path.unwrap_or_else(|| PathBuf::from("/"));
+    let state = StatePaths::new(root_path, args.state_dir.as_deref());
+    let compress = args
+        .output
+        .extension()
+        .is_some_and(|extension| extension == "zst");
+
+    // Written aside and renamed, so a failed export leaves no truncated archive behind
+    let mut tmp_path = args.output.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let file = fs::File::create(&tmp_path)?;
+    let report = match export(&state, BufWriter::new(&file), compress) {
+        Ok(report) => report,
+        Err(e) => {
+            fs::remove_file(&tmp_path)?;
+            return Err(e.into());
+        }
+    };
+    file.sync_all()?;
+    fs::rename(&tmp_path, &args.output)?;
+
+    info!(
+        manifest = %report.manifest_hash,
+        chunks = report.chunks,
+        bytes = report.bytes,
+        "Exported {} manifests and {} chunks, {}kb, to {}",
+        report.manifests,
+        report.chunks,
+        report.bytes / 1024,
+        args.output.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use clap::Parser;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+use pkgsmgr::chunks::{CleanReport, clean_old_chunks, clean_shared_cache};
+use pkgsmgr::logging::{LogOptions, init_logging};
+use pkgsmgr::root::StatePaths;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(long)]
+    root_path: Option<PathBuf>,
+    /// Directory holding pkgsmgr's state, absolute or relative to the root [default: .pkgsmgr]
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    /// How many previous manifests keep their chunks, 0 keeps only the current one
+    #[arg(long, default_value_t = 1)]
+    keep_generations: usize,
+    /// List the chunks that would be removed without removing them
+    #[arg(long)]
+    dry_run: bool,
+    /// Also remove chunks from this shared chunk cache that no root links to any more. Don't run
+    /// it while any root sharing the cache is updating
+    #[arg(long)]
+    shared_chunk_cache: Option<PathBuf>,
+    #[command(flatten)]
+    log: LogOptions,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    init_logging(&args.log);
+
+    let root_path = &args.root_path.unwrap_or_else(|| PathBuf::from("/"));
+    let state = StatePaths::new(root_path, args.state_dir.as_deref());
+    let chunks_path = &state.chunkstore;
+    let manifests_path = &state.manifests;
+
+    if !manifests_path.join("current").exists() {
+        return Err(format!("nothing is installed under {}", root_path.display()).into());
+    }
+
+    let report = clean_old_chunks(
+        manifests_path,
+        chunks_path,
+        args.keep_generations,
+        args.dry_run,
+    )?;
+    print_report(&report, args.dry_run);
+
+    // The root's own cleanup drops its links first, so the cache can then free those chunks
+    if let Some(cache_path) = &args.shared_chunk_cache {
+        info!("Cleaning the shared chunk cache {}", cache_path.display());
+        print_report(&clean_shared_cache(cache_path, args.dry_run)?, args.dry_run);
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &CleanReport, dry_run: bool) {
+    for (path, e) in &report.failures {
+        warn!("Couldn't remove {}: {e}", path.display());
+    }
+
+    if dry_run {
+        for (path, size) in &report.removed {
+            println!("{} {}kb", path.display(), size / 1024);
+        }
+        info!(
+            bytes = report.freed_bytes,
+            "Would free {}kb",
+            report.freed_bytes / 1024
+        );
+    } else {
+        info!(
+            bytes = report.freed_bytes,
+            "Freed {}kb",
+            report.freed_bytes / 1024
+        );
+    }
+}
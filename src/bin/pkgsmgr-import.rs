@@ -0,0 +1,48 @@
+use clap::Parser;
+use std::fs;
+use std::io::BufReader;
+use std::path::PathBuf;
+use tracing::info;
+
+use pkgsmgr::archive::import;
+use pkgsmgr::logging::{LogOptions, init_logging};
+use pkgsmgr::root::StatePaths;
+
+/// Unpacks an archive from pkgsmgr-export into a root's chunkstore, checking every chunk.
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(long)]
+    root_path: Option<PathBuf>,
+    /// Directory holding pkgsmgr's state, absolute or relative to the root [default: .pkgsmgr]
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    #[command(flatten)]
+    log: LogOptions,
+
+    /// Archive written by pkgsmgr-export, compressed or not
+    archive: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    init_logging(&args.log);
+
+    let root_path = &args.root_path.unwrap_or_else(|| PathBuf::from("/"));
+    let state = StatePaths::new(root_path, args.state_dir.as_deref());
+    let archive = BufReader::new(fs::File::open(&args.archive)?);
+    let report = import(&state, archive).await?;
+
+    info!(
+        manifest = %report.manifest_hash,
+        chunks = report.chunks,
+        bytes = report.bytes,
+        "Imported {} chunks, {}kb. Install with pkgsmgr-updater --offline --manifest-file {} <repo_url>",
+        report.chunks,
+        report.bytes / 1024,
+        report.manifest_path.display()
+    );
+
+    Ok(())
+}
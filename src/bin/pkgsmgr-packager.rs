@@ -1,16 +1,32 @@
-use async_compression::tokio::write::ZstdEncoder;
+use async_compression::Level;
 use clap::Parser;
-use nix::fcntl::{AT_FDCWD, RenameFlags, renameat2};
 use std::boxed::Box;
-use std::collections::HashMap;
-use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
-
+use tracing::{info, warn};
+
+use pkgsmgr::chunks::ChunkLayout;
+use pkgsmgr::dictionary::Dictionary;
+use pkgsmgr::exclude::{Excludes, IGNORE_FILENAME, read_ignore_file};
+use pkgsmgr::logging::{LogOptions, init_logging};
+use pkgsmgr::manifest::{
+    compress_manifest, compressed_manifest_name, manifest_to_json, pointer_name,
+};
+use pkgsmgr::packager::{
+    BaseManifest, ChunkOptions, Discovered, ManifestOptions, PackageStats, compression_report,
+    discover, format_report_csv, generate_manifest, hash_existing_chunks, latest_manifest,
+    package_stats, resolve_input_path, verify_roundtrip, write_chunks, write_deltas,
+};
+use pkgsmgr::platform::exchange;
 use pkgsmgr::types::*;
-use pkgsmgr::utils::Hasher;
+use pkgsmgr::utils::DEFAULT_BUFFER_SIZE;
+
+/// zstd's own default dictionary size
+const MAX_DICTIONARY_SIZE: usize = 110 * 1024;
+/// Largest chunk `--chunk-cids` allows, so every chunk fits in one IPFS block.
+const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -19,94 +35,311 @@ struct Args {
     hash: HashType,
     #[arg(long)]
     compression: Compression,
+    /// Brotli quality, from 0 to 11
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..=11))]
+    brotli_quality: Option<i32>,
+    /// Record each file's modification time in the manifest
+    #[arg(long)]
+    record_mtime: bool,
+    /// Clamp recorded modification times to this Unix timestamp, like SOURCE_DATE_EPOCH.
+    /// Implies --record-mtime
+    #[arg(long)]
+    clamp_mtime: Option<i64>,
+    /// Record when the manifest was generated, for updaters' --max-manifest-age. The time is
+    /// SOURCE_DATE_EPOCH when set, else --clamp-mtime, else now. Off by default, as it gives
+    /// every run a new manifest hash
+    #[arg(long)]
+    generated: bool,
+    /// Train a zstd dictionary on the input's small files and compress chunks with it.
+    /// Requires --compression zstd
+    #[arg(long)]
+    train_dict: bool,
+    /// Store files that are already compressed, like images and archives, as they are rather than
+    /// compressing them again. Judged by their extension and first bytes
+    #[arg(long)]
+    smart_compression: bool,
+    /// Previous manifest, recorded with --record-mtime. Files whose size and mtime still match it
+    /// reuse its hashes instead of being read again
+    #[arg(long)]
+    base_manifest: Option<PathBuf>,
+    /// Use the manifest output_path currently points to as --base-manifest, so repackaging an
+    /// unchanged tree only stats its files
+    #[arg(long, conflicts_with_all = ["base_manifest", "output_manifest_only"])]
+    incremental_chunks: bool,
+    /// Hash every file rather than trusting --base-manifest, for trees whose mtimes aren't
+    /// reliable. Chunks already in the output still aren't recompressed
+    #[arg(long, requires = "base_manifest")]
+    no_mtime_trust: bool,
+    /// Split files larger than this many bytes into chunks of that size, so a small edit to a
+    /// large file only changes one chunk. Must be a multiple of 1024
+    #[arg(long)]
+    chunk_size: Option<u64>,
+    /// Where chunks go within output_path/chunks, such as `{hash:0:2}/{hash}{ext}` to shard them
+    /// by the first two characters of their hash. Updaters must be given the same template
+    /// [default: {hash}{ext}]
+    #[arg(long)]
+    chunk_path_template: Option<ChunkLayout>,
+    /// Bytes read at a time while hashing and compressing [default: 65536]
+    #[arg(long)]
+    buffer_size: Option<NonZeroUsize>,
+    /// Only rewrite the manifest and its pointer, from chunks already in output_path. Fails if
+    /// any file's chunk is missing
+    #[arg(long, conflicts_with = "base_manifest")]
+    output_manifest_only: bool,
+    /// Also write zstd patches from the previous version of each changed file, which updaters that
+    /// have it fetch instead of the whole chunk. The previous version is --base-manifest's, or
+    /// else the manifest output_path points to
+    #[arg(long, conflicts_with = "output_manifest_only")]
+    deltas: bool,
+    /// Declare that each chunk is also published as a raw IPFS block, so gateways can serve it by
+    /// CID. Requires --hash blake3, --compression none and a --chunk-size of at most 1MiB
+    #[arg(long)]
+    chunk_cids: bool,
+    /// Also write the manifest compressed with zstd, which updaters fetch in its place. The plain
+    /// manifest is still written for older updaters
+    #[arg(long)]
+    compress_manifest: bool,
+    /// Also write the manifest as JSON to this path, for tooling that can't read its line format
+    #[arg(long)]
+    emit_json: Option<PathBuf>,
+    /// Write each unique chunk's original and stored size, compression ratio and codec to this
+    /// path, as CSV if it ends in `.csv` and JSON otherwise
+    #[arg(long, conflicts_with = "output_manifest_only")]
+    report: Option<PathBuf>,
+    /// Only walk input_path and print how many files and chunks it would make, and how much
+    /// deduplication saves, without compressing or writing anything
+    #[arg(long)]
+    stats_only: bool,
+    /// Rebuild the tree from the written output and compare it against input_path
+    #[arg(long)]
+    verify_roundtrip: bool,
+    /// Point `manifest-<channel>` at the new manifest rather than `manifest`, so a repo can carry
+    /// several release channels sharing its chunks. Also picks the pointer --incremental-chunks
+    /// and --deltas start from
+    #[arg(long)]
+    channel: Option<String>,
+    /// Glob of paths to leave out, relative to input_path. Prefix with `!` to re-include.
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    #[command(flatten)]
+    log: LogOptions,
 
     input_path: PathBuf,
-    output_path: PathBuf,
+    #[arg(required_unless_present = "stats_only")]
+    output_path: Option<PathBuf>,
+}
+
+/// When `--generated` says the manifest was made. A reproducible build claims SOURCE_DATE_EPOCH,
+/// or the time mtimes are clamped to.
+fn generated_time(clamp_mtime: Option<i64>) -> Result<i64, Box<dyn std::error::Error>> {
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        return Ok(epoch
+            .trim()
+            .parse()
+            .map_err(|e| format!("SOURCE_DATE_EPOCH {epoch:?} isn't a Unix timestamp: {e}"))?);
+    }
+
+    Ok(match clamp_mtime {
+        Some(clamp) => clamp,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+    })
 }
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    init_logging(&args.log);
+    let input_path = &resolve_input_path(&args.input_path)?;
+
+    let mut patterns = read_ignore_file(&input_path.join(IGNORE_FILENAME))?;
+    patterns.push(IGNORE_FILENAME.to_string());
+    patterns.extend(args.exclude.iter().cloned());
+    let excludes = Excludes::new(&patterns)?;
+
+    info!(phase = "discover", "Discovering files...");
+    let Discovered {
+        files,
+        directories,
+        symlinks,
+        special,
+    } = discover(input_path, &excludes)?;
+
+    let buffer_size = args
+        .buffer_size
+        .map_or(DEFAULT_BUFFER_SIZE, NonZeroUsize::get);
+    let layout = &args.chunk_path_template.unwrap_or_default();
+    let pointer = &pointer_name(args.channel.as_deref())?;
+    if args.stats_only {
+        info!(phase = "stats", "Finding duplicate files...");
+        let stats = PackageStats {
+            directories: directories.len(),
+            symlinks,
+            special: special.len(),
+            ..package_stats(&files, args.hash, args.chunk_size, buffer_size).await?
+        };
 
-    let chunks_path = &args.output_path.join("chunks");
+        println!("Files:       {}", stats.files);
+        println!("Directories: {}", stats.directories);
+        println!("Symlinks:    {} (skipped)", stats.symlinks);
+        println!("Special:     {} (skipped)", stats.special);
+        println!("Total:       {}kb", stats.total_bytes / 1024);
+        println!(
+            "Unique:      {} files, {}kb",
+            stats.unique_files,
+            stats.unique_bytes / 1024
+        );
+        println!(
+            "Dedup saves: {} files, {}kb",
+            stats.files - stats.unique_files,
+            (stats.total_bytes - stats.unique_bytes) / 1024
+        );
+        println!("Chunks:      {}", stats.chunks);
+        return Ok(());
+    }
+
+    let output_path = &args.output_path.ok_or("output_path is required")?;
+    let chunks_path = &output_path.join("chunks");
     if !chunks_path.exists() {
         std::fs::create_dir_all(chunks_path)?;
     }
 
-    let mut directories = Vec::new();
-    let mut files = Vec::new();
-    let mut symlinks = Vec::new();
-
-    println!("Discovering files...");
-    for entry in walkdir::WalkDir::new(&args.input_path).min_depth(1) {
-        let entry = entry?;
-        let path = entry.path().to_path_buf();
-
-        if entry.file_type().is_dir() {
-            directories.push(path);
-        } else if entry.file_type().is_symlink() {
-            symlinks.push(path);
-        } else if entry.file_type().is_file() {
-            files.push(path.clone());
-        }
+    // A block's CID hashes exactly its bytes, and gateways refuse blocks over about 2MiB
+    if args.chunk_cids
+        && (args.hash != HashType::Blake3
+            || args.compression != Compression::None
+            || args.chunk_size.is_none_or(|size| size > MAX_BLOCK_SIZE))
+    {
+        return Err(
+            "--chunk-cids requires --hash blake3, --compression none and a --chunk-size of at most 1MiB"
+                .into(),
+        );
     }
 
-    println!("Beginning hashing and compressing...");
-    let mut hashes = HashMap::new();
-
-    for file_path in &files {
-        let hash = hash_file(file_path, args.hash).await?;
+    info!(phase = "compress", "Beginning hashing and compressing...");
+    let quality = match (args.compression, args.brotli_quality) {
+        (Compression::Brotli, Some(quality)) => Level::Precise(quality),
+        _ => Level::Default,
+    };
+    let dictionary = if args.train_dict {
+        if args.compression != Compression::Zstd {
+            return Err("--train-dict requires --compression zstd".into());
+        }
 
-        compress(file_path, args.compression, chunks_path, &hash).await?;
+        info!(phase = "compress", "Training dictionary...");
+        let dictionary = Dictionary::train(&files, MAX_DICTIONARY_SIZE)?;
+        if !args.output_manifest_only {
+            dictionary.write(output_path)?;
+        }
+        Some(dictionary)
+    } else {
+        None
+    };
+    let base = match &args.base_manifest {
+        Some(base_manifest) if !args.no_mtime_trust => {
+            Some(BaseManifest::load(base_manifest, input_path).await?)
+        }
+        None if args.incremental_chunks => {
+            BaseManifest::latest(output_path, input_path, pointer).await?
+        }
+        _ => None,
+    };
+    let chunk_options = ChunkOptions {
+        hash_method: args.hash,
+        compression: args.compression,
+        dictionary: dictionary.as_ref(),
+        quality,
+        chunk_size: args.chunk_size,
+        buffer_size,
+        layout: layout.clone(),
+        smart_compression: args.smart_compression,
+    };
+    let hashes = if args.output_manifest_only {
+        hash_existing_chunks(&files, chunks_path, &chunk_options).await?
+    } else {
+        write_chunks(&files, chunks_path, base.as_ref(), &chunk_options).await?
+    };
 
-        if fs::hard_link(&file_path, chunks_path.join(&hash))
-            .await
-            .is_err()
-        {
-            fs::copy(&file_path, chunks_path.join(&hash)).await?;
+    let deltas = if args.deltas {
+        let base_manifest_path = match &args.base_manifest {
+            Some(base_manifest) => Some(base_manifest.clone()),
+            None => latest_manifest(output_path, pointer).await?,
         };
+        match base_manifest_path {
+            Some(base_manifest_path) => {
+                info!(phase = "delta", "Writing deltas...");
+                let base_manifest = fs::read_to_string(base_manifest_path).await?;
+                write_deltas(
+                    input_path,
+                    output_path,
+                    &base_manifest,
+                    &hashes,
+                    &chunk_options,
+                )
+                .await?
+            }
+            None => {
+                warn!(
+                    "Nothing packaged in the output yet, so there's nothing to write deltas from"
+                );
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
 
-        hashes.insert(file_path, hash);
-    }
-
-    println!("Generating manifest...");
-    let mut manifest = "".to_string();
-
-    match args.compression {
-        Compression::Zstd => manifest += "Compression: zstd\n",
-        Compression::None => (),
-    }
-    match args.hash {
-        HashType::Blake3 => manifest += "Hasher: blake3\n",
-        HashType::Xxh3_128 => manifest += "Hasher: xxh3_128\n",
+    if let Some(report_path) = &args.report {
+        let rows = compression_report(
+            input_path,
+            &files,
+            &hashes,
+            chunks_path,
+            layout,
+            args.compression,
+            dictionary.as_ref(),
+        )
+        .await?;
+        let report = match report_path.extension() {
+            Some(extension) if extension == "csv" => format_report_csv(&rows),
+            _ => serde_json::to_string_pretty(&rows)?,
+        };
+        fs::write(report_path, report).await?;
     }
 
-    manifest += "---\n";
-
-    for file in &files {
-        let hash = hashes
-            .get(&file)
-            .expect("tried adding file to manifest that has no hash");
-        let metadata = fs::metadata(&file).await?;
-        // Unix permission mode
-        let mode = metadata.mode();
-        // Size in KILOBYTES
-        let size = metadata.size() / 1024;
-        let path = file
-            .strip_prefix(&args.input_path)
-            .expect("tried adding file to manifest that is outside of input_path")
-            .to_str()
-            .unwrap();
-
-        manifest += &format!("{mode};{size};{hash};{path}\n");
+    info!(phase = "manifest", "Generating manifest...");
+    let manifest_options = ManifestOptions {
+        compression: args.compression,
+        hash_method: args.hash,
+        record_mtime: args.record_mtime,
+        clamp_mtime: args.clamp_mtime,
+        dictionary: dictionary.map(|dictionary| dictionary.hash),
+        generated: match args.generated {
+            true => Some(generated_time(args.clamp_mtime)?),
+            false => None,
+        },
+        deltas,
+        chunk_cids: args.chunk_cids,
+    };
+    let manifest = generate_manifest(input_path, &files, &hashes, &manifest_options).await?;
+    if let Some(json_path) = &args.emit_json {
+        let document = manifest_to_json(&manifest)?;
+        fs::write(json_path, serde_json::to_string_pretty(&document)?).await?;
     }
 
     // Atomically replace on-disk manifest
     let hash = &blake3::hash(manifest.as_bytes()).to_hex().to_string();
-    let tmp_link_path = args.output_path.join("manifest.tmp");
-    let main_link_path = args.output_path.join("manifest");
-    let manifest_path = args.output_path.join(hash);
-
+    let tmp_link_path = output_path.join(format!("{pointer}.tmp"));
+    let main_link_path = output_path.join(pointer);
+    let manifest_path = output_path.join(hash);
+
+    if args.compress_manifest {
+        fs::write(
+            output_path.join(compressed_manifest_name(hash)),
+            compress_manifest(&manifest)?,
+        )
+        .await?;
+    }
     fs::write(manifest_path, manifest).await?;
     fs::write(&tmp_link_path, hash).await?;
 
@@ -114,90 +347,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         fs::write(&main_link_path, "").await?;
     }
 
-    renameat2(
-        AT_FDCWD,
-        &tmp_link_path,
-        AT_FDCWD,
-        &main_link_path,
-        RenameFlags::RENAME_EXCHANGE,
-    )?;
+    exchange(&tmp_link_path, &main_link_path)?;
 
     fs::remove_file(&tmp_link_path).await?;
 
-    Ok(())
-}
-
-async fn hash_file(
-    file_path: &Path,
-    hash_method: HashType,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut source_file = match File::open(&file_path).await {
-        Ok(file) => file,
-        Err(e) => {
-            eprintln!("couldn't open source file: {}", file_path.display());
-            panic!("{e}")
-        }
-    };
-
-    let mut hasher = Hasher::new(hash_method);
-
-    let mut buf = [0; 8192];
-    loop {
-        let n = source_file.read(&mut buf).await?;
-        if n == 0 {
-            break;
-        }
-
-        let chunk = &buf[0..n];
-        hasher.write(chunk);
+    if args.verify_roundtrip {
+        info!(phase = "verify", "Verifying output...");
+        verify_roundtrip(input_path, output_path, layout, pointer).await?;
+        info!(phase = "verify", "Output matches input.");
     }
 
-    let hash = hasher.digest();
-
-    Ok(hash)
-}
-
-async fn compress(
-    file_path: &Path,
-    compression: Compression,
-    chunks_path: &Path,
-    hash: &str,
-) -> Result<(), std::io::Error> {
-    let compressed_chunk_filename = match compression {
-        Compression::Zstd => format!("{hash}.zstd"),
-        Compression::None => panic!("Tried to compress on a non-compressable request."),
-    };
-    let compressed_chunk_path = &chunks_path.join(compressed_chunk_filename);
-
-    if !compressed_chunk_path.exists() {
-        let mut source_file = File::open(&file_path).await.unwrap();
-        let temp_file_path = temp_file::TempFile::new()?;
-        let mut temp_file = File::create(&temp_file_path).await?;
-
-        let mut compressor: Box<dyn AsyncWrite + Sync + Unpin> = match compression {
-            Compression::Zstd => Box::new(ZstdEncoder::new(&mut temp_file)),
-            Compression::None => panic!("Tried to copmress on a non-compressable request."),
-        };
-
-        let mut buf = [0; 8192];
-        loop {
-            let n = source_file.read(&mut buf).await?;
-            if n == 0 {
-                break;
-            }
-
-            compressor.write_all(&buf[0..n]).await?;
-        }
-
-        // Finish compressing
-        compressor.flush().await?;
-        compressor.shutdown().await?;
-
-        // Move compressed from memory and onto disk
-        fs::copy(temp_file_path, compressed_chunk_path).await?;
-
-        println!("Compressed chunk from path {file_path:?}");
-    };
-
     Ok(())
 }
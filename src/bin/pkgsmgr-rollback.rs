@@ -1,53 +1,150 @@
 use clap::Parser;
-use nix::fcntl::{AT_FDCWD, RenameFlags, renameat2};
 use std::fs;
 use std::path::PathBuf;
+use tracing::{error, info};
 
-use pkgsmgr::manifest::{build_tree, parse_manifest, update_manifest};
+use pkgsmgr::chunks::ChunkLayout;
+use pkgsmgr::logging::{LogOptions, init_logging};
+use pkgsmgr::manifest::diff_manifests;
+use pkgsmgr::rollback::{
+    RollbackFailure, check_rollback_chunks, restore_rollback_chunks, rollback, rollback_targets,
+};
+use pkgsmgr::root::{StatePaths, check_root, confirm_swap, target_path};
+use pkgsmgr::source::source_from_url;
+use pkgsmgr::update::UpdateOptions;
+use pkgsmgr::utils::{ClientOptions, build_client};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(long)]
     root_path: Option<PathBuf>,
+    /// Directory holding pkgsmgr's state, absolute or relative to the root [default: .pkgsmgr]
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    /// Directory under the root that's replaced, such as opt/app [default: usr]
+    #[arg(long)]
+    target: Option<PathBuf>,
+    /// Allow operating on `/`
+    #[arg(long)]
+    allow_root: bool,
+    /// Print the paths rolling back adds, removes, and modifies
+    #[arg(long)]
+    show_changes: bool,
+    /// Don't ask for confirmation before swapping
+    #[arg(long, short)]
+    yes: bool,
+    /// Print what was rolled back from and to, and the paths that changed, as JSON. Failures are
+    /// printed as JSON too, with an `error` code and a `message`
+    #[arg(long)]
+    json: bool,
+    /// Print the stored generations older than the current one as JSON, then exit without
+    /// rolling back
+    #[arg(long)]
+    list: bool,
+    /// Download chunks the previous generation needs from this repo, if a cleanup freed them. An
+    /// HTTP(S) URL, file:// URL, or local path
+    #[arg(long)]
+    repo_url: Option<String>,
+    /// Copy chunks the previous generation needs from this repo or cache, if a cleanup freed them.
+    /// Looked in before --repo-url
+    #[arg(long)]
+    additional_cache_path: Option<PathBuf>,
+    /// Where chunks are within the repo's chunks directory, as given to the packager
+    /// [default: {hash}{ext}]
+    #[arg(long)]
+    chunk_path_template: Option<ChunkLayout>,
+    #[command(flatten)]
+    log: LogOptions,
+    #[command(flatten)]
+    client: ClientOptions,
+}
+
+/// Reports a failure, as JSON with `--json`, and exits with 1.
+fn fail(json: bool, failure: RollbackFailure) -> ! {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&failure).expect("failures serialize")
+        );
+    } else {
+        error!("{}", failure.message);
+    }
+    std::process::exit(1)
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    init_logging(&args.log);
 
     let root_path = &args.root_path.unwrap_or_else(|| PathBuf::from("/"));
-    let internal_path = &root_path.join(".pkgsmgr");
-    let chunks_path = &internal_path.join("chunkstore");
-    fs::create_dir_all(chunks_path)?;
-    let staging_path = &internal_path.join("staging");
-    let manifests_path = &internal_path.join("manifests");
+    let state = StatePaths::new(root_path, args.state_dir.as_deref());
+    let target_path = &target_path(root_path, args.target.as_deref(), &state)?;
+    check_root(root_path, target_path, &state, args.allow_root)?;
+    fs::create_dir_all(&state.chunkstore)?;
+    let manifests_path = &state.manifests;
     fs::create_dir_all(manifests_path)?;
+    state.warn_if_cross_device(root_path);
+
+    if args.list {
+        let targets = rollback_targets(manifests_path)?;
+        println!("{}", serde_json::to_string_pretty(&targets)?);
+        return Ok(());
+    }
 
     let old_manifest_path = &manifests_path.join("old");
 
     if !old_manifest_path.exists() {
-        eprintln!("No previous versions exist to rollback to.");
-        std::process::exit(1)
+        fail(args.json, RollbackFailure::no_previous_version());
+    }
+
+    if args.repo_url.is_some() || args.additional_cache_path.is_some() {
+        let source = match &args.repo_url {
+            Some(repo_url) => Some(source_from_url(
+                &build_client(&args.client)?,
+                repo_url,
+                None,
+            )?),
+            None => None,
+        };
+        let options = UpdateOptions {
+            additional_cache_path: args.additional_cache_path,
+            chunk_layout: args.chunk_path_template.unwrap_or_default(),
+            ..Default::default()
+        };
+        match restore_rollback_chunks(source.as_deref(), &state, &options).await {
+            Ok(0) => (),
+            Ok(restored) => info!("Restored {restored} chunks the previous generation needs."),
+            Err(e) => fail(args.json, RollbackFailure::failed(&e)),
+        }
+    }
+    if let Err(e) = check_rollback_chunks(&state) {
+        fail(args.json, RollbackFailure::failed(&e));
     }
 
-    // Rollback to previous manifest
     let old_manifest = fs::read_to_string(old_manifest_path)?;
-    update_manifest(&old_manifest, manifests_path)?;
 
-    let (_, chunklist) = parse_manifest(&old_manifest);
+    let current_manifest = fs::read_to_string(manifests_path.join("current"))?;
+    let diff = diff_manifests(&current_manifest, &old_manifest)?;
+    if args.show_changes {
+        print!("{diff}");
+    }
 
-    build_tree(staging_path, chunks_path, &chunklist).expect("could not build staging");
+    if !args.yes && !confirm_swap("the previous generation", target_path, &diff) {
+        return Err("aborted".into());
+    }
 
-    renameat2(
-        AT_FDCWD,
-        staging_path,
-        AT_FDCWD,
-        &root_path.join("usr"),
-        RenameFlags::RENAME_EXCHANGE,
-    )?;
+    let summary = match rollback(root_path, &state, args.target.as_deref()) {
+        Ok(summary) => summary,
+        Err(e) => fail(args.json, RollbackFailure::failed(&e)),
+    };
 
-    println!("Rolled back successfully.");
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        info!("Rolled back successfully.");
+    }
 
     Ok(())
 }
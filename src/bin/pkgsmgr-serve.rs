@@ -0,0 +1,51 @@
+use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use pkgsmgr::logging::{LogOptions, init_logging};
+use pkgsmgr::manifest::POINTER;
+use pkgsmgr::serve::serve;
+
+/// Serves a packaged repo over HTTP, for testing updates locally.
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+    #[command(flatten)]
+    log: LogOptions,
+
+    /// The packager's output_path
+    repo_path: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    init_logging(&args.log);
+
+    // Any channel's pointer will do
+    let has_pointer = std::fs::read_dir(&args.repo_path)?.any(|entry| {
+        entry.is_ok_and(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name == POINTER || name.starts_with(&format!("{POINTER}-"))
+        })
+    });
+    if !has_pointer {
+        return Err(format!("{} has no manifest pointer", args.repo_path.display()).into());
+    }
+
+    let listener = TcpListener::bind(args.listen).await?;
+    info!(
+        "Serving {} on http://{}",
+        args.repo_path.display(),
+        listener.local_addr()?
+    );
+    serve(listener, args.repo_path).await?;
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+use pkgsmgr::root::StatePaths;
+use pkgsmgr::status::read_status;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(long)]
+    root_path: Option<PathBuf>,
+    /// Directory holding pkgsmgr's state, absolute or relative to the root [default: .pkgsmgr]
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    /// Print the status as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let root_path = &args.root_path.unwrap_or_else(|| PathBuf::from("/"));
+    let state = StatePaths::new(root_path, args.state_dir.as_deref());
+    let chunks_path = &state.chunkstore;
+    let manifests_path = &state.manifests;
+
+    let status = read_status(manifests_path, chunks_path)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    let Some(manifest_hash) = &status.manifest_hash else {
+        println!("Nothing installed.");
+        return Ok(());
+    };
+
+    println!("Manifest:    {manifest_hash}");
+    println!("Compression: {}", status.compression.unwrap_or_default());
+    println!("Hasher:      {}", status.hasher.unwrap_or_default());
+    if let Some(generated) = &status.generated {
+        println!("Generated:   {generated}");
+    }
+    println!("Files:       {}", status.files);
+    println!("Chunks:      {}", status.chunks);
+    println!("Installed:   {}kb", status.installed_kb);
+    if let Some(tree_hash) = &status.tree_hash {
+        println!("Tree hash:   {tree_hash}");
+    }
+    println!(
+        "Rollback:    {}",
+        if status.rollback_available {
+            "available"
+        } else {
+            "unavailable"
+        }
+    );
+    println!("Chunkstore:  {}kb", status.chunkstore_bytes / 1024);
+
+    Ok(())
+}
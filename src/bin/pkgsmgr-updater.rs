@@ -1,147 +1,336 @@
 use clap::Parser;
-use nix::fcntl::{AT_FDCWD, RenameFlags, renameat2};
-use std::fs;
-use std::path::PathBuf;
-use std::sync::LazyLock;
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use tracing::{info, warn};
 
-use pkgsmgr::chunks::{chunk_filename, clean_old_chunks, install_chunk};
-use pkgsmgr::manifest::{build_tree, parse_manifest, try_update_manifest_hash, update_manifest};
+use pkgsmgr::chunks::ChunkLayout;
+use pkgsmgr::config::{CONFIG_FILENAME, Config};
+use pkgsmgr::logging::{LogOptions, init_logging};
+use pkgsmgr::manifest::{ManifestDiff, manifest_to_json, parse_manifest};
+use pkgsmgr::notify::notify;
+use pkgsmgr::root::{StatePaths, check_root, confirm_swap, target_path};
+use pkgsmgr::source::source_from_url;
 use pkgsmgr::types::{Compression, HashType};
-use pkgsmgr::utils::get;
-
-static MAJOR_VERSION: LazyLock<usize> =
-    LazyLock::new(|| env!("CARGO_PKG_VERSION_MAJOR").parse::<usize>().unwrap());
-static MINOR_VERSION: LazyLock<usize> =
-    LazyLock::new(|| env!("CARGO_PKG_VERSION_MINOR").parse::<usize>().unwrap());
+use pkgsmgr::update::{
+    ConfirmSwap, UpdateOptions, list_files, read_manifest, requested_manifest_hash, update,
+};
+use pkgsmgr::utils::{ClientOptions, DEFAULT_BUFFER_SIZE, RateLimiter, build_client};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
-    repo_url: String,
+    /// HTTP(S) URL, file:// URL, or local path of the repo
+    repo_url: Option<String>,
+    /// Follow the repo's `manifest-<channel>` pointer, such as beta, rather than `manifest`
+    #[arg(long)]
+    channel: Option<String>,
     #[arg(long)]
     root_path: Option<PathBuf>,
+    /// Directory holding pkgsmgr's state, absolute or relative to the root [default: .pkgsmgr]
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    /// Directory under the root that's replaced, such as opt/app [default: usr]
+    #[arg(long)]
+    target: Option<PathBuf>,
+    /// TOML file supplying defaults for these flags [default: <state dir>/config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
     #[arg(long)]
     /// Useful for installers, where the installation media may contain relevant chunks already
     additional_cache_path: Option<PathBuf>,
+    /// Chunkstore shared by every root on this host. Chunks are downloaded into it once and
+    /// hardlinked into each root, and are never cleaned from it
+    #[arg(long)]
+    shared_chunk_cache: Option<PathBuf>,
+    /// Limit chunk downloads to this many bytes per second, across all downloads
+    #[arg(long)]
+    max_rate: Option<u64>,
+    /// Allow operating on `/`, or replacing a usr tree pkgsmgr doesn't manage
+    #[arg(long)]
+    allow_root: bool,
+    /// Install only from the chunkstore and --additional-cache-path, failing if any chunk, or the
+    /// dictionary they need, is missing
+    #[arg(long)]
+    offline: bool,
+    /// Install this manifest instead of fetching the latest one from the repo. `-` reads it from
+    /// stdin. Chunks are still fetched from the repo
+    #[arg(long)]
+    manifest_file: Option<PathBuf>,
+    /// Install the repo's manifest with this hash instead of the latest one, pinning a release
+    #[arg(long, conflicts_with = "manifest_file")]
+    manifest_hash: Option<String>,
+    /// Refuse manifests generated more than this many seconds ago, or without a Generated header,
+    /// so a mirror can't hold clients on an old release. The repo must be packaged with
+    /// --generated
+    #[arg(long)]
+    max_manifest_age: Option<u64>,
+    /// Shell command run after staging is verified, before swapping. A non-zero exit aborts the
+    /// swap. PKGSMGR_ROOT, PKGSMGR_NEW_MANIFEST and PKGSMGR_OLD_MANIFEST are set
+    #[arg(long)]
+    pre_swap_hook: Option<String>,
+    /// Shell command run after a successful swap, with the same environment as --pre-swap-hook.
+    /// A failure is only warned about, as the update is already applied
+    #[arg(long)]
+    post_swap_hook: Option<String>,
+    /// Print the paths the update added, removed, and modified once it's done. --json output lists
+    /// them anyway, under `changes`
+    #[arg(long)]
+    show_changes: bool,
+    /// How many previous manifests keep their chunks, 0 keeps only the current one [default: 1]
+    #[arg(long)]
+    keep_generations: Option<usize>,
+    /// Keep every old chunk and manifest after updating, for rolling back or forward more than
+    /// --keep-generations allows. Free them later with pkgsmgr-gc
+    #[arg(long)]
+    no_clean: bool,
+    /// Skip flushing chunks to disk as they're installed. Faster, but a crash can leave corrupt
+    /// chunks behind, so only for ephemeral roots
+    #[arg(long)]
+    no_sync: bool,
+    /// Bytes read at a time while downloading and hashing chunks. Larger suits fast links,
+    /// smaller suits devices short on memory [default: 65536]
+    #[arg(long)]
+    buffer_size: Option<NonZeroUsize>,
+    /// Download even if the chunkstore's filesystem looks too full for the update
+    #[arg(long)]
+    skip_space_check: bool,
+    /// Decode chunks with this instead of the manifest's Compression header, for mislabeled repos
+    #[arg(long)]
+    force_compression: Option<Compression>,
+    /// Check chunks with this instead of the manifest's Hasher header, for mislabeled repos
+    #[arg(long)]
+    force_hasher: Option<HashType>,
+    /// Decode chunks with this when the manifest has no Compression header [default: none]
+    #[arg(long)]
+    assume_compression: Option<Compression>,
+    /// Check chunks with this when the manifest has no Hasher header. Without it such manifests
+    /// are refused
+    #[arg(long)]
+    assume_hasher: Option<HashType>,
+    /// Where the repo keeps chunks within its chunks directory, as given to the packager
+    /// [default: {hash}{ext}]
+    #[arg(long)]
+    chunk_path_template: Option<ChunkLayout>,
+    /// Re-hash already downloaded chunks before reusing them, downloading any that are corrupt
+    /// again. Slower, as every chunk the manifest references is read
+    #[arg(long)]
+    verify_cache: bool,
+    /// Fetch missing chunks, rebuild the tree and swap it in even when the manifest is already
+    /// installed. With --verify-cache, corrupt chunks are fetched again too
+    #[arg(long)]
+    force: bool,
+    /// Install even if the manifest's MinVersion says this client is too old, only warning. For
+    /// recovering from a repo that declares it by mistake
+    #[arg(long)]
+    ignore_min_version: bool,
+    /// Refuse manifests with unknown headers, such as a misspelled `Compresion`, or with values
+    /// of known ones this client can't use, rather than only warning
+    #[arg(long)]
+    strict_headers: bool,
+    /// POST a JSON summary of each successful update to this URL
+    #[arg(long)]
+    notify_url: Option<String>,
+    /// Don't ask for confirmation once an update is found. Otherwise, on a terminal, the paths it
+    /// changes are listed before asking
+    #[arg(long, short)]
+    yes: bool,
+    /// Print every file the manifest installs, with its mode, size and hash, then exit without
+    /// installing anything
+    #[arg(long)]
+    list_files: bool,
+    /// Print the update summary as JSON. With --list-files, print the whole manifest as JSON,
+    /// its headers and its files in order
+    #[arg(long)]
+    json: bool,
+    #[command(flatten)]
+    log: LogOptions,
+    #[command(flatten)]
+    client: ClientOptions,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    init_logging(&args.log);
 
-    let root_path = &args.root_path.unwrap_or_else(|| PathBuf::from("/"));
-    let internal_path = &root_path.join(".pkgsmgr");
-    let chunks_path = &internal_path.join("chunkstore");
-    fs::create_dir_all(chunks_path)?;
-    let staging_path = &internal_path.join("staging");
-    let manifests_path = &internal_path.join("manifests");
-    fs::create_dir_all(manifests_path)?;
-
-    let manifest_hash = get(&format!("{}/manifest", &args.repo_url))
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
-
-    if !try_update_manifest_hash(manifests_path, &manifest_hash)? {
-        println!("[INFO] Skipping, no update found.");
-        std::process::exit(0);
-    };
-    println!("[INFO] Update found, downloading manifest...");
-
-    let manifest_raw = get(&format!("{}/{}", &args.repo_url, manifest_hash))
-        .await?
-        .text()
-        .await
-        .expect("server responded with 200, yet not valid utf8 text.");
-
-    let (headers, chunklist) = parse_manifest(&manifest_raw);
-
-    let mut compression = Compression::None;
-    let mut hasher = HashType::Blake3;
-
-    for (key, value) in headers {
-        match key {
-            "MinVersion" => {
-                let parts: Vec<usize> = value.split('.').map(|str| str.parse().unwrap()).collect();
-
-                // Major version check
-                if parts[0] < *MAJOR_VERSION {
-                    panic!("MinVersion declares major incompatibility. Outdated update client.")
-                }
-
-                // Minor version check
-                // Also checks major version is the same.
-                if let Some(min_version) = parts.get(1)
-                    && *min_version > *MINOR_VERSION
-                    && *MAJOR_VERSION == parts[0]
-                {
-                    panic!("MinVersion declares minor incompatibility. Outdated update client.")
-                }
-            }
-            "Compression" => match value.to_lowercase().as_str() {
-                "zstd" => {
-                    compression = Compression::Zstd;
-                }
-                _ => {
-                    eprintln!("Unknown compression requested: {}", value);
-                }
-            },
-            "Hasher" => match value.to_lowercase().as_str() {
-                "blake3" => {
-                    hasher = HashType::Blake3;
-                }
-                "xxh3_128" => hasher = HashType::Xxh3_128,
-                _ => {
-                    eprintln!("Unknown compression requested: {}", value);
-                }
-            },
-            _ => {
-                eprintln!("[WARNING] Unknown header: {key}");
-            }
+    let config = match &args.config {
+        Some(config_path) => Config::load(config_path)?,
+        None => {
+            let root_path = args.root_path.as_deref().unwrap_or(Path::new("/"));
+            let state = StatePaths::new(root_path, args.state_dir.as_deref());
+            Config::load_or_default(&state.state.join(CONFIG_FILENAME))?
         }
-    }
+    };
+    let config = config.merge(Config {
+        repo_url: args.repo_url,
+        channel: args.channel,
+        root_path: args.root_path,
+        state_dir: args.state_dir,
+        target: args.target,
+        additional_cache_path: args.additional_cache_path,
+        shared_chunk_cache: args.shared_chunk_cache,
+        max_rate: args.max_rate,
+        keep_generations: args.keep_generations,
+        ca_cert: args.client.ca_cert,
+        client_cert: args.client.client_cert,
+        client_key: args.client.client_key,
+        connect_timeout: args.client.connect_timeout,
+        request_timeout: args.client.request_timeout,
+        headers: (!args.client.headers.is_empty()).then_some(args.client.headers),
+        no_transfer_compression: args.client.no_transfer_compression.then_some(true),
+        notify_url: args.notify_url,
+    });
 
-    // Install all chunks in chunklist before doing anything else.
-    for chunk in &chunklist {
-        let chunk_path = chunks_path.join(chunk_filename(chunk));
+    let repo_url = &config
+        .repo_url
+        .ok_or("no repo_url given on the command line or in the config")?;
+    let client = build_client(&ClientOptions {
+        ca_cert: config.ca_cert,
+        client_cert: config.client_cert,
+        client_key: config.client_key,
+        connect_timeout: config.connect_timeout,
+        request_timeout: config.request_timeout,
+        headers: config.headers.unwrap_or_default(),
+        no_transfer_compression: config.no_transfer_compression.unwrap_or(false),
+    })?;
+    let source = source_from_url(&client, repo_url, config.channel.as_deref())?;
 
-        if !chunk_path.exists() {
-            install_chunk(chunk, &args.repo_url, chunks_path, &compression, hasher)
-                .await
-                .expect("could not download chunk");
+    // Read up front, as stdin can only be read once but the update needs the manifest twice
+    let (manifest, manifest_file) = match args.manifest_file {
+        Some(path) if path == Path::new("-") => {
+            let mut manifest = String::new();
+            std::io::stdin().read_to_string(&mut manifest)?;
+            parse_manifest(&manifest).map_err(|e| format!("invalid manifest on stdin: {e}"))?;
+            (Some(manifest), None)
+        }
+        manifest_file => (None, manifest_file),
+    };
+
+    if args.list_files {
+        let options = UpdateOptions {
+            manifest_file,
+            manifest,
+            manifest_hash: args.manifest_hash,
+            ..Default::default()
+        };
+        if args.json {
+            let manifest_hash = requested_manifest_hash(source.as_ref(), &options).await?;
+            let manifest = read_manifest(source.as_ref(), &options, &manifest_hash).await?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&manifest_to_json(&manifest)?)?
+            );
+            return Ok(());
         }
-    }
 
-    // Quit early if nothing has changed
-    if !update_manifest(&manifest_raw, manifests_path)
-        .expect("could not update local manifest cache")
-    {
+        for file in list_files(source.as_ref(), &options).await? {
+            println!(
+                "{:o} {}kb {} {}",
+                file.permissions, file.size, file.hash, file.path
+            );
+        }
         return Ok(());
     }
 
-    build_tree(staging_path, chunks_path, &chunklist).expect("could not build staging");
+    let root_path = &config.root_path.unwrap_or_else(|| PathBuf::from("/"));
+    let state = StatePaths::new(root_path, config.state_dir.as_deref());
+    let target_path = &target_path(root_path, config.target.as_deref(), &state)?;
+    check_root(root_path, target_path, &state, args.allow_root)?;
+    let confirm = (!args.yes).then(|| {
+        let repo_url = repo_url.clone();
+        let target_path = target_path.clone();
+        Arc::new(move |manifest_hash: &str, diff: &ManifestDiff| {
+            let source = format!("manifest {manifest_hash} from {repo_url}");
+            confirm_swap(&source, &target_path, diff)
+        }) as ConfirmSwap
+    });
+
+    let options = UpdateOptions {
+        rate_limiter: config.max_rate.map(RateLimiter::new),
+        additional_cache_path: config.additional_cache_path,
+        shared_chunk_cache: config.shared_chunk_cache,
+        keep_generations: config.keep_generations.unwrap_or(1),
+        clean: !args.no_clean,
+        offline: args.offline,
+        manifest_file,
+        manifest,
+        manifest_hash: args.manifest_hash,
+        pre_swap_hook: args.pre_swap_hook,
+        post_swap_hook: args.post_swap_hook,
+        state_dir: config.state_dir,
+        target: config.target,
+        max_manifest_age: args.max_manifest_age,
+        sync: !args.no_sync,
+        buffer_size: args
+            .buffer_size
+            .map_or(DEFAULT_BUFFER_SIZE, NonZeroUsize::get),
+        skip_space_check: args.skip_space_check,
+        force_compression: args.force_compression,
+        force_hasher: args.force_hasher,
+        assume_compression: args.assume_compression,
+        assume_hasher: args.assume_hasher,
+        chunk_layout: args.chunk_path_template.unwrap_or_default(),
+        verify_cache: args.verify_cache,
+        force: args.force,
+        ignore_min_version: args.ignore_min_version,
+        strict_headers: args.strict_headers,
+        abort: abort_on_ctrl_c(),
+        confirm,
+    };
 
-    println!("[INFO] Swapping tree...");
+    let started = Instant::now();
+    let Some(summary) = update(source.as_ref(), root_path, &options).await? else {
+        return Ok(());
+    };
+    if let Some(notify_url) = &config.notify_url {
+        notify(&client, notify_url, &summary, started.elapsed()).await;
+    }
 
-    let usr_path = root_path.join("usr");
-    if !usr_path.exists() {
-        fs::create_dir_all(&usr_path)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        if args.show_changes {
+            print!("{}", summary.changes);
+        }
+        info!(
+            tree_hash = %summary.tree_hash,
+            "Updated to {}: {} files changed, {} chunks downloaded ({}kb), {} chunks patched ({}kb \
+             of deltas), {} chunks freed ({}kb)",
+            summary.manifest_hash,
+            summary.files_changed,
+            summary.chunks_downloaded,
+            summary.bytes_downloaded / 1024,
+            summary.chunks_patched,
+            summary.delta_bytes / 1024,
+            summary.chunks_freed,
+            summary.bytes_freed / 1024
+        );
     }
 
-    renameat2(
-        AT_FDCWD,
-        staging_path,
-        AT_FDCWD,
-        &usr_path,
-        RenameFlags::RENAME_EXCHANGE,
-    )?;
+    Ok(())
+}
 
-    println!("[INFO] Cleaning up old chunks...");
+/// Asks the update to stop at its next safe point on the first Ctrl-C. A second one exits at once.
+fn abort_on_ctrl_c() -> Arc<AtomicBool> {
+    let abort = Arc::new(AtomicBool::new(false));
+    let flag = abort.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        warn!(
+            "Interrupted, stopping once the current chunk is installed. Press Ctrl-C again to quit now"
+        );
+        flag.store(true, Ordering::Relaxed);
 
-    let freed_bytes =
-        clean_old_chunks(manifests_path, chunks_path).expect("could not free old chunks");
-    println!("Freed {}kb", freed_bytes / 1024);
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
 
-    Ok(())
+    abort
 }
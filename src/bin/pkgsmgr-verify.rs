@@ -0,0 +1,80 @@
+use clap::Parser;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+use pkgsmgr::chunks::verify_chunkstore;
+use pkgsmgr::logging::{LogOptions, init_logging};
+use pkgsmgr::manifest::parse_manifest;
+use pkgsmgr::root::StatePaths;
+use pkgsmgr::types::HashType;
+use pkgsmgr::update::{HeaderPolicy, read_headers_assuming};
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(long)]
+    root_path: Option<PathBuf>,
+    /// Directory holding pkgsmgr's state, absolute or relative to the root [default: .pkgsmgr]
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    /// How many chunks to hash at once [default: one per core]
+    #[arg(long)]
+    jobs: Option<NonZeroUsize>,
+    /// Check chunks with this when the installed manifest has no Hasher header
+    #[arg(long)]
+    assume_hasher: Option<HashType>,
+    #[command(flatten)]
+    log: LogOptions,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    init_logging(&args.log);
+
+    let root_path = &args.root_path.unwrap_or_else(|| PathBuf::from("/"));
+    let state = StatePaths::new(root_path, args.state_dir.as_deref());
+
+    let current = match fs::read_to_string(state.manifests.join("current")) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!("nothing is installed under {}", root_path.display()).into());
+        }
+        current => current?,
+    };
+    let (headers, chunklist) = parse_manifest(&current)?;
+    let policy = HeaderPolicy {
+        assume_hasher: args.assume_hasher,
+        ..Default::default()
+    };
+    let (_, hash_method) = read_headers_assuming(&headers, &policy)?;
+
+    let report = verify_chunkstore(
+        &chunklist,
+        &state.chunkstore,
+        hash_method,
+        args.jobs.map(NonZeroUsize::get),
+    )?;
+
+    for path in &report.missing {
+        warn!("Missing {}", path.display());
+    }
+    for (path, e) in &report.corrupt {
+        warn!("Corrupt {}: {e}", path.display());
+    }
+    for (path, e) in &report.failures {
+        warn!("Couldn't read {}: {e}", path.display());
+    }
+
+    let bad = report.missing.len() + report.corrupt.len() + report.failures.len();
+    if bad > 0 {
+        return Err(format!(
+            "{bad} of {} chunks failed verification",
+            report.checked + bad
+        )
+        .into());
+    }
+    info!(chunks = report.checked, "Chunkstore verified");
+
+    Ok(())
+}
@@ -1,132 +1,1456 @@
-use async_compression::tokio::bufread::ZstdDecoder;
-use futures_util::TryStreamExt;
+use async_compression::tokio::bufread::{BrotliDecoder, Lz4Decoder, ZstdDecoder};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_util::io::StreamReader;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, warn};
 
-use crate::manifest::parse_manifest;
+use crate::dictionary::Dictionary;
+use crate::error::Error;
+use crate::manifest::{generation_paths, parse_manifest};
+use crate::platform;
+use crate::source::{ChunkReader, RepoSource};
 use crate::types::{Compression, HashType};
-use crate::utils::{Hasher, get};
+use crate::utils::{DEFAULT_BUFFER_SIZE, Hasher, RateLimiter};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Chunk {
     pub hash: String,
     pub size: u64,
     pub path: String,
     pub permissions: u32,
+    /// Modification time in Unix seconds, when the manifest records timestamps
+    pub mtime: Option<i64>,
+}
+
+/// Why a downloaded chunk was rejected.
+#[derive(Debug, PartialEq)]
+pub enum ChunkError {
+    /// The decompressed contents don't match the manifest's size, which is in kilobytes
+    SizeMismatch {
+        expected_kb: u64,
+        received_bytes: u64,
+    },
+    HashMismatch {
+        expected: String,
+        received: String,
+    },
 }
 
+impl std::fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkError::SizeMismatch {
+                expected_kb,
+                received_bytes,
+            } => write!(
+                f,
+                "Invalid size recieved. Got {received_bytes} bytes, but expected {expected_kb}kb"
+            ),
+            ChunkError::HashMismatch { expected, received } => write!(
+                f,
+                "Invalid hash recieved. Got {received}, but expected {expected}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+
+/// Judges a stream's compression from its first bytes. Brotli has no magic, so is never detected.
+pub fn detect_compression(prefix: &[u8]) -> Compression {
+    if prefix.starts_with(&ZSTD_MAGIC) {
+        Compression::Zstd
+    } else if prefix.starts_with(&LZ4_MAGIC) {
+        Compression::Lz4
+    } else {
+        Compression::None
+    }
+}
+
+/// How `install_chunk` finds, decodes and stores chunks.
+#[derive(Clone)]
+pub struct InstallOptions<'a> {
+    /// The manifest's compression
+    pub compression: Compression,
+    pub dictionary: Option<&'a Dictionary>,
+    pub hash_method: HashType,
+    /// Caps the download, shared with other installs
+    pub rate_limiter: Option<&'a RateLimiter>,
+    /// Flush the chunk and the chunkstore's entry for it to disk before returning
+    pub sync: bool,
+    /// Bytes read at a time
+    pub buffer_size: usize,
+    /// Where the repo keeps chunks within its `chunks` directory
+    pub layout: ChunkLayout,
+}
+
+impl Default for InstallOptions<'_> {
+    fn default() -> Self {
+        Self {
+            compression: Compression::None,
+            dictionary: None,
+            hash_method: HashType::Blake3,
+            rate_limiter: None,
+            sync: true,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            layout: ChunkLayout::default(),
+        }
+    }
+}
+
+/// Fetches a chunk from `source` into the chunkstore, returning how many bytes it holds.
+/// Chunks missing under the manifest's compression are looked for under their bare hash, as a
+/// repo may store some uncompressed, and decoded according to their magic bytes.
 pub async fn install_chunk(
+    source: &dyn RepoSource,
     chunk: &Chunk,
-    repo_url: &str,
     chunk_path: &Path,
-    compression: &Compression,
-    hash_method: HashType,
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("[INFO] Downloading {}", chunk.path);
-    let extension = match compression {
-        Compression::None => "",
-        Compression::Zstd => ".zstd",
+    options: &InstallOptions<'_>,
+) -> Result<u64, Error> {
+    let InstallOptions {
+        ref compression,
+        dictionary,
+        ref layout,
+        ..
+    } = *options;
+    let install = |reader, compression, dictionary| {
+        write_chunk(reader, chunk, chunk_path, compression, dictionary, options)
     };
-    let chunk_url = format!("{repo_url}/chunks/{}{extension}", chunk.hash);
-    let res = get(&chunk_url).await?;
 
-    let mut hasher: Hasher = Hasher::new(hash_method);
+    match source
+        .fetch_chunk(&repo_chunk_path(
+            layout,
+            &chunk.hash,
+            compression,
+            dictionary,
+        ))
+        .await
+    {
+        Ok(reader) => return install(reader, compression, dictionary).await,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && *compression != Compression::None => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    let uncompressed_path = layout.path(&chunk.hash, &Compression::None);
+    let mut reader = source.fetch_chunk(&uncompressed_path).await?;
+    let detected = detect_compression(reader.fill_buf().await?);
+    if detected != Compression::None {
+        if let Ok(bytes) = install(reader, &detected, None).await {
+            return Ok(bytes);
+        }
+
+        // Uncompressed files can start with the same magic, such as an already compressed file
+        // stored as is, so fetch it again to install verbatim
+        reader = source.fetch_chunk(&uncompressed_path).await?;
+    }
 
-    let temp_file_path = chunk_path.join(format!("{}.new", chunk.hash));
-    let mut temp_file = fs::File::create(&temp_file_path).await?;
+    install(reader, &Compression::None, None).await
+}
 
-    // Turn the response into a stream
-    let stream = res.bytes_stream();
+/// Installs a chunk's contents already in memory, such as one rebuilt from a delta, checking
+/// them like a download.
+pub async fn install_chunk_data(
+    data: Vec<u8>,
+    chunk: &Chunk,
+    chunk_path: &Path,
+    hash_method: HashType,
+    sync: bool,
+    buffer_size: usize,
+) -> Result<u64, Error> {
+    let options = InstallOptions {
+        hash_method,
+        sync,
+        buffer_size,
+        ..Default::default()
+    };
+    write_chunk(
+        Box::new(std::io::Cursor::new(data)),
+        chunk,
+        chunk_path,
+        &Compression::None,
+        None,
+        &options,
+    )
+    .await
+}
 
-    let stream_reader = StreamReader::new(stream.map_err(std::io::Error::other));
+/// Numbers this process's temporary chunk files, which also carry its pid.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
 
-    // Turn the response into a reader, decompressing if required.
+/// Decodes `raw_reader` into the chunkstore, checking it against the chunk's size and hash.
+/// It's decoded with `compression` and `dictionary` rather than `options`' own, which
+/// `install_chunk` falls back from for chunks stored otherwise.
+async fn write_chunk(
+    raw_reader: ChunkReader,
+    chunk: &Chunk,
+    chunk_path: &Path,
+    compression: &Compression,
+    dictionary: Option<&Dictionary>,
+    options: &InstallOptions<'_>,
+) -> Result<u64, Error> {
+    let InstallOptions {
+        hash_method,
+        rate_limiter,
+        sync,
+        buffer_size,
+        ..
+    } = *options;
+    let mut hasher = Hasher::new(hash_method)?;
+
+    // Several processes may fetch the same chunk into a shared cache at once, so each writes its
+    // own temporary file
+    let temp_file_path = chunk_path.join(format!(
+        "{}.{}-{}.new",
+        chunk.hash,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut temp_file = fs::File::create_new(&temp_file_path).await?;
+
+    // Decompress the chunk if required.
     let mut reader: Box<dyn tokio::io::AsyncRead + Unpin + Send> = match compression {
-        Compression::Zstd => Box::new(ZstdDecoder::new(stream_reader)),
-        Compression::None => Box::new(stream_reader),
+        Compression::Zstd => match dictionary {
+            Some(dictionary) => Box::new(ZstdDecoder::with_dict(raw_reader, &dictionary.data)?),
+            None => Box::new(ZstdDecoder::new(raw_reader)),
+        },
+        Compression::Brotli => Box::new(BrotliDecoder::new(raw_reader)),
+        Compression::Lz4 => Box::new(Lz4Decoder::new(raw_reader)),
+        Compression::None => Box::new(raw_reader),
     };
 
-    // 64kb buf
-    let mut buf = [0u8; 1024 * 64];
+    let mut buf = vec![0u8; buffer_size];
+    let mut bytes = 0;
     loop {
-        let n = reader.read(&mut buf).await.expect("network buf err");
+        let n = reader.read(&mut buf).await?;
         if n == 0 {
             break;
         }
 
-        let chunk = &buf[0..n];
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.consume(n).await;
+        }
+
+        let data = &buf[0..n];
+
+        hasher.write(data);
 
-        hasher.write(chunk);
+        temp_file.write_all(data).await?;
+        bytes += n as u64;
 
-        temp_file.write_all(chunk).await?;
+        // Stop early rather than download the rest of an oversized chunk
+        if bytes / 1024 > chunk.size {
+            break;
+        }
     }
 
-    let hash = hasher.digest();
+    let error = if bytes / 1024 != chunk.size {
+        Some(ChunkError::SizeMismatch {
+            expected_kb: chunk.size,
+            received_bytes: bytes,
+        })
+    } else {
+        let hash = hasher.digest();
+        (hash != chunk.hash).then(|| ChunkError::HashMismatch {
+            expected: chunk.hash.clone(),
+            received: hash,
+        })
+    };
 
-    if hash != *chunk.hash {
-        panic!(
-            "Invalid hash recieved. Got {hash}, but expected {}",
-            chunk.hash
-        )
+    if let Some(error) = error {
+        warn!(hash = %chunk.hash, "{error} for {}", chunk.path);
+        drop(temp_file);
+        fs::remove_file(&temp_file_path).await?;
+        return Err(error.into());
+    }
+
+    temp_file.flush().await?;
+    // Otherwise a crash can leave a renamed but empty chunk, which would be trusted next run
+    if sync {
+        temp_file.sync_all().await?;
     }
 
     // Set permissions
     let mut perms = temp_file.metadata().await?.permissions();
-    perms.set_mode(chunk.permissions);
+    platform::set_mode(&mut perms, chunk.permissions);
     perms.set_readonly(true);
     temp_file.set_permissions(perms).await?;
 
-    fs::rename(&temp_file_path, chunk_path.join(chunk_filename(chunk))).await?;
+    // Another process may have installed the same chunk meanwhile, verified just the same
+    drop(temp_file);
+    let installed_path = chunk_path.join(chunk_filename(chunk));
+    if installed_path.exists() {
+        platform::remove_readonly_file(&temp_file_path)?;
+        debug!(phase = "install", hash = %chunk.hash, "{} was already installed", chunk.path);
+        return Ok(bytes);
+    }
+    platform::replace_file(&temp_file_path, &installed_path)?;
+    if sync {
+        platform::sync_dir(chunk_path)?;
+    }
+    debug!(phase = "install", hash = %chunk.hash, bytes, "Installed {}", chunk.path);
 
-    Ok(())
+    Ok(bytes)
 }
 
+/// Chunks in the chunklist that aren't in the chunkstore yet, each listed once.
+pub fn missing_chunks<'a>(chunklist: &'a [Chunk], chunkstore_path: &Path) -> Vec<&'a Chunk> {
+    let mut seen = HashSet::new();
+
+    chunklist
+        .iter()
+        .filter(|chunk| {
+            let filename = chunk_filename(chunk);
+            !chunkstore_path.join(&filename).exists() && seen.insert(filename)
+        })
+        .collect()
+}
+
+/// The outcome of a chunkstore cleanup.
+#[derive(Debug, Default)]
+pub struct CleanReport {
+    pub freed_bytes: u64,
+    /// Every chunk removed, or that would be in a dry run, with its size in bytes
+    pub removed: Vec<(PathBuf, u64)>,
+    /// Chunks that couldn't be removed, left for the next cleanup
+    pub failures: Vec<(PathBuf, std::io::Error)>,
+}
+
+/// Chunks in the chunkstore that none of the chunklists in `keep` reference, sorted.
+/// Files still being downloaded, and ones pkgsmgr couldn't have named, are never listed.
+pub fn unreferenced_chunks(
+    chunkstore_path: &Path,
+    keep: &[&[Chunk]],
+) -> Result<Vec<PathBuf>, std::io::Error> {
+    let allowed_chunks: HashSet<_> = keep
+        .iter()
+        .flat_map(|chunklist| chunklist.iter().map(chunk_filename))
+        .collect();
+
+    let mut unreferenced = Vec::new();
+    for entry in std::fs::read_dir(chunkstore_path)? {
+        let entry = entry?;
+        // Chunk names are always ASCII, so anything else wasn't put there by pkgsmgr
+        let Ok(filename) = entry.file_name().into_string() else {
+            warn!("Skipping unrecognized file {}", entry.path().display());
+            continue;
+        };
+
+        // `.new` files may belong to a download still in progress
+        if filename.ends_with(".new") {
+            continue;
+        }
+
+        if !allowed_chunks.contains(&filename) {
+            unreferenced.push(entry.path());
+        }
+    }
+    unreferenced.sort();
+
+    Ok(unreferenced)
+}
+
+/// Removes every chunk not referenced by the newest `keep_generations` manifests before `current`.
+/// Manifests older than that are forgotten too, though `old` is always kept for rollback.
+/// Failing to remove a single chunk or old manifest doesn't stop the rest from being cleaned.
+/// A kept manifest that can't be read is reported too, but then no chunk is removed, as any of
+/// them could be one it needs.
+/// A dry run only reports what would be removed.
 pub fn clean_old_chunks(
     manifests_path: &Path,
     chunkstore_path: &Path,
-) -> Result<u64, std::io::Error> {
+    keep_generations: usize,
+    dry_run: bool,
+) -> Result<CleanReport, std::io::Error> {
+    use std::fs;
+
+    let mut failures = Vec::new();
+    let mut chunklists = Vec::new();
+    let mut unreadable = false;
+    for (generation, manifest_path) in generation_paths(manifests_path).iter().enumerate() {
+        if generation > keep_generations {
+            if generation > 1
+                && !dry_run
+                && let Err(e) = fs::remove_file(manifest_path)
+            {
+                failures.push((manifest_path.clone(), e));
+            }
+            continue;
+        }
+
+        let chunklist = fs::read_to_string(manifest_path).and_then(|manifest| {
+            parse_manifest(&manifest)
+                .map(|(_, chunklist)| chunklist)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        });
+        match chunklist {
+            Ok(chunklist) => chunklists.push(chunklist),
+            Err(e) => {
+                failures.push((manifest_path.clone(), e));
+                unreadable = true;
+            }
+        }
+    }
+
+    let mut report = match unreadable {
+        true => CleanReport::default(),
+        false => {
+            let keep: Vec<&[Chunk]> = chunklists.iter().map(Vec::as_slice).collect();
+            remove_chunks(unreferenced_chunks(chunkstore_path, &keep)?, dry_run)
+        }
+    };
+    report.failures.extend(failures);
+
+    Ok(report)
+}
+
+/// Removes every chunk in a shared chunk cache that no root links to any more, which is every one
+/// with no other hardlink. Roots that fell back to copying chunks don't keep them alive, and
+/// platforms that can't count links never remove anything.
+/// Chunks an update has just fetched aren't linked yet, so this shouldn't run during updates.
+pub fn clean_shared_cache(cache_path: &Path, dry_run: bool) -> Result<CleanReport, std::io::Error> {
+    let mut unlinked = Vec::new();
+    for path in unreferenced_chunks(cache_path, &[])? {
+        if platform::link_count(&std::fs::symlink_metadata(&path)?) == Some(1) {
+            unlinked.push(path);
+        }
+    }
+
+    Ok(remove_chunks(unlinked, dry_run))
+}
+
+/// Removes each chunk, carrying on past failures. A dry run only totals their sizes.
+fn remove_chunks(paths: Vec<PathBuf>, dry_run: bool) -> CleanReport {
     use std::fs;
 
-    let mut freed = 0;
-    let mut allowed_chunks = HashSet::new();
+    let remove = |path: &Path| -> Result<u64, std::io::Error> {
+        let size = fs::metadata(path)?.len();
+        if !dry_run {
+            platform::remove_readonly_file(path)?;
+        }
+        Ok(size)
+    };
+    let results: Vec<_> = paths
+        .into_par_iter()
+        .map(|path| match remove(&path) {
+            Ok(size) => Ok((path, size)),
+            Err(e) => Err((path, e)),
+        })
+        .collect();
+
+    let mut report = CleanReport::default();
+    for result in results {
+        match result {
+            Ok((path, size)) => {
+                report.freed_bytes += size;
+                report.removed.push((path, size));
+            }
+            Err(failure) => report.failures.push(failure),
+        }
+    }
+    report.removed.sort();
+
+    report
+}
+
+/// The outcome of checking the chunkstore against a chunklist.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// How many chunks were read and hashed
+    pub checked: usize,
+    pub missing: Vec<PathBuf>,
+    pub corrupt: Vec<(PathBuf, ChunkError)>,
+    /// Chunks that couldn't be read
+    pub failures: Vec<(PathBuf, std::io::Error)>,
+}
+
+/// Chunks at least this large are memory-mapped for hashing rather than read.
+const MMAP_THRESHOLD: u64 = 1024 * 1024;
 
-    let current_path = manifests_path.join("current");
-    let old_path = manifests_path.join("old");
+/// Re-hashes every chunk the chunklist references, across `jobs` threads or all cores.
+/// Each chunk is checked once, however many files share it.
+pub fn verify_chunkstore(
+    chunklist: &[Chunk],
+    chunkstore_path: &Path,
+    hash_method: HashType,
+    jobs: Option<usize>,
+) -> Result<VerifyReport, std::io::Error> {
+    let mut seen = HashSet::new();
+    let unique: Vec<_> = chunklist
+        .iter()
+        .filter(|chunk| seen.insert(chunk_filename(chunk)))
+        .collect();
+
+    let verify = || -> Vec<_> {
+        unique
+            .par_iter()
+            .map(|chunk| {
+                let path = chunkstore_path.join(chunk_filename(chunk));
+                let result = verify_chunk(chunk, &path, hash_method);
+                (path, result)
+            })
+            .collect()
+    };
+    let results = match jobs {
+        Some(jobs) => rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .map_err(io::Error::other)?
+            .install(verify),
+        None => verify(),
+    };
 
-    // Calculate a list of all chunks
-    if current_path.exists() {
-        let (_, chunklist) = parse_manifest(&fs::read_to_string(current_path)?);
-        for chunk in chunklist {
-            allowed_chunks.insert(chunk_filename(&chunk));
+    let mut report = VerifyReport::default();
+    for (path, result) in results {
+        match result {
+            Ok(None) => report.checked += 1,
+            Ok(Some(error)) => {
+                report.checked += 1;
+                report.corrupt.push((path, error));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => report.missing.push(path),
+            Err(e) => report.failures.push((path, e)),
         }
     }
-    if old_path.exists() {
-        let (_, chunklist) = parse_manifest(&fs::read_to_string(old_path)?);
-        for chunk in chunklist {
-            allowed_chunks.insert(chunk_filename(&chunk));
+    report.missing.sort();
+    report.corrupt.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(report)
+}
+
+/// Checks one installed chunk's size and hash, returning what's wrong with it if anything.
+fn verify_chunk(
+    chunk: &Chunk,
+    path: &Path,
+    hash_method: HashType,
+) -> Result<Option<ChunkError>, std::io::Error> {
+    use std::io::Read;
+
+    let file = std::fs::File::open(path)?;
+    let bytes = file.metadata()?.len();
+    if bytes / 1024 != chunk.size {
+        return Ok(Some(ChunkError::SizeMismatch {
+            expected_kb: chunk.size,
+            received_bytes: bytes,
+        }));
+    }
+
+    let mut hasher = Hasher::new(hash_method).map_err(io::Error::other)?;
+    // SAFETY: installed chunks are read-only and only ever replaced by rename, never modified
+    // in place, so the mapping can't change underneath the hasher.
+    // Filesystems that can't be mapped fall back to reading.
+    match (bytes >= MMAP_THRESHOLD).then(|| unsafe { memmap2::Mmap::map(&file) }) {
+        Some(Ok(map)) => hasher.write(&map),
+        _ => {
+            let mut reader = io::BufReader::new(file);
+            let mut buf = vec![0u8; DEFAULT_BUFFER_SIZE];
+            loop {
+                let n = reader.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.write(&buf[..n]);
+            }
         }
     }
 
-    for entry in fs::read_dir(chunkstore_path)? {
+    let hash = hasher.digest();
+    Ok((hash != chunk.hash).then(|| ChunkError::HashMismatch {
+        expected: chunk.hash.clone(),
+        received: hash,
+    }))
+}
+
+/// How long a `.new` temp file goes unwritten before it's taken as left behind by an interrupted
+/// `install_chunk`, rather than belonging to a download still in progress.
+pub const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Removes `.new` temp files left behind by an interrupted `install_chunk`, which are the ones not
+/// written to for `STALE_TEMP_AGE`.
+pub fn clean_temp_chunks(chunkstore_path: &Path) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(chunkstore_path)? {
         let entry = entry?;
-        let filename = entry
-            .file_name()
-            .into_string()
-            .expect("non utf8 filename in chunkstore.");
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "new") {
+            continue;
+        }
 
-        if !allowed_chunks.contains(&filename) {
-            freed += fs::metadata(entry.path())?.len();
-            fs::remove_file(entry.path())?;
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+        if age >= STALE_TEMP_AGE {
+            platform::remove_readonly_file(&path)?;
         }
     }
 
-    Ok(freed)
+    Ok(())
+}
+
+/// Shortest hash any hasher produces, in hex characters, which bounds `{hash:S:E}` ranges.
+const MIN_HASH_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum LayoutPart {
+    Literal(String),
+    /// Characters of the hash, or all of them
+    Hash(Option<(usize, usize)>),
+    Extension,
+}
+
+/// How chunks are arranged within a repo's `chunks` directory, given as a template like
+/// `{hash:0:2}/{hash}{ext}`. `{hash}` is the chunk's hash, `{hash:S:E}` its characters `S` to
+/// `E`, and `{ext}` the extension of its compression. Sharding by a hash prefix keeps directories
+/// small in repos with millions of chunks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkLayout {
+    parts: Vec<LayoutPart>,
+}
+
+impl Default for ChunkLayout {
+    /// Every chunk directly in `chunks`, as `<hash><ext>`.
+    fn default() -> Self {
+        Self {
+            parts: vec![LayoutPart::Hash(None), LayoutPart::Extension],
+        }
+    }
 }
 
+impl std::str::FromStr for ChunkLayout {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid chunk path template {template:?}: {reason}");
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(LayoutPart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid("unclosed `{`"))?
+                + start;
+
+            let placeholder = &rest[start + 1..end];
+            parts.push(match placeholder.split(':').collect::<Vec<_>>()[..] {
+                ["hash"] => LayoutPart::Hash(None),
+                ["ext"] => LayoutPart::Extension,
+                ["hash", from, to] => {
+                    let (Ok(from), Ok(to)) = (from.parse(), to.parse()) else {
+                        return Err(invalid("hash ranges are `{hash:S:E}`"));
+                    };
+                    if from >= to || to > MIN_HASH_LEN {
+                        return Err(invalid(&format!(
+                            "hash ranges must be non-empty and end by character {MIN_HASH_LEN}"
+                        )));
+                    }
+                    LayoutPart::Hash(Some((from, to)))
+                }
+                _ => return Err(invalid(&format!("unknown placeholder {{{placeholder}}}"))),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(LayoutPart::Literal(rest.to_string()));
+        }
+
+        // Anything less than the whole hash could give two chunks the same path
+        if !parts.contains(&LayoutPart::Hash(None)) {
+            return Err(invalid("it must contain {hash}"));
+        }
+        if template.starts_with('/')
+            || template
+                .split('/')
+                .any(|component| component.is_empty() || component == "..")
+        {
+            return Err(invalid(
+                "it must be a relative path without empty or `..` components",
+            ));
+        }
+
+        Ok(Self { parts })
+    }
+}
+
+impl ChunkLayout {
+    /// Path of a chunk relative to the `chunks` directory.
+    pub fn path(&self, hash: &str, compression: &Compression) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                LayoutPart::Literal(literal) => literal.as_str(),
+                LayoutPart::Hash(None) => hash,
+                LayoutPart::Hash(Some((from, to))) => hash.get(*from..*to).unwrap_or(hash),
+                LayoutPart::Extension => compression.extension(),
+            })
+            .collect()
+    }
+}
+
+/// Where a chunk lives under the repo's `chunks` directory. The packager writes exactly these
+/// paths, so its output can be served as is. Chunks compressed with a dictionary are kept apart
+/// by its hash, since they can only be decompressed with that same dictionary.
+pub fn repo_chunk_path(
+    layout: &ChunkLayout,
+    hash: &str,
+    compression: &Compression,
+    dictionary: Option<&Dictionary>,
+) -> String {
+    match dictionary {
+        Some(dictionary) => format!("{}/{}", dictionary.hash, layout.path(hash, compression)),
+        None => layout.path(hash, compression),
+    }
+}
+
+/// Name of an installed chunk within the chunkstore. Only the hash, so contents shared by paths
+/// with different modes are stored once. The chunk takes the mode of the path it was installed
+/// for, and `build_tree` copies it for paths wanting another.
 pub fn chunk_filename(chunk: &Chunk) -> String {
-    format!("{}{}", chunk.hash, chunk.permissions)
+    chunk.hash.clone()
+}
+
+/// The permission bits a chunk ends up with once installed. Chunks are stored read-only.
+pub fn installed_mode(chunk: &Chunk) -> u32 {
+    chunk.permissions & 0o7777 & !0o222
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::generation_path;
+    use crate::source::HttpSource;
+    use crate::test_utils::TestServer;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_chunk_layouts() {
+        let hash = "ab12cd34ef56ab12cd34ef56ab12cd34";
+
+        let flat = ChunkLayout::default();
+        assert_eq!(flat, "{hash}{ext}".parse().unwrap());
+        assert_eq!(flat.path(hash, &Compression::Zstd), format!("{hash}.zstd"));
+        assert_eq!(flat.path(hash, &Compression::None), hash);
+
+        let sharded: ChunkLayout = "{hash:0:2}/{hash}{ext}".parse().unwrap();
+        assert_eq!(
+            sharded.path(hash, &Compression::Lz4),
+            format!("ab/{hash}.lz4")
+        );
+        let dictionary = Dictionary::new(b"dictionary".to_vec());
+        assert_eq!(
+            repo_chunk_path(&sharded, hash, &Compression::Zstd, Some(&dictionary)),
+            format!("{}/ab/{hash}.zstd", dictionary.hash)
+        );
+
+        let nested: ChunkLayout = "store/{hash:0:2}/{hash:2:4}/{hash}".parse().unwrap();
+        assert_eq!(
+            nested.path(hash, &Compression::Brotli),
+            format!("store/ab/12/{hash}")
+        );
+
+        for invalid in [
+            "{hash:0:2}",
+            "{hash}{ext",
+            "{hash}{size}",
+            "{hash:2:2}/{hash}",
+            "{hash:0:64}/{hash}",
+            "/{hash}",
+            "../{hash}",
+            "a//{hash}",
+        ] {
+            assert!(invalid.parse::<ChunkLayout>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_download() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+
+        let content = vec![7u8; 10_000];
+        let hash = blake3::hash(&content).to_hex().to_string();
+        std::fs::create_dir_all(repo.child("chunks")).unwrap();
+        std::fs::write(repo.child("chunks").join(&hash), &content).unwrap();
+
+        let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+        let chunk = Chunk {
+            hash,
+            size: 9,
+            path: "file".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+
+        let rate_limiter = RateLimiter::new(10_000);
+        let start = Instant::now();
+        install_chunk(
+            &HttpSource::new(reqwest::Client::new(), &server.url),
+            &chunk,
+            chunkstore.path(),
+            &InstallOptions {
+                rate_limiter: Some(&rate_limiter),
+                sync: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(chunkstore.child(chunk_filename(&chunk)).exists());
+    }
+
+    #[tokio::test]
+    async fn test_redownload_over_readonly_chunk() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+
+        let content = "downloaded twice";
+        let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+        std::fs::create_dir_all(repo.child("chunks")).unwrap();
+        std::fs::write(repo.child("chunks").join(&hash), content).unwrap();
+
+        let chunk = Chunk {
+            hash: hash.clone(),
+            size: 0,
+            path: "file".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+        let source = crate::source::FileSource::new(repo.path());
+        let options = InstallOptions {
+            sync: false,
+            ..Default::default()
+        };
+        let install = || install_chunk(&source, &chunk, chunkstore.path(), &options);
+
+        install().await.unwrap();
+        let installed = chunkstore.child(chunk_filename(&chunk));
+        assert!(
+            std::fs::metadata(&installed)
+                .unwrap()
+                .permissions()
+                .readonly()
+        );
+
+        // A read-only leftover from an interrupted install, alongside the read-only chunk, could
+        // as well be another process's download, so installing leaves it to the cleanup
+        let leftover = chunkstore.child(format!("{hash}.1-0.new"));
+        std::fs::write(&leftover, "partial").unwrap();
+        let interrupted = std::time::SystemTime::now() - STALE_TEMP_AGE;
+        std::fs::File::options()
+            .write(true)
+            .open(&leftover)
+            .unwrap()
+            .set_modified(interrupted)
+            .unwrap();
+        let mut permissions = std::fs::metadata(&leftover).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&leftover, permissions).unwrap();
+
+        install().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&installed).unwrap(), content);
+        assert_eq!(std::fs::read_to_string(&leftover).unwrap(), "partial");
+        clean_temp_chunks(chunkstore.path()).unwrap();
+        assert!(!leftover.exists());
+
+        let manifests = temp_dir::TempDir::new().unwrap();
+        std::fs::write(manifests.child("current"), "---\n").unwrap();
+        let report = clean_old_chunks(manifests.path(), chunkstore.path(), 0, false).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert!(!installed.exists());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_installs() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+
+        let content = vec![5u8; 200_000];
+        let hash = blake3::hash(&content).to_hex().to_string();
+        std::fs::create_dir_all(repo.child("chunks")).unwrap();
+        std::fs::write(repo.child("chunks").join(&hash), &content).unwrap();
+
+        let chunk = Chunk {
+            hash: hash.clone(),
+            size: 195,
+            path: "file".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+        let source = crate::source::FileSource::new(repo.path());
+        let options = InstallOptions {
+            sync: false,
+            buffer_size: 1024,
+            ..Default::default()
+        };
+        let install = || install_chunk(&source, &chunk, chunkstore.path(), &options);
+
+        let results = futures_util::future::join_all((0..8).map(|_| install())).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(
+            std::fs::read(chunkstore.child(chunk_filename(&chunk))).unwrap(),
+            content
+        );
+        assert_eq!(std::fs::read_dir(chunkstore.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_synced_install() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+
+        let content = vec![3u8; 10_000];
+        let hash = blake3::hash(&content).to_hex().to_string();
+        let layout = ChunkLayout::default();
+        let repo_path =
+            repo.child("chunks")
+                .join(repo_chunk_path(&layout, &hash, &Compression::Zstd, None));
+        std::fs::create_dir_all(repo_path.parent().unwrap()).unwrap();
+        std::fs::write(&repo_path, zstd::encode_all(&content[..], 0).unwrap()).unwrap();
+
+        let chunk = Chunk {
+            hash: hash.clone(),
+            size: 9,
+            path: "file".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+        let options = InstallOptions {
+            compression: Compression::Zstd,
+            sync: true,
+            ..Default::default()
+        };
+        install_chunk(
+            &crate::source::FileSource::new(repo.path()),
+            &chunk,
+            chunkstore.path(),
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let installed = chunkstore.child(chunk_filename(&chunk));
+        assert_eq!(std::fs::read(&installed).unwrap(), content);
+        assert!(
+            std::fs::metadata(&installed)
+                .unwrap()
+                .permissions()
+                .readonly()
+        );
+        assert!(!chunkstore.child(format!("{hash}.new")).exists());
+    }
+
+    #[tokio::test]
+    async fn test_size_mismatch() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+
+        let content = vec![7u8; 10_000];
+        let hash = blake3::hash(&content).to_hex().to_string();
+        std::fs::create_dir_all(repo.child("chunks")).unwrap();
+        std::fs::write(repo.child("chunks").join(&hash), &content).unwrap();
+
+        let chunk = Chunk {
+            hash,
+            size: 20,
+            path: "file".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+
+        let error = install_chunk(
+            &crate::source::FileSource::new(repo.path()),
+            &chunk,
+            chunkstore.path(),
+            &InstallOptions {
+                sync: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+
+        assert!(
+            matches!(
+                error,
+                Error::Mismatch(ChunkError::SizeMismatch {
+                    expected_kb: 20,
+                    received_bytes: 10_000
+                })
+            ),
+            "{error:?}"
+        );
+        assert_eq!(std::fs::read_dir(chunkstore.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_compression() {
+        use crate::utils::{ClientOptions, build_client};
+
+        let content = "uncompressed in the repo ".repeat(100);
+        let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+        let repo = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir(repo.child("chunks")).unwrap();
+        std::fs::write(repo.child("chunks").join(&hash), &content).unwrap();
+
+        // Compresses in transit only when asked to
+        let server = TestServer::serve_dir_zstd(repo.path().to_path_buf()).await;
+
+        let chunk = Chunk {
+            hash: hash.clone(),
+            size: content.len() as u64 / 1024,
+            path: "file".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+        for no_transfer_compression in [false, true] {
+            let client = build_client(&ClientOptions {
+                no_transfer_compression,
+                ..Default::default()
+            })
+            .unwrap();
+            let chunkstore = temp_dir::TempDir::new().unwrap();
+            install_chunk(
+                &HttpSource::new(client, &server.url),
+                &chunk,
+                chunkstore.path(),
+                &InstallOptions {
+                    sync: false,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                std::fs::read_to_string(chunkstore.child(&hash)).unwrap(),
+                content
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunks_share_the_sources_client() {
+        use crate::utils::{ClientOptions, build_client};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let contents = ["first", "second", "third"];
+        let bodies: std::collections::HashMap<String, &str> = contents
+            .iter()
+            .map(|content| {
+                (
+                    blake3::hash(content.as_bytes()).to_hex().to_string(),
+                    *content,
+                )
+            })
+            .collect();
+
+        // Keeps connections open, counting them and the requests carrying the client's header
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let configured = Arc::new(AtomicUsize::new(0));
+        let (connections_log, configured_log) = (connections.clone(), configured.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                connections_log.fetch_add(1, Ordering::Relaxed);
+                let bodies = bodies.clone();
+                let configured_log = configured_log.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    loop {
+                        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match stream.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => request.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        let text = String::from_utf8_lossy(&request).to_lowercase();
+                        if text.contains("x-fleet: eu-west") {
+                            configured_log.fetch_add(1, Ordering::Relaxed);
+                        }
+                        let path = text.split_whitespace().nth(1).unwrap_or("/").to_string();
+                        request.clear();
+
+                        let body = bodies[path.trim_start_matches("/chunks/")];
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                            body.len()
+                        );
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        let client = build_client(&ClientOptions {
+            headers: vec!["X-Fleet: eu-west".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        let install_all = async |source: &HttpSource| {
+            let chunkstore = temp_dir::TempDir::new().unwrap();
+            for content in contents {
+                let chunk = Chunk {
+                    hash: blake3::hash(content.as_bytes()).to_hex().to_string(),
+                    size: 0,
+                    path: content.into(),
+                    permissions: 0o100644,
+                    mtime: None,
+                };
+                install_chunk(
+                    source,
+                    &chunk,
+                    chunkstore.path(),
+                    &InstallOptions {
+                        sync: false,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            }
+        };
+        install_all(&HttpSource::new(client, &url)).await;
+        assert_eq!(configured.load(Ordering::Relaxed), contents.len());
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        // The default client pools its one connection the same way, without the header
+        install_all(&HttpSource::with_default_client(&url).unwrap()).await;
+        assert_eq!(configured.load(Ordering::Relaxed), contents.len());
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mixed_compression() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        let chunks = repo.child("chunks");
+        std::fs::create_dir_all(&chunks).unwrap();
+
+        let zstd = |content: &[u8]| zstd::encode_all(content, 3).unwrap();
+        let compressed = b"compressed under its extension".to_vec();
+        let raw = b"stored as is".to_vec();
+        let bare_zstd = b"compressed without an extension".to_vec();
+        // A packaged .zst file stored as is still starts with zstd's magic
+        let raw_zst = zstd(b"an archive inside the tree");
+
+        let hash = |content: &[u8]| blake3::hash(content).to_hex().to_string();
+        std::fs::write(
+            chunks.join(format!("{}.zstd", hash(&compressed))),
+            zstd(&compressed),
+        )
+        .unwrap();
+        std::fs::write(chunks.join(hash(&raw)), &raw).unwrap();
+        std::fs::write(chunks.join(hash(&bare_zstd)), zstd(&bare_zstd)).unwrap();
+        std::fs::write(chunks.join(hash(&raw_zst)), &raw_zst).unwrap();
+
+        let source = crate::source::FileSource::new(repo.path());
+        for content in [compressed, raw, bare_zstd, raw_zst] {
+            let chunk = Chunk {
+                hash: hash(&content),
+                size: 0,
+                path: "file".into(),
+                permissions: 0o100644,
+                mtime: None,
+            };
+            install_chunk(
+                &source,
+                &chunk,
+                chunkstore.path(),
+                &InstallOptions {
+                    compression: Compression::Zstd,
+                    sync: false,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+            let installed = std::fs::read(chunkstore.child(chunk_filename(&chunk))).unwrap();
+            assert_eq!(installed, content);
+        }
+    }
+
+    #[test]
+    fn test_clean_continues_past_failures() {
+        let manifests = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        std::fs::write(manifests.child("current"), "---\n33188;0;kept;file\n").unwrap();
+
+        std::fs::write(chunkstore.child("kept"), "kept").unwrap();
+        std::fs::write(chunkstore.child("stale"), "stale").unwrap();
+        std::fs::write(chunkstore.child("other"), "other").unwrap();
+        std::fs::write(chunkstore.child("downloading.new"), "partial").unwrap();
+        // remove_file can't remove a directory, even as root
+        std::fs::create_dir_all(chunkstore.child("unremovable/inner")).unwrap();
+
+        let report = clean_old_chunks(manifests.path(), chunkstore.path(), 1, false).unwrap();
+
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0, chunkstore.child("unremovable"));
+        assert_eq!(
+            report.freed_bytes,
+            "stale".len() as u64 + "other".len() as u64
+        );
+        assert!(chunkstore.child("kept").exists());
+        assert!(chunkstore.child("downloading.new").exists());
+        assert!(!chunkstore.child("stale").exists());
+        assert!(!chunkstore.child("other").exists());
+
+        // Neither an unremovable old manifest nor an unreadable kept one stops the cleanup, though
+        // the latter keeps every chunk
+        std::fs::create_dir_all(generation_path(manifests.path(), 2).join("inner")).unwrap();
+        std::fs::create_dir(generation_path(manifests.path(), 1)).unwrap();
+        std::fs::write(chunkstore.child("unneeded"), "unneeded").unwrap();
+        let report = clean_old_chunks(manifests.path(), chunkstore.path(), 0, false).unwrap();
+        let mut failed: Vec<_> = report.failures.iter().map(|(path, _)| path).collect();
+        failed.sort();
+        assert_eq!(
+            failed,
+            [
+                &generation_path(manifests.path(), 2),
+                &chunkstore.child("unremovable")
+            ]
+        );
+        assert!(!chunkstore.child("unneeded").exists());
+
+        std::fs::write(chunkstore.child("unneeded"), "unneeded").unwrap();
+        let report = clean_old_chunks(manifests.path(), chunkstore.path(), 1, false).unwrap();
+        let failed: Vec<_> = report.failures.iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            failed,
+            [
+                &generation_path(manifests.path(), 1),
+                &generation_path(manifests.path(), 2)
+            ]
+        );
+        assert!(report.removed.is_empty());
+        assert!(chunkstore.child("unneeded").exists());
+    }
+
+    #[test]
+    fn test_clean_temp_chunks_only_removes_stale_ones() {
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        for name in ["aaaa.1-0.new", "aaaa.2-0.new", "aaaa"] {
+            std::fs::write(chunkstore.child(name), name).unwrap();
+        }
+        std::fs::File::options()
+            .write(true)
+            .open(chunkstore.child("aaaa.1-0.new"))
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - STALE_TEMP_AGE)
+            .unwrap();
+
+        clean_temp_chunks(chunkstore.path()).unwrap();
+        assert!(!chunkstore.child("aaaa.1-0.new").exists());
+        assert!(chunkstore.child("aaaa.2-0.new").exists());
+        assert!(chunkstore.child("aaaa").exists());
+    }
+
+    #[test]
+    fn test_unreferenced_chunks() {
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        for name in ["aaaa", "bbbb", "cccc", "dddd", "eeee.new"] {
+            std::fs::write(chunkstore.child(name), name).unwrap();
+        }
+        let chunk = |hash: &str| Chunk {
+            hash: hash.into(),
+            size: 0,
+            path: "file".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+        let current = [chunk("aaaa"), chunk("bbbb")];
+        let previous = [chunk("bbbb"), chunk("cccc"), chunk("ffff")];
+
+        let unreferenced = unreferenced_chunks(chunkstore.path(), &[&current, &previous]).unwrap();
+        assert_eq!(unreferenced, vec![chunkstore.child("dddd")]);
+
+        let unreferenced = unreferenced_chunks(chunkstore.path(), &[&current]).unwrap();
+        assert_eq!(
+            unreferenced,
+            vec![chunkstore.child("cccc"), chunkstore.child("dddd")]
+        );
+
+        // Keeping nothing still leaves downloads in progress alone
+        assert_eq!(
+            unreferenced_chunks(chunkstore.path(), &[]).unwrap().len(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_keep_generations() {
+        for keep_generations in [0, 1, 3] {
+            let manifests = temp_dir::TempDir::new().unwrap();
+            let chunkstore = temp_dir::TempDir::new().unwrap();
+
+            for generation in 0..5 {
+                let manifest = format!("---\n33188;0;gen{generation};file\n");
+                std::fs::write(generation_path(manifests.path(), generation), manifest).unwrap();
+                std::fs::write(chunkstore.child(format!("gen{generation}")), "").unwrap();
+            }
+
+            clean_old_chunks(manifests.path(), chunkstore.path(), keep_generations, false).unwrap();
+
+            for generation in 0..5 {
+                let kept = chunkstore.child(format!("gen{generation}")).exists();
+                assert_eq!(kept, generation <= keep_generations);
+
+                let manifest_kept = generation_path(manifests.path(), generation).exists();
+                assert_eq!(manifest_kept, generation <= keep_generations.max(1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_chunks_size() {
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        std::fs::write(chunkstore.child("installed"), "").unwrap();
+
+        let chunk = |hash: &str, size, path: &str| Chunk {
+            hash: hash.into(),
+            size,
+            path: path.into(),
+            permissions: 33188,
+            mtime: None,
+        };
+        let chunklist = [
+            chunk("installed", 100, "a"),
+            chunk("missing", 20, "b"),
+            chunk("missing", 20, "c"),
+            chunk("other", 3, "d"),
+        ];
+
+        let missing = missing_chunks(&chunklist, chunkstore.path());
+        let total_kb: u64 = missing.iter().map(|chunk| chunk.size).sum();
+
+        assert_eq!(missing.len(), 2);
+        assert_eq!(total_kb, 23);
+    }
+
+    #[test]
+    fn test_clean_dry_run() {
+        let manifests = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        std::fs::write(manifests.child("current"), "---\n33188;0;kept;file\n").unwrap();
+        std::fs::write(chunkstore.child("kept"), "kept").unwrap();
+        std::fs::write(chunkstore.child("stale"), "stale").unwrap();
+
+        let report = clean_old_chunks(manifests.path(), chunkstore.path(), 1, true).unwrap();
+        assert_eq!(report.removed, [(chunkstore.child("stale"), 5)]);
+        assert_eq!(report.freed_bytes, 5);
+        assert!(chunkstore.child("stale").exists());
+
+        let report = clean_old_chunks(manifests.path(), chunkstore.path(), 1, false).unwrap();
+        assert_eq!(report.removed, [(chunkstore.child("stale"), 5)]);
+        assert!(!chunkstore.child("stale").exists());
+        assert!(chunkstore.child("kept").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_clean_shared_cache() {
+        let cache = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        for name in ["linked", "unlinked", "downloading.1-0.new"] {
+            std::fs::write(cache.child(name), name).unwrap();
+        }
+        std::fs::hard_link(cache.child("linked"), chunkstore.child("linked")).unwrap();
+
+        let report = clean_shared_cache(cache.path(), false).unwrap();
+        assert_eq!(report.removed, [(cache.child("unlinked"), 8)]);
+        assert!(cache.child("linked").exists());
+        assert!(cache.child("downloading.1-0.new").exists());
+
+        std::fs::remove_file(chunkstore.child("linked")).unwrap();
+        let report = clean_shared_cache(cache.path(), false).unwrap();
+        assert_eq!(report.removed, [(cache.child("linked"), 6)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_clean_skips_non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let manifests = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        std::fs::write(manifests.child("current"), "---\n33188;0;kept;file\n").unwrap();
+        std::fs::write(chunkstore.child("kept"), "kept").unwrap();
+        std::fs::write(chunkstore.child("stale"), "stale").unwrap();
+        let stray = chunkstore.path().join(OsStr::from_bytes(b"stray\xff"));
+        std::fs::write(&stray, "stray").unwrap();
+
+        let report = clean_old_chunks(manifests.path(), chunkstore.path(), 1, false).unwrap();
+
+        assert_eq!(report.removed, [(chunkstore.child("stale"), 5)]);
+        assert!(chunkstore.child("kept").exists());
+        assert!(stray.exists());
+    }
+
+    #[test]
+    fn test_verify_chunkstore_in_parallel() {
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        let mut chunklist = Vec::new();
+        for i in 0..200 {
+            // Every tenth file is large enough to be mapped
+            let content = match i % 10 {
+                0 => vec![i as u8; MMAP_THRESHOLD as usize + i],
+                _ => format!("file {i}").repeat(300).into_bytes(),
+            };
+            let chunk = Chunk {
+                hash: blake3::hash(&content).to_hex().to_string(),
+                size: content.len() as u64 / 1024,
+                path: format!("file{i}"),
+                permissions: 0o100644,
+                mtime: None,
+            };
+            std::fs::write(chunkstore.child(chunk_filename(&chunk)), &content).unwrap();
+            chunklist.push(chunk);
+        }
+        // A file sharing a chunk doesn't get it checked twice
+        chunklist.push(Chunk {
+            path: "copy".into(),
+            ..chunklist[1].clone()
+        });
+
+        let path = |i: usize| chunkstore.child(chunk_filename(&chunklist[i]));
+        std::fs::write(path(7), "file 7".repeat(300).replace('7', "8")).unwrap();
+        let mut large = std::fs::read(path(30)).unwrap();
+        large[1000] ^= 0xff;
+        std::fs::write(path(30), large).unwrap();
+        std::fs::write(path(51), "short").unwrap();
+        std::fs::remove_file(path(99)).unwrap();
+
+        let report =
+            verify_chunkstore(&chunklist, chunkstore.path(), HashType::Blake3, Some(4)).unwrap();
+
+        assert_eq!(report.checked, 199);
+        assert_eq!(report.missing, [path(99)]);
+        let mut corrupt: Vec<_> = report
+            .corrupt
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut expected = vec![path(7), path(30), path(51)];
+        corrupt.sort();
+        expected.sort();
+        assert_eq!(corrupt, expected);
+        assert!(report.failures.is_empty());
+    }
 }
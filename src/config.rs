@@ -0,0 +1,140 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the updater's config, inside the state directory.
+pub const CONFIG_FILENAME: &str = "config.toml";
+
+/// Updater settings read from a TOML file. Every field is optional, and command line flags take
+/// precedence over it.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub repo_url: Option<String>,
+    /// Release channel whose pointer, `manifest-<channel>`, is followed
+    pub channel: Option<String>,
+    pub root_path: Option<PathBuf>,
+    /// Only takes effect after loading, so a config found in the default state directory can't
+    /// move it
+    pub state_dir: Option<PathBuf>,
+    pub target: Option<PathBuf>,
+    pub additional_cache_path: Option<PathBuf>,
+    pub shared_chunk_cache: Option<PathBuf>,
+    pub max_rate: Option<u64>,
+    pub keep_generations: Option<usize>,
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub connect_timeout: Option<u64>,
+    pub request_timeout: Option<u64>,
+    /// Extra `Name: value` headers sent with every request
+    pub headers: Option<Vec<String>>,
+    pub no_transfer_compression: Option<bool>,
+    /// URL POSTed a JSON summary after each successful update
+    pub notify_url: Option<String>,
+}
+
+impl Config {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        toml::from_str(raw).map_err(|e| format!("invalid config: {e}"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let raw = fs::read_to_string(path)
+            .map_err(|e| format!("could not read config {}: {e}", path.display()))?;
+
+        Self::parse(&raw)
+    }
+
+    /// Loads the config if it exists, otherwise returns an empty one.
+    pub fn load_or_default(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Self::load(path)
+    }
+
+    /// Fills every field `overrides` leaves unset from this config.
+    pub fn merge(self, overrides: Config) -> Config {
+        Config {
+            repo_url: overrides.repo_url.or(self.repo_url),
+            channel: overrides.channel.or(self.channel),
+            root_path: overrides.root_path.or(self.root_path),
+            state_dir: overrides.state_dir.or(self.state_dir),
+            target: overrides.target.or(self.target),
+            additional_cache_path: overrides
+                .additional_cache_path
+                .or(self.additional_cache_path),
+            shared_chunk_cache: overrides.shared_chunk_cache.or(self.shared_chunk_cache),
+            max_rate: overrides.max_rate.or(self.max_rate),
+            keep_generations: overrides.keep_generations.or(self.keep_generations),
+            ca_cert: overrides.ca_cert.or(self.ca_cert),
+            client_cert: overrides.client_cert.or(self.client_cert),
+            client_key: overrides.client_key.or(self.client_key),
+            connect_timeout: overrides.connect_timeout.or(self.connect_timeout),
+            request_timeout: overrides.request_timeout.or(self.request_timeout),
+            headers: overrides.headers.or(self.headers),
+            no_transfer_compression: overrides
+                .no_transfer_compression
+                .or(self.no_transfer_compression),
+            notify_url: overrides.notify_url.or(self.notify_url),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+repo_url = "https://example.com/repo"
+root_path = "/mnt/target"
+max_rate = 1000
+"#;
+
+    #[test]
+    fn test_config_only() {
+        let config = Config::parse(CONFIG).unwrap().merge(Config::default());
+
+        assert_eq!(config.repo_url.as_deref(), Some("https://example.com/repo"));
+        assert_eq!(config.root_path, Some(PathBuf::from("/mnt/target")));
+        assert_eq!(config.max_rate, Some(1000));
+        assert_eq!(config.keep_generations, None);
+    }
+
+    #[test]
+    fn test_flags_override_config() {
+        let flags = Config {
+            repo_url: Some("/media/repo".into()),
+            ..Default::default()
+        };
+        let config = Config::parse(CONFIG).unwrap().merge(flags);
+
+        assert_eq!(config.repo_url.as_deref(), Some("/media/repo"));
+        assert_eq!(config.max_rate, Some(1000));
+    }
+
+    #[test]
+    fn test_layout_and_transfer_keys() {
+        let raw = r#"
+state_dir = "var/lib/pkgsmgr"
+target = "opt/app"
+no_transfer_compression = true
+"#;
+        let flags = Config {
+            target: Some("opt/other".into()),
+            ..Default::default()
+        };
+        let config = Config::parse(raw).unwrap().merge(flags);
+
+        assert_eq!(config.state_dir, Some(PathBuf::from("var/lib/pkgsmgr")));
+        assert_eq!(config.target, Some(PathBuf::from("opt/other")));
+        assert_eq!(config.no_transfer_compression, Some(true));
+    }
+
+    #[test]
+    fn test_rejects_unknown_keys() {
+        assert!(Config::parse("repo-url = \"typo\"").is_err());
+    }
+}
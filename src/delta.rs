@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use zstd::zstd_safe::{CParameter, DParameter};
+
+/// Directory of the repo holding deltas, named by their blake3 hash.
+pub const DELTA_DIR: &str = "deltas";
+
+/// The largest window a delta may need, which bounds the files worth patching to 1GiB.
+const MAX_WINDOW_LOG: u32 = 30;
+
+/// A zstd patch rebuilding one chunk from another, as listed in a manifest's `Deltas` header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    /// Hash of the chunk the delta rebuilds
+    pub target: String,
+    /// Hash of the chunk it's applied to
+    pub base: String,
+    /// Blake3 hash of the delta itself
+    pub hash: String,
+}
+
+/// Formats deltas as the value of the `Deltas` header: `target:base:delta` triples separated by
+/// spaces.
+pub fn format_deltas(deltas: &[Delta]) -> String {
+    deltas
+        .iter()
+        .map(|delta| format!("{}:{}:{}", delta.target, delta.base, delta.hash))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Reads a `Deltas` header, by the hash of the chunk each delta rebuilds.
+pub fn parse_deltas(value: &str) -> Result<HashMap<String, Delta>, String> {
+    value
+        .split_whitespace()
+        .map(|entry| {
+            let mut fields = entry.split(':');
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(target), Some(base), Some(hash), None)
+                    if !target.is_empty() && !base.is_empty() && !hash.is_empty() =>
+                {
+                    let delta = Delta {
+                        target: target.to_string(),
+                        base: base.to_string(),
+                        hash: hash.to_string(),
+                    };
+                    Ok((delta.target.clone(), delta))
+                }
+                _ => Err(format!(
+                    "invalid Deltas entry {entry:?}, expected target:base:delta"
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Window large enough for zstd to match anywhere in the base while compressing the target.
+fn window_log(base: &[u8], target: &[u8]) -> u32 {
+    let size = base.len().max(target.len()).max(1) as u64;
+    (u64::BITS - (size - 1).leading_zeros()).clamp(10, MAX_WINDOW_LOG)
+}
+
+/// Compresses `target` against `base`, like `zstd --patch-from`, so what they share costs next to
+/// nothing.
+pub fn create_delta(base: &[u8], target: &[u8], level: i32) -> Result<Vec<u8>, io::Error> {
+    let mut compressor = zstd::bulk::Compressor::with_dictionary(level, base)?;
+    compressor.set_parameter(CParameter::WindowLog(window_log(base, target)))?;
+    compressor.set_parameter(CParameter::EnableLongDistanceMatching(true))?;
+
+    compressor.compress(target)
+}
+
+/// Rebuilds a delta's target from its base. The result is at most `max_size` bytes, and still
+/// needs checking against the target's hash.
+pub fn apply_delta(base: &[u8], delta: &[u8], max_size: usize) -> Result<Vec<u8>, io::Error> {
+    let mut decompressor = zstd::bulk::Decompressor::with_dictionary(base)?;
+    decompressor.set_parameter(DParameter::WindowLogMax(MAX_WINDOW_LOG))?;
+
+    decompressor.decompress(delta, max_size)
+}
+
+/// Writes a delta into the repo at `output_path`, returning its hash.
+pub fn write_delta(output_path: &Path, delta: &[u8]) -> Result<String, io::Error> {
+    let hash = blake3::hash(delta).to_hex().to_string();
+    let deltas_path = output_path.join(DELTA_DIR);
+    fs::create_dir_all(&deltas_path)?;
+    fs::write(deltas_path.join(&hash), delta)?;
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_roundtrip() {
+        // Incompressible on its own, so only matching the base can make the delta small
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let base: Vec<u8> = (0..256 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let mut target = base.clone();
+        target[100_000..100_016].copy_from_slice(b"a small edit....");
+
+        let delta = create_delta(&base, &target, 3).unwrap();
+        assert!(delta.len() < 1024, "{} bytes", delta.len());
+        assert_eq!(apply_delta(&base, &delta, target.len()).unwrap(), target);
+        assert!(apply_delta(&base, &delta, target.len() - 1).is_err());
+
+        let deltas = vec![Delta {
+            target: "bbbb".into(),
+            base: "aaaa".into(),
+            hash: "cccc".into(),
+        }];
+        let parsed = parse_deltas(&format_deltas(&deltas)).unwrap();
+        assert_eq!(parsed["bbbb"], deltas[0]);
+        assert!(parse_deltas("bbbb:aaaa").is_err());
+        assert!(parse_deltas("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Directory of the repo holding dictionaries, named by their blake3 hash.
+pub const DICTIONARY_DIR: &str = "dictionaries";
+
+/// Files larger than this gain little from a dictionary, so aren't trained on.
+const MAX_SAMPLE_SIZE: u64 = 128 * 1024;
+
+/// A zstd dictionary shared by every chunk of a manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dictionary {
+    pub hash: String,
+    pub data: Vec<u8>,
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            hash: blake3::hash(&data).to_hex().to_string(),
+            data,
+        }
+    }
+
+    /// Trains a dictionary of up to `max_size` bytes over the small files in `files`.
+    pub fn train(files: &[PathBuf], max_size: usize) -> Result<Self, io::Error> {
+        let mut samples = Vec::new();
+        for file in files {
+            // Empty files have nothing to teach it
+            if (1..=MAX_SAMPLE_SIZE).contains(&fs::metadata(file)?.len()) {
+                samples.push(fs::read(file)?);
+            }
+        }
+
+        Ok(Self::new(zstd::dict::from_samples(&samples, max_size)?))
+    }
+
+    /// Writes the dictionary into the repo at `output_path`.
+    pub fn write(&self, output_path: &Path) -> Result<(), io::Error> {
+        let dictionaries_path = output_path.join(DICTIONARY_DIR);
+        fs::create_dir_all(&dictionaries_path)?;
+        fs::write(dictionaries_path.join(&self.hash), &self.data)
+    }
+}
@@ -0,0 +1,143 @@
+use std::io;
+
+use crate::chunks::ChunkError;
+
+/// What the library's entry points fail with, so embedders can tell failures apart.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The repo couldn't be reached, or answered with an error
+    #[error(transparent)]
+    Network(io::Error),
+    #[error(transparent)]
+    Io(io::Error),
+    /// A manifest, pointer or header that couldn't be understood
+    #[error("{0}")]
+    Parse(String),
+    /// A chunk whose contents don't match the manifest
+    #[error(transparent)]
+    Mismatch(#[from] ChunkError),
+    /// The manifest needs a newer client
+    #[error("{0}")]
+    Incompatible(String),
+    /// The new tree couldn't be swapped in, so the old one is still in place
+    #[error(transparent)]
+    Swap(io::Error),
+    /// Stopped through `UpdateOptions::abort`, or declined by `UpdateOptions::confirm`, before the
+    /// swap
+    #[error("aborted, no changes applied")]
+    Aborted,
+    #[error("{0}")]
+    Other(String),
+}
+
+impl Error {
+    /// Prefixes the message with what was being done, keeping the kind of failure.
+    pub(crate) fn context(self, context: impl std::fmt::Display) -> Self {
+        let wrap = |e: io::Error| io::Error::new(e.kind(), format!("{context}: {e}"));
+        match self {
+            Error::Network(e) => Error::Network(wrap(e)),
+            Error::Io(e) => Error::Io(wrap(e)),
+            Error::Parse(message) => Error::Parse(format!("{context}: {message}")),
+            // Logged with the chunk's path where it's found
+            Error::Mismatch(e) => Error::Mismatch(e),
+            Error::Incompatible(message) => Error::Incompatible(format!("{context}: {message}")),
+            Error::Swap(e) => Error::Swap(wrap(e)),
+            Error::Aborted => Error::Aborted,
+            Error::Other(message) => Error::Other(format!("{context}: {message}")),
+        }
+    }
+}
+
+// Sources report HTTP failures as IO errors wrapping reqwest's, or as timeouts
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.get_ref() {
+            Some(inner) if inner.is::<reqwest::Error>() => Error::Network(e),
+            _ if e.kind() == io::ErrorKind::TimedOut => Error::Network(e),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Other(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::Other(message.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::HttpSource;
+    use crate::test_utils::MemorySource;
+    use crate::update::{UpdateOptions, update};
+
+    #[tokio::test]
+    async fn test_error_variants() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let options = UpdateOptions::default();
+        let install = |source| {
+            let root = root.path().to_path_buf();
+            let options = &options;
+            async move { update(&source, &root, options).await.unwrap_err() }
+        };
+
+        let mut source = MemorySource::default();
+        source.insert("manifest", "<html>Gateway Timeout</html>");
+        assert!(matches!(install(source).await, Error::Parse(_)));
+
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "tool")]);
+        let manifest = "MinVersion: 999.0\nHasher: blake3\n---\n";
+        source.publish_manifest(manifest);
+        assert!(matches!(install(source).await, Error::Incompatible(_)));
+
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "tool")]);
+        let hash = blake3::hash(b"tool").to_hex().to_string();
+        source.insert(&format!("chunks/{hash}"), "toot");
+        assert!(matches!(
+            install(source).await,
+            Error::Mismatch(ChunkError::HashMismatch { .. })
+        ));
+
+        // A dictionary that isn't the one the manifest names
+        let mut source = MemorySource::default();
+        let chunk_hash = blake3::hash(b"tool").to_hex().to_string();
+        let dictionary_hash = "0".repeat(64);
+        let manifest = format!(
+            "Compression: zstd\nDictionary: {dictionary_hash}\nHasher: blake3\n---\n\
+            420;0;{chunk_hash};bin/tool\n"
+        );
+        source.publish_manifest(&manifest);
+        source.insert(&format!("dictionaries/{dictionary_hash}"), "not it");
+        assert!(matches!(
+            install(source).await,
+            Error::Mismatch(ChunkError::HashMismatch { .. })
+        ));
+
+        // A cached chunk trusted as is, but the wrong size, fails staging's verification
+        let fresh = temp_dir::TempDir::new().unwrap();
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "tool")]);
+        let chunkstore = crate::root::StatePaths::new(fresh.path(), None).chunkstore;
+        std::fs::create_dir_all(&chunkstore).unwrap();
+        std::fs::write(chunkstore.join(&chunk_hash), [0; 2048]).unwrap();
+        let error = update(&source, fresh.path(), &options).await.unwrap_err();
+        assert!(matches!(error, Error::Swap(_)), "{error:?}");
+
+        // Nothing listens on a port just released
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let source = HttpSource::new(reqwest::Client::new(), &url);
+        let error = update(&source, root.path(), &options).await.unwrap_err();
+        assert!(matches!(error, Error::Network(_)), "{error:?}");
+    }
+}
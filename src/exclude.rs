@@ -0,0 +1,87 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const IGNORE_FILENAME: &str = ".pkgsmgrignore";
+
+/// Glob based filter for paths relative to the packaged tree.
+/// Patterns starting with `!` re-include paths matched by an earlier exclusion.
+pub struct Excludes {
+    exclude: GlobSet,
+    include: GlobSet,
+}
+
+impl Excludes {
+    pub fn new(patterns: &[String]) -> Result<Self, globset::Error> {
+        let mut exclude = GlobSetBuilder::new();
+        let mut include = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            match pattern.strip_prefix('!') {
+                Some(negated) => include.add(Glob::new(negated)?),
+                None => exclude.add(Glob::new(pattern)?),
+            };
+        }
+
+        Ok(Self {
+            exclude: exclude.build()?,
+            include: include.build()?,
+        })
+    }
+
+    /// Matches a path relative to the packaged tree.
+    /// An excluded directory is pruned entirely, so negations can't reach inside it.
+    pub fn is_excluded(&self, relative_path: &Path) -> bool {
+        self.exclude.is_match(relative_path) && !self.include.is_match(relative_path)
+    }
+}
+
+/// Reads patterns from an ignore file, one per line. Blank lines and `#` comments are skipped.
+pub fn read_ignore_file(path: &Path) -> Result<Vec<String>, io::Error> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excludes(patterns: &[&str]) -> Excludes {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        Excludes::new(&patterns).unwrap()
+    }
+
+    #[test]
+    fn test_directory_exclusion() {
+        let excludes = excludes(&[".git"]);
+
+        assert!(excludes.is_excluded(Path::new(".git")));
+        assert!(!excludes.is_excluded(Path::new("src/.gitkeep")));
+    }
+
+    #[test]
+    fn test_recursive_glob() {
+        let excludes = excludes(&["**/cache/**", "**/*.tmp"]);
+
+        assert!(excludes.is_excluded(Path::new("share/app/cache/a/b")));
+        assert!(excludes.is_excluded(Path::new("deep/nested/file.tmp")));
+        assert!(!excludes.is_excluded(Path::new("share/app/file.txt")));
+    }
+
+    #[test]
+    fn test_negation() {
+        let excludes = excludes(&["*.log", "!keep.log"]);
+
+        assert!(excludes.is_excluded(Path::new("debug.log")));
+        assert!(!excludes.is_excluded(Path::new("keep.log")));
+    }
+}
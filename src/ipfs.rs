@@ -0,0 +1,207 @@
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio_util::io::StreamReader;
+use tracing::debug;
+
+use crate::source::{ChunkReader, HttpSource, RepoSource, http_error};
+use crate::utils::get;
+
+/// Value of the `ChunkCids` header for repos that also publish each chunk as a raw IPFS block,
+/// addressed by a CID built from its blake3 hash.
+pub const RAW_CHUNK_CIDS: &str = "raw";
+
+const CID_VERSION: u8 = 0x01;
+const RAW_CODEC: u8 = 0x55;
+const BLAKE3_MULTIHASH: u8 = 0x1e;
+
+/// RFC 4648 base32, lowercase and unpadded, as CIDs are written.
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[usize::from((buffer >> bits) & 31)] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[usize::from((buffer << (5 - bits)) & 31)] as char);
+    }
+
+    encoded
+}
+
+/// The CIDv1 of a raw block whose blake3 hash is `hash`, or `None` if `hash` isn't one.
+pub fn raw_cid(hash: &str) -> Option<String> {
+    if hash.len() != 64 {
+        return None;
+    }
+    let digest = hex::decode(hash).ok()?;
+
+    let mut cid = vec![CID_VERSION, RAW_CODEC, BLAKE3_MULTIHASH, digest.len() as u8];
+    cid.extend(digest);
+    // Multibase prefix for base32
+    Some(format!("b{}", base32(&cid)))
+}
+
+/// A repo read through an IPFS HTTP gateway. Everything is fetched by path under the repo's root,
+/// except that chunks of manifests declaring `ChunkCids: raw` are fetched by CID, so any gateway
+/// or peer holding them can serve them.
+pub struct IpfsSource {
+    client: reqwest::Client,
+    gateway: String,
+    repo: HttpSource,
+    raw_chunks: AtomicBool,
+}
+
+impl IpfsSource {
+    /// `root` is the repo's path on the gateway, such as `/ipns/repo.example.org` or
+    /// `/ipfs/<cid>`.
+    pub fn new(client: reqwest::Client, gateway: &str, root: &str) -> Self {
+        let gateway = gateway.trim_end_matches('/').to_string();
+        let repo = HttpSource::new(
+            client.clone(),
+            &format!("{gateway}/{}", root.trim_start_matches('/')),
+        );
+
+        Self {
+            client,
+            gateway,
+            repo,
+            raw_chunks: AtomicBool::new(false),
+        }
+    }
+
+    /// Reads the pointer named `pointer` rather than `manifest`, see `pointer_name`.
+    pub fn with_pointer(mut self, pointer: impl Into<String>) -> Self {
+        self.repo = self.repo.with_pointer(pointer);
+        self
+    }
+
+    async fn fetch_block(&self, cid: &str) -> Result<ChunkReader, io::Error> {
+        let res = get(&self.client, &format!("{}/ipfs/{cid}", self.gateway))
+            .await
+            .map_err(http_error)?;
+
+        Ok(Box::new(StreamReader::new(
+            res.bytes_stream().map_err(http_error),
+        )))
+    }
+}
+
+#[async_trait]
+impl RepoSource for IpfsSource {
+    async fn fetch_pointer(&self) -> Result<String, io::Error> {
+        self.repo.fetch_pointer().await
+    }
+
+    async fn fetch_manifest(&self, hash: &str) -> Result<String, io::Error> {
+        let manifest = self.repo.fetch_manifest(hash).await?;
+        let raw_chunks = manifest
+            .lines()
+            .take_while(|line| *line != "---")
+            .filter_map(|line| line.split_once(':'))
+            .any(|(key, value)| key == "ChunkCids" && value.trim() == RAW_CHUNK_CIDS);
+        self.raw_chunks.store(raw_chunks, Ordering::Relaxed);
+
+        Ok(manifest)
+    }
+
+    async fn fetch_chunk(&self, filename: &str) -> Result<ChunkReader, io::Error> {
+        // Only uncompressed chunks are stored under their bare hash, the one a CID can be built from
+        let hash = filename.rsplit('/').next().unwrap_or(filename);
+        if self.raw_chunks.load(Ordering::Relaxed)
+            && let Some(cid) = raw_cid(hash)
+        {
+            match self.fetch_block(&cid).await {
+                Ok(reader) => return Ok(reader),
+                Err(e) => debug!("Fetching block {cid} failed, falling back to its path: {e}"),
+            }
+        }
+
+        self.repo.fetch_chunk(filename).await
+    }
+
+    async fn fetch_dictionary(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        self.repo.fetch_dictionary(hash).await
+    }
+
+    async fn fetch_delta(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        self.repo.fetch_delta(hash).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestServer;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_raw_cid() {
+        assert_eq!(base32(b"foobar"), "mzxw6ytboi");
+        assert_eq!(base32(b"fooba"), "mzxw6ytb");
+
+        let cid = raw_cid(&blake3::hash(b"").to_hex()).unwrap();
+        assert!(cid.starts_with("bafkr4i"), "{cid}");
+        // The multibase prefix, then 36 bytes in base32
+        assert_eq!(cid.len(), 1 + 58);
+        assert_eq!(raw_cid("abcd"), None);
+        assert_eq!(raw_cid(&"z".repeat(64)), None);
+    }
+
+    #[tokio::test]
+    async fn test_ipfs_gateway() {
+        let gateway = temp_dir::TempDir::new().unwrap();
+        let repo = gateway.child("ipns/repo.example.org");
+        std::fs::create_dir_all(repo.join("chunks")).unwrap();
+        std::fs::create_dir_all(gateway.child("ipfs")).unwrap();
+
+        // One chunk is only pinned as a block, the other only sits in the repo
+        let block = blake3::hash(b"block").to_hex().to_string();
+        let pathed = blake3::hash(b"pathed").to_hex().to_string();
+        std::fs::write(
+            gateway.child("ipfs").join(raw_cid(&block).unwrap()),
+            "block",
+        )
+        .unwrap();
+        std::fs::write(repo.join("chunks").join(&pathed), "pathed").unwrap();
+        let manifest = format!(
+            "ChunkCids: raw\nHasher: blake3\n---\n420;0;{block};block\n420;0;{pathed};pathed\n"
+        );
+        let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        std::fs::write(repo.join(&manifest_hash), &manifest).unwrap();
+        std::fs::write(repo.join("manifest"), &manifest_hash).unwrap();
+
+        let server = TestServer::serve_dir(gateway.path().to_path_buf()).await;
+        let source = IpfsSource::new(
+            reqwest::Client::new(),
+            &server.url,
+            "/ipns/repo.example.org",
+        );
+        assert_eq!(source.fetch_pointer().await.unwrap(), manifest_hash);
+        assert_eq!(
+            source.fetch_manifest(&manifest_hash).await.unwrap(),
+            manifest
+        );
+
+        for (hash, expected) in [(&block, "block"), (&pathed, "pathed")] {
+            let mut chunk = String::new();
+            let mut reader = source.fetch_chunk(hash).await.unwrap();
+            reader.read_to_string(&mut chunk).await.unwrap();
+            assert_eq!(chunk, expected);
+        }
+        assert!(
+            server
+                .requested_paths()
+                .contains(&format!("/ipfs/{}", raw_cid(&block).unwrap()))
+        );
+    }
+}
@@ -1,4 +1,28 @@
+pub mod archive;
 pub mod chunks;
+pub mod config;
+pub mod delta;
+pub mod dictionary;
+pub mod error;
+pub mod exclude;
+#[cfg(feature = "ipfs")]
+pub mod ipfs;
+pub mod logging;
 pub mod manifest;
+pub mod notify;
+pub mod packager;
+pub mod platform;
+pub mod rollback;
+pub mod root;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod source;
+pub mod status;
+#[cfg(test)]
+mod test_utils;
+pub mod transaction;
 pub mod types;
+pub mod update;
 pub mod utils;
+
+pub use error::Error;
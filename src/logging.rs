@@ -0,0 +1,248 @@
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::writer::{MakeWriter, MakeWriterExt};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// `[INFO] message` lines, warnings on stderr
+    #[default]
+    Human,
+    /// One JSON object per event on stdout, including its fields
+    Json,
+}
+
+/// Logging flags shared by every binary.
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct LogOptions {
+    /// How log lines are printed
+    #[arg(long, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+    /// Log more: -v adds per-chunk sizes, URLs and timings, -vv everything
+    #[arg(long, short, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Only log errors
+    #[arg(long, short, conflicts_with = "verbose")]
+    pub quiet: bool,
+}
+
+impl LogOptions {
+    /// The most detailed level that gets logged.
+    pub fn level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::ERROR,
+            (false, 0) => LevelFilter::INFO,
+            (false, 1) => LevelFilter::DEBUG,
+            (false, _) => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Prints only an event's message, prefixed by its level.
+struct HumanFormat;
+
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for HumanFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut message = MessageVisitor::default();
+        event.record(&mut message);
+
+        let level = match *event.metadata().level() {
+            Level::ERROR => "ERROR",
+            Level::WARN => "WARNING",
+            Level::INFO => "INFO",
+            Level::DEBUG => "DEBUG",
+            Level::TRACE => "TRACE",
+        };
+
+        writeln!(writer, "[{level}] {}", message.0)
+    }
+}
+
+/// Installs the global subscriber every log line goes through.
+pub fn init_logging(options: &LogOptions) {
+    match options.log_format {
+        LogFormat::Human => human_subscriber(
+            options.level(),
+            std::io::stderr
+                .with_max_level(Level::WARN)
+                .or_else(std::io::stdout),
+        )
+        .init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_max_level(options.level())
+            .init(),
+    }
+}
+
+fn human_subscriber<W>(level: LevelFilter, writer: W) -> impl Subscriber + Send + Sync
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
+        .event_format(HumanFormat)
+        .with_max_level(level)
+        .with_writer(writer)
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::FileSource;
+    use crate::update::{UpdateOptions, update};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_levels() {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Args {
+            #[command(flatten)]
+            log: LogOptions,
+        }
+
+        let level = |args: &[&str]| Args::try_parse_from(args).map(|args| args.log.level());
+        assert_eq!(level(&["bin"]).unwrap(), LevelFilter::INFO);
+        assert_eq!(level(&["bin", "-q"]).unwrap(), LevelFilter::ERROR);
+        assert_eq!(level(&["bin", "-v"]).unwrap(), LevelFilter::DEBUG);
+        assert_eq!(level(&["bin", "-vv"]).unwrap(), LevelFilter::TRACE);
+        assert!(level(&["bin", "-q", "-v"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quiet_hides_downloads() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir_all(repo.child("chunks")).unwrap();
+        let mut hashes = Vec::new();
+        for version in ["v1", "v2"] {
+            let hash = blake3::hash(version.as_bytes()).to_hex().to_string();
+            std::fs::write(repo.child("chunks").join(&hash), version).unwrap();
+            hashes.push(hash);
+        }
+
+        let run = |hash: String, quiet: bool| {
+            let repo = repo.path().to_path_buf();
+            async move {
+                let root = temp_dir::TempDir::new().unwrap();
+                let manifest = format!("Hasher: blake3\n---\n{};0;{hash};bin/tool\n", 0o100644);
+                let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+                std::fs::write(repo.join(&manifest_hash), manifest).unwrap();
+                std::fs::write(repo.join("manifest"), &manifest_hash).unwrap();
+
+                let captured = Captured::default();
+                let writer = captured.clone();
+                let options = LogOptions {
+                    quiet,
+                    ..Default::default()
+                };
+                let subscriber = human_subscriber(options.level(), move || writer.clone());
+                let _guard = tracing::subscriber::set_default(subscriber);
+
+                update(
+                    &FileSource::new(&repo),
+                    root.path(),
+                    &UpdateOptions::default(),
+                )
+                .await
+                .unwrap();
+
+                String::from_utf8(captured.0.lock().unwrap().clone()).unwrap()
+            }
+        };
+
+        let output = run(hashes[0].clone(), false).await;
+        assert!(output.contains("[INFO] Downloading bin/tool"), "{output}");
+        assert_eq!(run(hashes[1].clone(), true).await, "");
+    }
+
+    #[tokio::test]
+    async fn test_json_download_event() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir_all(repo.child("chunks")).unwrap();
+
+        let content = "logged content";
+        let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+        std::fs::write(repo.child("chunks").join(&hash), content).unwrap();
+
+        let manifest = format!("Hasher: blake3\n---\n{};0;{hash};share/logged\n", 0o100644);
+        let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        std::fs::write(repo.child(&manifest_hash), manifest).unwrap();
+        std::fs::write(repo.child("manifest"), &manifest_hash).unwrap();
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_max_level(Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let source = FileSource::new(repo.path());
+        update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap();
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        let download = events
+            .iter()
+            .find(|event| event["fields"]["phase"] == "download")
+            .expect("no download event");
+        assert_eq!(download["fields"]["hash"], hash.as_str());
+        assert_eq!(download["fields"]["path"], "share/logged");
+
+        let installed = events
+            .iter()
+            .find(|event| event["fields"]["phase"] == "install")
+            .expect("no install event");
+        assert_eq!(installed["fields"]["hash"], hash.as_str());
+        assert_eq!(installed["fields"]["bytes"], content.len());
+    }
+}
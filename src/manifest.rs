@@ -1,32 +1,269 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
-use crate::chunks::{Chunk, chunk_filename};
+use serde::Serialize;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
 
-pub fn try_update_manifest_hash(manifests_path: &Path, hash: &str) -> Result<bool, io::Error> {
-    let hash_path = &manifests_path.join("latest_hash");
+use crate::chunks::{Chunk, chunk_filename, installed_mode};
+use crate::error::Error;
+use crate::platform::{
+    device, exchange, file_mode, link_or_copy, reflink_or_copy, set_mode, set_mtime,
+};
 
-    let old_hash = fs::read_to_string(hash_path).unwrap_or("_".to_string());
+/// Whether `hash` differs from the last manifest hash an update completed with.
+pub fn manifest_hash_changed(manifests_path: &Path, hash: &str) -> bool {
+    let old_hash =
+        fs::read_to_string(manifests_path.join("latest_hash")).unwrap_or("_".to_string());
 
-    if old_hash == hash {
-        Ok(false)
-    } else {
-        fs::write(hash_path, hash)?;
-        Ok(true)
+    old_hash != hash
+}
+
+/// Records `hash` as installed, so later updates skip it until the repo moves on.
+pub fn record_manifest_hash(manifests_path: &Path, hash: &str) -> Result<(), io::Error> {
+    fs::write(manifests_path.join("latest_hash"), hash)
+}
+
+/// The ETag the repo's pointer had when the installed manifest was taken from it, if any.
+pub fn read_pointer_etag(manifests_path: &Path) -> Option<String> {
+    fs::read_to_string(manifests_path.join("pointer_etag")).ok()
+}
+
+/// Records the pointer's ETag, or forgets it when the installed manifest didn't come from the
+/// pointer or the repo sent none.
+pub fn record_pointer_etag(manifests_path: &Path, etag: Option<&str>) -> Result<(), io::Error> {
+    let path = manifests_path.join("pointer_etag");
+    match etag {
+        Some(etag) => fs::write(path, etag),
+        None => match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Name of a repo's pointer to its latest manifest.
+pub const POINTER: &str = "manifest";
+
+/// Name of the pointer for a release channel, `manifest-<channel>`, or `manifest` without one.
+/// Channels of one repo share its manifests and chunks.
+pub fn pointer_name(channel: Option<&str>) -> Result<String, String> {
+    let Some(channel) = channel else {
+        return Ok(POINTER.to_string());
+    };
+
+    let valid = channel
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'));
+    if channel.is_empty() || !valid || channel.starts_with('.') {
+        return Err(format!(
+            "invalid channel {channel:?}, expected letters, digits, `-`, `_` and `.`"
+        ));
+    }
+
+    Ok(format!("{POINTER}-{channel}"))
+}
+
+/// Checks a repo's `manifest` pointer holds a single blake3 hash, as the packager writes it,
+/// rather than something truncated or an error page from a proxy.
+pub fn parse_pointer(raw_pointer: &str) -> Result<String, Error> {
+    let hash = raw_pointer.strip_suffix('\n').unwrap_or(raw_pointer);
+
+    if hash.len() != blake3::OUT_LEN * 2 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        let preview: String = hash.chars().take(32).collect();
+        return Err(Error::Parse(format!(
+            "Invalid manifest pointer, expected a {}-character hex hash but got {preview:?}",
+            blake3::OUT_LEN * 2
+        )));
+    }
+
+    Ok(hash.to_string())
+}
+
+/// Formats Unix seconds for the `Generated` header, as an RFC 3339 UTC timestamp.
+pub fn format_generated(unix_seconds: i64) -> Result<String, String> {
+    let error =
+        |e: &dyn std::error::Error| format!("could not format timestamp {unix_seconds}: {e}");
+    OffsetDateTime::from_unix_timestamp(unix_seconds)
+        .map_err(|e| error(&e))?
+        .format(&Rfc3339)
+        .map_err(|e| error(&e))
+}
+
+/// Reads a `Generated` header back into Unix seconds.
+pub fn parse_generated(value: &str) -> Result<i64, String> {
+    OffsetDateTime::parse(value, &Rfc3339)
+        .map(OffsetDateTime::unix_timestamp)
+        .map_err(|e| format!("invalid Generated header {value:?}: {e}"))
+}
+
+/// Newest version of the chunk line layout this client reads. Version 2 splits files across
+/// several chunks, so the packager only writes it for manifests that do.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// File type bits of a regular file, `S_IFREG`, which chunk modes carry.
+const REGULAR_FILE: u32 = 0o100000;
+
+/// Groups a chunklist by file. A file split across several chunks lists them on consecutive lines
+/// sharing its path, in order.
+pub fn file_chunks(chunks: &[Chunk]) -> impl Iterator<Item = &[Chunk]> {
+    chunks.chunk_by(|a, b| a.path == b.path)
+}
+
+/// Root hash over the tree a chunklist describes, attesting to every path's contents and mode
+/// without reading the files. Each (path, hash, mode) is hashed into a leaf, and the root hashes
+/// the leaves sorted by path, so it doesn't depend on the manifest's line order.
+pub fn tree_hash(chunks: &[Chunk]) -> String {
+    let mut sorted: Vec<&Chunk> = chunks.iter().collect();
+    // Stable, so a split file's parts stay in order
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut root = blake3::Hasher::new();
+    for chunk in sorted {
+        let leaf = format!("{}\0{}\0{}", chunk.path, chunk.hash, chunk.permissions);
+        root.update(blake3::hash(leaf.as_bytes()).as_bytes());
     }
+
+    root.finalize().to_hex().to_string()
 }
 
-pub fn parse_manifest(raw_manifest: &str) -> (HashMap<&str, &str>, Vec<Chunk>) {
+/// Name of a manifest's zstd-compressed copy in the repo, beside the plain one older clients read.
+pub fn compressed_manifest_name(hash: &str) -> String {
+    format!("{hash}.zstd")
+}
+
+pub fn compress_manifest(raw_manifest: &str) -> Result<Vec<u8>, io::Error> {
+    zstd::encode_all(raw_manifest.as_bytes(), zstd::DEFAULT_COMPRESSION_LEVEL)
+}
+
+pub fn decompress_manifest(compressed: &[u8]) -> Result<String, io::Error> {
+    String::from_utf8(zstd::decode_all(compressed)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Splits a manifest into its headers and chunks, refusing layouts this client doesn't know.
+pub fn parse_manifest(raw_manifest: &str) -> Result<(HashMap<&str, &str>, Vec<Chunk>), Error> {
     let (raw_headers, raw_chunklist) = raw_manifest
         .split_once("---")
-        .expect("No divider. Invalid repo.");
+        .ok_or_else(|| Error::Parse("No divider. Invalid repo.".into()))?;
 
     let headers = parse_headers(raw_headers);
-    let chunklist = parse_chunklist(raw_chunklist);
+    let format_version = check_format_version(&headers)?;
+    let with_mtime = headers.get("Timestamps") == Some(&"mtime");
+    let (chunklist, invalid) = parse_chunklist(raw_chunklist, with_mtime);
+
+    // The chunklist starts on the divider's line, numbered from 1
+    let first_line = raw_headers.lines().count() + 1;
+    // Installing what parsed would silently leave files out, so refuse the whole manifest
+    if !invalid.is_empty() {
+        let lines: Vec<_> = invalid
+            .iter()
+            .map(|invalid| format!("line {}: {}", first_line + invalid.line, invalid.reason))
+            .collect();
+        return Err(Error::Parse(format!(
+            "Manifest has {} invalid chunk lines: {}",
+            invalid.len(),
+            lines.join("; ")
+        )));
+    }
+
+    if let Some(count) = headers.get("ChunkCount") {
+        let count: usize = count
+            .parse()
+            .map_err(|_| Error::Parse(format!("Invalid ChunkCount {count:?}")))?;
+        if count != chunklist.len() {
+            return Err(Error::Parse(format!(
+                "Manifest declares {count} chunks but lists {}, so it may have been truncated",
+                chunklist.len()
+            )));
+        }
+    }
 
-    (headers, chunklist)
+    // Every non-blank line parsed, so they pair up with the chunks in order
+    let line_numbers: Vec<usize> = raw_chunklist
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, _)| first_line + index)
+        .collect();
+    check_duplicate_paths(&chunklist, &line_numbers, format_version >= 2).map_err(Error::Parse)?;
+
+    Ok((headers, chunklist))
+}
+
+/// Refuses paths listed more than once, which staging can't hold both of. Only a split file's
+/// parts may share a path, on consecutive lines, and only from format version 2.
+fn check_duplicate_paths(
+    chunklist: &[Chunk],
+    line_numbers: &[usize],
+    splits_allowed: bool,
+) -> Result<(), String> {
+    let mut first_lines = HashMap::new();
+    let mut duplicates = Vec::new();
+
+    let mut index = 0;
+    for parts in file_chunks(chunklist) {
+        let path = parts[0].path.as_str();
+        let line = line_numbers[index];
+        if !splits_allowed && parts.len() > 1 {
+            duplicates.push(format!("{path} on lines {line} and {}", line + 1));
+        } else if let Some(first_line) = first_lines.get(path) {
+            duplicates.push(format!("{path} on lines {first_line} and {line}"));
+        }
+        first_lines.entry(path).or_insert(line);
+        index += parts.len();
+    }
+
+    if !duplicates.is_empty() {
+        return Err(format!(
+            "Manifest lists {} paths more than once: {}",
+            duplicates.len(),
+            duplicates.join("; ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Manifests written before the `FormatVersion` header existed are version 1.
+fn check_format_version(headers: &HashMap<&str, &str>) -> Result<u32, Error> {
+    let Some(value) = headers.get("FormatVersion") else {
+        return Ok(1);
+    };
+
+    let version: u32 = value
+        .parse()
+        .map_err(|_| Error::Parse(format!("Invalid FormatVersion {value:?}")))?;
+    if !(1..=FORMAT_VERSION).contains(&version) {
+        return Err(Error::Incompatible(format!(
+            "Manifest uses format version {version}, but this client reads up to version \
+             {FORMAT_VERSION}. Update your client."
+        )));
+    }
+
+    Ok(version)
+}
+
+/// Whether `raw` has a manifest's shape: `Key: value` header lines, then a `---` divider. Catches
+/// pages served in a manifest's place, like a proxy's error page, before they're parsed.
+pub fn looks_like_manifest(raw: &str) -> bool {
+    for line in raw.lines() {
+        if line == "---" {
+            return true;
+        }
+
+        let is_header = line.split_once(':').is_some_and(|(key, _)| {
+            !key.is_empty() && key.bytes().all(|byte| byte.is_ascii_alphanumeric())
+        });
+        if !line.is_empty() && !is_header {
+            return false;
+        }
+    }
+
+    false
 }
 
 fn parse_headers(raw_headers: &str) -> HashMap<&str, &str> {
@@ -41,36 +278,270 @@ fn parse_headers(raw_headers: &str) -> HashMap<&str, &str> {
     headers
 }
 
-fn parse_chunklist(raw_chunklist: &str) -> Vec<Chunk> {
+/// A manifest as a structured document, for tooling that can't read the line format: its headers
+/// as an object, and its chunks in manifest order as `files`.
+pub fn manifest_to_json(raw_manifest: &str) -> Result<serde_json::Value, String> {
+    let (headers, chunklist) = parse_manifest(raw_manifest).map_err(|e| e.to_string())?;
+
+    Ok(serde_json::json!({ "headers": headers, "files": chunklist }))
+}
+
+/// Writes a document from `manifest_to_json` back in the line format. Headers come out sorted by
+/// name, so the result parses the same as the original but needn't hash the same.
+pub fn manifest_from_json(document: &serde_json::Value) -> Result<String, String> {
+    #[derive(serde::Deserialize)]
+    struct Document {
+        headers: BTreeMap<String, String>,
+        files: Vec<Chunk>,
+    }
+
+    let document: Document = serde_json::from_value(document.clone())
+        .map_err(|e| format!("invalid manifest document: {e}"))?;
+    let with_mtime = document.headers.get("Timestamps").map(String::as_str) == Some("mtime");
+
+    let mut manifest = String::new();
+    for (name, value) in &document.headers {
+        manifest += &format!("{name}: {value}\n");
+    }
+    manifest += "---\n";
+    for chunk in &document.files {
+        let Chunk {
+            hash,
+            size,
+            path,
+            permissions,
+            mtime,
+        } = chunk;
+        match (with_mtime, mtime) {
+            (true, Some(mtime)) => {
+                manifest += &format!("{permissions};{size};{hash};{mtime};{path}\n")
+            }
+            (false, None) => manifest += &format!("{permissions};{size};{hash};{path}\n"),
+            _ => {
+                return Err(format!(
+                    "{path} must have an mtime exactly when the Timestamps header is mtime"
+                ));
+            }
+        }
+    }
+
+    // Catches what the document can hold but a manifest can't, like a path with a newline
+    parse_manifest(&manifest).map_err(|e| e.to_string())?;
+
+    Ok(manifest)
+}
+
+/// A chunk line that couldn't be parsed.
+#[derive(Debug, PartialEq)]
+pub struct InvalidLine {
+    /// Index of the line within the chunklist
+    pub line: usize,
+    pub reason: String,
+}
+
+/// Chunk lines are `mode;size;hash;path`, or `mode;size;hash;mtime;path` when the manifest declares
+/// `Timestamps: mtime`. Lines that don't parse are returned alongside the chunks, blank ones are
+/// skipped.
+fn parse_chunklist(raw_chunklist: &str, with_mtime: bool) -> (Vec<Chunk>, Vec<InvalidLine>) {
     let mut chunklist = Vec::new();
+    let mut invalid = Vec::new();
 
-    for line in raw_chunklist.lines() {
-        let parts: Vec<&str> = line.split(";").collect();
-        if parts.len() < 3 {
+    for (index, line) in raw_chunklist.lines().enumerate() {
+        if line.trim().is_empty() {
             continue;
         }
 
-        let chunk = Chunk {
-            permissions: parts[0]
-                .parse()
-                .expect("permissions/first field in chunk invalid, expected u32"),
-            size: parts[1]
+        match parse_chunk_line(line, with_mtime) {
+            Ok(chunk) => chunklist.push(chunk),
+            Err(reason) => invalid.push(InvalidLine {
+                line: index,
+                reason,
+            }),
+        }
+    }
+
+    (chunklist, invalid)
+}
+
+/// Checks a chunk's mode is a regular file's, as packagers record it with its file type bits,
+/// or bare permission bits as some older manifests have. Only the permission, setuid, setgid and
+/// sticky bits are applied.
+fn check_mode(mode: u32) -> Result<(), String> {
+    match mode & !0o7777 {
+        0 | REGULAR_FILE => Ok(()),
+        _ => Err(format!(
+            "permissions/first field in chunk invalid, {mode:o} isn't a regular file's mode"
+        )),
+    }
+}
+
+fn parse_chunk_line(line: &str, with_mtime: bool) -> Result<Chunk, String> {
+    let mut parts: Vec<&str> = line.split(";").collect();
+    let fields = if with_mtime { 5 } else { 4 };
+    if parts.len() < fields {
+        return Err(format!(
+            "expected at least {fields} fields, found {}",
+            parts.len()
+        ));
+    }
+
+    let mtime = if with_mtime {
+        Some(
+            parts
+                .remove(3)
                 .parse()
-                .expect("size/second field in chunk invalid, expected u32"),
-            hash: parts[2].into(),
-            path: parts[3..].join(";"),
-        };
+                .map_err(|_| "mtime/fourth field in chunk invalid, expected i64")?,
+        )
+    } else {
+        None
+    };
+
+    let permissions = parts[0]
+        .parse()
+        .map_err(|_| "permissions/first field in chunk invalid, expected u32")?;
+    check_mode(permissions)?;
+
+    Ok(Chunk {
+        permissions,
+        size: parts[1]
+            .parse()
+            .map_err(|_| "size/second field in chunk invalid, expected u64")?,
+        hash: parts[2].into(),
+        path: parts[3..].join(";"),
+        mtime,
+    })
+}
+
+/// Parses a `MinVersion` header into its major and, if present, minor version.
+/// Pre-release and build suffixes such as `-rc1` are ignored.
+pub fn parse_min_version(value: &str) -> Result<(usize, Option<usize>), String> {
+    let invalid = || format!("invalid MinVersion header: {value:?}");
+
+    let core = value.trim().split(['-', '+']).next().unwrap_or_default();
+    let mut parts = core
+        .split('.')
+        .map(|part| part.parse::<usize>().map_err(|_| invalid()));
+
+    let major = parts.next().ok_or_else(invalid)??;
+    let minor = parts.next().transpose()?;
+    // The patch version never makes a client incompatible, but must still be well formed
+    for part in parts {
+        part?;
+    }
+
+    Ok((major, minor))
+}
 
-        chunklist.push(chunk);
+/// Errors if a `MinVersion` header requires a newer client than `major.minor`.
+pub fn check_min_version(value: &str, major: usize, minor: usize) -> Result<(), Error> {
+    let (min_major, min_minor) = parse_min_version(value).map_err(Error::Parse)?;
+
+    if min_major > major {
+        return Err(Error::Incompatible(
+            "MinVersion declares major incompatibility. Outdated update client.".into(),
+        ));
+    }
+
+    if min_major == major && min_minor.is_some_and(|min_minor| min_minor > minor) {
+        return Err(Error::Incompatible(
+            "MinVersion declares minor incompatibility. Outdated update client.".into(),
+        ));
     }
 
-    chunklist
+    Ok(())
+}
+
+/// Paths that differ between two manifests.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ManifestDiff {
+    pub added: BTreeSet<String>,
+    pub removed: BTreeSet<String>,
+    /// Paths present in both, with different contents or permissions
+    pub modified: BTreeSet<String>,
 }
 
-// Returns whether the manifest has changed
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl fmt::Display for ManifestDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for path in &self.added {
+            writeln!(f, "+ {path}")?;
+        }
+        for path in &self.removed {
+            writeln!(f, "- {path}")?;
+        }
+        for path in &self.modified {
+            writeln!(f, "~ {path}")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn diff_manifests(old: &str, new: &str) -> Result<ManifestDiff, Error> {
+    let (_, old_chunklist) = parse_manifest(old)?;
+    let (_, new_chunklist) = parse_manifest(new)?;
+
+    let by_path = |chunklist| -> HashMap<&str, &[Chunk]> {
+        file_chunks(chunklist)
+            .map(|parts| (parts[0].path.as_str(), parts))
+            .collect()
+    };
+    let old_chunks = by_path(&old_chunklist);
+    let new_chunks = by_path(&new_chunklist);
+
+    let mut diff = ManifestDiff::default();
+
+    for (path, new_parts) in &new_chunks {
+        match old_chunks.get(path) {
+            None => {
+                diff.added.insert(path.to_string());
+            }
+            Some(old_parts)
+                if old_parts.len() != new_parts.len()
+                    || old_parts.iter().zip(*new_parts).any(|(old, new)| {
+                        old.hash != new.hash || old.permissions != new.permissions
+                    }) =>
+            {
+                diff.modified.insert(path.to_string());
+            }
+            Some(_) => (),
+        }
+    }
+
+    for path in old_chunks.keys() {
+        if !new_chunks.contains_key(path) {
+            diff.removed.insert(path.to_string());
+        }
+    }
+
+    Ok(diff)
+}
+
+/// The manifest installed `generation` updates ago: `current`, then `old`, then `old.2`, `old.3`...
+pub fn generation_path(manifests_path: &Path, generation: usize) -> PathBuf {
+    match generation {
+        0 => manifests_path.join("current"),
+        1 => manifests_path.join("old"),
+        n => manifests_path.join(format!("old.{n}")),
+    }
+}
+
+/// Paths of every stored generation, newest first.
+pub fn generation_paths(manifests_path: &Path) -> Vec<PathBuf> {
+    (0..)
+        .map(|generation| generation_path(manifests_path, generation))
+        .take_while(|path| path.exists())
+        .collect()
+}
+
+/// Returns whether the manifest has changed
 pub fn update_manifest(new_manifest: &str, manifests_path: &Path) -> Result<bool, io::Error> {
     let current_path = &manifests_path.join("current");
-    let old_path = &manifests_path.join("old");
 
     if !current_path.exists() {
         fs::write(current_path, new_manifest)?;
@@ -84,30 +555,246 @@ pub fn update_manifest(new_manifest: &str, manifests_path: &Path) -> Result<bool
         return Ok(false);
     }
 
-    fs::rename(current_path, old_path)?;
+    // Shift every generation back by one, oldest first
+    let generations = generation_paths(manifests_path).len();
+    for generation in (0..generations).rev() {
+        fs::rename(
+            generation_path(manifests_path, generation),
+            generation_path(manifests_path, generation + 1),
+        )?;
+    }
     fs::write(current_path, new_manifest)?;
 
     Ok(true)
 }
 
+/// Rejects manifest paths that could place files outside the tree being built, such as
+/// absolute paths or ones climbing out with `..`.
+fn check_chunk_path(path: &str) -> Result<(), io::Error> {
+    let mut depth = 0;
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("refusing manifest path {path:?}, it escapes the tree"),
+                ));
+            }
+        }
+    }
+
+    if depth == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("refusing manifest path {path:?}, it names no file"),
+        ));
+    }
+
+    Ok(())
+}
+
 pub fn build_tree(
     staging_path: &Path,
     chunkstore_path: &Path,
     chunks: &[Chunk],
 ) -> Result<(), io::Error> {
+    // Manifests may come from an untrusted mirror, so check every path before writing anything
+    for chunk in chunks {
+        check_chunk_path(&chunk.path)?;
+    }
+
     if staging_path.exists() {
         fs::remove_dir_all(staging_path)?;
     }
     fs::create_dir_all(staging_path)?;
 
-    for chunk in chunks {
+    // Hardlinked paths share an inode and therefore a mode and mtime. The mode is the chunk's
+    // own, so track which mtime each chunk holds.
+    let mut chunk_mtimes = HashMap::new();
+
+    for parts in file_chunks(chunks) {
+        let chunk = &parts[0];
         let path = staging_path.join(&chunk.path);
         let parent_path = path.parent().unwrap_or_else(|| Path::new("/"));
         if !parent_path.exists() {
             fs::create_dir_all(parent_path)?;
         }
 
-        fs::hard_link(chunkstore_path.join(chunk_filename(chunk)), path)?;
+        if parts.len() > 1 {
+            assemble_file(&path, chunkstore_path, parts)?;
+            continue;
+        }
+
+        let chunk_path = chunkstore_path.join(chunk_filename(chunk));
+        // Platforms without Unix modes store every chunk read-only, as every path wants it
+        let chunk_mode = file_mode(&fs::metadata(&chunk_path)?).map(|mode| mode & 0o7777);
+        let shares_inode = chunk_mode.is_none_or(|mode| mode == installed_mode(chunk))
+            && chunk.mtime.is_none_or(|mtime| {
+                *chunk_mtimes.entry(chunk_filename(chunk)).or_insert(mtime) == mtime
+            });
+
+        if shares_inode {
+            link_or_copy(&chunk_path, &path)?;
+        } else {
+            // The chunk was installed with another mode, or another path already gave it a
+            // different mtime, so this one needs its own inode. Its data can still be shared on
+            // filesystems that clone.
+            reflink_or_copy(&chunk_path, &path)?;
+            set_installed_mode(&path, chunk)?;
+        }
+
+        if let Some(mtime) = chunk.mtime {
+            set_mtime(&path, mtime)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a file split across several chunks by concatenating them. Unlike a single chunk it
+/// can't be a hardlink, so it gets the chunks' mode and mtime itself.
+fn assemble_file(path: &Path, chunkstore_path: &Path, parts: &[Chunk]) -> Result<(), io::Error> {
+    let mut file = fs::File::create_new(path)?;
+    for part in parts {
+        let mut part_file = fs::File::open(chunkstore_path.join(chunk_filename(part)))?;
+        io::copy(&mut part_file, &mut file)?;
+    }
+    drop(file);
+
+    if let Some(mtime) = parts[0].mtime {
+        set_mtime(path, mtime)?;
+    }
+
+    set_installed_mode(path, &parts[0])
+}
+
+/// Gives a file in staging the chunk's mode, read-only as installed chunks are.
+fn set_installed_mode(path: &Path, chunk: &Chunk) -> Result<(), io::Error> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    set_mode(&mut permissions, chunk.permissions);
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)
+}
+
+/// Atomically exchanges the staging tree with `target_path`, creating the target if needed.
+/// Both must be directories on the same filesystem, which is checked first so a misconfigured
+/// root gets an explanation rather than an errno.
+pub fn swap_tree(staging_path: &Path, target_path: &Path) -> Result<(), Error> {
+    check_swappable(staging_path, target_path)
+        .and_then(|()| exchange(staging_path, target_path))
+        .map_err(Error::Swap)
+}
+
+fn check_swappable(staging_path: &Path, target_path: &Path) -> Result<(), io::Error> {
+    if !target_path.exists() {
+        fs::create_dir_all(target_path)?;
+    }
+
+    let staging = fs::symlink_metadata(staging_path)?;
+    let target = fs::symlink_metadata(target_path)?;
+    for (path, metadata) in [(staging_path, &staging), (target_path, &target)] {
+        if !metadata.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!(
+                    "{} isn't a directory, so it can't be swapped. Move it aside and retry",
+                    path.display()
+                ),
+            ));
+        }
+    }
+
+    if let (Some(staging_device), Some(target_device)) = (device(&staging), device(&target))
+        && staging_device != target_device
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::CrossesDevices,
+            format!(
+                "{} and {} are on different filesystems, so they can't be swapped. Put the state \
+                 directory on the same filesystem as {}",
+                staging_path.display(),
+                target_path.display(),
+                target_path.display()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Removes a staging tree when dropped, so a failed or aborted update doesn't leave one behind.
+/// Disarm it once the tree has been swapped into place.
+pub struct StagingGuard<'a> {
+    path: &'a Path,
+    armed: bool,
+}
+
+impl<'a> StagingGuard<'a> {
+    pub fn new(path: &'a Path) -> Self {
+        Self { path, armed: true }
+    }
+
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for StagingGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed && self.path.exists() {
+            let _ = fs::remove_dir_all(self.path);
+        }
+    }
+}
+
+/// Confirms every chunk in the chunklist was placed in staging with the expected size and mode.
+pub fn verify_tree(staging_path: &Path, chunks: &[Chunk]) -> Result<(), io::Error> {
+    for parts in file_chunks(chunks) {
+        let chunk = &parts[0];
+        // Every part but the last is a whole number of kilobytes, so their sizes add up exactly
+        let size: u64 = parts.iter().map(|part| part.size).sum();
+        let path = staging_path.join(&chunk.path);
+        let metadata = fs::symlink_metadata(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("{} missing from staging: {e}", chunk.path),
+            )
+        })?;
+
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a regular file in staging", chunk.path),
+            ));
+        }
+
+        // Sizes are recorded in kilobytes
+        if metadata.len() / 1024 != size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has size {}kb, expected {size}kb",
+                    chunk.path,
+                    metadata.len() / 1024,
+                ),
+            ));
+        }
+
+        // Platforms without Unix modes have nothing to compare
+        if let Some(mode) = file_mode(&metadata).map(|mode| mode & 0o7777)
+            && mode != installed_mode(chunk)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has mode {mode:o}, expected {:o}",
+                    chunk.path,
+                    installed_mode(chunk)
+                ),
+            ));
+        }
     }
 
     Ok(())
@@ -122,8 +809,9 @@ mod tests {
         let raw_chunklist =
             "420;16000;example_hash;this/is/a;path\n420;127510;anotherhash;path/path/path/path";
 
-        let chunklist = parse_chunklist(raw_chunklist);
+        let (chunklist, invalid) = parse_chunklist(raw_chunklist, false);
 
+        assert!(invalid.is_empty());
         assert_eq!(chunklist.len(), 2);
         assert_eq!(
             chunklist[0],
@@ -131,11 +819,72 @@ mod tests {
                 permissions: 420,
                 size: 16000,
                 hash: "example_hash".into(),
-                path: "this/is/a;path".into()
+                path: "this/is/a;path".into(),
+                mtime: None,
             }
         )
     }
 
+    #[test]
+    fn test_short_chunk_lines() {
+        // Three fields has no path, two has neither a hash nor a path
+        let raw_chunklist = "\n420;0;hash;ok\n420;0;hash\n\n420;0\n";
+
+        let (chunklist, invalid) = parse_chunklist(raw_chunklist, false);
+
+        assert_eq!(chunklist.len(), 1);
+        assert_eq!(
+            invalid
+                .iter()
+                .map(|invalid| invalid.line)
+                .collect::<Vec<_>>(),
+            [2, 4]
+        );
+        assert!(invalid[0].reason.contains("found 3"));
+
+        // With timestamps, a path is still required after the mtime
+        let (_, invalid) = parse_chunklist("420;0;hash;1700000000", true);
+        assert_eq!(invalid.len(), 1);
+
+        let error = parse_manifest("Hasher: blake3\n---\n420;0;hash;ok\n420;0\n").unwrap_err();
+        assert!(
+            matches!(&error, Error::Parse(message) if message.contains("line 4")),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_chunk_modes() {
+        let (chunklist, invalid) = parse_chunklist("420;0;hash;old\n35309;0;hash;setuid\n", false);
+        assert!(invalid.is_empty());
+        assert_eq!(chunklist[1].permissions, 0o104755);
+
+        // Symlinks, directories and modes wider than st_mode aren't files this can install
+        for mode in [0o120777, 0o040755, 0o1100644] {
+            let (_, invalid) = parse_chunklist(&format!("{mode};0;hash;path"), false);
+            assert!(invalid[0].reason.contains("regular file"), "{mode:o}");
+        }
+    }
+
+    #[test]
+    fn test_chunk_count() {
+        let manifest = "ChunkCount: 2\nHasher: blake3\n---\n420;0;aaaa;a\n420;0;bbbb;b\n";
+        assert_eq!(parse_manifest(manifest).unwrap().1.len(), 2);
+
+        // Cut off after the first line, as an interrupted transfer would
+        let truncated = &manifest[..manifest.find("420;0;bbbb").unwrap()];
+        let error = parse_manifest(truncated).unwrap_err();
+        assert!(
+            matches!(&error, Error::Parse(message) if message.contains("declares 2 chunks but lists 1")),
+            "{error}"
+        );
+
+        let invalid = manifest.replace("ChunkCount: 2", "ChunkCount: two");
+        assert!(matches!(parse_manifest(&invalid), Err(Error::Parse(_))));
+        // Older manifests without the header are still read
+        assert!(parse_manifest("Hasher: blake3\n---\n420;0;aaaa;a\n").is_ok());
+    }
+
     #[test]
     fn test_header_parsing() {
         let raw_headers = "Header: Key\nAnotherHeader: Slightly secret key \n ";
@@ -145,4 +894,413 @@ mod tests {
         assert_eq!(headers.len(), 2);
         assert_eq!(headers.get("Header").unwrap(), &"Key")
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_verify_tree_refuses_corrupt_staging() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let chunkstore_path = root.child("chunkstore");
+        let staging_path = root.child("staging");
+        fs::create_dir_all(&chunkstore_path).unwrap();
+
+        let chunk = Chunk {
+            permissions: 0o100644,
+            size: 2,
+            hash: "example_hash".into(),
+            path: "bin/tool".into(),
+            mtime: None,
+        };
+        let chunk_path = chunkstore_path.join(chunk_filename(&chunk));
+        fs::write(&chunk_path, [0u8; 2048]).unwrap();
+        fs::set_permissions(&chunk_path, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let chunks = [chunk];
+        build_tree(&staging_path, &chunkstore_path, &chunks).unwrap();
+        assert!(verify_tree(&staging_path, &chunks).is_ok());
+
+        // Replace the placed file with a truncated one
+        let placed = staging_path.join("bin/tool");
+        fs::remove_file(&placed).unwrap();
+        fs::write(&placed, "").unwrap();
+        fs::set_permissions(&placed, fs::Permissions::from_mode(0o444)).unwrap();
+        assert!(verify_tree(&staging_path, &chunks).is_err());
+
+        fs::remove_file(&placed).unwrap();
+        assert!(verify_tree(&staging_path, &chunks).is_err());
+    }
+
+    #[test]
+    fn test_staging_guard_cleans_failed_build() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let chunkstore_path = root.child("chunkstore");
+        let staging_path = root.child("staging");
+        fs::create_dir_all(&chunkstore_path).unwrap();
+
+        let present = Chunk {
+            permissions: 0o100644,
+            size: 0,
+            hash: "present".into(),
+            path: "a/present".into(),
+            mtime: None,
+        };
+        let missing = Chunk {
+            permissions: 0o100644,
+            size: 0,
+            hash: "missing".into(),
+            path: "b/missing".into(),
+            mtime: None,
+        };
+        fs::write(chunkstore_path.join(chunk_filename(&present)), "").unwrap();
+
+        let guard = StagingGuard::new(&staging_path);
+        assert!(build_tree(&staging_path, &chunkstore_path, &[present, missing]).is_err());
+        assert!(staging_path.join("a/present").exists());
+        drop(guard);
+
+        assert!(!staging_path.exists());
+    }
+
+    #[test]
+    fn test_swap_tree() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let staging_path = root.child("staging");
+        let usr_path = root.child("usr");
+        fs::create_dir(&staging_path).unwrap();
+        fs::write(staging_path.join("new"), "").unwrap();
+
+        swap_tree(&staging_path, &usr_path).unwrap();
+        assert!(usr_path.join("new").exists());
+        assert!(staging_path.exists());
+
+        fs::remove_dir_all(&usr_path).unwrap();
+        fs::write(&usr_path, "not a tree").unwrap();
+        let error = swap_tree(&staging_path, &usr_path).unwrap_err();
+        assert!(matches!(&error, Error::Swap(e) if e.kind() == io::ErrorKind::NotADirectory));
+        assert!(error.to_string().contains("isn't a directory"), "{error}");
+        assert_eq!(fs::read_to_string(&usr_path).unwrap(), "not a tree");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_swap_tree_refuses_other_filesystems() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let staging_path = root.child("staging");
+        fs::create_dir(&staging_path).unwrap();
+
+        // procfs is never the filesystem a temporary directory lives on
+        let error = swap_tree(&staging_path, Path::new("/proc")).unwrap_err();
+        assert!(matches!(&error, Error::Swap(e) if e.kind() == io::ErrorKind::CrossesDevices));
+        assert!(
+            error.to_string().contains("different filesystems"),
+            "{error}"
+        );
+        assert!(staging_path.exists());
+    }
+
+    #[test]
+    fn test_build_tree_rejects_escaping_paths() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let chunkstore_path = root.child("chunkstore");
+        let staging_path = root.child("staging");
+        fs::create_dir_all(&chunkstore_path).unwrap();
+
+        let chunk = |path: &str| Chunk {
+            permissions: 0o100644,
+            size: 0,
+            hash: "payload".into(),
+            path: path.into(),
+            mtime: None,
+        };
+        fs::write(chunkstore_path.join(chunk_filename(&chunk("x"))), "").unwrap();
+
+        for path in ["../escape", "nested/../../escape", "/abs/path"] {
+            let error = build_tree(&staging_path, &chunkstore_path, &[chunk(path)]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{path}");
+        }
+        assert!(!root.child("escape").exists());
+        assert!(!staging_path.exists());
+
+        build_tree(&staging_path, &chunkstore_path, &[chunk("nested/ok/path")]).unwrap();
+        assert!(staging_path.join("nested/ok/path").exists());
+    }
+
+    #[test]
+    fn test_compressed_manifest_roundtrip() {
+        let mut manifest = String::from("FormatVersion: 1\nHasher: blake3\n---\n");
+        for i in 0..1000 {
+            manifest += &format!("{};{i};{:064x};usr/share/file{i}\n", 0o100644, i);
+        }
+
+        let compressed = compress_manifest(&manifest).unwrap();
+        assert!(compressed.len() < manifest.len() / 4);
+        let decompressed = decompress_manifest(&compressed).unwrap();
+        assert_eq!(decompressed, manifest);
+        assert_eq!(
+            parse_manifest(&decompressed).unwrap(),
+            parse_manifest(&manifest).unwrap()
+        );
+
+        assert!(decompress_manifest(manifest.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_looks_like_manifest() {
+        assert!(looks_like_manifest("---\n"));
+        assert!(looks_like_manifest(
+            "FormatVersion: 1\nHasher: blake3\n\n---\n420;0;aaaa;file\n"
+        ));
+        assert!(!looks_like_manifest(""));
+        assert!(!looks_like_manifest("Hasher: blake3\n"));
+        assert!(!looks_like_manifest(
+            "<!DOCTYPE html>\n<p>Gateway Timeout</p>\n<hr>\n---\n"
+        ));
+        assert!(!looks_like_manifest("{\"error\": \"not found\"}"));
+    }
+
+    #[test]
+    fn test_parse_pointer() {
+        let hash = blake3::hash(b"manifest").to_hex().to_string();
+        assert_eq!(parse_pointer(&hash).unwrap(), hash);
+        assert_eq!(parse_pointer(&format!("{hash}\n")).unwrap(), hash);
+
+        assert!(parse_pointer("").is_err());
+        assert!(parse_pointer(&hash[..40]).is_err());
+        assert!(parse_pointer(&format!("{hash}\n{hash}")).is_err());
+        assert!(parse_pointer("<html><body><h1>502 Bad Gateway</h1></body></html>\n").is_err());
+    }
+
+    #[test]
+    fn test_generated_header() {
+        assert_eq!(
+            format_generated(1_700_000_000).unwrap(),
+            "2023-11-14T22:13:20Z"
+        );
+        assert_eq!(
+            parse_generated("2023-11-14T22:13:20Z").unwrap(),
+            1_700_000_000
+        );
+        assert_eq!(
+            parse_generated("2023-11-15T00:13:20+02:00").unwrap(),
+            1_700_000_000
+        );
+        assert!(parse_generated("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_tree_hash() {
+        let (_, chunklist) =
+            parse_manifest("FormatVersion: 2\n---\n420;1;aaaa;b\n420;1;bbbb;a\n420;1;cccc;a\n")
+                .unwrap();
+        let (_, reordered) =
+            parse_manifest("FormatVersion: 2\n---\n420;1;bbbb;a\n420;1;cccc;a\n420;1;aaaa;b\n")
+                .unwrap();
+        assert_eq!(tree_hash(&chunklist), tree_hash(&reordered));
+
+        // Contents, modes, paths, and the order of a file's parts all change it
+        for changed in [
+            "FormatVersion: 2\n---\n420;1;aaab;b\n420;1;bbbb;a\n420;1;cccc;a\n",
+            "FormatVersion: 2\n---\n493;1;aaaa;b\n420;1;bbbb;a\n420;1;cccc;a\n",
+            "FormatVersion: 2\n---\n420;1;aaaa;c\n420;1;bbbb;a\n420;1;cccc;a\n",
+            "FormatVersion: 2\n---\n420;1;aaaa;b\n420;1;cccc;a\n420;1;bbbb;a\n",
+        ] {
+            let (_, changed) = parse_manifest(changed).unwrap();
+            assert_ne!(tree_hash(&changed), tree_hash(&chunklist));
+        }
+    }
+
+    #[test]
+    fn test_format_version() {
+        let current = format!("FormatVersion: {FORMAT_VERSION}\n---\n420;1;hash;path");
+        assert_eq!(parse_manifest(&current).unwrap().1.len(), 1);
+
+        // Older manifests have no version, and are read as version 1
+        assert_eq!(parse_manifest("---\n420;1;hash;path").unwrap().1.len(), 1);
+
+        let newer = format!(
+            "FormatVersion: {}\n---\n420;1;hash;path",
+            FORMAT_VERSION + 1
+        );
+        assert!(matches!(
+            parse_manifest(&newer),
+            Err(Error::Incompatible(message)) if message.contains("Update your client")
+        ));
+        assert!(matches!(
+            parse_manifest("FormatVersion: one\n---\n"),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let raw_manifest = "FormatVersion: 2\nTimestamps: mtime\nHasher: blake3\n---\n\
+            33188;1;aaaa;1700000000;etc/a;b\n33261;4;bbbb;1700000001;bin/split\n\
+            33261;2;cccc;1700000001;bin/split\n";
+
+        let document = manifest_to_json(raw_manifest).unwrap();
+        assert_eq!(document["headers"]["Hasher"], "blake3");
+        assert_eq!(document["files"][2]["hash"], "cccc");
+        assert_eq!(document["files"][0]["mtime"], 1700000000);
+
+        let written = manifest_from_json(&document).unwrap();
+        assert_eq!(
+            parse_manifest(&written).unwrap(),
+            parse_manifest(raw_manifest).unwrap()
+        );
+
+        let mut untimed = document.clone();
+        untimed["headers"]
+            .as_object_mut()
+            .unwrap()
+            .remove("Timestamps");
+        assert!(manifest_from_json(&untimed).is_err());
+        assert!(manifest_from_json(&serde_json::json!({ "files": [] })).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_paths() {
+        let duplicated = "Hasher: blake3\n---\n420;1;aaaa;bin/tool\n420;1;bbbb;etc/conf\n\n\
+            420;1;cccc;bin/tool\n";
+        assert_eq!(
+            parse_manifest(duplicated).unwrap_err().to_string(),
+            "Manifest lists 1 paths more than once: bin/tool on lines 3 and 6"
+        );
+
+        // Consecutive lines are a split file's parts, but only version 2 splits files
+        let consecutive = "---\n420;1;aaaa;bin/tool\n420;1;bbbb;bin/tool\n";
+        assert!(
+            parse_manifest(consecutive)
+                .unwrap_err()
+                .to_string()
+                .contains("lines 2 and 3")
+        );
+        assert!(parse_manifest(&format!("FormatVersion: 2\n{consecutive}")).is_ok());
+        let split_twice =
+            format!("FormatVersion: 2\n{consecutive}420;1;cccc;etc\n420;1;dddd;bin/tool\n");
+        assert!(
+            parse_manifest(&split_twice)
+                .unwrap_err()
+                .to_string()
+                .contains("lines 3 and 6")
+        );
+    }
+
+    #[test]
+    fn test_mtime_parsing() {
+        let raw_manifest = "Timestamps: mtime\n---\n420;1;hash;1700000000;a;path";
+
+        let (_, chunklist) = parse_manifest(raw_manifest).unwrap();
+
+        assert_eq!(chunklist[0].mtime, Some(1700000000));
+        assert_eq!(chunklist[0].path, "a;path");
+    }
+
+    #[test]
+    fn test_build_tree_restores_mtimes() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let chunkstore_path = root.child("chunkstore");
+        let staging_path = root.child("staging");
+        fs::create_dir_all(&chunkstore_path).unwrap();
+
+        // Two paths sharing one chunk, with different mtimes
+        let first = Chunk {
+            permissions: 0o100644,
+            size: 0,
+            hash: "shared".into(),
+            path: "first".into(),
+            mtime: Some(1_000_000_000),
+        };
+        let second = Chunk {
+            path: "second".into(),
+            mtime: Some(1_500_000_000),
+            ..first.clone()
+        };
+        fs::write(chunkstore_path.join(chunk_filename(&first)), "").unwrap();
+
+        build_tree(&staging_path, &chunkstore_path, &[first, second]).unwrap();
+
+        let mtime =
+            |path: &str| crate::platform::mtime(&fs::metadata(staging_path.join(path)).unwrap());
+        assert_eq!(mtime("first"), 1_000_000_000);
+        assert_eq!(mtime("second"), 1_500_000_000);
+    }
+
+    #[test]
+    fn test_build_tree_assembles_split_files() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let chunkstore_path = root.child("chunkstore");
+        let staging_path = root.child("staging");
+        fs::create_dir_all(&chunkstore_path).unwrap();
+
+        let raw_manifest = "FormatVersion: 2\nTimestamps: mtime\n---\n\
+            33261;1;head;1700000000;bin/large\n33261;1;head;1700000000;bin/large\n\
+            33261;0;tail;1700000000;bin/large\n33188;0;tail;1700000000;small\n";
+        let (_, chunklist) = parse_manifest(raw_manifest).unwrap();
+        let store = |chunk: &Chunk, content: &[u8]| {
+            let path = chunkstore_path.join(chunk_filename(chunk));
+            fs::write(&path, content).unwrap();
+            let mut permissions = fs::metadata(&path).unwrap().permissions();
+            set_mode(&mut permissions, installed_mode(chunk));
+            fs::set_permissions(&path, permissions).unwrap();
+        };
+        store(&chunklist[0], &[1; 1024]);
+        store(&chunklist[2], b"end");
+        store(&chunklist[3], b"end");
+
+        build_tree(&staging_path, &chunkstore_path, &chunklist).unwrap();
+        verify_tree(&staging_path, &chunklist).unwrap();
+
+        let large = fs::read(staging_path.join("bin/large")).unwrap();
+        assert_eq!(large.len(), 2048 + 3);
+        assert!(large.ends_with(b"end"));
+        let metadata = fs::metadata(staging_path.join("bin/large")).unwrap();
+        assert_eq!(crate::platform::mtime(&metadata), 1_700_000_000);
+        assert!(metadata.permissions().readonly());
+        assert_eq!(
+            fs::read_to_string(staging_path.join("small")).unwrap(),
+            "end"
+        );
+
+        // Changing one part modifies the file, and only that file
+        let edited = raw_manifest.replacen("33261;1;head", "33261;1;edit", 1);
+        let diff = diff_manifests(raw_manifest, &edited).unwrap();
+        assert_eq!(diff.modified, BTreeSet::from(["bin/large".to_string()]));
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+    }
+
+    #[test]
+    fn test_min_version_parsing() {
+        assert_eq!(parse_min_version("1"), Ok((1, None)));
+        assert_eq!(parse_min_version("1.2"), Ok((1, Some(2))));
+        assert_eq!(parse_min_version("1.2.3"), Ok((1, Some(2))));
+        assert_eq!(parse_min_version("1.2.0-rc1"), Ok((1, Some(2))));
+        assert!(parse_min_version("1.x").is_err());
+        assert!(parse_min_version("").is_err());
+    }
+
+    #[test]
+    fn test_min_version_check() {
+        assert!(check_min_version("0.1", 0, 1).is_ok());
+        assert!(check_min_version("0.2", 0, 1).is_err());
+        assert!(check_min_version("1", 0, 5).is_err());
+        assert!(check_min_version("0.9", 1, 0).is_ok());
+        assert!(check_min_version("oops", 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_diff_manifests() {
+        let old = "---\n420;1;aaaa;kept\n420;1;bbbb;removed\n420;1;cccc;content\n420;1;dddd;mode\n";
+        let new = "---\n420;1;aaaa;kept\n420;1;eeee;added\n420;1;ffff;content\n493;1;dddd;mode\n";
+
+        let diff = diff_manifests(old, new).unwrap();
+
+        assert_eq!(diff.added, BTreeSet::from(["added".to_string()]));
+        assert_eq!(diff.removed, BTreeSet::from(["removed".to_string()]));
+        assert_eq!(
+            diff.modified,
+            BTreeSet::from(["content".to_string(), "mode".to_string()])
+        );
+        assert!(diff_manifests(old, old).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,84 @@
+use serde_json::json;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::update::UpdateSummary;
+
+/// POSTs what an update changed to `url`, for fleet monitoring. The update has already
+/// succeeded, so failing to deliver it is only warned about.
+pub async fn notify(
+    client: &reqwest::Client,
+    url: &str,
+    summary: &UpdateSummary,
+    duration: Duration,
+) {
+    let payload = json!({
+        "old_hash": summary.previous_manifest_hash,
+        "new_hash": summary.manifest_hash,
+        "files_changed": summary.files_changed,
+        "bytes_downloaded": summary.bytes_downloaded,
+        "duration_secs": duration.as_secs_f64(),
+    });
+
+    match client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(payload.to_string())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+    {
+        Ok(_) => info!(phase = "notify", url, "Notified {url}"),
+        Err(e) => warn!("Couldn't notify {url} of the update: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestServer;
+
+    #[tokio::test]
+    async fn test_notify_posts_summary() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let server = TestServer::serve_dir(root.path().to_path_buf()).await;
+        let url = format!("{}/updated", server.url);
+
+        let summary = UpdateSummary {
+            manifest_hash: "new".into(),
+            previous_manifest_hash: Some("old".into()),
+            bytes_downloaded: 2048,
+            files_changed: 3,
+            ..Default::default()
+        };
+        notify(
+            &reqwest::Client::new(),
+            &url,
+            &summary,
+            Duration::from_millis(1500),
+        )
+        .await;
+
+        let posted = server.posted();
+        assert_eq!(posted.len(), 1);
+        let (path, body) = &posted[0];
+        assert_eq!(path, "/updated");
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "old_hash": "old",
+                "new_hash": "new",
+                "files_changed": 3,
+                "bytes_downloaded": 2048,
+                "duration_secs": 1.5,
+            })
+        );
+
+        // Nothing listening is only warned about
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/updated", listener.local_addr().unwrap());
+        drop(listener);
+        notify(&reqwest::Client::new(), &url, &summary, Duration::ZERO).await;
+    }
+}
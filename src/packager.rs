@@ -0,0 +1,2210 @@
+use async_compression::Level;
+use async_compression::tokio::write::{BrotliEncoder, Lz4Encoder, ZstdEncoder};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, error, info, warn};
+
+use crate::chunks::{
+    Chunk, ChunkLayout, InstallOptions, chunk_filename, install_chunk, missing_chunks,
+    repo_chunk_path,
+};
+use crate::delta::{Delta, create_delta, format_deltas, write_delta};
+use crate::dictionary::Dictionary;
+use crate::error::Error;
+use crate::exclude::Excludes;
+use crate::manifest::{
+    FORMAT_VERSION, build_tree, file_chunks, format_generated, parse_manifest, tree_hash,
+    verify_tree,
+};
+use crate::platform;
+use crate::source::{FileSource, RepoSource};
+use crate::types::{Compression, HashType};
+use crate::update::{read_dictionary, read_headers};
+use crate::utils::{DEFAULT_BUFFER_SIZE, Hasher};
+
+/// Mode recorded for files on platforms without Unix modes.
+const DEFAULT_MODE: u32 = 0o100644;
+
+/// Canonicalizes the packager's input, which must be a directory.
+/// A symlink to a directory is resolved to its target.
+pub fn resolve_input_path(input_path: &Path) -> Result<PathBuf, String> {
+    let resolved = input_path
+        .canonicalize()
+        .map_err(|e| format!("could not open input path {}: {e}", input_path.display()))?;
+
+    if !resolved.is_dir() {
+        return Err(format!(
+            "input path {} isn't a directory",
+            input_path.display()
+        ));
+    }
+
+    Ok(resolved)
+}
+
+/// What's under the packager's input, sorted so output stays reproducible.
+#[derive(Debug, Default)]
+pub struct Discovered {
+    pub files: Vec<PathBuf>,
+    pub directories: Vec<PathBuf>,
+    /// Symlinks found, which manifests have no way to record
+    pub symlinks: usize,
+    /// Device nodes, FIFOs and sockets, which manifests can't record either
+    pub special: Vec<PathBuf>,
+}
+
+/// Walks `input_path` for what to package, leaving out what `excludes` matches. Symlinks and
+/// special files are skipped with a warning.
+pub fn discover(input_path: &Path, excludes: &Excludes) -> Result<Discovered, walkdir::Error> {
+    let mut discovered = Discovered::default();
+    let walker = walkdir::WalkDir::new(input_path)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| {
+            // Returning false on a directory also stops WalkDir from descending into it
+            let relative_path = entry
+                .path()
+                .strip_prefix(input_path)
+                .unwrap_or(entry.path());
+            !excludes.is_excluded(relative_path)
+        });
+    for entry in walker {
+        let entry = entry?;
+        let path = entry.path().to_path_buf();
+        let file_type = entry.file_type();
+
+        if file_type.is_dir() {
+            discovered.directories.push(path);
+        } else if file_type.is_symlink() {
+            // Links aren't followed, and manifests have no way to record them
+            warn!("Skipping symlink {}", path.display());
+            discovered.symlinks += 1;
+        } else if file_type.is_file() {
+            discovered.files.push(path);
+        } else {
+            let kind = platform::special_file_kind(&file_type).unwrap_or("special file");
+            warn!("Skipping {kind} {}", path.display());
+            discovered.special.push(path);
+        }
+    }
+    // Discovery order depends on the filesystem
+    discovered.directories.sort();
+    discovered.files.sort();
+    discovered.special.sort();
+
+    Ok(discovered)
+}
+
+/// Path of the manifest `output_path`'s pointer named `pointer` names, or `None` if nothing has
+/// been packaged there yet.
+pub async fn latest_manifest(
+    output_path: &Path,
+    pointer: &str,
+) -> Result<Option<PathBuf>, std::io::Error> {
+    let hash = match fs::read_to_string(output_path.join(pointer)).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        hash => hash?,
+    };
+    let hash = hash.trim();
+
+    Ok((!hash.is_empty()).then(|| output_path.join(hash)))
+}
+
+/// A previously generated manifest, whose hashes are reused for files that look unchanged.
+pub struct BaseManifest {
+    hash_method: HashType,
+    /// Size in kilobytes, mtime, and hash of each recorded file, by its path under the input
+    files: HashMap<PathBuf, (u64, i64, String)>,
+}
+
+impl BaseManifest {
+    /// Reads the manifest at `manifest_path`. Only files recorded with an mtime can be reused.
+    pub async fn load(
+        manifest_path: &Path,
+        input_path: &Path,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let manifest_raw = fs::read_to_string(manifest_path).await?;
+        let (headers, chunklist) = parse_manifest(&manifest_raw)?;
+        let (_, hash_method) = read_headers(&headers)?;
+        if !headers.contains_key("Timestamps") {
+            warn!(
+                "{} records no mtimes, so every file will be hashed",
+                manifest_path.display()
+            );
+        }
+
+        // Split files are always rehashed, as their parts depend on the chunk size
+        let files = file_chunks(&chunklist)
+            .filter_map(|parts| {
+                let [chunk] = parts else {
+                    return None;
+                };
+                let mtime = chunk.mtime?;
+                Some((
+                    input_path.join(&chunk.path),
+                    (chunk.size, mtime, chunk.hash.clone()),
+                ))
+            })
+            .collect();
+
+        Ok(Self { hash_method, files })
+    }
+
+    /// Loads the manifest `output_path`'s pointer named `pointer` names, or `None` if nothing
+    /// has been packaged there yet.
+    pub async fn latest(
+        output_path: &Path,
+        input_path: &Path,
+        pointer: &str,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        match latest_manifest(output_path, pointer).await? {
+            Some(manifest_path) => Ok(Some(Self::load(&manifest_path, input_path).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The recorded hash of `file_path`, if its size and mtime still match the manifest.
+    async fn unchanged_hash(&self, file_path: &Path) -> Result<Option<String>, std::io::Error> {
+        let Some((size, mtime, hash)) = self.files.get(file_path) else {
+            return Ok(None);
+        };
+
+        let metadata = fs::metadata(file_path).await?;
+        if metadata.len() / 1024 == *size && platform::mtime(&metadata) == *mtime {
+            Ok(Some(hash.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// One chunk of a packaged file. Files are a single part unless split by `chunk_size`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Part {
+    pub hash: String,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// What packaging a tree would produce, estimated without compressing anything.
+#[derive(Debug, Default, PartialEq)]
+pub struct PackageStats {
+    pub files: usize,
+    pub directories: usize,
+    /// Symlinks found, which packaging skips
+    pub symlinks: usize,
+    /// Device nodes, FIFOs and sockets found, which packaging skips too
+    pub special: usize,
+    pub total_bytes: u64,
+    /// Files left once those with identical contents are stored once
+    pub unique_files: usize,
+    pub unique_bytes: u64,
+    /// Chunks the unique files make, once split by the chunk size
+    pub chunks: usize,
+}
+
+/// Counts and sizes `files`, and how much storing identical contents once saves. Only files
+/// sharing their size with another are hashed, as no other file can have the same contents.
+pub async fn package_stats(
+    files: &[PathBuf],
+    hash_method: HashType,
+    chunk_size: Option<u64>,
+    buffer_size: usize,
+) -> Result<PackageStats, Box<dyn std::error::Error>> {
+    check_chunk_size(chunk_size)?;
+
+    let mut by_size: HashMap<u64, Vec<&PathBuf>> = HashMap::new();
+    for file_path in files {
+        let size = fs::metadata(file_path).await?.len();
+        by_size.entry(size).or_default().push(file_path);
+    }
+
+    let mut stats = PackageStats {
+        files: files.len(),
+        ..Default::default()
+    };
+    for (size, paths) in by_size {
+        stats.total_bytes += size * paths.len() as u64;
+
+        let unique = if paths.len() == 1 {
+            1
+        } else {
+            let mut hashes = HashSet::new();
+            for file_path in paths {
+                hashes.insert(hash_file(file_path, hash_method, buffer_size).await?);
+            }
+            hashes.len()
+        };
+        stats.unique_files += unique;
+        stats.unique_bytes += size * unique as u64;
+
+        let chunks = match chunk_size {
+            Some(chunk_size) if size > chunk_size => size.div_ceil(chunk_size) as usize,
+            _ => 1,
+        };
+        stats.chunks += chunks * unique;
+    }
+
+    Ok(stats)
+}
+
+/// How chunks are hashed, split, compressed and laid out when packaging.
+#[derive(Debug, Clone)]
+pub struct ChunkOptions<'a> {
+    pub hash_method: HashType,
+    pub compression: Compression,
+    pub dictionary: Option<&'a Dictionary>,
+    pub quality: Level,
+    /// Files larger than this are split into parts of this many bytes, which must be a whole
+    /// number of kilobytes as manifests record sizes in them
+    pub chunk_size: Option<u64>,
+    /// Bytes read at a time
+    pub buffer_size: usize,
+    /// Where chunks are placed within the `chunks` directory
+    pub layout: ChunkLayout,
+    /// Store files `looks_compressed` picks as is under their bare hash, which updaters fall
+    /// back to
+    pub smart_compression: bool,
+}
+
+impl Default for ChunkOptions<'_> {
+    fn default() -> Self {
+        Self {
+            hash_method: HashType::Blake3,
+            compression: Compression::None,
+            dictionary: None,
+            quality: Level::Default,
+            chunk_size: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            layout: ChunkLayout::default(),
+            smart_compression: false,
+        }
+    }
+}
+
+/// Hashes every file and writes its chunks into `chunks_path`, processing each unique hash only
+/// once.
+/// Files that `base` shows unchanged, and whose chunk is already written, aren't read at all.
+/// Returns the parts of every file, including duplicates.
+pub async fn write_chunks(
+    files: &[PathBuf],
+    chunks_path: &Path,
+    base: Option<&BaseManifest>,
+    options: &ChunkOptions<'_>,
+) -> Result<HashMap<PathBuf, Vec<Part>>, Box<dyn std::error::Error>> {
+    let ChunkOptions {
+        hash_method,
+        compression,
+        dictionary,
+        quality,
+        chunk_size,
+        buffer_size,
+        ref layout,
+        smart_compression,
+    } = *options;
+    check_chunk_size(chunk_size)?;
+    if let Some(dictionary) = dictionary {
+        fs::create_dir_all(chunks_path.join(&dictionary.hash)).await?;
+    }
+
+    let mut hashes = HashMap::new();
+    let mut written = HashSet::new();
+    let requested = (compression, dictionary);
+
+    for file_path in files {
+        let (compression, dictionary) = if smart_compression
+            && requested.0 != Compression::None
+            && looks_compressed(file_path).await?
+        {
+            debug!("Storing {} as is", file_path.display());
+            (Compression::None, None)
+        } else {
+            requested
+        };
+        let size = fs::metadata(file_path).await?.len();
+        if let Some(chunk_size) = chunk_size
+            && size > chunk_size
+        {
+            let mut file = File::open(file_path).await?;
+            let mut parts = Vec::new();
+            loop {
+                let data = read_part(&mut file, chunk_size).await?;
+                if data.is_empty() {
+                    break;
+                }
+
+                let hash = hash_bytes(&data, hash_method)?;
+                let chunk_path =
+                    chunks_path.join(repo_chunk_path(layout, &hash, &compression, dictionary));
+                if written.insert(hash.clone()) && !chunk_path.exists() {
+                    create_chunk_dir(&chunk_path).await?;
+                    if compression == Compression::None {
+                        fs::write(&chunk_path, &data).await?;
+                    } else {
+                        compress_reader(
+                            &data[..],
+                            compression,
+                            dictionary,
+                            quality,
+                            &chunk_path,
+                            buffer_size,
+                        )
+                        .await?;
+                    }
+                }
+
+                parts.push(Part {
+                    hash,
+                    size: data.len() as u64,
+                });
+            }
+
+            hashes.insert(file_path.clone(), parts);
+            continue;
+        }
+
+        let base_hash = match base {
+            Some(base) if base.hash_method == hash_method => base.unchanged_hash(file_path).await?,
+            _ => None,
+        };
+        let hash = match base_hash {
+            Some(hash)
+                if chunks_path
+                    .join(repo_chunk_path(layout, &hash, &compression, dictionary))
+                    .exists() =>
+            {
+                hash
+            }
+            _ => hash_file(file_path, hash_method, buffer_size).await?,
+        };
+
+        // Identical content is shared, so only the path needs recording.
+        let chunk_path = chunks_path.join(repo_chunk_path(layout, &hash, &compression, dictionary));
+        if written.insert(hash.clone()) && !chunk_path.exists() {
+            create_chunk_dir(&chunk_path).await?;
+            if compression == Compression::None {
+                if fs::hard_link(&file_path, &chunk_path).await.is_err() {
+                    fs::copy(&file_path, &chunk_path).await?;
+                }
+            } else {
+                compress(
+                    file_path,
+                    compression,
+                    dictionary,
+                    quality,
+                    &chunk_path,
+                    buffer_size,
+                )
+                .await?;
+            }
+        }
+
+        hashes.insert(file_path.clone(), vec![Part { hash, size }]);
+    }
+
+    Ok(hashes)
+}
+
+/// Extensions of formats that are already compressed.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avif", "br", "bz2", "gz", "jar", "jpeg", "jpg", "lz4", "mp3", "mp4", "ogg",
+    "png", "webm", "webp", "woff2", "xz", "zip", "zst",
+];
+
+/// First bytes of formats that are already compressed, for files without a telling extension.
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    b"\x89PNG",
+    b"\xff\xd8\xff",
+    b"GIF8",
+    b"\x28\xb5\x2f\xfd",
+    b"\x1f\x8b",
+    b"\xfd7zXZ\x00",
+    b"BZh",
+    b"PK\x03\x04",
+    b"7z\xbc\xaf\x27\x1c",
+    b"\x04\x22\x4d\x18",
+];
+
+/// Whether a file is already compressed, judging by its extension or first bytes, so
+/// compressing it again would only waste time.
+pub async fn looks_compressed(file_path: &Path) -> Result<bool, std::io::Error> {
+    let extension = file_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    if extension.is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str())) {
+        return Ok(true);
+    }
+
+    let mut prefix = Vec::with_capacity(8);
+    File::open(file_path)
+        .await?
+        .take(8)
+        .read_to_end(&mut prefix)
+        .await?;
+
+    Ok(COMPRESSED_MAGIC
+        .iter()
+        .any(|magic| prefix.starts_with(magic)))
+}
+
+/// Where a chunk was written under `chunks_path`: compressed, or else as is under its bare hash,
+/// as `write_chunks` stores files that are already compressed.
+fn stored_chunk_path(
+    chunks_path: &Path,
+    layout: &ChunkLayout,
+    hash: &str,
+    compression: &Compression,
+    dictionary: Option<&Dictionary>,
+) -> PathBuf {
+    let compressed = chunks_path.join(repo_chunk_path(layout, hash, compression, dictionary));
+    let raw = chunks_path.join(layout.path(hash, &Compression::None));
+    if !compressed.exists() && raw.exists() {
+        raw
+    } else {
+        compressed
+    }
+}
+
+/// Creates the directory a chunk goes in, which a sharded layout may not have yet.
+async fn create_chunk_dir(chunk_path: &Path) -> Result<(), std::io::Error> {
+    match chunk_path.parent() {
+        Some(parent) => fs::create_dir_all(parent).await,
+        None => Ok(()),
+    }
+}
+
+/// Hashes every file like `write_chunks`, but only checks its chunks are already in
+/// `chunks_path` rather than writing them, for regenerating a manifest over existing output.
+pub async fn hash_existing_chunks(
+    files: &[PathBuf],
+    chunks_path: &Path,
+    options: &ChunkOptions<'_>,
+) -> Result<HashMap<PathBuf, Vec<Part>>, Box<dyn std::error::Error>> {
+    let ChunkOptions {
+        hash_method,
+        compression,
+        dictionary,
+        chunk_size,
+        buffer_size,
+        ref layout,
+        ..
+    } = *options;
+    check_chunk_size(chunk_size)?;
+
+    let mut hashes = HashMap::new();
+
+    for file_path in files {
+        let size = fs::metadata(file_path).await?.len();
+        let mut parts = Vec::new();
+        match chunk_size {
+            Some(chunk_size) if size > chunk_size => {
+                let mut file = File::open(file_path).await?;
+                loop {
+                    let data = read_part(&mut file, chunk_size).await?;
+                    if data.is_empty() {
+                        break;
+                    }
+
+                    parts.push(Part {
+                        hash: hash_bytes(&data, hash_method)?,
+                        size: data.len() as u64,
+                    });
+                }
+            }
+            _ => parts.push(Part {
+                hash: hash_file(file_path, hash_method, buffer_size).await?,
+                size,
+            }),
+        }
+
+        for part in &parts {
+            let chunk_path =
+                stored_chunk_path(chunks_path, layout, &part.hash, &compression, dictionary);
+            if !chunk_path.exists() {
+                return Err(format!(
+                    "{} needs chunk {}, which hasn't been written. Package it in full first",
+                    file_path.display(),
+                    chunk_path.display()
+                )
+                .into());
+            }
+        }
+
+        hashes.insert(file_path.clone(), parts);
+    }
+
+    Ok(hashes)
+}
+
+/// Split files record sizes per part in kilobytes, so parts must be whole kilobytes.
+fn check_chunk_size(chunk_size: Option<u64>) -> Result<(), String> {
+    match chunk_size {
+        Some(chunk_size) if chunk_size == 0 || chunk_size % 1024 != 0 => Err(format!(
+            "chunk size {chunk_size} isn't a whole number of kilobytes"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reads the next part of a file being split, empty once it's all read.
+async fn read_part(file: &mut File, chunk_size: u64) -> Result<Vec<u8>, std::io::Error> {
+    let mut data = Vec::with_capacity(chunk_size as usize);
+    file.take(chunk_size).read_to_end(&mut data).await?;
+
+    Ok(data)
+}
+
+fn hash_bytes(data: &[u8], hash_method: HashType) -> Result<String, Error> {
+    let mut hasher = Hasher::new(hash_method)?;
+    hasher.write(data);
+
+    Ok(hasher.digest())
+}
+
+/// What the manifest declares, and records about each file.
+pub struct ManifestOptions {
+    pub compression: Compression,
+    pub hash_method: HashType,
+    pub record_mtime: bool,
+    /// Clamps recorded modification times to this Unix timestamp. Implies `record_mtime`
+    pub clamp_mtime: Option<i64>,
+    /// Hash of the dictionary chunks were compressed with
+    pub dictionary: Option<String>,
+    /// Unix timestamp recorded in the `Generated` header, letting clients refuse stale manifests
+    pub generated: Option<i64>,
+    /// Patches from the previous manifest's chunks, listed in the `Deltas` header
+    pub deltas: Vec<Delta>,
+    /// Declares chunks addressable as raw IPFS blocks in a `ChunkCids` header. Only meaningful
+    /// for uncompressed blake3 chunks
+    pub chunk_cids: bool,
+}
+
+/// Writes the manifest for `files`, hashed by `write_chunks`.
+/// Lines are sorted by path, so the same tree always produces the same manifest.
+pub async fn generate_manifest(
+    input_path: &Path,
+    files: &[PathBuf],
+    hashes: &HashMap<PathBuf, Vec<Part>>,
+    options: &ManifestOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // Only split files need the newer format, so other manifests stay readable by older clients
+    let split = files
+        .iter()
+        .any(|file| hashes.get(file).is_some_and(|parts| parts.len() > 1));
+    let format_version = if split { FORMAT_VERSION } else { 1 };
+    let mut manifest = format!("FormatVersion: {format_version}\n");
+
+    if options.compression != Compression::None {
+        manifest += &format!("Compression: {}\n", options.compression.header_value());
+    }
+    if let Some(dictionary) = &options.dictionary {
+        manifest += &format!("Dictionary: {dictionary}\n");
+    }
+    if !options.deltas.is_empty() {
+        manifest += &format!("Deltas: {}\n", format_deltas(&options.deltas));
+    }
+    if options.chunk_cids {
+        manifest += "ChunkCids: raw\n";
+    }
+    if let Some(generated) = options.generated {
+        manifest += &format!("Generated: {}\n", format_generated(generated)?);
+    }
+    manifest += &format!("Hasher: {}\n", options.hash_method.header_value());
+
+    let record_mtime = options.record_mtime || options.clamp_mtime.is_some();
+    if record_mtime {
+        manifest += "Timestamps: mtime\n";
+    }
+
+    let mut files = files.to_vec();
+    files.sort();
+
+    let mut chunklist = String::new();
+    let mut chunks = Vec::new();
+
+    for file in &files {
+        let parts = hashes
+            .get(file)
+            .expect("tried adding file to manifest that has no hash");
+        let metadata = fs::metadata(&file).await?;
+        // Unix permission mode
+        let mode = platform::file_mode(&metadata).unwrap_or(DEFAULT_MODE);
+        let path = file
+            .strip_prefix(input_path)
+            .expect("tried adding file to manifest that is outside of input_path")
+            .to_str()
+            .unwrap();
+
+        let mtime = match options.clamp_mtime {
+            Some(clamp) => platform::mtime(&metadata).min(clamp),
+            None => platform::mtime(&metadata),
+        };
+
+        for Part { hash, size } in parts {
+            // Size in KILOBYTES
+            let size = size / 1024;
+            if record_mtime {
+                chunklist += &format!("{mode};{size};{hash};{mtime};{path}\n");
+            } else {
+                chunklist += &format!("{mode};{size};{hash};{path}\n");
+            }
+
+            chunks.push(Chunk {
+                hash: hash.clone(),
+                size,
+                path: path.to_string(),
+                permissions: mode,
+                mtime: record_mtime.then_some(mtime),
+            });
+        }
+    }
+
+    // Lets clients and monitoring compare the installed tree against what was packaged
+    manifest += &format!("TreeHash: {}\n", tree_hash(&chunks));
+    // Lets clients notice a chunklist cut short
+    manifest += &format!("ChunkCount: {}\n", chunks.len());
+    manifest += "---\n";
+    manifest += &chunklist;
+
+    Ok(manifest)
+}
+
+/// How well one unique chunk compressed, for `--report`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportRow {
+    /// First file holding the chunk, relative to the input path
+    pub path: String,
+    pub hash: String,
+    pub original_bytes: u64,
+    pub stored_bytes: u64,
+    /// Stored over original bytes, so lower is better
+    pub ratio: f64,
+    /// `none` for chunks `smart_compression` stored as is
+    pub codec: &'static str,
+}
+
+/// Lists every unique chunk `write_chunks` produced with its original size, from `hashes`, and
+/// the size it was stored at under `chunks_path`. Rows are sorted by path.
+pub async fn compression_report(
+    input_path: &Path,
+    files: &[PathBuf],
+    hashes: &HashMap<PathBuf, Vec<Part>>,
+    chunks_path: &Path,
+    layout: &ChunkLayout,
+    compression: Compression,
+    dictionary: Option<&Dictionary>,
+) -> Result<Vec<ReportRow>, std::io::Error> {
+    let mut files = files.to_vec();
+    files.sort();
+
+    let mut seen = HashSet::new();
+    let mut rows = Vec::new();
+    for file in &files {
+        let Some(parts) = hashes.get(file) else {
+            continue;
+        };
+        let path = file.strip_prefix(input_path).unwrap_or(file);
+
+        for Part { hash, size } in parts {
+            if !seen.insert(hash) {
+                continue;
+            }
+
+            let stored_path =
+                stored_chunk_path(chunks_path, layout, hash, &compression, dictionary);
+            let codec = if stored_path == chunks_path.join(layout.path(hash, &Compression::None)) {
+                Compression::None.header_value()
+            } else {
+                compression.header_value()
+            };
+            let stored_bytes = fs::metadata(&stored_path).await?.len();
+            rows.push(ReportRow {
+                path: path.to_string_lossy().into_owned(),
+                hash: hash.clone(),
+                original_bytes: *size,
+                stored_bytes,
+                ratio: match size {
+                    0 => 1.0,
+                    size => stored_bytes as f64 / *size as f64,
+                },
+                codec,
+            });
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Formats `compression_report`'s rows as CSV with a header line.
+pub fn format_report_csv(rows: &[ReportRow]) -> String {
+    let mut csv = String::from("path,hash,original_bytes,stored_bytes,ratio,codec\n");
+    for row in rows {
+        let path = if row.path.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", row.path.replace('"', "\"\""))
+        } else {
+            row.path.clone()
+        };
+        csv += &format!(
+            "{path},{},{},{},{:.4},{}\n",
+            row.hash, row.original_bytes, row.stored_bytes, row.ratio, row.codec
+        );
+    }
+    csv
+}
+
+pub async fn hash_file(
+    file_path: &Path,
+    hash_method: HashType,
+    buffer_size: usize,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut source_file = match File::open(&file_path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("couldn't open source file: {}", file_path.display());
+            panic!("{e}")
+        }
+    };
+
+    let mut hasher = Hasher::new(hash_method)?;
+
+    let mut buf = vec![0; buffer_size];
+    loop {
+        let n = source_file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        let chunk = &buf[0..n];
+        hasher.write(chunk);
+    }
+
+    let hash = hasher.digest();
+
+    Ok(hash)
+}
+
+pub async fn compress(
+    file_path: &Path,
+    compression: Compression,
+    dictionary: Option<&Dictionary>,
+    quality: Level,
+    compressed_chunk_path: &Path,
+    buffer_size: usize,
+) -> Result<(), std::io::Error> {
+    if compression == Compression::None {
+        panic!("Tried to compress on a non-compressable request.")
+    }
+
+    if !compressed_chunk_path.exists() {
+        let source_file = File::open(&file_path).await.unwrap();
+        compress_reader(
+            source_file,
+            compression,
+            dictionary,
+            quality,
+            compressed_chunk_path,
+            buffer_size,
+        )
+        .await?;
+
+        info!(phase = "compress", path = %file_path.display(), "Compressed chunk from path {file_path:?}");
+    };
+
+    Ok(())
+}
+
+/// Compresses everything `source` yields into `compressed_chunk_path`.
+async fn compress_reader(
+    mut source: impl AsyncRead + Unpin,
+    compression: Compression,
+    dictionary: Option<&Dictionary>,
+    quality: Level,
+    compressed_chunk_path: &Path,
+    buffer_size: usize,
+) -> Result<(), std::io::Error> {
+    let temp_file_path = temp_file::TempFile::new()?;
+    let mut temp_file = File::create(&temp_file_path).await?;
+
+    let mut compressor: Box<dyn AsyncWrite + Sync + Unpin> = match compression {
+        Compression::Zstd => match dictionary {
+            Some(dictionary) => Box::new(ZstdEncoder::with_dict(
+                &mut temp_file,
+                quality,
+                &dictionary.data,
+            )?),
+            None => Box::new(ZstdEncoder::with_quality(&mut temp_file, quality)),
+        },
+        Compression::Brotli => Box::new(BrotliEncoder::with_quality(&mut temp_file, quality)),
+        Compression::Lz4 => Box::new(Lz4Encoder::with_quality(&mut temp_file, quality)),
+        Compression::None => panic!("Tried to copmress on a non-compressable request."),
+    };
+
+    let mut buf = vec![0; buffer_size];
+    loop {
+        let n = source.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        compressor.write_all(&buf[0..n]).await?;
+    }
+
+    // Finish compressing
+    compressor.flush().await?;
+    compressor.shutdown().await?;
+
+    // Move compressed from memory and onto disk
+    fs::copy(temp_file_path, compressed_chunk_path).await?;
+
+    Ok(())
+}
+
+/// Writes a zstd patch into `output_path` for each file whose contents changed since
+/// `base_manifest`, against that manifest's chunk for the same path, which must be in `output_path`. Patches are only kept when
+/// under half the size of the chunk they stand in for, stored as `options` describes.
+/// Split files aren't patched.
+pub async fn write_deltas(
+    input_path: &Path,
+    output_path: &Path,
+    base_manifest: &str,
+    hashes: &HashMap<PathBuf, Vec<Part>>,
+    options: &ChunkOptions<'_>,
+) -> Result<Vec<Delta>, Box<dyn std::error::Error>> {
+    let ChunkOptions {
+        compression,
+        dictionary,
+        buffer_size,
+        ref layout,
+        ..
+    } = *options;
+    let source = FileSource::new(output_path);
+    let (base_headers, base_chunklist) = parse_manifest(base_manifest)?;
+    let (base_compression, base_hasher) = read_headers(&base_headers)?;
+    let base_dictionary = read_dictionary(&source, &base_headers, base_compression).await?;
+    let base_chunks: HashMap<&str, &Chunk> = file_chunks(&base_chunklist)
+        .filter_map(|parts| match parts {
+            [chunk] => Some((chunk.path.as_str(), chunk)),
+            _ => None,
+        })
+        .collect();
+
+    let mut files: Vec<_> = hashes.iter().collect();
+    files.sort_by_key(|(file, _)| *file);
+
+    // Base chunks are installed as the updater would, to read them back decompressed
+    let chunkstore_path = &std::env::temp_dir().join(format!(
+        "pkgsmgr-deltas-{}-{}",
+        std::process::id(),
+        blake3::hash(base_manifest.as_bytes()).to_hex()
+    ));
+    fs::create_dir_all(chunkstore_path).await?;
+
+    let result = async {
+        let mut deltas = Vec::new();
+        let mut seen = HashSet::new();
+        for (file, parts) in files {
+            let [part] = parts.as_slice() else {
+                continue;
+            };
+            let Some(path) = file.strip_prefix(input_path).ok().and_then(Path::to_str) else {
+                continue;
+            };
+            let Some(base) = base_chunks.get(path) else {
+                continue;
+            };
+            if base.hash == part.hash || !seen.insert(&part.hash) {
+                continue;
+            }
+
+            if let Err(e) = install_chunk(
+                &source,
+                base,
+                chunkstore_path,
+                &InstallOptions {
+                    compression: base_compression,
+                    dictionary: base_dictionary.as_ref(),
+                    hash_method: base_hasher,
+                    sync: false,
+                    buffer_size,
+                    layout: layout.clone(),
+                    ..Default::default()
+                },
+            )
+            .await
+            {
+                warn!("Not writing a delta for {path}, as its previous chunk is unusable: {e}");
+                continue;
+            }
+            let base_data = fs::read(chunkstore_path.join(chunk_filename(base))).await?;
+            let target = fs::read(file).await?;
+            let delta = create_delta(&base_data, &target, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+
+            let chunk_path = stored_chunk_path(
+                &output_path.join("chunks"),
+                layout,
+                &part.hash,
+                &compression,
+                dictionary,
+            );
+            let stored = fs::metadata(&chunk_path).await?.len();
+            if delta.len() as u64 >= stored / 2 {
+                continue;
+            }
+
+            info!(
+                phase = "delta",
+                path,
+                bytes = delta.len(),
+                "Wrote a {}-byte delta for {path}",
+                delta.len()
+            );
+            deltas.push(Delta {
+                target: part.hash.clone(),
+                base: base.hash.clone(),
+                hash: write_delta(output_path, &delta)?,
+            });
+        }
+
+        Ok::<_, Box<dyn std::error::Error>>(deltas)
+    }
+    .await;
+
+    fs::remove_dir_all(chunkstore_path).await?;
+
+    result
+}
+
+/// Installs every chunk of the packaged output at `output_path` as the updater would, rebuilds the
+/// tree in a temporary directory, and compares each file's contents and mode against `input_path`.
+/// Chunks are read from where `layout` placed them, and the manifest found through `pointer`.
+pub async fn verify_roundtrip(
+    input_path: &Path,
+    output_path: &Path,
+    layout: &ChunkLayout,
+    pointer: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let source = FileSource::new(output_path).with_pointer(pointer);
+    let manifest_hash = source.fetch_pointer().await?;
+    let manifest_raw = source.fetch_manifest(manifest_hash.trim()).await?;
+    let (headers, chunklist) = parse_manifest(&manifest_raw)?;
+    let (compression, hasher) = read_headers(&headers)?;
+    let dictionary = read_dictionary(&source, &headers, compression).await?;
+
+    let temp_path = std::env::temp_dir().join(format!(
+        "pkgsmgr-roundtrip-{}-{}",
+        std::process::id(),
+        manifest_hash.trim()
+    ));
+    let chunkstore_path = &temp_path.join("chunkstore");
+    let tree_path = &temp_path.join("tree");
+    fs::create_dir_all(chunkstore_path).await?;
+
+    let result = async {
+        for chunk in missing_chunks(&chunklist, chunkstore_path) {
+            install_chunk(
+                &source,
+                chunk,
+                chunkstore_path,
+                &InstallOptions {
+                    compression,
+                    dictionary: dictionary.as_ref(),
+                    hash_method: hasher,
+                    sync: false,
+                    layout: layout.clone(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| format!("{}: {e}", chunk.path))?;
+        }
+
+        build_tree(tree_path, chunkstore_path, &chunklist)?;
+        verify_tree(tree_path, &chunklist)?;
+
+        for parts in file_chunks(&chunklist) {
+            let chunk = &parts[0];
+            let original_path = input_path.join(&chunk.path);
+            let rebuilt_path = tree_path.join(&chunk.path);
+
+            if fs::read(&original_path).await? != fs::read(&rebuilt_path).await? {
+                return Err(format!("{} differs from the original", chunk.path).into());
+            }
+
+            let original_mode = platform::file_mode(&fs::metadata(&original_path).await?)
+                .map(|mode| mode & 0o7777 & !0o222);
+            let rebuilt_mode =
+                platform::file_mode(&fs::metadata(&rebuilt_path).await?).map(|mode| mode & 0o7777);
+            if let (Some(original_mode), Some(rebuilt_mode)) = (original_mode, rebuilt_mode)
+                && original_mode != rebuilt_mode
+            {
+                return Err(format!(
+                    "{} has mode {rebuilt_mode:o}, expected {original_mode:o}",
+                    chunk.path
+                )
+                .into());
+            }
+        }
+
+        Ok::<(), Box<dyn std::error::Error>>(())
+    }
+    .await;
+
+    fs::remove_dir_all(&temp_path).await?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::POINTER;
+    use crate::update::check_tree_hash;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_discover_skips_special_files() {
+        let input = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir(input.child("dir")).unwrap();
+        std::fs::write(input.child("dir/file"), "file").unwrap();
+        nix::unistd::mkfifo(&input.child("dir/fifo"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(input.child("socket")).unwrap();
+        std::os::unix::fs::symlink("dir/file", input.child("link")).unwrap();
+
+        let discovered = discover(input.path(), &Excludes::new(&[]).unwrap()).unwrap();
+        assert_eq!(discovered.files, vec![input.child("dir/file")]);
+        assert_eq!(discovered.directories, vec![input.child("dir")]);
+        assert_eq!(discovered.symlinks, 1);
+        assert_eq!(
+            discovered.special,
+            vec![input.child("dir/fifo"), input.child("socket")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_input_path() {
+        let input = temp_dir::TempDir::new().unwrap();
+        std::fs::write(input.child("file"), "").unwrap();
+        std::fs::create_dir(input.child("tree")).unwrap();
+        std::os::unix::fs::symlink(input.child("tree"), input.child("link")).unwrap();
+
+        assert!(resolve_input_path(&input.child("file")).is_err());
+        assert!(resolve_input_path(&input.child("missing")).is_err());
+        assert_eq!(
+            resolve_input_path(&input.child("link")).unwrap(),
+            input.child("tree").canonicalize().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_files_share_one_chunk() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let output = temp_dir::TempDir::new().unwrap();
+
+        let mut files = Vec::new();
+        for name in ["a", "b", "c"] {
+            let path = input.child(name);
+            std::fs::write(&path, "identical content").unwrap();
+            files.push(path);
+        }
+
+        let hashes = write_chunks(
+            &files,
+            output.path(),
+            None,
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(hashes.values().collect::<HashSet<_>>().len(), 1);
+
+        let chunks: Vec<_> = std::fs::read_dir(output.path()).unwrap().collect();
+        assert_eq!(chunks.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_compression_report() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let output = temp_dir::TempDir::new().unwrap();
+
+        let mut files = Vec::new();
+        let compressible = "a".repeat(64 * 1024);
+        for (name, content) in [
+            ("a, b", compressible.as_str()),
+            ("copy", compressible.as_str()),
+            ("small", "tiny"),
+        ] {
+            let path = input.child(name);
+            std::fs::write(&path, content).unwrap();
+            files.push(path);
+        }
+
+        let hashes = write_chunks(
+            &files,
+            output.path(),
+            None,
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let rows = compression_report(
+            input.path(),
+            &files,
+            &hashes,
+            output.path(),
+            &ChunkLayout::default(),
+            Compression::Zstd,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // The copy shares the first file's chunk, so only gets the one row
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].path, "a, b");
+        assert_eq!(rows[0].hash, hashes[&files[0]][0].hash);
+        assert_eq!(rows[0].original_bytes, 64 * 1024);
+        assert_eq!(rows[1].path, "small");
+        assert_eq!(rows[1].original_bytes, 4);
+        for row in &rows {
+            let stored = output.child(ChunkLayout::default().path(&row.hash, &Compression::Zstd));
+            assert_eq!(row.stored_bytes, std::fs::metadata(stored).unwrap().len());
+            assert_eq!(row.codec, "zstd");
+        }
+        assert!(rows[0].ratio < 0.1);
+
+        let csv = format_report_csv(&rows);
+        assert!(csv.starts_with("path,hash,original_bytes,stored_bytes,ratio,codec\n"));
+        assert!(csv.contains(&format!("\"a, b\",{},65536,", rows[0].hash)));
+        assert_eq!(csv.lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_packaged_chunks_match_updater_names() {
+        let input = temp_dir::TempDir::new().unwrap();
+
+        let mut files = Vec::new();
+        for (name, content) in [("a", "first"), ("b", "second"), ("c", "first")] {
+            let path = input.child(name);
+            std::fs::write(&path, content).unwrap();
+            files.push(path);
+        }
+
+        for compression in [
+            Compression::None,
+            Compression::Zstd,
+            Compression::Brotli,
+            Compression::Lz4,
+        ] {
+            let output = temp_dir::TempDir::new().unwrap();
+            let hashes = write_chunks(
+                &files,
+                output.path(),
+                None,
+                &ChunkOptions {
+                    compression,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+            let expected: HashSet<String> = hashes
+                .values()
+                .map(|parts| ChunkLayout::default().path(&parts[0].hash, &compression))
+                .collect();
+            let written: HashSet<String> = std::fs::read_dir(output.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+
+            assert_eq!(written, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_brotli_package_and_install() {
+        use crate::chunks::{Chunk, chunk_filename, install_chunk};
+        use crate::source::HttpSource;
+        use crate::test_utils::TestServer;
+
+        let input = temp_dir::TempDir::new().unwrap();
+        let repo = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        let chunks_path = repo.child("chunks");
+        std::fs::create_dir_all(&chunks_path).unwrap();
+
+        let content = "text heavy payload ".repeat(1000);
+        let file = input.child("file");
+        std::fs::write(&file, &content).unwrap();
+
+        let hashes = write_chunks(
+            std::slice::from_ref(&file),
+            &chunks_path,
+            None,
+            &ChunkOptions {
+                compression: Compression::Brotli,
+                quality: Level::Precise(5),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let hash = hashes[&file][0].hash.clone();
+        assert!(chunks_path.join(format!("{hash}.br")).exists());
+
+        let compression = Compression::from_header(Compression::Brotli.header_value()).unwrap();
+        assert_eq!(compression, Compression::Brotli);
+
+        let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+        let chunk = Chunk {
+            hash,
+            size: content.len() as u64 / 1024,
+            path: "file".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+        install_chunk(
+            &HttpSource::new(reqwest::Client::new(), &server.url),
+            &chunk,
+            chunkstore.path(),
+            &InstallOptions {
+                compression,
+                sync: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let installed = std::fs::read_to_string(chunkstore.child(chunk_filename(&chunk))).unwrap();
+        assert_eq!(installed, content);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_empty_files_roundtrip() {
+        use crate::source::HttpSource;
+        use crate::test_utils::TestServer;
+        use crate::update::{UpdateOptions, update};
+        use std::os::unix::fs::PermissionsExt;
+
+        let input = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir_all(input.child("etc")).unwrap();
+        std::fs::write(input.child("etc/marker"), "").unwrap();
+        std::fs::write(input.child("etc/run"), "").unwrap();
+        std::fs::set_permissions(
+            input.child("etc/run"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        std::fs::write(input.child("etc/data"), "data").unwrap();
+        let files = vec![
+            input.child("etc/data"),
+            input.child("etc/marker"),
+            input.child("etc/run"),
+        ];
+
+        for compression in [
+            Compression::None,
+            Compression::Zstd,
+            Compression::Brotli,
+            Compression::Lz4,
+        ] {
+            let repo = temp_dir::TempDir::new().unwrap();
+            let hashes = write_chunks(
+                &files,
+                &repo.child("chunks"),
+                None,
+                &ChunkOptions {
+                    compression,
+                    chunk_size: Some(1024),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            // Both empty files share the one empty chunk
+            assert_eq!(hashes[&files[1]], hashes[&files[2]]);
+            assert_eq!(hashes[&files[1]].len(), 1);
+
+            let options = ManifestOptions {
+                compression,
+                hash_method: HashType::Blake3,
+                record_mtime: false,
+                clamp_mtime: None,
+                dictionary: None,
+                generated: None,
+                deltas: Vec::new(),
+                chunk_cids: false,
+            };
+            let manifest = generate_manifest(input.path(), &files, &hashes, &options)
+                .await
+                .unwrap();
+            let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+            std::fs::write(repo.child(&manifest_hash), &manifest).unwrap();
+            std::fs::write(repo.child("manifest"), &manifest_hash).unwrap();
+            // A compressed repo may keep the empty chunk as is, under its bare hash
+            if compression == Compression::Zstd {
+                let empty = &hashes[&files[1]][0].hash;
+                std::fs::remove_file(repo.child("chunks").join(format!("{empty}.zstd"))).unwrap();
+                std::fs::write(repo.child("chunks").join(empty), "").unwrap();
+            }
+
+            let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+            let file_source = FileSource::new(repo.path());
+            let http_source = HttpSource::new(reqwest::Client::new(), &server.url);
+            for source in [&file_source as &dyn RepoSource, &http_source] {
+                let root = temp_dir::TempDir::new().unwrap();
+                update(source, root.path(), &UpdateOptions::default())
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                for (path, mode) in [("usr/etc/marker", 0o444), ("usr/etc/run", 0o555)] {
+                    let metadata = std::fs::metadata(root.child(path)).unwrap();
+                    assert!(metadata.is_file(), "{compression:?} {path}");
+                    assert_eq!(metadata.len(), 0, "{compression:?} {path}");
+                    assert_eq!(
+                        metadata.permissions().mode() & 0o777,
+                        mode,
+                        "{compression:?}"
+                    );
+                }
+                assert_eq!(
+                    std::fs::read_to_string(root.child("usr/etc/data")).unwrap(),
+                    "data"
+                );
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_special_mode_bits_roundtrip() {
+        use crate::update::{UpdateOptions, update};
+        use std::os::unix::fs::PermissionsExt;
+
+        let input = temp_dir::TempDir::new().unwrap();
+        let repo = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir(input.child("bin")).unwrap();
+        // The same contents with and without setuid must not end up sharing an inode
+        let files = [
+            ("bin/setuid", 0o4755),
+            ("bin/setgid", 0o2755),
+            ("bin/sticky", 0o1644),
+            ("bin/plain", 0o755),
+        ]
+        .map(|(path, mode)| {
+            let path = input.child(path);
+            std::fs::write(&path, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+            path
+        });
+
+        let hashes = write_chunks(
+            &files,
+            &repo.child("chunks"),
+            None,
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let options = ManifestOptions {
+            compression: Compression::Zstd,
+            hash_method: HashType::Blake3,
+            record_mtime: false,
+            clamp_mtime: None,
+            dictionary: None,
+            generated: None,
+            deltas: Vec::new(),
+            chunk_cids: false,
+        };
+        let manifest = generate_manifest(input.path(), &files, &hashes, &options)
+            .await
+            .unwrap();
+        assert!(manifest.contains(&format!("{};", 0o104755)), "{manifest}");
+        let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        std::fs::write(repo.child(&manifest_hash), &manifest).unwrap();
+        std::fs::write(repo.child(POINTER), &manifest_hash).unwrap();
+
+        let root = temp_dir::TempDir::new().unwrap();
+        update(
+            &FileSource::new(repo.path()),
+            root.path(),
+            &UpdateOptions::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        for (path, mode) in [
+            ("usr/bin/setuid", 0o4555),
+            ("usr/bin/setgid", 0o2555),
+            ("usr/bin/sticky", 0o1444),
+            ("usr/bin/plain", 0o555),
+        ] {
+            let metadata = std::fs::metadata(root.child(path)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o7777, mode, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_smart_compression() {
+        use crate::update::{UpdateOptions, update};
+
+        let input = temp_dir::TempDir::new().unwrap();
+        let repo = temp_dir::TempDir::new().unwrap();
+        let image = b"\x89PNG\r\n\x1a\n pretend pixels ".repeat(64);
+        let text = "plain text compresses well ".repeat(64);
+        std::fs::write(input.child("logo.png"), &image).unwrap();
+        // Recognized by its first bytes alone
+        std::fs::write(
+            input.child("photo"),
+            [&[0xff, 0xd8, 0xff][..], &image].concat(),
+        )
+        .unwrap();
+        std::fs::write(input.child("readme.txt"), &text).unwrap();
+        let files = vec![
+            input.child("logo.png"),
+            input.child("photo"),
+            input.child("readme.txt"),
+        ];
+
+        let hashes = write_chunks(
+            &files,
+            &repo.child("chunks"),
+            None,
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                smart_compression: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let stored = |file: &PathBuf| repo.child("chunks").join(&hashes[file][0].hash);
+        for raw in &files[..2] {
+            assert_eq!(
+                std::fs::read(stored(raw)).unwrap(),
+                std::fs::read(raw).unwrap()
+            );
+            assert!(!stored(raw).with_extension("zstd").exists());
+        }
+        assert!(!stored(&files[2]).exists());
+        assert!(stored(&files[2]).with_extension("zstd").exists());
+
+        // Checking the output finds the chunks stored as is too
+        hash_existing_chunks(
+            &files,
+            &repo.child("chunks"),
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let options = ManifestOptions {
+            compression: Compression::Zstd,
+            hash_method: HashType::Blake3,
+            record_mtime: false,
+            clamp_mtime: None,
+            dictionary: None,
+            generated: None,
+            deltas: Vec::new(),
+            chunk_cids: false,
+        };
+        let manifest = generate_manifest(input.path(), &files, &hashes, &options)
+            .await
+            .unwrap();
+        let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        std::fs::write(repo.child(&manifest_hash), &manifest).unwrap();
+        std::fs::write(repo.child("manifest"), &manifest_hash).unwrap();
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let source = FileSource::new(repo.path());
+        update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(root.child("usr/logo.png")).unwrap(), image);
+        assert_eq!(
+            std::fs::read_to_string(root.child("usr/readme.txt")).unwrap(),
+            text
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_roundtrip_detects_corruption() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let output = temp_dir::TempDir::new().unwrap();
+        let chunks_path = output.child("chunks");
+        std::fs::create_dir_all(&chunks_path).unwrap();
+
+        let file = input.child("file");
+        std::fs::write(&file, "original contents").unwrap();
+
+        let hashes = write_chunks(
+            std::slice::from_ref(&file),
+            &chunks_path,
+            None,
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let hash = &hashes[&file][0].hash;
+
+        let mode = platform::file_mode(&std::fs::metadata(&file).unwrap()).unwrap();
+        let manifest = format!("Compression: zstd\nHasher: blake3\n---\n{mode};0;{hash};file\n");
+        let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        std::fs::write(output.child(&manifest_hash), manifest).unwrap();
+        std::fs::write(output.child("manifest"), &manifest_hash).unwrap();
+
+        verify_roundtrip(
+            input.path(),
+            output.path(),
+            &ChunkLayout::default(),
+            POINTER,
+        )
+        .await
+        .unwrap();
+
+        std::fs::write(chunks_path.join(format!("{hash}.zstd")), "corrupted").unwrap();
+        let error = verify_roundtrip(
+            input.path(),
+            output.path(),
+            &ChunkLayout::default(),
+            POINTER,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().starts_with("file: "));
+    }
+
+    #[tokio::test]
+    async fn test_lz4_package_and_install() {
+        use crate::chunks::{Chunk, chunk_filename, install_chunk};
+
+        let input = temp_dir::TempDir::new().unwrap();
+        let repo = temp_dir::TempDir::new().unwrap();
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        let chunks_path = repo.child("chunks");
+        std::fs::create_dir_all(&chunks_path).unwrap();
+
+        let content = vec![0u8; 256 * 1024];
+        let file = input.child("zeroes");
+        std::fs::write(&file, &content).unwrap();
+
+        let hashes = write_chunks(
+            std::slice::from_ref(&file),
+            &chunks_path,
+            None,
+            &ChunkOptions {
+                compression: Compression::Lz4,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let hash = hashes[&file][0].hash.clone();
+        let packaged = chunks_path.join(format!("{hash}.lz4"));
+        assert!(std::fs::metadata(&packaged).unwrap().len() < content.len() as u64 / 10);
+
+        let compression = Compression::from_header(Compression::Lz4.header_value()).unwrap();
+        assert_eq!(compression, Compression::Lz4);
+
+        let chunk = Chunk {
+            hash,
+            size: content.len() as u64 / 1024,
+            path: "zeroes".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+        install_chunk(
+            &FileSource::new(repo.path()),
+            &chunk,
+            chunkstore.path(),
+            &InstallOptions {
+                compression,
+                sync: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let installed = std::fs::read(chunkstore.child(chunk_filename(&chunk))).unwrap();
+        assert_eq!(installed, content);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_sizes_agree() {
+        use crate::chunks::{Chunk, chunk_filename, install_chunk};
+
+        let input = temp_dir::TempDir::new().unwrap();
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let file = input.child("file");
+        std::fs::write(&file, &content).unwrap();
+
+        let mut hashes = Vec::new();
+        // Sizes that don't divide the file, or the decompressor's output, evenly
+        for buffer_size in [1000, 4096, DEFAULT_BUFFER_SIZE, 1024 * 1024] {
+            let repo = temp_dir::TempDir::new().unwrap();
+            let chunkstore = temp_dir::TempDir::new().unwrap();
+            let chunks_path = repo.child("chunks");
+            std::fs::create_dir_all(&chunks_path).unwrap();
+
+            let parts = write_chunks(
+                std::slice::from_ref(&file),
+                &chunks_path,
+                None,
+                &ChunkOptions {
+                    compression: Compression::Zstd,
+                    buffer_size,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let hash = parts[&file][0].hash.clone();
+            assert_eq!(hash, hash_file(&file, HashType::Blake3, 7).await.unwrap());
+
+            let chunk = Chunk {
+                hash: hash.clone(),
+                size: content.len() as u64 / 1024,
+                path: "file".into(),
+                permissions: 0o100644,
+                mtime: None,
+            };
+            install_chunk(
+                &FileSource::new(repo.path()),
+                &chunk,
+                chunkstore.path(),
+                &InstallOptions {
+                    compression: Compression::Zstd,
+                    sync: false,
+                    buffer_size,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let installed = std::fs::read(chunkstore.child(chunk_filename(&chunk))).unwrap();
+            assert_eq!(installed, content);
+            hashes.push(hash);
+        }
+
+        assert!(hashes.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[tokio::test]
+    async fn test_dictionary_shrinks_small_chunks() {
+        use crate::chunks::{Chunk, chunk_filename, install_chunk};
+        use crate::test_utils::MemorySource;
+
+        let input = temp_dir::TempDir::new().unwrap();
+        let mut files = Vec::new();
+        for i in 0..1000 {
+            let path = input.child(format!("unit-{i}.service"));
+            let content = format!(
+                "[Unit]\nDescription=Example service number {i}\nAfter=network-online.target\n\
+                 Wants=network-online.target\n\n[Service]\nType=simple\n\
+                 ExecStart=/usr/bin/example-daemon --instance {i} --port {}\n\
+                 Restart=on-failure\nUser=example{}\n\n[Install]\nWantedBy=multi-user.target\n",
+                8000 + i * 7,
+                i % 13
+            );
+            std::fs::write(&path, content).unwrap();
+            files.push(path);
+        }
+
+        let packaged_size = |path: &Path| -> u64 {
+            walkdir::WalkDir::new(path)
+                .into_iter()
+                .map(|entry| entry.unwrap().metadata().unwrap())
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .sum()
+        };
+
+        let plain = temp_dir::TempDir::new().unwrap();
+        write_chunks(
+            &files,
+            plain.path(),
+            None,
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let dictionary = Dictionary::train(&files, 8 * 1024).unwrap();
+        let repo = temp_dir::TempDir::new().unwrap();
+        let chunks_path = repo.child("chunks");
+        let hashes = write_chunks(
+            &files,
+            &chunks_path,
+            None,
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                dictionary: Some(&dictionary),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(packaged_size(&chunks_path) < packaged_size(plain.path()));
+
+        let content = std::fs::read(&files[42]).unwrap();
+        let chunk = Chunk {
+            hash: hashes[&files[42]][0].hash.clone(),
+            size: content.len() as u64 / 1024,
+            path: "unit-42.service".into(),
+            permissions: 0o100644,
+            mtime: None,
+        };
+        dictionary.write(repo.path()).unwrap();
+        let source = MemorySource::from_dir(repo.path());
+        let fetched = Dictionary::new(source.fetch_dictionary(&dictionary.hash).await.unwrap());
+        assert_eq!(fetched, dictionary);
+
+        let chunkstore = temp_dir::TempDir::new().unwrap();
+        install_chunk(
+            &source,
+            &chunk,
+            chunkstore.path(),
+            &InstallOptions {
+                compression: Compression::Zstd,
+                dictionary: Some(&fetched),
+                sync: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let installed = std::fs::read(chunkstore.child(chunk_filename(&chunk))).unwrap();
+        assert_eq!(installed, content);
+    }
+
+    #[tokio::test]
+    async fn test_incremental_rebuild() {
+        use std::time::{Duration, SystemTime};
+
+        let input = temp_dir::TempDir::new().unwrap();
+        let output = temp_dir::TempDir::new().unwrap();
+
+        let mut files = Vec::new();
+        for (name, content) in [("a", "alpha"), ("b", "bravo"), ("c", "charlie")] {
+            let path = input.child(name);
+            std::fs::write(&path, content).unwrap();
+            files.push(path);
+        }
+
+        let options = ManifestOptions {
+            compression: Compression::Zstd,
+            hash_method: HashType::Blake3,
+            record_mtime: true,
+            clamp_mtime: None,
+            dictionary: None,
+            generated: None,
+            deltas: Vec::new(),
+            chunk_cids: false,
+        };
+        let package = |base: Option<BaseManifest>| {
+            let files = files.clone();
+            let output = output.path().to_path_buf();
+            async move {
+                write_chunks(
+                    &files,
+                    &output,
+                    base.as_ref(),
+                    &ChunkOptions {
+                        compression: Compression::Zstd,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap()
+            }
+        };
+        let chunks = || -> HashSet<PathBuf> {
+            std::fs::read_dir(output.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect()
+        };
+
+        let hashes = package(None).await;
+        let manifest = generate_manifest(input.path(), &files, &hashes, &options)
+            .await
+            .unwrap();
+        let base_path = input.path().parent().unwrap().join(format!(
+            "base-{}",
+            blake3::hash(manifest.as_bytes()).to_hex()
+        ));
+        std::fs::write(&base_path, manifest).unwrap();
+        let before = chunks();
+
+        let set_mtime = |path: &Path, mtime: SystemTime| {
+            let file = std::fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(mtime).unwrap();
+        };
+        let mtime = std::fs::metadata(&files[1]).unwrap().modified().unwrap();
+
+        // Manifests record whole seconds, so make sure the change is visible
+        std::fs::write(&files[1], "bravo, changed").unwrap();
+        set_mtime(&files[1], mtime + Duration::from_secs(10));
+        // Same size and mtime, so the base manifest's stale hash is trusted
+        std::fs::write(&files[2], "CHARLIE").unwrap();
+        set_mtime(&files[2], mtime);
+
+        let base = BaseManifest::load(&base_path, input.path()).await.unwrap();
+        let rebuilt = package(Some(base)).await;
+        std::fs::remove_file(&base_path).unwrap();
+
+        let added: Vec<_> = chunks().difference(&before).cloned().collect();
+        assert_eq!(
+            added,
+            [output.child(format!("{}.zstd", rebuilt[&files[1]][0].hash))]
+        );
+        assert_eq!(rebuilt[&files[0]], hashes[&files[0]]);
+        assert_eq!(rebuilt[&files[2]], hashes[&files[2]]);
+
+        // Without trusting mtimes every file is hashed again
+        let rehashed = package(None).await;
+        assert_ne!(rehashed[&files[2]], hashes[&files[2]]);
+    }
+
+    #[tokio::test]
+    async fn test_package_stats() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let mut files = Vec::new();
+        for (name, content) in [
+            ("a", "x".repeat(3000)),
+            ("b", "x".repeat(3000)),
+            ("c", "y".repeat(3000)),
+            ("d", "z".repeat(10)),
+            ("e", String::new()),
+        ] {
+            let path = input.child(name);
+            std::fs::write(&path, content).unwrap();
+            files.push(path);
+        }
+
+        let stats = package_stats(&files, HashType::Blake3, Some(1024), DEFAULT_BUFFER_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            PackageStats {
+                files: 5,
+                total_bytes: 9010,
+                unique_files: 4,
+                unique_bytes: 6010,
+                // Each 3000 byte file splits into three, the small ones stay whole
+                chunks: 8,
+                ..Default::default()
+            }
+        );
+
+        let unsplit = package_stats(&files, HashType::Blake3, None, DEFAULT_BUFFER_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(unsplit.chunks, 4);
+    }
+
+    #[tokio::test]
+    async fn test_incremental_chunks_skip_unchanged_tree() {
+        use std::time::{Duration, SystemTime};
+
+        let input = temp_dir::TempDir::new().unwrap();
+        let output = temp_dir::TempDir::new().unwrap();
+        let chunks_path = output.child("chunks");
+        std::fs::create_dir(&chunks_path).unwrap();
+
+        let mut files = Vec::new();
+        for (name, content) in [("a", "alpha"), ("b", "bravo"), ("c", "alpha")] {
+            let path = input.child(name);
+            std::fs::write(&path, content).unwrap();
+            files.push(path);
+        }
+
+        let options = ManifestOptions {
+            compression: Compression::Zstd,
+            hash_method: HashType::Blake3,
+            record_mtime: true,
+            clamp_mtime: None,
+            dictionary: None,
+            generated: Some(0),
+            deltas: Vec::new(),
+            chunk_cids: false,
+        };
+        let package = || async {
+            let base = BaseManifest::latest(output.path(), input.path(), POINTER)
+                .await
+                .unwrap();
+            let hashes = write_chunks(
+                &files,
+                &chunks_path,
+                base.as_ref(),
+                &ChunkOptions {
+                    compression: Compression::Zstd,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let manifest = generate_manifest(input.path(), &files, &hashes, &options)
+                .await
+                .unwrap();
+            let hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+            std::fs::write(output.child(&hash), &manifest).unwrap();
+            std::fs::write(output.child("manifest"), &hash).unwrap();
+            manifest
+        };
+
+        let first = package().await;
+
+        // Backdate every chunk, so rewriting one would show
+        let backdated = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        let chunks: Vec<_> = std::fs::read_dir(&chunks_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        for chunk in &chunks {
+            let file = std::fs::File::options().write(true).open(chunk).unwrap();
+            file.set_modified(backdated).unwrap();
+        }
+
+        // Same size and mtime, so a run that reads it would notice the new content
+        let mtime = std::fs::metadata(&files[1]).unwrap().modified().unwrap();
+        std::fs::write(&files[1], "BRAVO").unwrap();
+        let file = std::fs::File::options()
+            .write(true)
+            .open(&files[1])
+            .unwrap();
+        file.set_modified(mtime).unwrap();
+
+        assert_eq!(package().await, first);
+        for chunk in &chunks {
+            let modified = std::fs::metadata(chunk).unwrap().modified().unwrap();
+            assert_eq!(modified, backdated, "{} was rewritten", chunk.display());
+        }
+        assert_eq!(
+            std::fs::read_dir(&chunks_path).unwrap().count(),
+            chunks.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_manifest_is_reproducible() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let output = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir_all(input.child("dir")).unwrap();
+
+        let mut files = Vec::new();
+        for (name, content) in [("b", "2"), ("a", "1"), ("dir/c", "3"), ("d", "1")] {
+            let path = input.child(name);
+            std::fs::write(&path, content).unwrap();
+            files.push(path);
+        }
+
+        let options = ManifestOptions {
+            compression: Compression::None,
+            hash_method: HashType::Blake3,
+            record_mtime: false,
+            clamp_mtime: None,
+            dictionary: None,
+            generated: None,
+            deltas: Vec::new(),
+            chunk_cids: false,
+        };
+        let hashes = write_chunks(
+            &files,
+            output.path(),
+            None,
+            &ChunkOptions {
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let first = generate_manifest(input.path(), &files, &hashes, &options)
+            .await
+            .unwrap();
+        files.reverse();
+        let second = generate_manifest(input.path(), &files, &hashes, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            blake3::hash(first.as_bytes()),
+            blake3::hash(second.as_bytes())
+        );
+        let paths: Vec<_> = first
+            .lines()
+            .skip_while(|line| *line != "---")
+            .skip(1)
+            .map(|line| line.rsplit(';').next().unwrap())
+            .collect();
+        assert_eq!(paths, ["a", "b", "d", "dir/c"]);
+
+        // The updater derives the same tree hash from the chunklist as the packager declared
+        let (headers, chunklist) = parse_manifest(&first).unwrap();
+        assert_eq!(headers["TreeHash"], tree_hash(&chunklist));
+        assert_eq!(
+            check_tree_hash(&headers, &chunklist),
+            Ok(tree_hash(&chunklist))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_large_files() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let output = temp_dir::TempDir::new().unwrap();
+        let chunks_path = output.child("chunks");
+        std::fs::create_dir_all(&chunks_path).unwrap();
+
+        let file = input.child("large");
+        let mut content: Vec<u8> = (0..4 * 4096 + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&file, &content).unwrap();
+        let files = vec![file.clone()];
+        let package = async |chunk_size| {
+            let options = ChunkOptions {
+                compression: Compression::Zstd,
+                chunk_size: Some(chunk_size),
+                ..Default::default()
+            };
+            write_chunks(&files, &chunks_path, None, &options).await
+        };
+
+        assert!(package(1000).await.is_err());
+        let before = package(4096).await.unwrap();
+        assert_eq!(before[&file].len(), 5);
+
+        let options = ManifestOptions {
+            compression: Compression::Zstd,
+            hash_method: HashType::Blake3,
+            record_mtime: false,
+            clamp_mtime: None,
+            dictionary: None,
+            generated: None,
+            deltas: Vec::new(),
+            chunk_cids: false,
+        };
+        let manifest = generate_manifest(input.path(), &files, &before, &options)
+            .await
+            .unwrap();
+        assert!(manifest.starts_with("FormatVersion: 2\n"));
+        let hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        std::fs::write(output.child(&hash), manifest).unwrap();
+        std::fs::write(output.child("manifest"), &hash).unwrap();
+        verify_roundtrip(
+            input.path(),
+            output.path(),
+            &ChunkLayout::default(),
+            POINTER,
+        )
+        .await
+        .unwrap();
+
+        // Editing one byte only changes the part holding it
+        content[2 * 4096 + 7] ^= 0xff;
+        std::fs::write(&file, &content).unwrap();
+        let after = package(4096).await.unwrap();
+        let changed: Vec<_> = before[&file]
+            .iter()
+            .zip(&after[&file])
+            .map(|(before, after)| before != after)
+            .collect();
+        assert_eq!(changed, [false, false, true, false, false]);
+    }
+
+    #[tokio::test]
+    async fn test_manifest_only_matches_full_run() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let output = temp_dir::TempDir::new().unwrap();
+        let chunks_path = output.child("chunks");
+        std::fs::create_dir_all(&chunks_path).unwrap();
+
+        std::fs::write(input.child("small"), "small file").unwrap();
+        let large: Vec<u8> = (0..2 * 4096 + 10).map(|i| (i % 251) as u8).collect();
+        std::fs::write(input.child("large"), &large).unwrap();
+        let files = vec![input.child("large"), input.child("small")];
+        let options = ManifestOptions {
+            compression: Compression::Zstd,
+            hash_method: HashType::Blake3,
+            record_mtime: true,
+            clamp_mtime: Some(0),
+            dictionary: None,
+            generated: Some(0),
+            deltas: Vec::new(),
+            chunk_cids: false,
+        };
+
+        let hashes = write_chunks(
+            &files,
+            &chunks_path,
+            None,
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                chunk_size: Some(4096),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let full = generate_manifest(input.path(), &files, &hashes, &options)
+            .await
+            .unwrap();
+
+        let chunk_options = ChunkOptions {
+            compression: Compression::Zstd,
+            chunk_size: Some(4096),
+            ..Default::default()
+        };
+        let existing = || hash_existing_chunks(&files, &chunks_path, &chunk_options);
+        let hashes = existing().await.unwrap();
+        let manifest_only = generate_manifest(input.path(), &files, &hashes, &options)
+            .await
+            .unwrap();
+        assert_eq!(manifest_only, full);
+
+        let small_hash = hashes[&input.child("small")][0].hash.clone();
+        std::fs::remove_file(chunks_path.join(repo_chunk_path(
+            &ChunkLayout::default(),
+            &small_hash,
+            &Compression::Zstd,
+            None,
+        )))
+        .unwrap();
+        let error = existing().await.unwrap_err().to_string();
+        assert!(error.contains("small"), "{error}");
+    }
+}
@@ -0,0 +1,436 @@
+//! Filesystem operations that differ between platforms. Linux gets atomic swaps and real
+//! permission modes, Windows gets the closest approximation it supports.
+
+use std::fs::{self, Metadata, Permissions};
+use std::io;
+use std::path::Path;
+use tracing::warn;
+
+/// Exchanges two paths, so each holds what the other did.
+/// This is atomic on Linux, unless the kernel or filesystem doesn't support `RENAME_EXCHANGE`.
+/// Then, and elsewhere, there is a brief window where `b` doesn't exist.
+#[cfg(unix)]
+pub fn exchange(a: &Path, b: &Path) -> Result<(), io::Error> {
+    use nix::errno::Errno;
+    use nix::fcntl::{AT_FDCWD, RenameFlags, renameat2};
+
+    match renameat2(AT_FDCWD, a, AT_FDCWD, b, RenameFlags::RENAME_EXCHANGE) {
+        Err(e @ (Errno::EINVAL | Errno::ENOSYS)) => {
+            warn!(
+                "Can't exchange {} and {} atomically ({e}), falling back to renaming them in turn",
+                a.display(),
+                b.display()
+            );
+            rename_exchange(a, b)
+        }
+        result => Ok(result?),
+    }
+}
+
+#[cfg(windows)]
+pub fn exchange(a: &Path, b: &Path) -> Result<(), io::Error> {
+    rename_exchange(a, b)
+}
+
+/// Exchanges two paths by moving `b` aside, moving `a` into its place, then moving the old `b`
+/// to `a`. If moving `a` fails, `b` is put back. Once `a` is in place the exchange has succeeded,
+/// so if the old `b` can't be moved to `a` it's left at `<b>.swap` with a warning. The next
+/// exchange removes it first.
+fn rename_exchange(a: &Path, b: &Path) -> Result<(), io::Error> {
+    let mut aside = b.as_os_str().to_owned();
+    aside.push(".swap");
+    let aside = Path::new(&aside);
+
+    match fs::symlink_metadata(aside) {
+        Ok(metadata) => {
+            warn!(
+                "Removing {}, left behind by an earlier exchange",
+                aside.display()
+            );
+            match metadata.is_dir() {
+                true => fs::remove_dir_all(aside)?,
+                false => remove_readonly_file(aside)?,
+            }
+        }
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) => (),
+    }
+
+    fs::rename(b, aside)?;
+    if let Err(e) = fs::rename(a, b) {
+        fs::rename(aside, b)?;
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(aside, a) {
+        warn!(
+            "Exchanged {} into place, but couldn't move its old contents to {}, leaving them at {}: {e}",
+            b.display(),
+            a.display(),
+            aside.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// What kind of special file this is, such as a FIFO or device node, or `None` for regular files,
+/// directories and symlinks.
+#[cfg(unix)]
+pub fn special_file_kind(file_type: &fs::FileType) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_fifo() {
+        Some("FIFO")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() {
+        Some("block device")
+    } else if file_type.is_char_device() {
+        Some("character device")
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+pub fn special_file_kind(file_type: &fs::FileType) -> Option<&'static str> {
+    (!file_type.is_file() && !file_type.is_dir() && !file_type.is_symlink())
+        .then_some("special file")
+}
+
+/// Applies a manifest's Unix mode, without its file type bits. Windows can only express whether
+/// the file is writable.
+#[cfg(unix)]
+pub fn set_mode(permissions: &mut Permissions, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+
+    permissions.set_mode(mode & 0o7777);
+}
+
+#[cfg(windows)]
+pub fn set_mode(permissions: &mut Permissions, mode: u32) {
+    permissions.set_readonly(mode & 0o222 == 0);
+}
+
+/// The Unix mode of a file, or `None` where the platform has no such thing.
+#[cfg(unix)]
+pub fn file_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.mode())
+}
+
+#[cfg(windows)]
+pub fn file_mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+/// How many hardlinks a file has, or `None` where the platform can't tell.
+#[cfg(unix)]
+pub fn link_count(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.nlink())
+}
+
+#[cfg(windows)]
+pub fn link_count(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Modification time of a file, in seconds since the Unix epoch.
+#[cfg(unix)]
+pub fn mtime(metadata: &Metadata) -> i64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.mtime()
+}
+
+#[cfg(windows)]
+pub fn mtime(metadata: &Metadata) -> i64 {
+    use std::time::SystemTime;
+
+    match metadata
+        .modified()
+        .map(|modified| modified.duration_since(SystemTime::UNIX_EPOCH))
+    {
+        Ok(Ok(since)) => since.as_secs() as i64,
+        Ok(Err(before)) => -(before.duration().as_secs() as i64),
+        Err(_) => 0,
+    }
+}
+
+/// Identifies the filesystem holding `metadata`'s file, where the platform exposes one.
+#[cfg(unix)]
+pub fn device(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.dev())
+}
+
+#[cfg(windows)]
+pub fn device(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`, if known.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Result<Option<u64>, io::Error> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+
+    Ok(Some(
+        stat.blocks_available() as u64 * stat.fragment_size() as u64,
+    ))
+}
+
+#[cfg(windows)]
+pub fn available_space(_path: &Path) -> Result<Option<u64>, io::Error> {
+    Ok(None)
+}
+
+/// Sets the modification time of `path`, leaving its access time alone.
+#[cfg(unix)]
+pub fn set_mtime(path: &Path, mtime: i64) -> Result<(), io::Error> {
+    use nix::fcntl::AT_FDCWD;
+    use nix::sys::stat::{UtimensatFlags, utimensat};
+    use nix::sys::time::TimeSpec;
+
+    utimensat(
+        AT_FDCWD,
+        path,
+        &TimeSpec::UTIME_OMIT,
+        &TimeSpec::new(mtime, 0),
+        UtimensatFlags::NoFollowSymlink,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn set_mtime(path: &Path, mtime: i64) -> Result<(), io::Error> {
+    use std::time::{Duration, SystemTime};
+
+    let mtime = match u64::try_from(mtime) {
+        Ok(secs) => SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        Err(_) => SystemTime::UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs()),
+    };
+    fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(mtime)
+}
+
+/// Removes a file even if it's read-only. Unix only needs write access to the directory, Windows
+/// refuses until the file's read-only attribute is cleared.
+#[cfg(unix)]
+pub fn remove_readonly_file(path: &Path) -> Result<(), io::Error> {
+    fs::remove_file(path)
+}
+
+#[cfg(windows)]
+pub fn remove_readonly_file(path: &Path) -> Result<(), io::Error> {
+    clear_readonly(path)?;
+    fs::remove_file(path)
+}
+
+/// Renames `from` over `to`, replacing it even if it's read-only.
+#[cfg(unix)]
+pub fn replace_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+    fs::rename(from, to)
+}
+
+#[cfg(windows)]
+pub fn replace_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+    match clear_readonly(to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    fs::rename(from, to)
+}
+
+#[cfg(windows)]
+fn clear_readonly(path: &Path) -> Result<(), io::Error> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)
+}
+
+/// Flushes a directory's entries to disk, making renames into it durable. Windows can't open
+/// directories as files, and commits renames without being asked.
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> Result<(), io::Error> {
+    fs::File::open(path)?.sync_all()
+}
+
+#[cfg(windows)]
+pub fn sync_dir(_path: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Hardlinks `original` to `link`, cloning or copying it instead on filesystems that can't link.
+pub fn link_or_copy(original: &Path, link: &Path) -> Result<(), io::Error> {
+    match fs::hard_link(original, link) {
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::Unsupported | io::ErrorKind::CrossesDevices
+            ) =>
+        {
+            reflink_or_copy(original, link)
+        }
+        result => result,
+    }
+}
+
+/// Makes `copy` a copy-on-write clone of `original` with its permissions, so the two share
+/// extents until either is written to. Only filesystems such as btrfs and XFS support it, and
+/// elsewhere this fails without leaving `copy` behind.
+#[cfg(target_os = "linux")]
+pub fn reflink(original: &Path, copy: &Path) -> Result<(), io::Error> {
+    use std::os::fd::AsRawFd;
+
+    // FICLONE from linux/fs.h
+    nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+    let source = fs::File::open(original)?;
+    let dest = fs::File::create_new(copy)?;
+    // Both descriptors stay open for the duration of the call
+    let cloned = unsafe { ficlone(dest.as_raw_fd(), source.as_raw_fd() as _) };
+    let result = cloned
+        .map_err(io::Error::from)
+        .and_then(|_| dest.set_permissions(source.metadata()?.permissions()));
+    if result.is_err() {
+        drop(dest);
+        let _ = fs::remove_file(copy);
+    }
+
+    result
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_original: &Path, _copy: &Path) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+/// Clones `original` to `copy` where the filesystem supports it, and copies it otherwise.
+pub fn reflink_or_copy(original: &Path, copy: &Path) -> Result<(), io::Error> {
+    match reflink(original, copy) {
+        Ok(()) => Ok(()),
+        // The copy reports anything that wasn't only the filesystem declining to clone
+        Err(_) => fs::copy(original, copy).map(|_| ()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rename_exchange() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let staging = dir.child("staging");
+        let usr = dir.child("usr");
+        fs::create_dir(&staging).unwrap();
+        fs::create_dir(&usr).unwrap();
+        fs::write(staging.join("new"), "").unwrap();
+        fs::write(usr.join("old"), "").unwrap();
+
+        rename_exchange(&staging, &usr).unwrap();
+
+        assert!(usr.join("new").exists());
+        assert!(staging.join("old").exists());
+        assert!(!dir.child("usr.swap").exists());
+    }
+
+    #[test]
+    fn test_rename_exchange_restores_on_failure() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let usr = dir.child("usr");
+        fs::create_dir(&usr).unwrap();
+        fs::write(usr.join("old"), "").unwrap();
+
+        assert!(rename_exchange(&dir.child("missing"), &usr).is_err());
+        assert!(usr.join("old").exists());
+    }
+
+    #[test]
+    fn test_rename_exchange_removes_leftover() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let staging = dir.child("staging");
+        let usr = dir.child("usr");
+        fs::create_dir(&staging).unwrap();
+        fs::create_dir(&usr).unwrap();
+        fs::write(staging.join("new"), "").unwrap();
+        fs::write(usr.join("old"), "").unwrap();
+        // An earlier exchange's old tree, which it couldn't move back
+        fs::create_dir_all(dir.child("usr.swap/bin")).unwrap();
+        fs::write(dir.child("usr.swap/bin/older"), "").unwrap();
+
+        rename_exchange(&staging, &usr).unwrap();
+
+        assert!(usr.join("new").exists());
+        assert!(staging.join("old").exists());
+        assert!(!dir.child("usr.swap").exists());
+    }
+
+    #[test]
+    fn test_reflink_or_copy() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let original = dir.child("chunk");
+        fs::write(&original, "shared extents").unwrap();
+        let mut permissions = fs::metadata(&original).unwrap().permissions();
+        set_mode(&mut permissions, 0o100555);
+        permissions.set_readonly(true);
+        fs::set_permissions(&original, permissions).unwrap();
+
+        // Whether cloning works depends on the filesystem, but it never leaves a partial copy
+        let clone = dir.child("clone");
+        match reflink(&original, &clone) {
+            Ok(()) => assert_eq!(fs::read_to_string(&clone).unwrap(), "shared extents"),
+            Err(_) => assert!(!clone.exists()),
+        }
+
+        let copy = dir.child("copy");
+        reflink_or_copy(&original, &copy).unwrap();
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "shared extents");
+        assert_eq!(
+            fs::metadata(&copy).unwrap().permissions(),
+            fs::metadata(&original).unwrap().permissions()
+        );
+        assert!(reflink_or_copy(&dir.child("missing"), &dir.child("other")).is_err());
+    }
+
+    #[test]
+    fn test_sync_dir() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        fs::write(dir.child("chunk"), "").unwrap();
+
+        sync_dir(dir.path()).unwrap();
+        #[cfg(unix)]
+        assert!(sync_dir(&dir.child("missing")).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_exchange_and_mode() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let staging = dir.child("staging");
+        let usr = dir.child("usr");
+        fs::create_dir(&staging).unwrap();
+        fs::create_dir(&usr).unwrap();
+        fs::write(staging.join("new"), "").unwrap();
+
+        exchange(&staging, &usr).unwrap();
+        assert!(usr.join("new").exists());
+
+        let file = usr.join("new");
+        let mut permissions = fs::metadata(&file).unwrap().permissions();
+        set_mode(&mut permissions, 0o100444);
+        fs::set_permissions(&file, permissions).unwrap();
+        assert!(fs::metadata(&file).unwrap().permissions().readonly());
+        assert_eq!(file_mode(&fs::metadata(&file).unwrap()), None);
+    }
+}
@@ -0,0 +1,345 @@
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tracing::info;
+
+use crate::chunks::{Chunk, InstallOptions, install_chunk, missing_chunks};
+use crate::error::Error;
+use crate::manifest::{
+    ManifestDiff, StagingGuard, build_tree, diff_manifests, generation_paths, parse_manifest,
+    swap_tree, tree_hash, update_manifest, verify_tree,
+};
+use crate::root::{StatePaths, target_path};
+use crate::source::{FileSource, RepoSource};
+use crate::update::{
+    HeaderPolicy, UpdateOptions, install_from_cache, read_dictionary, read_headers_assuming,
+};
+
+const NO_PREVIOUS_VERSION: &str = "No previous versions exist to rollback to.";
+
+/// What a rollback changed.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RollbackSummary {
+    /// Blake3 hash of the manifest swapped out
+    pub from: String,
+    /// Blake3 hash of the manifest swapped back in
+    pub to: String,
+    pub changes: ManifestDiff,
+}
+
+/// A stored generation older than the current one.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RollbackTarget {
+    /// 1 for the previous generation, which is what `rollback` swaps in
+    pub generation: usize,
+    pub manifest_hash: String,
+    /// When the repo generated the manifest, as written in its `Generated` header
+    pub generated: Option<String>,
+}
+
+/// Why a rollback failed, for `pkgsmgr-rollback --json`.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RollbackFailure {
+    /// `no_previous_version`, or `rollback_failed` for anything else
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl RollbackFailure {
+    pub fn no_previous_version() -> Self {
+        Self {
+            error: "no_previous_version",
+            message: NO_PREVIOUS_VERSION.to_string(),
+        }
+    }
+
+    pub fn failed(error: impl std::fmt::Display) -> Self {
+        Self {
+            error: "rollback_failed",
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Generations stored in `manifests_path` besides the current one, newest first.
+pub fn rollback_targets(manifests_path: &Path) -> Result<Vec<RollbackTarget>, io::Error> {
+    generation_paths(manifests_path)
+        .into_iter()
+        .enumerate()
+        .skip(1)
+        .map(|(generation, path)| {
+            let raw_manifest = fs::read_to_string(path)?;
+            let (headers, _) = parse_manifest(&raw_manifest)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            Ok(RollbackTarget {
+                generation,
+                manifest_hash: blake3::hash(raw_manifest.as_bytes()).to_hex().to_string(),
+                generated: headers
+                    .get("Generated")
+                    .map(|generated| generated.to_string()),
+            })
+        })
+        .collect()
+}
+
+fn read_old_manifest(state: &StatePaths) -> Result<String, Error> {
+    let old_manifest_path = state.manifests.join("old");
+    if !old_manifest_path.exists() {
+        return Err(NO_PREVIOUS_VERSION.into());
+    }
+
+    Ok(fs::read_to_string(old_manifest_path)?)
+}
+
+/// Checks every chunk the previous generation needs is still in the chunkstore, as a cleanup
+/// after the update being undone may have freed some, listing any that aren't.
+pub fn check_rollback_chunks(state: &StatePaths) -> Result<(), Error> {
+    let (_, chunklist) = parse_manifest(&read_old_manifest(state)?)?;
+    let missing = missing_chunks(&chunklist, &state.chunkstore);
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let listed = missing
+        .iter()
+        .map(|chunk| format!("{} ({})", chunk.hash, chunk.path))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(format!(
+        "{} chunks the previous generation needs are missing from the chunkstore: {listed}. \
+        Download them again from the repo, such as with pkgsmgr-rollback --repo-url",
+        missing.len()
+    )
+    .into())
+}
+
+/// Fetches chunks the previous generation needs but the chunkstore lost, from
+/// `options.additional_cache_path` when it has them and otherwise from `source`. Returns how many
+/// were restored. Without a `source`, chunks the cache lacks stay missing.
+pub async fn restore_rollback_chunks(
+    source: Option<&dyn RepoSource>,
+    state: &StatePaths,
+    options: &UpdateOptions,
+) -> Result<usize, Error> {
+    let old_manifest = read_old_manifest(state)?;
+    let (headers, chunklist) = parse_manifest(&old_manifest)?;
+    let missing: Vec<&Chunk> = missing_chunks(&chunklist, &state.chunkstore);
+    if missing.is_empty() {
+        return Ok(0);
+    }
+
+    let policy = HeaderPolicy {
+        assume_compression: options.assume_compression,
+        assume_hasher: options.assume_hasher,
+        ignore_min_version: options.ignore_min_version,
+        strict: options.strict_headers,
+    };
+    let (compression, hasher) = read_headers_assuming(&headers, &policy)?;
+    let cache = options
+        .additional_cache_path
+        .as_deref()
+        .map(FileSource::new);
+    let dictionary_source = match (source, &cache) {
+        (Some(source), _) => Some(source),
+        (None, Some(cache)) => Some(cache as &dyn RepoSource),
+        (None, None) => None,
+    };
+    let dictionary = match dictionary_source {
+        Some(dictionary_source) => {
+            read_dictionary(dictionary_source, &headers, compression).await?
+        }
+        None => None,
+    };
+
+    let install_options = InstallOptions {
+        compression,
+        dictionary: dictionary.as_ref(),
+        hash_method: hasher,
+        rate_limiter: options.rate_limiter.as_ref(),
+        sync: options.sync,
+        buffer_size: options.buffer_size,
+        layout: options.chunk_layout.clone(),
+    };
+    let mut restored = 0;
+    for chunk in missing {
+        if let Some(cache_path) = &options.additional_cache_path
+            && install_from_cache(cache_path, chunk, &state.chunkstore, &install_options).await
+        {
+            info!(phase = "cache", hash = %chunk.hash, "Copied {} from cache", chunk.path);
+        } else if let Some(source) = source {
+            info!(phase = "download", hash = %chunk.hash, "Downloading {}", chunk.path);
+            install_chunk(source, chunk, &state.chunkstore, &install_options)
+                .await
+                .map_err(|e| e.context(format_args!("could not download {}", chunk.path)))?;
+        } else {
+            continue;
+        }
+        restored += 1;
+    }
+
+    Ok(restored)
+}
+
+/// Swaps the previous generation back into `root_path`, at `target` or `usr` as the update that
+/// installed it. Its chunks must still be in the chunkstore, see `restore_rollback_chunks`.
+pub fn rollback(
+    root_path: &Path,
+    state: &StatePaths,
+    target: Option<&Path>,
+) -> Result<RollbackSummary, Error> {
+    let target_path = &target_path(root_path, target, state)?;
+    let chunks_path = &state.chunkstore;
+    let staging_path = &state.staging;
+    let manifests_path = &state.manifests;
+
+    let old_manifest = read_old_manifest(state)?;
+    // Checked before staging anything, rather than failing halfway through building it
+    check_rollback_chunks(state)?;
+    let current_manifest = fs::read_to_string(manifests_path.join("current"))?;
+    let summary = RollbackSummary {
+        from: blake3::hash(current_manifest.as_bytes())
+            .to_hex()
+            .to_string(),
+        to: blake3::hash(old_manifest.as_bytes()).to_hex().to_string(),
+        changes: diff_manifests(&current_manifest, &old_manifest)?,
+    };
+
+    let (_, chunklist) = parse_manifest(&old_manifest)?;
+
+    let staging_guard = StagingGuard::new(staging_path);
+    build_tree(staging_path, chunks_path, &chunklist)
+        .map_err(|e| format!("could not build staging: {e}"))?;
+
+    if let Err(e) = verify_tree(staging_path, &chunklist) {
+        return Err(format!("Staging failed verification, refusing to swap: {e}").into());
+    }
+
+    swap_tree(staging_path, target_path)?;
+    staging_guard.disarm();
+
+    // Only recorded once the swap succeeded, so a failed rollback leaves the manifests as they were
+    update_manifest(&old_manifest, manifests_path)?;
+    fs::write(&state.tree_hash, tree_hash(&chunklist))?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MemorySource;
+    use crate::update::{UpdateOptions, update};
+
+    #[tokio::test]
+    async fn test_update_then_rollback() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        let options = UpdateOptions::default();
+        let read = |path: &str| fs::read_to_string(root.child("usr").join(path)).ok();
+
+        let mut source = MemorySource::default();
+        let first = source.publish(&[("bin/tool", "v1"), ("share/removed", "gone in v2")]);
+        update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let second = source.publish(&[("bin/tool", "v2"), ("share/added", "new in v2")]);
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.manifest_hash, second);
+        assert_eq!(read("bin/tool").as_deref(), Some("v2"));
+        assert_eq!(read("share/removed"), None);
+
+        let summary = rollback(root.path(), &state, None).unwrap();
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            serde_json::json!({
+                "from": second,
+                "to": first,
+                "changes": {
+                    "added": ["share/removed"],
+                    "removed": ["share/added"],
+                    "modified": ["bin/tool"],
+                },
+            })
+        );
+
+        assert_eq!(read("bin/tool").as_deref(), Some("v1"));
+        assert_eq!(read("share/removed").as_deref(), Some("gone in v2"));
+        assert_eq!(read("share/added"), None);
+        let current = fs::read_to_string(state.manifests.join("current")).unwrap();
+        assert_eq!(blake3::hash(current.as_bytes()).to_hex().as_str(), first);
+
+        let targets = rollback_targets(&state.manifests).unwrap();
+        assert_eq!(
+            serde_json::to_value(&targets).unwrap(),
+            serde_json::json!([
+                {"generation": 1, "manifest_hash": second, "generated": null},
+                {"generation": 2, "manifest_hash": first, "generated": null},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rollback_restores_freed_chunks() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        let options = UpdateOptions::default();
+
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "v1")]);
+        update(&source, root.path(), &options).await.unwrap();
+        source.publish(&[("bin/tool", "v2")]);
+        update(&source, root.path(), &options).await.unwrap();
+
+        // As if a cleanup had freed the previous generation's chunk
+        let hash = blake3::hash(b"v1").to_hex().to_string();
+        crate::platform::remove_readonly_file(&state.chunkstore.join(&hash)).unwrap();
+
+        let error = rollback(root.path(), &state, None).unwrap_err().to_string();
+        assert!(error.contains(&format!("{hash} (bin/tool)")), "{error}");
+        assert_eq!(
+            fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+            "v2"
+        );
+
+        // Nothing to restore from
+        let restored = restore_rollback_chunks(None, &state, &options)
+            .await
+            .unwrap();
+        assert_eq!(restored, 0);
+
+        let restored = restore_rollback_chunks(Some(&source), &state, &options)
+            .await
+            .unwrap();
+        assert_eq!(restored, 1);
+        rollback(root.path(), &state, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+            "v1"
+        );
+    }
+
+    #[test]
+    fn test_no_previous_version() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        fs::create_dir_all(&state.manifests).unwrap();
+
+        assert!(rollback_targets(&state.manifests).unwrap().is_empty());
+        let error = rollback(root.path(), &state, None).unwrap_err();
+        assert_eq!(error.to_string(), NO_PREVIOUS_VERSION);
+        assert_eq!(
+            serde_json::to_value(RollbackFailure::no_previous_version()).unwrap(),
+            serde_json::json!({
+                "error": "no_previous_version",
+                "message": NO_PREVIOUS_VERSION,
+            })
+        );
+    }
+}
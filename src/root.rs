@@ -0,0 +1,222 @@
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::dictionary::DICTIONARY_DIR;
+use crate::manifest::ManifestDiff;
+use crate::platform::device;
+
+/// Default state directory, relative to the root.
+pub const STATE_DIR: &str = ".pkgsmgr";
+
+/// Default directory under the root that updates replace.
+pub const DEFAULT_TARGET: &str = "usr";
+
+/// Where pkgsmgr keeps its state for a root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatePaths {
+    pub state: PathBuf,
+    pub chunkstore: PathBuf,
+    pub staging: PathBuf,
+    pub manifests: PathBuf,
+    /// Holds the installed tree's `tree_hash`, for monitoring to read
+    pub tree_hash: PathBuf,
+    /// Records an update in progress, see `Transaction`
+    pub transaction: PathBuf,
+    /// The manifest an update in progress is installing
+    pub pending_manifest: PathBuf,
+    /// Copies of the dictionaries installed manifests were compressed with, for offline updates
+    pub dictionaries: PathBuf,
+}
+
+impl StatePaths {
+    /// `state_dir` may be absolute or relative to the root, and defaults to `STATE_DIR`.
+    pub fn new(root_path: &Path, state_dir: Option<&Path>) -> Self {
+        let state = root_path.join(state_dir.unwrap_or(Path::new(STATE_DIR)));
+
+        Self {
+            chunkstore: state.join("chunkstore"),
+            staging: state.join("staging"),
+            manifests: state.join("manifests"),
+            tree_hash: state.join("tree-hash"),
+            transaction: state.join("transaction"),
+            pending_manifest: state.join("manifests").join("pending"),
+            dictionaries: state.join(DICTIONARY_DIR),
+            state,
+        }
+    }
+
+    /// Warns when staging and `usr` can't share hardlinks or be swapped atomically.
+    pub fn warn_if_cross_device(&self, root_path: &Path) {
+        let device = |path: &Path| {
+            fs::metadata(path)
+                .ok()
+                .and_then(|metadata| device(&metadata))
+        };
+
+        if let (Some(state), Some(root)) = (device(&self.state), device(root_path))
+            && state != root
+        {
+            warn!(
+                "{} is on a different filesystem than {}, so swapping will fail",
+                self.state.display(),
+                root_path.display()
+            );
+        }
+    }
+}
+
+/// The directory under `root_path` swapped with staging: `target`, or `usr` by default.
+/// `target` must be a relative path going only down from the root, like `opt/app`, and mustn't
+/// hold the state directory, which swapping would carry away.
+pub fn target_path(
+    root_path: &Path,
+    target: Option<&Path>,
+    state: &StatePaths,
+) -> Result<PathBuf, String> {
+    let target = target.unwrap_or(Path::new(DEFAULT_TARGET));
+    let mut components = target.components().peekable();
+    if components.peek().is_none()
+        || !components.all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(format!(
+            "target {} must be a relative path under the root without `.` or `..`, like opt/app",
+            target.display()
+        ));
+    }
+
+    let target_path = root_path.join(target);
+    if state.state.starts_with(&target_path) {
+        return Err(format!(
+            "target {} holds the state directory {}, which swapping it would replace",
+            target_path.display(),
+            state.state.display()
+        ));
+    }
+
+    Ok(target_path)
+}
+
+/// Refuses roots where a swap could clobber a tree pkgsmgr doesn't own:
+/// the live `/`, or any root whose `target_path` has contents but no installed manifest.
+/// Both are allowed when `allow_root` is set.
+pub fn check_root(
+    root_path: &Path,
+    target_path: &Path,
+    state: &StatePaths,
+    allow_root: bool,
+) -> Result<(), String> {
+    if allow_root {
+        return Ok(());
+    }
+
+    let canonical = root_path.canonicalize().unwrap_or(root_path.to_path_buf());
+    if canonical == Path::new("/") {
+        return Err("refusing to operate on / without --allow-root".into());
+    }
+
+    let managed = state.manifests.join("current").exists();
+    let target_has_contents = fs::read_dir(target_path)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+
+    if target_has_contents && !managed {
+        return Err(format!(
+            "{} isn't managed by pkgsmgr, pass --allow-root to replace it",
+            target_path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Prints what will be swapped and, on a terminal, every path it changes before asking for
+/// confirmation. Always confirms when stdin isn't interactive.
+/// Writes to stderr, leaving stdout for `--json` output.
+pub fn confirm_swap(source: &str, target_path: &Path, diff: &ManifestDiff) -> bool {
+    info!("{} will be replaced by {source}", target_path.display());
+
+    if !io::stdin().is_terminal() {
+        return true;
+    }
+
+    eprint!("{diff}");
+    eprint!(
+        "{} added, {} removed, {} modified. Continue? [y/N] ",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.modified.len()
+    );
+    let _ = io::stderr().flush();
+
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refuses_live_root() {
+        let state = StatePaths::new(Path::new("/"), None);
+        assert!(check_root(Path::new("/"), Path::new("/usr"), &state, false).is_err());
+        assert!(check_root(Path::new("/"), Path::new("/usr"), &state, true).is_ok());
+    }
+
+    #[test]
+    fn test_target_path() {
+        let root = Path::new("/srv/root");
+        let state = StatePaths::new(root, None);
+        assert_eq!(target_path(root, None, &state), Ok(root.join("usr")));
+        assert_eq!(
+            target_path(root, Some(Path::new("opt/app/")), &state),
+            Ok(root.join("opt/app"))
+        );
+
+        for target in [
+            "",
+            ".",
+            "/opt",
+            "../app",
+            "opt/../usr",
+            ".pkgsmgr/chunkstore/..",
+        ] {
+            assert!(
+                target_path(root, Some(Path::new(target)), &state).is_err(),
+                "{target}"
+            );
+        }
+        // Swapping would carry the state away with it
+        let state = StatePaths::new(root, Some(Path::new("app/.state")));
+        assert!(target_path(root, Some(Path::new("app")), &state).is_err());
+    }
+
+    #[test]
+    fn test_refuses_unmanaged_usr() {
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(root.child("usr/bin")).unwrap();
+        let state = StatePaths::new(root.path(), None);
+
+        assert!(check_root(root.path(), &root.child("usr"), &state, false).is_err());
+        assert!(check_root(root.path(), &root.child("usr"), &state, true).is_ok());
+
+        fs::create_dir_all(&state.manifests).unwrap();
+        fs::write(state.manifests.join("current"), "---\n").unwrap();
+        assert!(check_root(root.path(), &root.child("usr"), &state, false).is_ok());
+    }
+
+    #[test]
+    fn test_accepts_fresh_root() {
+        let root = temp_dir::TempDir::new().unwrap();
+
+        let state = StatePaths::new(root.path(), None);
+
+        assert!(check_root(root.path(), &root.child("usr"), &state, false).is_ok());
+    }
+}
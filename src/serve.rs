@@ -0,0 +1,186 @@
+//! A minimal HTTP server over a repo directory, for trying updates out without setting up a web
+//! server.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+/// Longest request head read before the request is refused.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Serves the files under `repo_path` on `listener` until the task is dropped, answering `GET`
+/// and `HEAD` only. Each response closes its connection.
+pub async fn serve(listener: TcpListener, repo_path: PathBuf) -> Result<(), io::Error> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let repo_path = repo_path.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &repo_path).await {
+                warn!("Failed to answer {address}: {e}");
+            }
+        });
+    }
+}
+
+/// Where a request path points under `repo_path`, or `None` if it reaches outside it.
+fn resolve(repo_path: &Path, request_path: &str) -> Option<PathBuf> {
+    let path = request_path.split(['?', '#']).next()?;
+    let relative = Path::new(path.trim_start_matches('/'));
+    if path.contains('\\')
+        || relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    Some(repo_path.join(relative))
+}
+
+async fn respond(mut stream: TcpStream, repo_path: &Path) -> Result<(), io::Error> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return write_status(&mut stream, "431 Request Header Fields Too Large").await;
+        }
+        match stream.read(&mut buf).await? {
+            0 => return Ok(()),
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.split_whitespace();
+    let (method, request_path) = (
+        request_line.next().unwrap_or_default(),
+        request_line.next().unwrap_or_default(),
+    );
+    debug!("{method} {request_path}");
+    if method != "GET" && method != "HEAD" {
+        return write_status(&mut stream, "405 Method Not Allowed").await;
+    }
+
+    let file = match resolve(repo_path, request_path) {
+        Some(path) => File::open(path).await,
+        None => Err(io::ErrorKind::NotFound.into()),
+    };
+    let (mut file, metadata) = match file {
+        Ok(file) => {
+            let metadata = file.metadata().await?;
+            (file, metadata)
+        }
+        Err(_) => return write_status(&mut stream, "404 Not Found").await,
+    };
+    if !metadata.is_file() {
+        return write_status(&mut stream, "404 Not Found").await;
+    }
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        metadata.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    if method == "GET" {
+        tokio::io::copy(&mut file, &mut stream).await?;
+    }
+    stream.shutdown().await
+}
+
+async fn write_status(stream: &mut TcpStream, status: &str) -> Result<(), io::Error> {
+    let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packager::{ChunkOptions, ManifestOptions, generate_manifest, write_chunks};
+    use crate::source::HttpSource;
+    use crate::types::{Compression, HashType};
+    use crate::update::{UpdateOptions, update};
+
+    #[test]
+    fn test_resolve() {
+        let repo = Path::new("/srv/repo");
+        assert_eq!(
+            resolve(repo, "/chunks/aaaa.zstd?x=1"),
+            Some(repo.join("chunks/aaaa.zstd"))
+        );
+        assert_eq!(resolve(repo, "/../etc/passwd"), None);
+        assert_eq!(
+            resolve(repo, "/chunks/./aaaa"),
+            Some(repo.join("chunks/aaaa"))
+        );
+        assert_eq!(resolve(repo, "/chunks\\..\\secret"), None);
+    }
+
+    #[tokio::test]
+    async fn test_serve_update() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let repo = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir_all(input.child("bin")).unwrap();
+        std::fs::write(input.child("bin/tool"), "tool").unwrap();
+        std::fs::write(input.child("bin/other"), "other").unwrap();
+        let files = vec![input.child("bin/other"), input.child("bin/tool")];
+
+        let hashes = write_chunks(
+            &files,
+            &repo.child("chunks"),
+            None,
+            &ChunkOptions {
+                compression: Compression::Zstd,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let options = ManifestOptions {
+            compression: Compression::Zstd,
+            hash_method: HashType::Blake3,
+            record_mtime: false,
+            clamp_mtime: None,
+            dictionary: None,
+            generated: None,
+            deltas: Vec::new(),
+            chunk_cids: false,
+        };
+        let manifest = generate_manifest(input.path(), &files, &hashes, &options)
+            .await
+            .unwrap();
+        let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        std::fs::write(repo.child(&manifest_hash), &manifest).unwrap();
+        std::fs::write(repo.child("manifest"), &manifest_hash).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, repo.path().to_path_buf()));
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let source = HttpSource::new(reqwest::Client::new(), &url);
+        update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+            "tool"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.child("usr/bin/other")).unwrap(),
+            "other"
+        );
+
+        let client = reqwest::Client::new();
+        let status = |path: &'static str| {
+            let request = client.get(format!("{url}{path}")).send();
+            async move { request.await.unwrap().status() }
+        };
+        assert_eq!(status("/chunks").await, reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(status("/missing").await, reqwest::StatusCode::NOT_FOUND);
+    }
+}
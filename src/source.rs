@@ -0,0 +1,434 @@
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use std::io;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::io::{AsyncBufRead, BufReader};
+use tokio_util::io::StreamReader;
+use tracing::debug;
+
+use crate::delta::DELTA_DIR;
+use crate::dictionary::DICTIONARY_DIR;
+use crate::error::Error;
+use crate::manifest::{POINTER, compressed_manifest_name, looks_like_manifest, pointer_name};
+use crate::utils::{ClientOptions, DEFAULT_BUFFER_SIZE, build_client, get};
+
+/// Raw, possibly compressed, chunk contents.
+pub type ChunkReader = Box<dyn AsyncBufRead + Send + Unpin>;
+
+/// The outcome of a conditional pointer fetch.
+#[derive(Debug, PartialEq)]
+pub enum PointerFetch {
+    /// The pointer still has the ETag it was asked about
+    Unchanged,
+    Fetched {
+        pointer: String,
+        /// For asking about it next time, if the source gave one
+        etag: Option<String>,
+    },
+}
+
+/// Somewhere a repo can be read from.
+#[async_trait]
+pub trait RepoSource: Send + Sync {
+    /// Reads the pointer, `manifest` or a channel's, which holds the hash of the latest manifest.
+    async fn fetch_pointer(&self) -> Result<String, io::Error>;
+    /// Reads the pointer unless it still has the ETag `etag`. Sources without ETags always read it.
+    async fn fetch_pointer_if_changed(
+        &self,
+        _etag: Option<&str>,
+    ) -> Result<PointerFetch, io::Error> {
+        Ok(PointerFetch::Fetched {
+            pointer: self.fetch_pointer().await?,
+            etag: None,
+        })
+    }
+    async fn fetch_manifest(&self, hash: &str) -> Result<String, io::Error>;
+    /// Reads the manifest's zstd-compressed copy, named by `compressed_manifest_name`. Repos
+    /// needn't have one, so sources that can't tell report it missing.
+    async fn fetch_compressed_manifest(&self, _hash: &str) -> Result<Vec<u8>, io::Error> {
+        Err(io::ErrorKind::NotFound.into())
+    }
+    /// Opens `chunks/<path>`, placed by `repo_chunk_path`.
+    async fn fetch_chunk(&self, filename: &str) -> Result<ChunkReader, io::Error>;
+    /// Reads `dictionaries/<hash>`.
+    async fn fetch_dictionary(&self, hash: &str) -> Result<Vec<u8>, io::Error>;
+    /// Reads `deltas/<hash>`. Sources that can't serve deltas report them missing, so the chunk
+    /// is fetched whole.
+    async fn fetch_delta(&self, _hash: &str) -> Result<Vec<u8>, io::Error> {
+        Err(io::ErrorKind::NotFound.into())
+    }
+}
+
+/// Keeps timeouts and missing files distinguishable from other failures.
+pub(crate) fn http_error(e: reqwest::Error) -> io::Error {
+    if e.is_timeout() {
+        io::Error::new(io::ErrorKind::TimedOut, format!("timed out: {e}"))
+    } else if e.status() == Some(reqwest::StatusCode::NOT_FOUND) {
+        io::Error::new(io::ErrorKind::NotFound, e)
+    } else {
+        io::Error::other(e)
+    }
+}
+
+/// A repo served over HTTP(S). Every fetch goes through `client`, so its connection pool and its
+/// TLS, timeout and header settings are shared by all of them, such as the chunks of an update.
+pub struct HttpSource {
+    client: reqwest::Client,
+    url: String,
+    pointer: String,
+}
+
+impl HttpSource {
+    pub fn new(client: reqwest::Client, url: &str) -> Self {
+        Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+            pointer: POINTER.to_string(),
+        }
+    }
+
+    /// A source with its own client, built with the default `ClientOptions`, for callers that
+    /// don't need to configure one.
+    pub fn with_default_client(url: &str) -> Result<Self, Error> {
+        let client = build_client(&ClientOptions::default())
+            .map_err(|e| Error::Other(format!("could not build HTTP client: {e}")))?;
+
+        Ok(Self::new(client, url))
+    }
+
+    /// Reads the pointer named `pointer` rather than `manifest`, see `pointer_name`.
+    pub fn with_pointer(mut self, pointer: impl Into<String>) -> Self {
+        self.pointer = pointer.into();
+        self
+    }
+
+    async fn fetch_text(&self, path: &str) -> Result<String, io::Error> {
+        let res = get(&self.client, &format!("{}/{path}", self.url))
+            .await
+            .map_err(http_error)?;
+        check_content_type(&res)?;
+
+        res.text().await.map_err(http_error)
+    }
+}
+
+/// Refuses responses typed as HTML or JSON, such as the error page of a misconfigured proxy that
+/// answers `200 OK`. Servers type plain files all sorts of ways, such as S3's
+/// `binary/octet-stream`, so any other type is trusted, as are untyped responses.
+fn check_content_type(res: &reqwest::Response) -> Result<(), io::Error> {
+    let Some(content_type) = res.headers().get(reqwest::header::CONTENT_TYPE) else {
+        return Ok(());
+    };
+
+    let content_type = content_type.to_str().unwrap_or_default();
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    if matches!(
+        essence.to_ascii_lowercase().as_str(),
+        "text/html" | "application/xhtml+xml" | "application/json"
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "server returned unexpected content for {}, typed {content_type:?}",
+                res.url()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl RepoSource for HttpSource {
+    async fn fetch_pointer(&self) -> Result<String, io::Error> {
+        self.fetch_text(&self.pointer).await
+    }
+
+    async fn fetch_pointer_if_changed(
+        &self,
+        etag: Option<&str>,
+    ) -> Result<PointerFetch, io::Error> {
+        let url = format!("{}/{}", self.url, self.pointer);
+        debug!(url, "Fetching {url}");
+        let mut request = self.client.get(&url);
+        if let Some(etag) = etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+
+        let res = request.send().await.map_err(http_error)?;
+        if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(PointerFetch::Unchanged);
+        }
+        let res = res.error_for_status().map_err(http_error)?;
+        check_content_type(&res)?;
+        let etag = res
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+
+        Ok(PointerFetch::Fetched {
+            pointer: res.text().await.map_err(http_error)?,
+            etag,
+        })
+    }
+
+    async fn fetch_manifest(&self, hash: &str) -> Result<String, io::Error> {
+        let manifest = self.fetch_text(hash).await?;
+        if !looks_like_manifest(&manifest) {
+            let preview: String = manifest.chars().take(64).collect();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "server returned unexpected content for {}/{hash}, which isn't a manifest: {preview:?}",
+                    self.url
+                ),
+            ));
+        }
+
+        Ok(manifest)
+    }
+
+    async fn fetch_compressed_manifest(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        let url = format!("{}/{}", self.url, compressed_manifest_name(hash));
+        let res = get(&self.client, &url).await.map_err(http_error)?;
+
+        Ok(res.bytes().await.map_err(http_error)?.to_vec())
+    }
+
+    async fn fetch_chunk(&self, filename: &str) -> Result<ChunkReader, io::Error> {
+        let res = get(&self.client, &format!("{}/chunks/{filename}", self.url))
+            .await
+            .map_err(http_error)?;
+
+        Ok(Box::new(StreamReader::new(
+            res.bytes_stream().map_err(http_error),
+        )))
+    }
+
+    async fn fetch_dictionary(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        let url = format!("{}/{DICTIONARY_DIR}/{hash}", self.url);
+        let res = get(&self.client, &url).await.map_err(http_error)?;
+
+        Ok(res.bytes().await.map_err(http_error)?.to_vec())
+    }
+
+    async fn fetch_delta(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        let url = format!("{}/{DELTA_DIR}/{hash}", self.url);
+        let res = get(&self.client, &url).await.map_err(http_error)?;
+
+        Ok(res.bytes().await.map_err(http_error)?.to_vec())
+    }
+}
+
+/// A repo on a local filesystem, such as installation media.
+pub struct FileSource {
+    path: PathBuf,
+    pointer: String,
+}
+
+impl FileSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            pointer: POINTER.to_string(),
+        }
+    }
+
+    /// Reads the pointer named `pointer` rather than `manifest`, see `pointer_name`.
+    pub fn with_pointer(mut self, pointer: impl Into<String>) -> Self {
+        self.pointer = pointer.into();
+        self
+    }
+}
+
+#[async_trait]
+impl RepoSource for FileSource {
+    async fn fetch_pointer(&self) -> Result<String, io::Error> {
+        fs::read_to_string(self.path.join(&self.pointer)).await
+    }
+
+    async fn fetch_manifest(&self, hash: &str) -> Result<String, io::Error> {
+        fs::read_to_string(self.path.join(hash)).await
+    }
+
+    async fn fetch_compressed_manifest(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        fs::read(self.path.join(compressed_manifest_name(hash))).await
+    }
+
+    async fn fetch_chunk(&self, filename: &str) -> Result<ChunkReader, io::Error> {
+        let file = fs::File::open(self.path.join("chunks").join(filename)).await?;
+
+        Ok(Box::new(BufReader::with_capacity(
+            DEFAULT_BUFFER_SIZE,
+            file,
+        )))
+    }
+
+    async fn fetch_dictionary(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        fs::read(self.path.join(DICTIONARY_DIR).join(hash)).await
+    }
+
+    async fn fetch_delta(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        fs::read(self.path.join(DELTA_DIR).join(hash)).await
+    }
+}
+
+/// Picks a source for a repo location. `file://` URLs, and anything else that isn't an HTTP(S)
+/// URL, are read from the filesystem, with relative paths resolved against the working directory.
+/// With a `channel`, its pointer is read rather than `manifest`.
+pub fn source_from_url(
+    client: &reqwest::Client,
+    url: &str,
+    channel: Option<&str>,
+) -> Result<Box<dyn RepoSource>, String> {
+    let pointer = pointer_name(channel)?;
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Box::new(
+            HttpSource::new(client.clone(), url).with_pointer(pointer),
+        ))
+    } else if url.starts_with("file://") {
+        let path = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| format!("{url} isn't a valid local file URL"))?;
+        Ok(Box::new(FileSource::new(path).with_pointer(pointer)))
+    } else {
+        Ok(Box::new(FileSource::new(url).with_pointer(pointer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestServer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_file_url_matches_http() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let manifest = "---\n420;0;aaaa;file\n";
+        let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        std::fs::create_dir_all(repo.child("chunks")).unwrap();
+        std::fs::write(repo.child("chunks/aaaa"), "chunk").unwrap();
+        std::fs::write(repo.child(&manifest_hash), manifest).unwrap();
+        std::fs::write(repo.child("manifest"), &manifest_hash).unwrap();
+
+        let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+        let client = reqwest::Client::new();
+        let file_url = format!("file://{}", repo.path().display());
+
+        for url in [server.url.as_str(), &file_url] {
+            let source = source_from_url(&client, url, None).unwrap();
+            assert_eq!(source.fetch_pointer().await.unwrap(), manifest_hash);
+            assert_eq!(
+                source.fetch_manifest(&manifest_hash).await.unwrap(),
+                manifest
+            );
+
+            let mut chunk = String::new();
+            let mut reader = source.fetch_chunk("aaaa").await.unwrap();
+            reader.read_to_string(&mut chunk).await.unwrap();
+            assert_eq!(chunk, "chunk");
+        }
+
+        assert!(source_from_url(&client, "file://remote-host/repo", None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_channels() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        std::fs::create_dir_all(repo.child("chunks")).unwrap();
+        for (channel, content) in [("stable", "tool 1"), ("beta", "tool 2")] {
+            let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+            std::fs::write(repo.child(format!("chunks/{hash}")), content).unwrap();
+            let manifest = format!("Hasher: blake3\n---\n420;0;{hash};bin/tool\n");
+            let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+            std::fs::write(repo.child(&manifest_hash), manifest).unwrap();
+            let pointer = pointer_name(Some(channel)).unwrap();
+            std::fs::write(repo.child(pointer), &manifest_hash).unwrap();
+        }
+
+        let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+        let client = reqwest::Client::new();
+        for (channel, content) in [("stable", "tool 1"), ("beta", "tool 2")] {
+            let root = temp_dir::TempDir::new().unwrap();
+            let source = source_from_url(&client, &server.url, Some(channel)).unwrap();
+            crate::update::update(source.as_ref(), root.path(), &Default::default())
+                .await
+                .unwrap();
+            assert_eq!(
+                std::fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+                content
+            );
+        }
+
+        // Without a channel there's no `manifest` to follow
+        let source = source_from_url(&client, &server.url, None).unwrap();
+        assert!(source.fetch_pointer().await.is_err());
+        for channel in ["", "../manifest", ".hidden", "a/b"] {
+            assert!(source_from_url(&client, &server.url, Some(channel)).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_pages_are_refused() {
+        const PAGE: &str = "<html><body><h1>Service Unavailable</h1></body></html>";
+        let hash = "0".repeat(64);
+
+        // Served untyped, like any other file, so only its contents give it away
+        let repo = temp_dir::TempDir::new().unwrap();
+        std::fs::write(repo.child(&hash), PAGE).unwrap();
+        let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+        let source = HttpSource::new(reqwest::Client::new(), &server.url);
+        let error = source.fetch_manifest(&hash).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            error
+                .to_string()
+                .contains("server returned unexpected content"),
+            "{error}"
+        );
+        assert!(error.to_string().contains("Service Unavailable"), "{error}");
+
+        // Answers every request with `body`, typed as `content_type`
+        let serve_typed = async |content_type: &'static str, body: &'static str| {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                }
+            });
+            HttpSource::new(reqwest::Client::new(), &url)
+        };
+
+        for content_type in [
+            "text/html; charset=utf-8",
+            "application/xhtml+xml",
+            "application/json",
+        ] {
+            let source = serve_typed(content_type, PAGE).await;
+            for error in [
+                source.fetch_pointer().await.unwrap_err(),
+                source.fetch_manifest(&hash).await.unwrap_err(),
+            ] {
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+                assert!(error.to_string().contains(content_type), "{error}");
+            }
+        }
+
+        // Like S3 serves files without an extension
+        const POINTER: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+        let source = serve_typed("binary/octet-stream", POINTER).await;
+        assert_eq!(source.fetch_pointer().await.unwrap(), POINTER);
+    }
+}
@@ -0,0 +1,103 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::chunks::chunk_filename;
+use crate::manifest::{file_chunks, parse_manifest, tree_hash};
+
+/// Summary of the locally installed state, read without touching the network.
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct Status {
+    /// Blake3 hash of the current manifest, matching its name in the repo
+    pub manifest_hash: Option<String>,
+    pub compression: Option<String>,
+    pub hasher: Option<String>,
+    /// When the repo generated the manifest, as written in its `Generated` header
+    pub generated: Option<String>,
+    pub files: usize,
+    pub chunks: usize,
+    /// Sum of the file sizes declared by the manifest, in kilobytes
+    pub installed_kb: u64,
+    /// Root hash over every installed path's contents and mode, see `tree_hash`
+    pub tree_hash: Option<String>,
+    pub rollback_available: bool,
+    pub chunkstore_bytes: u64,
+}
+
+pub fn read_status(manifests_path: &Path, chunkstore_path: &Path) -> Result<Status, io::Error> {
+    let mut status = Status {
+        rollback_available: manifests_path.join("old").exists(),
+        ..Default::default()
+    };
+
+    let current_path = manifests_path.join("current");
+    if current_path.exists() {
+        let raw_manifest = fs::read_to_string(current_path)?;
+        let (headers, chunklist) = parse_manifest(&raw_manifest)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        status.manifest_hash = Some(blake3::hash(raw_manifest.as_bytes()).to_hex().to_string());
+        // Mirrors the updater's defaults when a header is absent
+        status.compression = Some(headers.get("Compression").unwrap_or(&"none").to_lowercase());
+        status.hasher = Some(headers.get("Hasher").unwrap_or(&"blake3").to_lowercase());
+        status.generated = headers
+            .get("Generated")
+            .map(|generated| generated.to_string());
+        status.files = file_chunks(&chunklist).count();
+        status.chunks = chunklist
+            .iter()
+            .map(chunk_filename)
+            .collect::<HashSet<_>>()
+            .len();
+        status.installed_kb = chunklist.iter().map(|chunk| chunk.size).sum();
+        status.tree_hash = Some(tree_hash(&chunklist));
+    }
+
+    if chunkstore_path.exists() {
+        for entry in fs::read_dir(chunkstore_path)? {
+            status.chunkstore_bytes += entry?.metadata()?.len();
+        }
+    }
+
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_counts() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let manifests_path = root.child("manifests");
+        let chunkstore_path = root.child("chunkstore");
+        fs::create_dir_all(&manifests_path).unwrap();
+        fs::create_dir_all(&chunkstore_path).unwrap();
+
+        let manifest = "Compression: zstd\nGenerated: 2023-11-14T22:13:20Z\nHasher: xxh3_128\n---\n\
+            420;4;aaaa;bin/a\n420;4;aaaa;bin/b\n493;10;bbbb;bin/c\n";
+        fs::write(manifests_path.join("current"), manifest).unwrap();
+        fs::write(chunkstore_path.join("aaaa"), [0u8; 4096]).unwrap();
+        fs::write(chunkstore_path.join("bbbb"), [0u8; 10240]).unwrap();
+
+        let status = read_status(&manifests_path, &chunkstore_path).unwrap();
+
+        assert_eq!(
+            status,
+            Status {
+                manifest_hash: Some(blake3::hash(manifest.as_bytes()).to_hex().to_string()),
+                compression: Some("zstd".into()),
+                hasher: Some("xxh3_128".into()),
+                generated: Some("2023-11-14T22:13:20Z".into()),
+                files: 3,
+                chunks: 2,
+                installed_kb: 18,
+                tree_hash: Some(tree_hash(&parse_manifest(manifest).unwrap().1)),
+                rollback_available: false,
+                chunkstore_bytes: 14336,
+            }
+        );
+    }
+}
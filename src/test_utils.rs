@@ -0,0 +1,275 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::delta::DELTA_DIR;
+use crate::dictionary::DICTIONARY_DIR;
+use crate::source::{ChunkReader, RepoSource};
+
+/// A minimal HTTP server exposing a directory, standing in for a repo.
+/// Every response carries an ETag, and requests whose `If-None-Match` still matches get a 304.
+/// POSTs are answered with 204 No Content, their bodies kept for `posted`.
+pub struct TestServer {
+    pub url: String,
+    requested: Arc<Mutex<Vec<String>>>,
+    not_modified: Arc<Mutex<Vec<String>>>,
+    posted: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+/// How `TestServer` encodes responses to requests accepting it.
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl TestServer {
+    pub async fn serve_dir(root: PathBuf) -> Self {
+        Self::serve(root, Encoding::Identity).await
+    }
+
+    /// Like `serve_dir`, but gzip-encodes responses to requests accepting it, like servers that
+    /// compress on the fly.
+    pub async fn serve_dir_gzipped(root: PathBuf) -> Self {
+        Self::serve(root, Encoding::Gzip).await
+    }
+
+    /// Like `serve_dir_gzipped`, but with zstd.
+    pub async fn serve_dir_zstd(root: PathBuf) -> Self {
+        Self::serve(root, Encoding::Zstd).await
+    }
+
+    async fn serve(root: PathBuf, encoding: Encoding) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let log = requested.clone();
+        let not_modified = Arc::new(Mutex::new(Vec::new()));
+        let not_modified_log = not_modified.clone();
+        let posted = Arc::new(Mutex::new(Vec::new()));
+        let posted_log = posted.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let root = root.clone();
+                let log = log.clone();
+                let not_modified_log = not_modified_log.clone();
+                let posted_log = posted_log.clone();
+
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    let head_end = loop {
+                        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            break end + 4;
+                        }
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    };
+
+                    let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+                    let mut words = head.split_whitespace();
+                    let method = words.next().unwrap_or("GET").to_string();
+                    let path = words.next().unwrap_or("/").to_string();
+                    log.lock().unwrap().push(path.clone());
+                    let header = |wanted: &str| {
+                        head.lines().find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case(wanted)
+                                .then(|| value.trim().to_string())
+                        })
+                    };
+
+                    if method == "POST" {
+                        // The body follows the headers, and is as long as their Content-Length says
+                        let length = header("content-length")
+                            .and_then(|value| value.parse::<usize>().ok())
+                            .unwrap_or(0);
+                        while request.len() < head_end + length {
+                            match stream.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => request.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        let body = String::from_utf8_lossy(&request[head_end..]).to_string();
+                        posted_log.lock().unwrap().push((path, body));
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                            .await;
+                        let _ = stream.shutdown().await;
+                        return;
+                    }
+
+                    let if_none_match = header("if-none-match");
+                    let accepts = |name: &str| {
+                        header("accept-encoding").is_some_and(|value| value.contains(name))
+                    };
+                    let response = match std::fs::read(root.join(path.trim_start_matches('/'))) {
+                        Ok(body) => {
+                            let etag = format!("\"{}\"", blake3::hash(&body).to_hex());
+                            if if_none_match.as_ref() == Some(&etag) {
+                                not_modified_log.lock().unwrap().push(path);
+                                let response = format!(
+                                    "HTTP/1.1 304 Not Modified\r\nETag: {etag}\r\nConnection: close\r\n\r\n"
+                                );
+                                let _ = stream.write_all(response.as_bytes()).await;
+                                let _ = stream.shutdown().await;
+                                return;
+                            }
+
+                            let (encoding, body) = match encoding {
+                                Encoding::Gzip if accepts("gzip") => {
+                                    let mut encoder = flate2::write::GzEncoder::new(
+                                        Vec::new(),
+                                        flate2::Compression::default(),
+                                    );
+                                    std::io::Write::write_all(&mut encoder, &body).unwrap();
+                                    ("Content-Encoding: gzip\r\n", encoder.finish().unwrap())
+                                }
+                                Encoding::Zstd if accepts("zstd") => (
+                                    "Content-Encoding: zstd\r\n",
+                                    zstd::encode_all(body.as_slice(), 3).unwrap(),
+                                ),
+                                _ => ("", body),
+                            };
+                            let mut response = format!(
+                                "HTTP/1.1 200 OK\r\n{encoding}Content-Length: {}\r\nETag: {etag}\r\nConnection: close\r\n\r\n",
+                                body.len()
+                            )
+                            .into_bytes();
+                            response.extend(body);
+                            response
+                        }
+                        Err(_) => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec(),
+                    };
+
+                    let _ = stream.write_all(&response).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+
+        Self {
+            url,
+            requested,
+            not_modified,
+            posted,
+        }
+    }
+
+    /// Paths requested so far, in order.
+    pub fn requested_paths(&self) -> Vec<String> {
+        self.requested.lock().unwrap().clone()
+    }
+
+    /// Paths answered with 304 Not Modified so far, in order.
+    pub fn not_modified_paths(&self) -> Vec<String> {
+        self.not_modified.lock().unwrap().clone()
+    }
+
+    /// Paths POSTed to so far, with the bodies sent, in order.
+    pub fn posted(&self) -> Vec<(String, String)> {
+        self.posted.lock().unwrap().clone()
+    }
+}
+
+/// Like `MemorySource::publish_manifest`, for a repo directory such as `TestServer` serves.
+pub fn publish_manifest_to(repo: &Path, manifest: &str) -> String {
+    let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+    std::fs::write(repo.join(&manifest_hash), manifest).unwrap();
+    std::fs::write(repo.join("manifest"), &manifest_hash).unwrap();
+    manifest_hash
+}
+
+/// A repo held in memory, for exercising the install pipeline without sockets.
+/// Paths are relative to the repo root, like `manifest` or `chunks/<hash>`.
+#[derive(Default)]
+pub struct MemorySource {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl MemorySource {
+    /// Loads every file under `root`, such as the packager's output.
+    pub fn from_dir(root: &Path) -> Self {
+        let mut source = Self::default();
+        for entry in walkdir::WalkDir::new(root) {
+            let entry = entry.unwrap();
+            if entry.file_type().is_file() {
+                let path = entry.path().strip_prefix(root).unwrap();
+                source.insert(path.to_str().unwrap(), std::fs::read(entry.path()).unwrap());
+            }
+        }
+
+        source
+    }
+
+    pub fn insert(&mut self, path: &str, data: impl Into<Vec<u8>>) {
+        self.files.insert(path.to_string(), data.into());
+    }
+
+    /// Stores uncompressed chunks for `files`, given as path and contents, and points `manifest`
+    /// at a new manifest listing them. Returns the manifest's hash.
+    pub fn publish(&mut self, files: &[(&str, &str)]) -> String {
+        let mut manifest = String::from("Hasher: blake3\n---\n");
+        for (path, content) in files {
+            let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+            manifest += &format!("{};{};{hash};{path}\n", 0o100644, content.len() / 1024);
+            self.insert(&format!("chunks/{hash}"), *content);
+        }
+
+        self.publish_manifest(&manifest)
+    }
+
+    /// Stores `manifest` as is, under its hash, and points `manifest` at it. Returns its hash.
+    pub fn publish_manifest(&mut self, manifest: &str) -> String {
+        let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        self.insert(&manifest_hash, manifest);
+        self.insert("manifest", manifest_hash.clone());
+        manifest_hash
+    }
+
+    fn read(&self, path: &str) -> Result<&[u8], io::Error> {
+        self.files
+            .get(path)
+            .map(Vec::as_slice)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.to_string()))
+    }
+
+    fn read_string(&self, path: &str) -> Result<String, io::Error> {
+        String::from_utf8(self.read(path)?.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[async_trait]
+impl RepoSource for MemorySource {
+    async fn fetch_pointer(&self) -> Result<String, io::Error> {
+        self.read_string("manifest")
+    }
+
+    async fn fetch_manifest(&self, hash: &str) -> Result<String, io::Error> {
+        self.read_string(hash)
+    }
+
+    async fn fetch_chunk(&self, filename: &str) -> Result<ChunkReader, io::Error> {
+        let data = self.read(&format!("chunks/{filename}"))?.to_vec();
+        Ok(Box::new(io::Cursor::new(data)))
+    }
+
+    async fn fetch_dictionary(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        Ok(self.read(&format!("{DICTIONARY_DIR}/{hash}"))?.to_vec())
+    }
+
+    async fn fetch_delta(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+        Ok(self.read(&format!("{DELTA_DIR}/{hash}"))?.to_vec())
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Steps of an update, in order. Each is recorded once the update reaches it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Fetching the pending manifest's chunks
+    Downloading,
+    /// Staging holds the pending manifest's verified tree
+    Built,
+    /// The tree is swapped in, but the local manifest state hasn't caught up yet
+    Swapped,
+}
+
+impl Phase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Downloading => "downloading",
+            Phase::Built => "built",
+            Phase::Swapped => "swapped",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "downloading" => Some(Phase::Downloading),
+            "built" => Some(Phase::Built),
+            "swapped" => Some(Phase::Swapped),
+            _ => None,
+        }
+    }
+}
+
+/// How far an update of the root got, kept in the state directory so an interrupted one can
+/// resume rather than leave the local manifests out of step with the installed tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    pub phase: Phase,
+    /// Hash of the manifest being installed
+    pub manifest_hash: String,
+}
+
+impl Transaction {
+    /// Reads the recorded transaction, or `None` if no update is in progress.
+    pub fn load(path: &Path) -> Result<Option<Self>, io::Error> {
+        let raw = match fs::read_to_string(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            raw => raw?,
+        };
+
+        let (phase, manifest_hash) = raw
+            .trim_end()
+            .split_once(' ')
+            .and_then(|(phase, hash)| Some((Phase::parse(phase)?, hash)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid transaction record {}: {raw:?}", path.display()),
+                )
+            })?;
+
+        Ok(Some(Self {
+            phase,
+            manifest_hash: manifest_hash.to_string(),
+        }))
+    }
+
+    /// Records the transaction, replacing the previous record in one rename.
+    pub fn save(&self, path: &Path) -> Result<(), io::Error> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".new");
+
+        fs::write(
+            &temp_path,
+            format!("{} {}\n", self.phase.as_str(), self.manifest_hash),
+        )?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Ends the transaction once the update is committed.
+    pub fn clear(path: &Path) -> Result<(), io::Error> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transaction_roundtrip() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let path = dir.child("transaction");
+        assert_eq!(Transaction::load(&path).unwrap(), None);
+
+        for phase in [Phase::Downloading, Phase::Built, Phase::Swapped] {
+            let transaction = Transaction {
+                phase,
+                manifest_hash: "abcd".into(),
+            };
+            transaction.save(&path).unwrap();
+            assert_eq!(Transaction::load(&path).unwrap(), Some(transaction));
+        }
+
+        Transaction::clear(&path).unwrap();
+        Transaction::clear(&path).unwrap();
+        assert_eq!(Transaction::load(&path).unwrap(), None);
+
+        fs::write(&path, "halfway abcd\n").unwrap();
+        assert!(Transaction::load(&path).is_err());
+    }
+}
@@ -1,13 +1,98 @@
 use clap::ValueEnum;
+use clap::builder::PossibleValue;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum Compression {
     None,
     Zstd,
+    Brotli,
+    Lz4,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+impl Compression {
+    /// Value of the manifest's `Compression` header
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Brotli => "brotli",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_header(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "none" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            "brotli" => Some(Compression::Brotli),
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Suffix of chunk files stored with this compression
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Zstd => ".zstd",
+            Compression::Brotli => ".br",
+            Compression::Lz4 => ".lz4",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HashType {
     Blake3,
     Xxh3_128,
+    Blake2b,
+    Blake2s,
+    /// A hasher added with `utils::register_hasher`, by the name manifests declare it under
+    Custom(&'static str),
+}
+
+impl HashType {
+    const BUILT_IN: [HashType; 4] = [
+        HashType::Blake3,
+        HashType::Xxh3_128,
+        HashType::Blake2b,
+        HashType::Blake2s,
+    ];
+
+    /// Value of the manifest's `Hasher` header
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            HashType::Blake3 => "blake3",
+            HashType::Xxh3_128 => "xxh3_128",
+            HashType::Blake2b => "blake2b",
+            HashType::Blake2s => "blake2s",
+            HashType::Custom(name) => name,
+        }
+    }
+
+    /// Looks up a built-in or registered hasher by its header value.
+    pub fn from_header(value: &str) -> Option<Self> {
+        let value = value.to_lowercase();
+        Self::BUILT_IN
+            .into_iter()
+            .find(|hash_type| hash_type.header_value() == value)
+            .or_else(|| crate::utils::registered_hasher(&value))
+    }
+}
+
+// Written out rather than derived, as custom hashers can't be picked on the command line
+impl ValueEnum for HashType {
+    fn value_variants<'a>() -> &'a [Self] {
+        &Self::BUILT_IN
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            HashType::Blake3 => Some(PossibleValue::new("blake3")),
+            HashType::Xxh3_128 => Some(PossibleValue::new("xxh3-128")),
+            HashType::Blake2b => Some(PossibleValue::new("blake2b")),
+            HashType::Blake2s => Some(PossibleValue::new("blake2s")),
+            HashType::Custom(_) => None,
+        }
+    }
 }
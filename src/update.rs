@@ -0,0 +1,2786 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::chunks::{
+    Chunk, ChunkError, ChunkLayout, InstallOptions, chunk_filename, clean_old_chunks,
+    clean_temp_chunks, install_chunk, install_chunk_data, missing_chunks, repo_chunk_path,
+    verify_chunkstore,
+};
+use crate::delta::{Delta, apply_delta, parse_deltas};
+use crate::dictionary::{DICTIONARY_DIR, Dictionary};
+use crate::error::Error;
+use crate::manifest::{
+    ManifestDiff, StagingGuard, build_tree, check_min_version, decompress_manifest, diff_manifests,
+    file_chunks, manifest_hash_changed, parse_generated, parse_manifest, parse_pointer,
+    read_pointer_etag, record_manifest_hash, record_pointer_etag, swap_tree, tree_hash,
+    update_manifest, verify_tree,
+};
+use crate::platform::{available_space, link_or_copy, remove_readonly_file};
+use crate::root::{StatePaths, target_path};
+use crate::source::{FileSource, PointerFetch, RepoSource};
+use crate::transaction::{Phase, Transaction};
+use crate::types::{Compression, HashType};
+use crate::utils::{DEFAULT_BUFFER_SIZE, RateLimiter};
+
+static MAJOR_VERSION: LazyLock<usize> =
+    LazyLock::new(|| env!("CARGO_PKG_VERSION_MAJOR").parse::<usize>().unwrap());
+static MINOR_VERSION: LazyLock<usize> =
+    LazyLock::new(|| env!("CARGO_PKG_VERSION_MINOR").parse::<usize>().unwrap());
+
+/// Reads the compression and hasher a manifest declares, checking it supports this client.
+/// A manifest without a `Hasher` header is refused, as guessing wrong fails every chunk.
+pub fn read_headers(headers: &HashMap<&str, &str>) -> Result<(Compression, HashType), Error> {
+    read_headers_assuming(headers, &HeaderPolicy::default())
+}
+
+/// How `read_headers_assuming` treats headers a manifest leaves out or gets wrong.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeaderPolicy {
+    /// Used when the manifest has no `Compression` header. Without it that means uncompressed
+    /// chunks, as the packager only writes it for compressed ones
+    pub assume_compression: Option<Compression>,
+    /// Used when the manifest has no usable `Hasher` header
+    pub assume_hasher: Option<HashType>,
+    /// Only warn about a `MinVersion` this client doesn't meet, or can't read, for recovering
+    /// from a repo that declares it by mistake
+    pub ignore_min_version: bool,
+    /// Refuse unknown headers, and values of known ones this client can't use, rather than
+    /// warning about them, catching typos like `Compresion: zstd` before any chunk is fetched
+    pub strict: bool,
+}
+
+/// Like `read_headers`, but following `policy` for missing, unknown and unusable headers.
+/// Headers are checked in sorted order, so the one reported is the same every run.
+pub fn read_headers_assuming(
+    headers: &HashMap<&str, &str>,
+    policy: &HeaderPolicy,
+) -> Result<(Compression, HashType), Error> {
+    let mut compression = None;
+    let mut hasher = None;
+    let refuse = |message: String| match policy.strict {
+        true => Err(Error::Parse(message)),
+        false => {
+            warn!("{message}");
+            Ok(())
+        }
+    };
+
+    let mut headers: Vec<(&&str, &&str)> = headers.iter().collect();
+    headers.sort_unstable();
+    for (key, value) in headers {
+        match *key {
+            "MinVersion" => match check_min_version(value, *MAJOR_VERSION, *MINOR_VERSION) {
+                Err(e) if policy.ignore_min_version => warn!("Ignoring MinVersion {value}: {e}"),
+                result => result?,
+            },
+            "Compression" => match Compression::from_header(value) {
+                Some(requested) => compression = Some(requested),
+                None => refuse(format!("Unknown compression requested: {value}"))?,
+            },
+            "Hasher" => match HashType::from_header(value) {
+                Some(requested) => hasher = Some(requested),
+                None => refuse(format!("Unknown hasher requested: {value}"))?,
+            },
+            // Handled while parsing the chunklist
+            "FormatVersion" | "Timestamps" => (),
+            // Handled by `read_dictionary`
+            "Dictionary" => (),
+            // Handled by `install_from_delta`
+            "Deltas" => (),
+            // Read by `IpfsSource`, other sources fetch chunks by path
+            "ChunkCids" => (),
+            // Checked against the chunklist by `check_tree_hash`
+            "TreeHash" => (),
+            // Checked against the chunklist by `parse_manifest`
+            "ChunkCount" => (),
+            // Checked by `check_manifest_age` when a maximum age is set
+            "Generated" => {
+                if let Err(e) = parse_generated(value) {
+                    refuse(format!("Invalid Generated header: {e}"))?;
+                }
+            }
+            _ => refuse(format!("Unknown header: {key}"))?,
+        }
+    }
+
+    let compression = compression.unwrap_or_else(|| {
+        let assumed = policy.assume_compression.unwrap_or(Compression::None);
+        info!(
+            "Manifest has no Compression header, assuming {}",
+            assumed.header_value()
+        );
+        assumed
+    });
+    let hasher = match (hasher, policy.assume_hasher) {
+        (Some(hasher), _) => hasher,
+        (None, Some(assumed)) => {
+            warn!("Manifest has no usable Hasher header, assuming {assumed:?}");
+            assumed
+        }
+        (None, None) => {
+            return Err(Error::Parse(
+                "manifest has no usable Hasher header. Pass --assume-hasher if you know how its \
+                 chunks were hashed"
+                    .into(),
+            ));
+        }
+    };
+
+    Ok((compression, hasher))
+}
+
+pub struct UpdateOptions {
+    /// Caps the combined rate of all chunk downloads
+    pub rate_limiter: Option<RateLimiter>,
+    /// A repo-shaped directory, such as installation media, checked for chunks before downloading
+    pub additional_cache_path: Option<PathBuf>,
+    /// How many manifests before `current` keep their chunks during cleanup
+    pub keep_generations: usize,
+    /// Clean up chunks and manifests older than `keep_generations` after swapping. Without it
+    /// they're kept until `pkgsmgr-gc` runs
+    pub clean: bool,
+    /// Only install chunks already in the chunkstore or additional cache, never downloading any
+    pub offline: bool,
+    /// A local manifest to install, instead of the latest one in the repo
+    pub manifest_file: Option<PathBuf>,
+    /// A manifest to install as is, such as one piped in on stdin. Takes precedence over
+    /// `manifest_file`
+    pub manifest: Option<String>,
+    /// Install the repo's manifest with this hash, instead of the latest one
+    pub manifest_hash: Option<String>,
+    /// Shell command run once staging is verified; a non-zero exit aborts the swap
+    pub pre_swap_hook: Option<String>,
+    /// Shell command run after a successful swap. The update is already applied, so its failure is
+    /// only warned about
+    pub post_swap_hook: Option<String>,
+    /// Where the chunkstore, staging, and manifests live, absolute or relative to the root
+    pub state_dir: Option<PathBuf>,
+    /// Directory under the root that's replaced, relative to it [default: usr]
+    pub target: Option<PathBuf>,
+    /// Refuse manifests whose `Generated` header is older than this many seconds
+    pub max_manifest_age: Option<u64>,
+    /// A chunkstore shared by several roots on one host. Chunks are fetched into it once, then
+    /// hardlinked into each root's chunkstore. Cleanup never removes chunks from it
+    pub shared_chunk_cache: Option<PathBuf>,
+    /// Flush each chunk to disk as it's installed, so a crash can't leave a truncated one behind
+    pub sync: bool,
+    /// Bytes read at a time while downloading and hashing chunks
+    pub buffer_size: usize,
+    /// Download even when there doesn't look to be enough free space for the update
+    pub skip_space_check: bool,
+    /// Decode chunks with this, whatever the manifest's `Compression` header says
+    pub force_compression: Option<Compression>,
+    /// Check chunks with this, whatever the manifest's `Hasher` header says
+    pub force_hasher: Option<HashType>,
+    /// Decode chunks with this when the manifest has no `Compression` header
+    pub assume_compression: Option<Compression>,
+    /// Check chunks with this when the manifest has no `Hasher` header, rather than refusing it
+    pub assume_hasher: Option<HashType>,
+    /// Where the repo, and the additional cache, keep chunks within their `chunks` directory
+    pub chunk_layout: ChunkLayout,
+    /// Re-hash cached chunks before reusing them, fetching any that no longer match again
+    pub verify_cache: bool,
+    /// Fetch any missing chunks, rebuild the tree and swap it in even when the manifest is
+    /// already installed, to repair a damaged tree. With `verify_cache`, corrupt chunks too
+    pub force: bool,
+    /// Install manifests whose `MinVersion` this client doesn't meet, warning instead of refusing
+    pub ignore_min_version: bool,
+    /// Refuse manifests with unknown headers, or known ones with values this client can't use,
+    /// rather than warning
+    pub strict_headers: bool,
+    /// Set, such as on Ctrl-C, to stop the update once the chunk being installed is done. It then
+    /// fails with `Error::Aborted`, without swapping or cleaning up
+    pub abort: Arc<AtomicBool>,
+    /// Asked before anything is downloaded whether to go ahead with the update. Unless it agrees,
+    /// the update fails with `Error::Aborted`
+    pub confirm: Option<ConfirmSwap>,
+}
+
+/// Given the hash of the manifest an update would install and what it changes, whether to go
+/// ahead with it.
+pub type ConfirmSwap = Arc<dyn Fn(&str, &ManifestDiff) -> bool + Send + Sync>;
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            rate_limiter: None,
+            additional_cache_path: None,
+            keep_generations: 1,
+            clean: true,
+            offline: false,
+            manifest_file: None,
+            manifest: None,
+            manifest_hash: None,
+            pre_swap_hook: None,
+            post_swap_hook: None,
+            state_dir: None,
+            target: None,
+            max_manifest_age: None,
+            shared_chunk_cache: None,
+            sync: true,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            skip_space_check: false,
+            force_compression: None,
+            force_hasher: None,
+            assume_compression: None,
+            assume_hasher: None,
+            chunk_layout: ChunkLayout::default(),
+            verify_cache: false,
+            force: false,
+            ignore_min_version: false,
+            strict_headers: false,
+            abort: Arc::new(AtomicBool::new(false)),
+            confirm: None,
+        }
+    }
+}
+
+/// The hash of the dictionary the manifest's `Dictionary` header names, if any.
+fn dictionary_hash<'a>(
+    headers: &HashMap<&str, &'a str>,
+    compression: Compression,
+) -> Result<Option<&'a str>, Error> {
+    let Some(hash) = headers.get("Dictionary") else {
+        return Ok(None);
+    };
+
+    if compression != Compression::Zstd {
+        return Err(Error::Parse(format!(
+            "manifest declares a dictionary, but {} compression can't use one",
+            compression.header_value()
+        )));
+    }
+
+    Ok(Some(hash))
+}
+
+/// Fetches the dictionary the manifest's `Dictionary` header names, if any, checking its hash.
+pub async fn read_dictionary(
+    source: &dyn RepoSource,
+    headers: &HashMap<&str, &str>,
+    compression: Compression,
+) -> Result<Option<Dictionary>, Error> {
+    let Some(hash) = dictionary_hash(headers, compression)? else {
+        return Ok(None);
+    };
+
+    let dictionary = Dictionary::new(source.fetch_dictionary(hash).await?);
+    if dictionary.hash != *hash {
+        let error = ChunkError::HashMismatch {
+            expected: hash.to_string(),
+            received: dictionary.hash,
+        };
+        warn!("{error} for the dictionary");
+        return Err(error.into());
+    }
+
+    Ok(Some(dictionary))
+}
+
+/// Like `read_dictionary`, but reuses the copy an earlier update kept in `dictionaries_path`, or
+/// the additional cache's, and only fetches it from the repo when neither has it and
+/// `options.offline` isn't set. A fetched dictionary is kept for later updates.
+async fn cached_dictionary(
+    source: &dyn RepoSource,
+    headers: &HashMap<&str, &str>,
+    compression: Compression,
+    dictionaries_path: &Path,
+    options: &UpdateOptions,
+) -> Result<Option<Dictionary>, Error> {
+    let Some(hash) = dictionary_hash(headers, compression)? else {
+        return Ok(None);
+    };
+
+    let kept_path = dictionaries_path.join(hash);
+    let cached_path = options
+        .additional_cache_path
+        .as_ref()
+        .map(|cache_path| cache_path.join(DICTIONARY_DIR).join(hash));
+    for path in [Some(&kept_path), cached_path.as_ref()]
+        .into_iter()
+        .flatten()
+    {
+        let Ok(data) = fs::read(path) else {
+            continue;
+        };
+        // A copy that doesn't match is skipped, and replaced once fetched
+        let dictionary = Dictionary::new(data);
+        if dictionary.hash == hash {
+            if *path != kept_path {
+                fs::create_dir_all(dictionaries_path)?;
+                fs::write(&kept_path, &dictionary.data)?;
+            }
+            return Ok(Some(dictionary));
+        }
+    }
+
+    if options.offline {
+        return Err(format!("dictionary {hash} isn't available offline").into());
+    }
+    let dictionary = read_dictionary(source, headers, compression).await?;
+    if let Some(dictionary) = &dictionary {
+        fs::create_dir_all(dictionaries_path)?;
+        fs::write(&kept_path, &dictionary.data)?;
+    }
+
+    Ok(dictionary)
+}
+
+/// Refuses a manifest generated more than `max_age` seconds before `now`, such as a stale one
+/// replayed by a mirror. A manifest without a `Generated` header can't be vouched for either.
+pub fn check_manifest_age(
+    headers: &HashMap<&str, &str>,
+    max_age: u64,
+    now: i64,
+) -> Result<(), String> {
+    let generated = headers
+        .get("Generated")
+        .ok_or("manifest has no Generated header, so its age can't be checked")?;
+    let generated = parse_generated(generated)?;
+
+    let age = now.saturating_sub(generated);
+    if age > max_age as i64 {
+        return Err(format!(
+            "manifest was generated {age} seconds ago, more than the allowed {max_age}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Refuses a manifest whose `TreeHash` header doesn't match its own chunklist.
+/// Returns the tree hash either way.
+pub fn check_tree_hash(headers: &HashMap<&str, &str>, chunks: &[Chunk]) -> Result<String, String> {
+    let computed = tree_hash(chunks);
+
+    match headers.get("TreeHash") {
+        Some(declared) if *declared != computed => Err(format!(
+            "manifest declares tree hash {declared}, but its chunklist hashes to {computed}"
+        )),
+        _ => Ok(computed),
+    }
+}
+
+/// Whether the tree at `target_path` doesn't match the recorded `current` manifest, such as one
+/// left by a client that recorded the manifest before swapping and crashed in between. Checked by
+/// path, size and mode like staging, so it's cheap enough for every run.
+fn installed_tree_stale(manifests_path: &Path, target_path: &Path) -> Result<bool, Error> {
+    let current_path = manifests_path.join("current");
+    if !current_path.exists() {
+        return Ok(false);
+    }
+
+    let (_, chunklist) = parse_manifest(&fs::read_to_string(current_path)?)?;
+    match verify_tree(target_path, &chunklist) {
+        Ok(()) => Ok(false),
+        Err(e) => {
+            warn!("The installed tree doesn't match the recorded manifest, installing again: {e}");
+            Ok(true)
+        }
+    }
+}
+
+/// Advances the local manifest state to a manifest whose tree was swapped in, ending the update's
+/// transaction. Safe to repeat if interrupted.
+fn commit(state: &StatePaths, manifest_raw: &str, manifest_hash: &str) -> Result<(), Error> {
+    let (_, chunklist) = parse_manifest(manifest_raw)?;
+
+    update_manifest(manifest_raw, &state.manifests)?;
+    record_manifest_hash(&state.manifests, manifest_hash)?;
+    fs::write(&state.tree_hash, tree_hash(&chunklist))?;
+
+    Transaction::clear(&state.transaction)?;
+    match fs::remove_file(&state.pending_manifest) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Runs a hook with `sh -c`, passing the root and both manifests' hashes in the environment.
+/// `PKGSMGR_OLD_MANIFEST` is empty on a first install.
+fn run_hook(command: &str, root_path: &Path, new_hash: &str, old_hash: &str) -> Result<(), String> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("PKGSMGR_ROOT", root_path)
+        .env("PKGSMGR_NEW_MANIFEST", new_hash)
+        .env("PKGSMGR_OLD_MANIFEST", old_hash)
+        .status()
+        .map_err(|e| format!("could not run hook `{command}`: {e}"))?;
+
+    if !status.success() {
+        return Err(format!("hook `{command}` failed with {status}"));
+    }
+
+    Ok(())
+}
+
+/// Swaps in the compression and hasher `options` force over the headers' own, for mislabeled
+/// repos. A wrong choice fails every chunk, so overriding is always warned about.
+fn override_headers(
+    compression: Compression,
+    hasher: HashType,
+    options: &UpdateOptions,
+) -> (Compression, HashType) {
+    if let Some(forced) = options.force_compression
+        && forced != compression
+    {
+        warn!(
+            "Forcing {} compression over the manifest's {}. Chunks will fail to decode if this is wrong",
+            forced.header_value(),
+            compression.header_value()
+        );
+    }
+    if let Some(forced) = options.force_hasher
+        && forced != hasher
+    {
+        warn!(
+            "Forcing the {forced:?} hasher over the manifest's {hasher:?}. Chunks will fail verification if this is wrong"
+        );
+    }
+
+    (
+        options.force_compression.unwrap_or(compression),
+        options.force_hasher.unwrap_or(hasher),
+    )
+}
+
+/// Fails if the filesystem holding `path` has less than `needed_kb` available.
+fn check_free_space(path: &Path, needed_kb: u64) -> Result<(), String> {
+    let available =
+        available_space(path).map_err(|e| format!("could not check free space: {e}"))?;
+
+    enough_space(path, needed_kb, available)
+}
+
+/// Compares the space needed against what's `available` in bytes, if the platform could tell.
+fn enough_space(path: &Path, needed_kb: u64, available: Option<u64>) -> Result<(), String> {
+    // Platforms that can't report free space skip the check
+    let Some(available) = available else {
+        return Ok(());
+    };
+
+    if available / 1024 < needed_kb {
+        return Err(format!(
+            "not enough space in {}: need {}MB, have {}MB. Pass --skip-space-check to try anyway",
+            path.display(),
+            needed_kb.div_ceil(1024),
+            available / 1024 / 1024
+        ));
+    }
+
+    Ok(())
+}
+
+/// Space staging takes beyond hardlinks to the chunkstore: split files are assembled into new
+/// copies, and every file is allowed a kilobyte for its directory entry.
+fn staging_kb(chunklist: &[Chunk]) -> u64 {
+    file_chunks(chunklist)
+        .map(|parts| match parts {
+            [_] => 1,
+            parts => parts.iter().map(|part| part.size).sum::<u64>() + 1,
+        })
+        .sum()
+}
+
+/// Installs a chunk from a local cache, if the cache has a valid copy.
+/// Reading the cache isn't rate limited, whatever `options` says.
+pub(crate) async fn install_from_cache(
+    cache_path: &Path,
+    chunk: &Chunk,
+    chunks_path: &Path,
+    options: &InstallOptions<'_>,
+) -> bool {
+    let cache = FileSource::new(cache_path);
+
+    // Caches may also hold chunks uncompressed, such as ones copied out of a chunkstore
+    for (compression, dictionary) in [
+        (options.compression, options.dictionary),
+        (Compression::None, None),
+    ] {
+        let cached_path = cache_path.join("chunks").join(repo_chunk_path(
+            &options.layout,
+            &chunk.hash,
+            &compression,
+            dictionary,
+        ));
+        if !cached_path.exists() {
+            continue;
+        }
+
+        let options = InstallOptions {
+            compression,
+            dictionary,
+            rate_limiter: None,
+            ..options.clone()
+        };
+        match install_chunk(&cache, chunk, chunks_path, &options).await {
+            Ok(_) => return true,
+            Err(e) => warn!("Ignoring cached {}: {e}", cached_path.display()),
+        }
+    }
+
+    false
+}
+
+/// Fails with `Error::Aborted` once `options.abort` is set.
+fn check_aborted(options: &UpdateOptions) -> Result<(), Error> {
+    match options.abort.load(Ordering::Relaxed) {
+        true => Err(Error::Aborted),
+        false => Ok(()),
+    }
+}
+
+/// Rebuilds `chunk` into `store_path` by patching its previous version, if the manifest lists a
+/// delta for it and one of `base_paths` holds that version. Returns the delta's size, or `None` if
+/// the chunk has to be downloaded whole.
+async fn install_from_delta(
+    source: &dyn RepoSource,
+    chunk: &Chunk,
+    deltas: &HashMap<String, Delta>,
+    base_paths: [&Path; 2],
+    store_path: &Path,
+    hasher: HashType,
+    options: &UpdateOptions,
+) -> Option<u64> {
+    let delta = deltas.get(&chunk.hash)?;
+    // Chunks are stored under their bare hash, as `chunk_filename` names them
+    let base = base_paths
+        .iter()
+        .find_map(|path| fs::read(path.join(&delta.base)).ok())?;
+
+    let result = async {
+        let data = source.fetch_delta(&delta.hash).await?;
+        let received = blake3::hash(&data).to_hex().to_string();
+        if received != delta.hash {
+            return Err(Error::Other(format!(
+                "delta {} hashes to {received}",
+                delta.hash
+            )));
+        }
+
+        // The manifest rounds sizes down to whole kilobytes
+        let max_size = (chunk.size as usize + 1) * 1024;
+        let target = apply_delta(&base, &data, max_size)?;
+        install_chunk_data(
+            target,
+            chunk,
+            store_path,
+            hasher,
+            options.sync,
+            options.buffer_size,
+        )
+        .await?;
+
+        Ok(data.len() as u64)
+    }
+    .await;
+
+    match result {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            warn!(
+                "Couldn't patch {} from its previous version, downloading it whole: {e}",
+                chunk.path
+            );
+            None
+        }
+    }
+}
+
+/// Removes the chunks the chunklist references from `chunks_path` if they no longer match their
+/// hash, so they're fetched again like missing ones rather than installed corrupt.
+fn discard_corrupt_chunks(
+    chunklist: &[Chunk],
+    chunks_path: &Path,
+    hasher: HashType,
+) -> Result<(), std::io::Error> {
+    if !chunks_path.exists() {
+        return Ok(());
+    }
+
+    let report = verify_chunkstore(chunklist, chunks_path, hasher, None)?;
+    info!(
+        phase = "verify",
+        chunks = report.checked,
+        corrupt = report.corrupt.len(),
+        "Verified {} cached chunks",
+        report.checked
+    );
+    for (path, error) in report.corrupt {
+        warn!("Fetching {} again: {error}", path.display());
+        remove_readonly_file(&path)?;
+    }
+
+    Ok(())
+}
+
+/// Hardlinks a chunk from the shared cache into the chunkstore, if the cache has it.
+fn link_from_shared(
+    shared_path: &Path,
+    chunk: &Chunk,
+    chunks_path: &Path,
+) -> Result<bool, std::io::Error> {
+    let filename = chunk_filename(chunk);
+    let shared_chunk = shared_path.join(&filename);
+    if !shared_chunk.exists() {
+        return Ok(false);
+    }
+
+    link_or_copy(&shared_chunk, &chunks_path.join(filename))?;
+    Ok(true)
+}
+
+/// The hash of the manifest `options` asks for: its given manifest, its manifest file, its pinned
+/// hash, or the latest.
+pub async fn requested_manifest_hash(
+    source: &dyn RepoSource,
+    options: &UpdateOptions,
+) -> Result<String, Error> {
+    if let Some(manifest) = &options.manifest {
+        return Ok(blake3::hash(manifest.as_bytes()).to_hex().to_string());
+    }
+
+    Ok(match (&options.manifest_file, &options.manifest_hash) {
+        (Some(manifest_file), _) => blake3::hash(&fs::read(manifest_file)?).to_hex().to_string(),
+        (None, Some(manifest_hash)) => manifest_hash.clone(),
+        (None, None) => parse_pointer(&source.fetch_pointer().await?)?,
+    })
+}
+
+/// Like `requested_manifest_hash`, but when following the repo's pointer only fetches it if its
+/// ETag changed since the installed manifest was taken from it, unless `force`. Returns `None` if
+/// it didn't, otherwise the hash and the pointer's new ETag, to record once that manifest is
+/// installed.
+async fn changed_manifest_hash(
+    source: &dyn RepoSource,
+    options: &UpdateOptions,
+    manifests_path: &Path,
+    force: bool,
+) -> Result<Option<(String, Option<String>)>, Error> {
+    if options.manifest.is_some()
+        || options.manifest_file.is_some()
+        || options.manifest_hash.is_some()
+    {
+        return Ok(Some((
+            requested_manifest_hash(source, options).await?,
+            None,
+        )));
+    }
+
+    let etag = read_pointer_etag(manifests_path).filter(|_| !force);
+    Ok(
+        match source.fetch_pointer_if_changed(etag.as_deref()).await? {
+            PointerFetch::Unchanged => None,
+            PointerFetch::Fetched { pointer, etag } => Some((parse_pointer(&pointer)?, etag)),
+        },
+    )
+}
+
+/// Reads the manifest with `manifest_hash`, from `options.manifest`, `options.manifest_file`, or
+/// the source.
+/// A pinned manifest is checked against its hash.
+pub async fn read_manifest(
+    source: &dyn RepoSource,
+    options: &UpdateOptions,
+    manifest_hash: &str,
+) -> Result<String, Error> {
+    let manifest_raw = match (&options.manifest, &options.manifest_file) {
+        (Some(manifest), _) => manifest.clone(),
+        (None, Some(manifest_file)) => fs::read_to_string(manifest_file)?,
+        (None, None) => fetch_manifest(source, manifest_hash).await?,
+    };
+
+    if options.manifest_hash.is_some() {
+        let received_hash = blake3::hash(manifest_raw.as_bytes()).to_hex().to_string();
+        if received_hash != manifest_hash {
+            return Err(format!(
+                "requested manifest {manifest_hash}, but received one hashing to {received_hash}"
+            )
+            .into());
+        }
+    }
+
+    Ok(manifest_raw)
+}
+
+/// Fetches a manifest, preferring its compressed copy. Repos without one, or with one that
+/// doesn't decompress, are read uncompressed. As the copy is optional, any error fetching it
+/// falls back too, since servers like S3 answer 403 for missing files.
+async fn fetch_manifest(
+    source: &dyn RepoSource,
+    manifest_hash: &str,
+) -> Result<String, std::io::Error> {
+    match source.fetch_compressed_manifest(manifest_hash).await {
+        Ok(compressed) => match decompress_manifest(&compressed) {
+            Ok(manifest) => return Ok(manifest),
+            Err(e) => {
+                warn!("Couldn't decompress manifest {manifest_hash}, fetching it uncompressed: {e}")
+            }
+        },
+        Err(e) => debug!("No compressed copy of manifest {manifest_hash}: {e}"),
+    }
+
+    source.fetch_manifest(manifest_hash).await
+}
+
+/// Every file the manifest `options` asks for would install, sorted by path. Nothing is
+/// written, and with a manifest file nothing is fetched either.
+pub async fn list_files(
+    source: &dyn RepoSource,
+    options: &UpdateOptions,
+) -> Result<Vec<Chunk>, Error> {
+    let manifest_hash = requested_manifest_hash(source, options).await?;
+    let manifest_raw = read_manifest(source, options, &manifest_hash).await?;
+
+    let (_, mut chunklist) = parse_manifest(&manifest_raw)?;
+    chunklist.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(chunklist)
+}
+
+/// What an update changed, for embedders to log or act on.
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct UpdateSummary {
+    /// Blake3 hash of the manifest swapped in
+    pub manifest_hash: String,
+    /// Blake3 hash of the manifest swapped out, or `None` on a first install
+    pub previous_manifest_hash: Option<String>,
+    /// Chunks fetched from the repo, not counting ones copied from the additional cache
+    pub chunks_downloaded: usize,
+    /// Uncompressed size of the downloaded chunks
+    pub bytes_downloaded: u64,
+    /// Chunks rebuilt by patching their previous version rather than downloaded whole
+    pub chunks_patched: usize,
+    /// Size of the deltas downloaded to patch them
+    pub delta_bytes: u64,
+    pub chunks_freed: usize,
+    pub bytes_freed: u64,
+    /// How many paths were added, removed, or modified relative to the previous manifest
+    pub files_changed: usize,
+    /// The paths themselves
+    pub changes: ManifestDiff,
+    /// `tree_hash` of the installed tree
+    pub tree_hash: String,
+}
+
+/// Brings the tree under `root_path` up to date with the latest manifest in `source`.
+/// Returns what changed if a new tree was swapped in, or `None` if already up to date.
+pub async fn update(
+    source: &dyn RepoSource,
+    root_path: &Path,
+    options: &UpdateOptions,
+) -> Result<Option<UpdateSummary>, Error> {
+    let state = StatePaths::new(root_path, options.state_dir.as_deref());
+    let target_path = &target_path(root_path, options.target.as_deref(), &state)?;
+    let chunks_path = &state.chunkstore;
+    fs::create_dir_all(chunks_path)?;
+    let staging_path = &state.staging;
+    let manifests_path = &state.manifests;
+    fs::create_dir_all(manifests_path)?;
+    state.warn_if_cross_device(root_path);
+
+    // An update interrupted after its swap only has its manifest left to record
+    if let Some(transaction) = Transaction::load(&state.transaction)?
+        && transaction.phase == Phase::Swapped
+    {
+        info!(
+            phase = "resume",
+            manifest = %transaction.manifest_hash,
+            "Recording the manifest of an interrupted update..."
+        );
+        let pending = fs::read_to_string(&state.pending_manifest)?;
+        commit(&state, &pending, &transaction.manifest_hash)?;
+    }
+
+    let reinstall = options.force || installed_tree_stale(manifests_path, target_path)?;
+    let Some((manifest_hash, pointer_etag)) =
+        changed_manifest_hash(source, options, manifests_path, reinstall).await?
+    else {
+        info!(
+            phase = "check",
+            "Skipping, the repo's pointer is unchanged."
+        );
+        return Ok(None);
+    };
+
+    if !reinstall && !manifest_hash_changed(manifests_path, &manifest_hash) {
+        record_pointer_etag(manifests_path, pointer_etag.as_deref())?;
+        info!(phase = "check", "Skipping, no update found.");
+        return Ok(None);
+    };
+    info!(
+        phase = "check",
+        manifest = %manifest_hash,
+        "Update found, downloading manifest..."
+    );
+
+    let manifest_raw = read_manifest(source, options, &manifest_hash).await?;
+
+    let (headers, chunklist) = parse_manifest(&manifest_raw)?;
+    let policy = HeaderPolicy {
+        assume_compression: options.assume_compression,
+        assume_hasher: options.assume_hasher.or(options.force_hasher),
+        ignore_min_version: options.ignore_min_version,
+        strict: options.strict_headers,
+    };
+    let (compression, hasher) = read_headers_assuming(&headers, &policy)?;
+    let (compression, hasher) = override_headers(compression, hasher, options);
+    let tree_hash = check_tree_hash(&headers, &chunklist)?;
+    if let Some(max_age) = options.max_manifest_age {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs() as i64;
+        check_manifest_age(&headers, max_age, now)?;
+    }
+
+    let current_path = manifests_path.join("current");
+    let current = match current_path.exists() {
+        true => Some(fs::read_to_string(current_path)?),
+        false => None,
+    };
+
+    // Nothing to install when only the record of the latest hash is missing
+    if !reinstall && current.as_deref() == Some(manifest_raw.as_str()) {
+        record_manifest_hash(manifests_path, &manifest_hash)?;
+        record_pointer_etag(manifests_path, pointer_etag.as_deref())?;
+        return Ok(None);
+    }
+
+    let diff = diff_manifests(current.as_deref().unwrap_or("---\n"), &manifest_raw)?;
+    if let Some(confirm) = &options.confirm
+        && !confirm(&manifest_hash, &diff)
+    {
+        return Err(Error::Aborted);
+    }
+
+    // A run interrupted before its swap left a verified staging tree for this manifest behind.
+    // Forced runs rebuild it, as it's only checked by path, size and mode
+    let resumed = !options.force
+        && Transaction::load(&state.transaction)?.is_some_and(|transaction| {
+            transaction.phase == Phase::Built && transaction.manifest_hash == manifest_hash
+        })
+        && verify_tree(staging_path, &chunklist).is_ok();
+
+    let mut summary = UpdateSummary {
+        files_changed: diff.added.len() + diff.removed.len() + diff.modified.len(),
+        changes: diff,
+        ..Default::default()
+    };
+    let staging_guard = StagingGuard::new(staging_path);
+    if resumed {
+        info!(
+            phase = "resume",
+            "Resuming with the staging tree already built..."
+        );
+    } else {
+        if options.verify_cache {
+            discard_corrupt_chunks(&chunklist, chunks_path, hasher)?;
+            if let Some(shared_path) = &options.shared_chunk_cache {
+                discard_corrupt_chunks(&chunklist, shared_path, hasher)?;
+            }
+        }
+
+        let missing = missing_chunks(&chunklist, chunks_path);
+        let total_kb: u64 = missing.iter().map(|chunk| chunk.size).sum();
+        info!(
+            phase = "plan",
+            chunks = missing.len(),
+            kb = total_kb,
+            "{} chunks to fetch, {total_kb}kb total",
+            missing.len()
+        );
+
+        // Fetched chunks land in the shared cache when there is one, and are linked in from there
+        let shared_path = options.shared_chunk_cache.as_deref();
+        if let Some(shared_path) = shared_path {
+            fs::create_dir_all(shared_path)?;
+        }
+        let store_path = shared_path.unwrap_or(chunks_path);
+
+        // Checked before anything is written, so running out of space can't strand an update
+        if !options.skip_space_check {
+            let staging_kb = staging_kb(&chunklist);
+            match shared_path {
+                Some(shared_path) => {
+                    check_free_space(shared_path, total_kb)?;
+                    check_free_space(chunks_path, staging_kb)?;
+                }
+                None => check_free_space(chunks_path, total_kb + staging_kb)?,
+            }
+        }
+
+        fs::write(&state.pending_manifest, &manifest_raw)?;
+        Transaction {
+            phase: Phase::Downloading,
+            manifest_hash: manifest_hash.clone(),
+        }
+        .save(&state.transaction)?;
+
+        // Only chunks still to fetch need decoding
+        let dictionary = match missing.is_empty() {
+            true => None,
+            false => {
+                cached_dictionary(source, &headers, compression, &state.dictionaries, options)
+                    .await?
+            }
+        };
+        let deltas = match headers.get("Deltas") {
+            Some(value) => parse_deltas(value).map_err(Error::Parse)?,
+            None => HashMap::new(),
+        };
+        let install_options = InstallOptions {
+            compression,
+            dictionary: dictionary.as_ref(),
+            hash_method: hasher,
+            rate_limiter: options.rate_limiter.as_ref(),
+            sync: options.sync,
+            buffer_size: options.buffer_size,
+            layout: options.chunk_layout.clone(),
+        };
+        clean_temp_chunks(chunks_path)?;
+
+        // Install all chunks in chunklist before doing anything else.
+        let mut done_kb = 0;
+        let mut unavailable = Vec::new();
+        for chunk in missing {
+            check_aborted(options)?;
+            let progress = done_kb * 100 / total_kb.max(1);
+            done_kb += chunk.size;
+
+            if let Some(shared_path) = shared_path
+                && link_from_shared(shared_path, chunk, chunks_path)?
+            {
+                info!(
+                    phase = "cache",
+                    hash = %chunk.hash,
+                    path = %chunk.path,
+                    "Linked {} from the shared cache",
+                    chunk.path
+                );
+                continue;
+            }
+
+            if let Some(cache_path) = &options.additional_cache_path
+                && install_from_cache(cache_path, chunk, store_path, &install_options).await
+            {
+                info!(
+                    phase = "cache",
+                    hash = %chunk.hash,
+                    path = %chunk.path,
+                    "Copied {} from cache",
+                    chunk.path
+                );
+            } else if options.offline {
+                unavailable.push(chunk.hash.as_str());
+                continue;
+            } else if let Some(bytes) = install_from_delta(
+                source,
+                chunk,
+                &deltas,
+                [chunks_path, store_path],
+                store_path,
+                hasher,
+                options,
+            )
+            .await
+            {
+                info!(
+                    phase = "delta",
+                    hash = %chunk.hash,
+                    path = %chunk.path,
+                    bytes,
+                    "Patched {} from its previous version with a {bytes}-byte delta",
+                    chunk.path
+                );
+                summary.chunks_patched += 1;
+                summary.delta_bytes += bytes;
+            } else {
+                info!(
+                    phase = "download",
+                    hash = %chunk.hash,
+                    path = %chunk.path,
+                    kb = chunk.size,
+                    progress,
+                    "Downloading {} ({progress}%)",
+                    chunk.path
+                );
+                let started = std::time::Instant::now();
+                summary.bytes_downloaded +=
+                    install_chunk(source, chunk, store_path, &install_options)
+                        .await
+                        .map_err(|e| {
+                            e.context(format_args!("could not download {}", chunk.path))
+                        })?;
+                summary.chunks_downloaded += 1;
+                let ms = started.elapsed().as_millis();
+                debug!(phase = "download", hash = %chunk.hash, ms, "Downloaded {} in {ms}ms", chunk.path);
+            }
+
+            if let Some(shared_path) = shared_path {
+                link_from_shared(shared_path, chunk, chunks_path)?;
+            }
+        }
+
+        if !unavailable.is_empty() {
+            return Err(format!(
+                "{} chunks aren't available offline: {}",
+                unavailable.len(),
+                unavailable.join(", ")
+            )
+            .into());
+        }
+
+        build_tree(staging_path, chunks_path, &chunklist)
+            .map_err(|e| format!("could not build staging: {e}"))?;
+
+        if let Err(e) = verify_tree(staging_path, &chunklist) {
+            return Err(Error::Swap(std::io::Error::new(
+                e.kind(),
+                format!("Staging failed verification, refusing to swap: {e}"),
+            )));
+        }
+        Transaction {
+            phase: Phase::Built,
+            manifest_hash: manifest_hash.clone(),
+        }
+        .save(&state.transaction)?;
+    }
+
+    let new_hash = blake3::hash(manifest_raw.as_bytes()).to_hex().to_string();
+    let old_hash = current
+        .map(|current| blake3::hash(current.as_bytes()).to_hex().to_string())
+        .unwrap_or_default();
+
+    check_aborted(options)?;
+    if let Some(hook) = &options.pre_swap_hook {
+        info!(phase = "hook", "Running pre-swap hook...");
+        run_hook(hook, root_path, &new_hash, &old_hash)
+            .map_err(|e| format!("refusing to swap: {e}"))?;
+    }
+
+    check_aborted(options)?;
+    info!(phase = "swap", "Swapping tree...");
+
+    swap_tree(staging_path, target_path)?;
+    staging_guard.disarm();
+    Transaction {
+        phase: Phase::Swapped,
+        manifest_hash: manifest_hash.clone(),
+    }
+    .save(&state.transaction)?;
+    commit(&state, &manifest_raw, &manifest_hash)?;
+    record_pointer_etag(manifests_path, pointer_etag.as_deref())?;
+
+    if let Some(hook) = &options.post_swap_hook {
+        info!(phase = "hook", "Running post-swap hook...");
+        if let Err(e) = run_hook(hook, root_path, &new_hash, &old_hash) {
+            warn!(phase = "hook", "Post-swap {e}");
+        }
+    }
+
+    if options.clean {
+        info!(phase = "clean", "Cleaning up old chunks...");
+
+        // The update is already committed, so a failed cleanup is left for pkgsmgr-gc
+        match clean_old_chunks(manifests_path, chunks_path, options.keep_generations, false) {
+            Ok(report) => {
+                for (path, e) in &report.failures {
+                    warn!("Couldn't remove {}: {e}", path.display());
+                }
+                info!(
+                    phase = "clean",
+                    bytes = report.freed_bytes,
+                    "Freed {}kb",
+                    report.freed_bytes / 1024
+                );
+                summary.chunks_freed = report.removed.len();
+                summary.bytes_freed = report.freed_bytes;
+            }
+            Err(e) => warn!(phase = "clean", "Couldn't clean up old chunks: {e}"),
+        }
+    } else {
+        info!(
+            phase = "clean",
+            "Keeping old chunks, run pkgsmgr-gc to free them"
+        );
+    }
+
+    summary.manifest_hash = new_hash;
+    summary.previous_manifest_hash = Some(old_hash).filter(|hash| !hash.is_empty());
+    summary.tree_hash = tree_hash;
+
+    Ok(Some(summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::generation_paths;
+    use crate::packager::ChunkOptions;
+    use crate::source::HttpSource;
+    use crate::test_utils::{MemorySource, TestServer, publish_manifest_to};
+
+    #[tokio::test]
+    async fn test_update_from_file_source() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+
+        let content = "hello from installation media";
+        let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+        fs::write(repo.child("chunks").join(&hash), content).unwrap();
+
+        let manifest = format!("Hasher: blake3\n---\n{};0;{hash};bin/hello\n", 0o100755);
+        publish_manifest_to(repo.path(), &manifest);
+
+        let source = FileSource::new(repo.path());
+        let options = UpdateOptions::default();
+        assert!(
+            update(&source, root.path(), &options)
+                .await
+                .unwrap()
+                .is_some()
+        );
+
+        let installed = fs::read_to_string(root.child("usr/bin/hello")).unwrap();
+        assert_eq!(installed, content);
+
+        // Nothing changed, so a second run is a no-op
+        assert!(
+            update(&source, root.path(), &options)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forced_compression_and_hasher() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let repo = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+        let content = "packaged with lz4 and blake3";
+        let file = input.child("tool");
+        fs::write(&file, content).unwrap();
+        let hashes = crate::packager::write_chunks(
+            std::slice::from_ref(&file),
+            &repo.child("chunks"),
+            None,
+            &ChunkOptions {
+                compression: Compression::Lz4,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let hash = hashes[&file][0].hash.clone();
+
+        // Labeled with neither the compression nor the hasher it was packaged with
+        let manifest =
+            format!("Compression: zstd\nHasher: blake2b\n---\n33188;0;{hash};bin/tool\n");
+        publish_manifest_to(repo.path(), &manifest);
+        let source = FileSource::new(repo.path());
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let options = UpdateOptions {
+            force_compression: Some(Compression::Lz4),
+            ..Default::default()
+        };
+        let error = update(&source, root.path(), &options).await.unwrap_err();
+        assert!(error.to_string().contains("Invalid hash"), "{error}");
+        assert!(!root.child("usr").exists());
+
+        let options = UpdateOptions {
+            force_compression: Some(Compression::Lz4),
+            force_hasher: Some(HashType::Blake3),
+            ..Default::default()
+        };
+        update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ignore_min_version() {
+        let ignoring = UpdateOptions {
+            ignore_min_version: true,
+            ..Default::default()
+        };
+
+        for (min_version, incompatible) in [("999.0", true), ("not.a.version", false)] {
+            let mut source = MemorySource::default();
+            let content = "needs a newer client, supposedly";
+            let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+            source.insert(&format!("chunks/{hash}"), content);
+            let manifest = format!(
+                "MinVersion: {min_version}\nHasher: blake3\n---\n{};0;{hash};bin/tool\n",
+                0o100644
+            );
+            source.publish_manifest(&manifest);
+
+            let root = temp_dir::TempDir::new().unwrap();
+            let error = update(&source, root.path(), &UpdateOptions::default())
+                .await
+                .unwrap_err();
+            match incompatible {
+                true => assert!(matches!(error, Error::Incompatible(_)), "{error:?}"),
+                false => assert!(matches!(error, Error::Parse(_)), "{error:?}"),
+            }
+            assert!(!root.child("usr").exists());
+
+            update(&source, root.path(), &ignoring)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+                content
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strict_headers() {
+        let mut source = MemorySource::default();
+        let content = "stored uncompressed";
+        let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+        source.insert(&format!("chunks/{hash}"), content);
+        let manifest = format!("Compresion: zstd\nHasher: blake3\n---\n33188;0;{hash};bin/tool\n");
+        source.publish_manifest(&manifest);
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let strict = UpdateOptions {
+            strict_headers: true,
+            ..Default::default()
+        };
+        let error = update(&source, root.path(), &strict).await.unwrap_err();
+        assert!(matches!(error, Error::Parse(_)), "{error:?}");
+        assert!(error.to_string().contains("Compresion"), "{error}");
+        assert!(!root.child("usr").exists());
+
+        // Leniently, the typo only warns and the missing header means uncompressed
+        update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+            content
+        );
+
+        let strict = HeaderPolicy {
+            strict: true,
+            ..Default::default()
+        };
+        let headers = HashMap::from([("Compression", "zsdt"), ("Hasher", "blake3")]);
+        assert!(read_headers_assuming(&headers, &strict).is_err());
+        let (compression, _) = read_headers(&headers).unwrap();
+        assert_eq!(compression, Compression::None);
+        let headers = HashMap::from([("Generated", "yesterday"), ("Hasher", "blake3")]);
+        assert!(read_headers_assuming(&headers, &strict).is_err());
+
+        // Of several bad headers, the first in sorted order is reported
+        let headers = HashMap::from([
+            ("Zzz", "1"),
+            ("Hasher", "blake3"),
+            ("Aaa", "1"),
+            ("Mmm", "1"),
+        ]);
+        for _ in 0..8 {
+            let error = read_headers_assuming(&headers, &strict).unwrap_err();
+            assert!(error.to_string().contains("Aaa"), "{error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_headerless_manifest() {
+        let mut source = MemorySource::default();
+        let content = "hashed with blake2s";
+        let mut hasher = crate::utils::Hasher::new(HashType::Blake2s).unwrap();
+        hasher.write(content.as_bytes());
+        let hash = hasher.digest();
+        source.insert(&format!("chunks/{hash}"), content);
+        let manifest = format!("---\n{};0;{hash};bin/tool\n", 0o100644);
+        source.publish_manifest(&manifest);
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let error = update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("no usable Hasher header"),
+            "{error}"
+        );
+        assert!(!root.child("usr").exists());
+
+        let options = UpdateOptions {
+            assume_compression: Some(Compression::None),
+            assume_hasher: Some(HashType::Blake2s),
+            ..Default::default()
+        };
+        update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+            content
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shared_content_with_different_modes() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let mut source = MemorySource::default();
+        let content = "same bytes, two modes";
+        let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+        source.insert(&format!("chunks/{hash}"), content);
+        let manifest = format!(
+            "Hasher: blake3\n---\n{};0;{hash};bin/tool\n{};0;{hash};share/tool.txt\n",
+            0o100755, 0o100644
+        );
+        source.publish_manifest(&manifest);
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let summary = update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.chunks_downloaded, 1);
+
+        let state = StatePaths::new(root.path(), None);
+        let stored: Vec<_> = fs::read_dir(&state.chunkstore)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(stored, [hash.as_str()]);
+
+        let mode = |path: &str| {
+            let metadata = fs::metadata(root.child("usr").join(path)).unwrap();
+            (metadata.permissions().mode() & 0o7777, metadata.ino())
+        };
+        let (tool_mode, tool_inode) = mode("bin/tool");
+        let (text_mode, text_inode) = mode("share/tool.txt");
+        assert_eq!(tool_mode, 0o555);
+        assert_eq!(text_mode, 0o444);
+        // The chunk took the first path's mode, so only the other needed a copy
+        let chunk_inode = fs::metadata(state.chunkstore.join(&hash)).unwrap().ino();
+        assert_eq!(tool_inode, chunk_inode);
+        assert_ne!(text_inode, chunk_inode);
+    }
+
+    #[tokio::test]
+    async fn test_verify_cache() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        let data = "shared data";
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "v1"), ("share/data", data)]);
+        update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let cached = state
+            .chunkstore
+            .join(blake3::hash(data.as_bytes()).to_hex().as_str());
+        remove_readonly_file(&cached).unwrap();
+        fs::write(&cached, "SHARED DATA").unwrap();
+        source.publish(&[("bin/tool", "v2"), ("share/data", data)]);
+
+        // Trusted as is, so the corrupt contents end up installed
+        let summary = update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.chunks_downloaded, 1);
+        assert_eq!(
+            fs::read_to_string(root.child("usr/share/data")).unwrap(),
+            "SHARED DATA"
+        );
+
+        source.publish(&[("bin/tool", "v3"), ("share/data", data)]);
+        let options = UpdateOptions {
+            verify_cache: true,
+            ..Default::default()
+        };
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.chunks_downloaded, 2);
+        assert_eq!(fs::read_to_string(&cached).unwrap(), data);
+        assert_eq!(
+            fs::read_to_string(root.child("usr/share/data")).unwrap(),
+            data
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abort() {
+        use crate::source::ChunkReader;
+        use std::io;
+
+        // Interrupted as soon as the first chunk is being downloaded
+        struct AbortingSource(MemorySource, Arc<AtomicBool>);
+
+        #[async_trait::async_trait]
+        impl RepoSource for AbortingSource {
+            async fn fetch_pointer(&self) -> Result<String, io::Error> {
+                self.0.fetch_pointer().await
+            }
+
+            async fn fetch_manifest(&self, hash: &str) -> Result<String, io::Error> {
+                self.0.fetch_manifest(hash).await
+            }
+
+            async fn fetch_chunk(&self, filename: &str) -> Result<ChunkReader, io::Error> {
+                self.1.store(true, Ordering::Relaxed);
+                self.0.fetch_chunk(filename).await
+            }
+
+            async fn fetch_dictionary(&self, hash: &str) -> Result<Vec<u8>, io::Error> {
+                self.0.fetch_dictionary(hash).await
+            }
+        }
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/a", "a"), ("bin/b", "b"), ("bin/c", "c")]);
+        let options = UpdateOptions::default();
+        let source = AbortingSource(source, options.abort.clone());
+
+        let error = update(&source, root.path(), &options).await.unwrap_err();
+        assert!(matches!(error, Error::Aborted), "{error:?}");
+        assert!(!state.staging.exists());
+        assert!(!root.child("usr").exists());
+        let leftovers = fs::read_dir(&state.chunkstore)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().ends_with(".new")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+
+        let summary = update(&source.0, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs::read_to_string(root.child("usr/bin/c")).unwrap(), "c");
+        assert!(summary.chunks_downloaded >= 2);
+    }
+
+    #[tokio::test]
+    async fn test_confirm() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/a", "a")]);
+        update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap();
+        let manifest_hash = source.publish(&[("bin/a", "changed"), ("bin/b", "b")]);
+
+        let asked = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let confirm_with = |answer: bool| {
+            let asked = asked.clone();
+            UpdateOptions {
+                confirm: Some(Arc::new(move |hash: &str, diff: &ManifestDiff| {
+                    let changed = (diff.added.clone(), diff.modified.clone());
+                    asked.lock().unwrap().push((hash.to_string(), changed));
+                    answer
+                })),
+                ..Default::default()
+            }
+        };
+
+        let error = update(&source, root.path(), &confirm_with(false))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Aborted), "{error:?}");
+        assert_eq!(fs::read_to_string(root.child("usr/bin/a")).unwrap(), "a");
+        let expected = (
+            manifest_hash,
+            (["bin/b".to_string()].into(), ["bin/a".to_string()].into()),
+        );
+        assert_eq!(*asked.lock().unwrap(), std::slice::from_ref(&expected));
+
+        update(&source, root.path(), &confirm_with(true))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs::read_to_string(root.child("usr/bin/b")).unwrap(), "b");
+        assert_eq!(*asked.lock().unwrap(), [expected.clone(), expected]);
+
+        // Nothing is asked when there's no update
+        update(&source, root.path(), &confirm_with(false))
+            .await
+            .unwrap();
+        assert_eq!(asked.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_custom_target() {
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(root.child("usr/bin")).unwrap();
+        fs::write(root.child("usr/bin/system"), "untouched").unwrap();
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "v1")]);
+        let options = UpdateOptions {
+            target: Some(PathBuf::from("opt/app")),
+            ..Default::default()
+        };
+
+        update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        source.publish(&[("bin/tool", "v2")]);
+        update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(root.child("opt/app/bin/tool")).unwrap(),
+            "v2"
+        );
+        assert_eq!(
+            fs::read_to_string(root.child("usr/bin/system")).unwrap(),
+            "untouched"
+        );
+        assert!(!root.child("usr/bin/tool").exists());
+
+        let state = StatePaths::new(root.path(), None);
+        crate::rollback::rollback(root.path(), &state, Some(Path::new("opt/app"))).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.child("opt/app/bin/tool")).unwrap(),
+            "v1"
+        );
+        assert!(root.child("usr/bin/system").exists());
+
+        let options = UpdateOptions {
+            target: Some(PathBuf::from("../outside")),
+            ..Default::default()
+        };
+        assert!(update(&source, root.path(), &options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_clean() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        let mut source = MemorySource::default();
+        let options = UpdateOptions {
+            keep_generations: 0,
+            clean: false,
+            ..Default::default()
+        };
+
+        for version in ["v1", "v2", "v3"] {
+            source.publish(&[("bin/tool", version)]);
+            let summary = update(&source, root.path(), &options)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(summary.chunks_freed, 0);
+        }
+        for version in ["v1", "v2", "v3"] {
+            let hash = blake3::hash(version.as_bytes()).to_hex().to_string();
+            assert!(state.chunkstore.join(hash).exists(), "{version}");
+        }
+
+        // Cleaning up later frees everything the current manifest doesn't need
+        let report = clean_old_chunks(&state.manifests, &state.chunkstore, 0, false).unwrap();
+        assert_eq!(report.removed.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_prefers_compressed_manifest() {
+        use crate::manifest::{compress_manifest, compressed_manifest_name};
+
+        let repo = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+        let content = "from the compressed manifest";
+        let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+        fs::write(repo.child("chunks").join(&hash), content).unwrap();
+
+        let manifest = format!("Hasher: blake3\n---\n{};0;{hash};share/doc\n", 0o100644);
+        let manifest_hash = blake3::hash(manifest.as_bytes()).to_hex().to_string();
+        fs::write(
+            repo.child(compressed_manifest_name(&manifest_hash)),
+            compress_manifest(&manifest).unwrap(),
+        )
+        .unwrap();
+        // Unreadable, so the update can only succeed through the compressed copy
+        fs::write(repo.child(&manifest_hash), "stale").unwrap();
+        fs::write(repo.child("manifest"), &manifest_hash).unwrap();
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let source = FileSource::new(repo.path());
+        update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.child("usr/share/doc")).unwrap(),
+            content
+        );
+
+        // Without the compressed copy the plain one is read, as it is when fetching the copy fails
+        // any other way
+        fs::remove_file(repo.child(compressed_manifest_name(&manifest_hash))).unwrap();
+        let options = UpdateOptions::default();
+        assert_eq!(
+            read_manifest(&source, &options, &manifest_hash)
+                .await
+                .unwrap(),
+            "stale"
+        );
+        fs::create_dir(repo.child(compressed_manifest_name(&manifest_hash))).unwrap();
+        assert_eq!(
+            read_manifest(&source, &options, &manifest_hash)
+                .await
+                .unwrap(),
+            "stale"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunk_layouts() {
+        for template in ["{hash}{ext}", "{hash:0:2}/{hash}{ext}"] {
+            let layout: ChunkLayout = template.parse().unwrap();
+            let input = temp_dir::TempDir::new().unwrap();
+            let repo = temp_dir::TempDir::new().unwrap();
+            let file = input.child("tool");
+            fs::write(&file, template).unwrap();
+
+            let hashes = crate::packager::write_chunks(
+                std::slice::from_ref(&file),
+                &repo.child("chunks"),
+                None,
+                &ChunkOptions {
+                    compression: Compression::Zstd,
+                    layout: layout.clone(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let hash = hashes[&file][0].hash.clone();
+            let expected = match template {
+                "{hash}{ext}" => format!("chunks/{hash}.zstd"),
+                _ => format!("chunks/{}/{hash}.zstd", &hash[..2]),
+            };
+            assert!(repo.child(&expected).exists(), "{expected}");
+
+            let manifest =
+                format!("Compression: zstd\nHasher: blake3\n---\n33188;0;{hash};bin/tool\n");
+            publish_manifest_to(repo.path(), &manifest);
+
+            let root = temp_dir::TempDir::new().unwrap();
+            let options = UpdateOptions {
+                chunk_layout: layout,
+                ..Default::default()
+            };
+            update(&FileSource::new(repo.path()), root.path(), &options)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+                template
+            );
+        }
+    }
+
+    /// FNV-1a alongside the length, standing in for an embedder's own algorithm.
+    struct Fnv(u64, u64);
+
+    impl crate::utils::ContentHasher for Fnv {
+        fn update(&mut self, data: &[u8]) {
+            for byte in data {
+                self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+            }
+            self.1 += data.len() as u64;
+        }
+
+        fn finalize(self: Box<Self>) -> String {
+            format!("{:016x}{:016x}", self.0, self.1)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_hasher() {
+        let hash_method =
+            crate::utils::register_hasher("fnv", || Box::new(Fnv(0xcbf29ce484222325, 0))).unwrap();
+        assert!(crate::utils::register_hasher("blake3", || Box::new(Fnv(0, 0))).is_err());
+        assert_eq!(HashType::from_header("FNV"), Some(hash_method));
+
+        let input = temp_dir::TempDir::new().unwrap();
+        let repo = temp_dir::TempDir::new().unwrap();
+        let file = input.child("tool");
+        fs::write(&file, "hashed my way").unwrap();
+
+        let files = vec![file.clone()];
+        let hashes = crate::packager::write_chunks(
+            &files,
+            &repo.child("chunks"),
+            None,
+            &ChunkOptions {
+                hash_method,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let manifest_options = crate::packager::ManifestOptions {
+            compression: Compression::None,
+            hash_method,
+            record_mtime: false,
+            clamp_mtime: None,
+            dictionary: None,
+            generated: None,
+            deltas: Vec::new(),
+            chunk_cids: false,
+        };
+        let manifest =
+            crate::packager::generate_manifest(input.path(), &files, &hashes, &manifest_options)
+                .await
+                .unwrap();
+        assert!(manifest.contains("Hasher: fnv\n"));
+        publish_manifest_to(repo.path(), &manifest);
+
+        let root = temp_dir::TempDir::new().unwrap();
+        update(
+            &FileSource::new(repo.path()),
+            root.path(),
+            &UpdateOptions::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.child("usr/tool")).unwrap(),
+            "hashed my way"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delta_update() {
+        let input = temp_dir::TempDir::new().unwrap();
+        let repo = temp_dir::TempDir::new().unwrap();
+        let file = input.child("lib/big.so");
+        fs::create_dir_all(input.child("lib")).unwrap();
+        let files = vec![file.clone()];
+
+        // Incompressible, so only a delta against the previous version can be small
+        let mut state = 0x9e3779b97f4a7c15_u64;
+        let mut content: Vec<u8> = (0..512 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+
+        let package = |base: Option<String>| {
+            let files = files.clone();
+            let input = input.path().to_path_buf();
+            let repo = repo.path().to_path_buf();
+            async move {
+                let layout = ChunkLayout::default();
+                let hashes = crate::packager::write_chunks(
+                    &files,
+                    &repo.join("chunks"),
+                    None,
+                    &ChunkOptions {
+                        compression: Compression::Zstd,
+                        layout: layout.clone(),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+                let deltas = match base {
+                    Some(base) => crate::packager::write_deltas(
+                        &input,
+                        &repo,
+                        &base,
+                        &hashes,
+                        &ChunkOptions {
+                            compression: Compression::Zstd,
+                            layout: layout.clone(),
+                            ..Default::default()
+                        },
+                    )
+                    .await
+                    .unwrap(),
+                    None => Vec::new(),
+                };
+                let options = crate::packager::ManifestOptions {
+                    compression: Compression::Zstd,
+                    hash_method: HashType::Blake3,
+                    record_mtime: false,
+                    clamp_mtime: None,
+                    dictionary: None,
+                    generated: None,
+                    deltas,
+                    chunk_cids: false,
+                };
+                let manifest =
+                    crate::packager::generate_manifest(&input, &files, &hashes, &options)
+                        .await
+                        .unwrap();
+                publish_manifest_to(&repo, &manifest);
+                manifest
+            }
+        };
+
+        fs::write(&file, &content).unwrap();
+        let first = package(None).await;
+        let root = temp_dir::TempDir::new().unwrap();
+        let source = FileSource::new(repo.path());
+        update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        content[300_000..300_012].copy_from_slice(b"small change");
+        fs::write(&file, &content).unwrap();
+        let second = package(Some(first)).await;
+        assert!(second.contains("Deltas: "));
+
+        let summary = update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.chunks_patched, 1);
+        assert_eq!(summary.chunks_downloaded, 0);
+        assert!(
+            summary.delta_bytes < content.len() as u64 / 100,
+            "{} bytes",
+            summary.delta_bytes
+        );
+        assert_eq!(fs::read(root.child("usr/lib/big.so")).unwrap(), content);
+
+        // Without the previous version to patch, the chunk is downloaded whole
+        let fresh = temp_dir::TempDir::new().unwrap();
+        let summary = update(&source, fresh.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.chunks_patched, 0);
+        assert_eq!(summary.chunks_downloaded, 1);
+        assert_eq!(fs::read(fresh.child("usr/lib/big.so")).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_update_uses_additional_cache() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let cache = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+
+        let content = "cached content";
+        let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+        fs::create_dir_all(cache.child("chunks")).unwrap();
+        fs::write(cache.child("chunks").join(&hash), content).unwrap();
+
+        let manifest = format!("Hasher: blake3\n---\n{};0;{hash};share/cached\n", 0o100644);
+        publish_manifest_to(repo.path(), &manifest);
+
+        let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+        let source = HttpSource::new(reqwest::Client::new(), &server.url);
+        let options = UpdateOptions {
+            additional_cache_path: Some(cache.path().to_path_buf()),
+            ..Default::default()
+        };
+        assert!(
+            update(&source, root.path(), &options)
+                .await
+                .unwrap()
+                .is_some()
+        );
+
+        assert!(
+            !server
+                .requested_paths()
+                .iter()
+                .any(|path| path.starts_with("/chunks/"))
+        );
+        assert_eq!(
+            fs::read_to_string(root.child("usr/share/cached")).unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_update() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let cache = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(cache.child("chunks")).unwrap();
+
+        let mut manifest = "Hasher: blake3\n---\n".to_string();
+        let mut hashes = Vec::new();
+        for name in ["first", "second"] {
+            let hash = blake3::hash(name.as_bytes()).to_hex().to_string();
+            fs::write(cache.child("chunks").join(&hash), name).unwrap();
+            manifest += &format!("{};0;{hash};share/{name}\n", 0o100644);
+            hashes.push(hash);
+        }
+        let manifest_file = repo.child("manifest-file");
+        fs::write(&manifest_file, &manifest).unwrap();
+
+        // The repo has nothing in it, so every chunk has to come from the cache
+        let source = FileSource::new(repo.path());
+        let options = UpdateOptions {
+            additional_cache_path: Some(cache.path().to_path_buf()),
+            offline: true,
+            manifest_file: Some(manifest_file),
+            ..Default::default()
+        };
+
+        fs::remove_file(cache.child("chunks").join(&hashes[1])).unwrap();
+        let error = update(&source, root.path(), &options).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("1 chunks aren't available offline: {}", hashes[1])
+        );
+
+        fs::write(cache.child("chunks").join(&hashes[1]), "second").unwrap();
+        assert!(
+            update(&source, root.path(), &options)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(
+            fs::read_to_string(root.child("usr/share/second")).unwrap(),
+            "second"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_dictionary() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let cache = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        let dictionary = Dictionary::new(b"shared phrases for every chunk".repeat(8));
+        dictionary.write(repo.path()).unwrap();
+
+        // Chunks compressed with a dictionary are kept under its hash
+        let chunk_path = |name: &str| {
+            let hash = blake3::hash(name.as_bytes()).to_hex().to_string();
+            let layout = ChunkLayout::default();
+            repo_chunk_path(&layout, &hash, &Compression::Zstd, Some(&dictionary))
+        };
+        let mut manifest = format!(
+            "Compression: zstd\nDictionary: {}\nHasher: blake3\n---\n",
+            dictionary.hash
+        );
+        for (name, store) in [("first", repo.path()), ("second", cache.path())] {
+            let hash = blake3::hash(name.as_bytes()).to_hex().to_string();
+            let compressed = zstd::bulk::Compressor::with_dictionary(0, &dictionary.data)
+                .unwrap()
+                .compress(name.as_bytes())
+                .unwrap();
+            let path = store.join("chunks").join(chunk_path(name));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, compressed).unwrap();
+            manifest += &format!("{};0;{hash};share/{name}\n", 0o100644);
+            // The first manifest only has the first file
+            if name == "first" {
+                let manifest_file = repo.child("first");
+                fs::write(&manifest_file, &manifest).unwrap();
+                let options = UpdateOptions {
+                    manifest_file: Some(manifest_file),
+                    ..Default::default()
+                };
+                update(&FileSource::new(repo.path()), root.path(), &options)
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+        }
+        let manifest_file = repo.child("second");
+        fs::write(&manifest_file, &manifest).unwrap();
+
+        // Kept from the online update, so the repo isn't needed for it
+        fs::remove_dir_all(repo.child(DICTIONARY_DIR)).unwrap();
+        let offline = UpdateOptions {
+            additional_cache_path: Some(cache.path().to_path_buf()),
+            offline: true,
+            manifest_file: Some(manifest_file.clone()),
+            ..Default::default()
+        };
+        update(&FileSource::new(repo.path()), root.path(), &offline)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.child("usr/share/second")).unwrap(),
+            "second"
+        );
+
+        // Or read from the additional cache
+        let fresh = temp_dir::TempDir::new().unwrap();
+        fs::copy(
+            repo.child("chunks").join(chunk_path("first")),
+            cache.child("chunks").join(chunk_path("first")),
+        )
+        .unwrap();
+        let error = update(&FileSource::new(repo.path()), fresh.path(), &offline)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("isn't available offline"),
+            "{error}"
+        );
+        dictionary.write(cache.path()).unwrap();
+        update(&FileSource::new(repo.path()), fresh.path(), &offline)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Not needed at all once every chunk is in the chunkstore
+        let state = StatePaths::new(root.path(), None);
+        fs::remove_dir_all(&state.dictionaries).unwrap();
+        let forced = UpdateOptions {
+            force: true,
+            ..offline
+        };
+        update(&FileSource::new(repo.path()), root.path(), &forced)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_given_manifest_only_fetches_chunks() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+
+        let mut manifest = "Hasher: blake3\n---\n".to_string();
+        let mut expected = Vec::new();
+        for name in ["first", "second"] {
+            let hash = blake3::hash(name.as_bytes()).to_hex().to_string();
+            fs::write(repo.child("chunks").join(&hash), name).unwrap();
+            manifest += &format!("{};0;{hash};share/{name}\n", 0o100644);
+            expected.push(format!("/chunks/{hash}"));
+        }
+        // The repo doesn't point at it, or even hold it
+        fs::write(repo.child("manifest"), "0000").unwrap();
+
+        let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+        let source = HttpSource::new(reqwest::Client::new(), &server.url);
+        let options = UpdateOptions {
+            manifest: Some(manifest.clone()),
+            ..Default::default()
+        };
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            summary.manifest_hash,
+            blake3::hash(manifest.as_bytes()).to_hex().as_str()
+        );
+        assert_eq!(
+            fs::read_to_string(root.child("usr/share/second")).unwrap(),
+            "second"
+        );
+        let mut requested = server.requested_paths();
+        requested.sort();
+        assert_eq!(requested, expected);
+    }
+
+    #[tokio::test]
+    async fn test_aborts_before_downloading_without_space() {
+        let need = enough_space(Path::new("chunkstore"), 20 * 1024, Some(10 * 1024 * 1024));
+        assert!(need.unwrap_err().contains("need 20MB, have 10MB"));
+        assert!(enough_space(Path::new("chunkstore"), 20 * 1024, None).is_ok());
+
+        // A split file is copied into staging, rather than linked
+        let (_, chunklist) = parse_manifest(
+            "FormatVersion: 2\n---\n33188;1;aaa;single\n33188;4;bbb;split\n33188;2;ccc;split\n",
+        )
+        .unwrap();
+        assert_eq!(staging_kb(&chunklist), 1 + 4 + 2 + 1);
+
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+        fs::write(repo.child("chunks").join("aaa"), "small").unwrap();
+        // Claims a petabyte, far more than any test machine has free
+        let manifest = format!("Hasher: blake3\n---\n33188;{};aaa;share/huge\n", 1u64 << 40);
+        publish_manifest_to(repo.path(), &manifest);
+
+        let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+        let source = HttpSource::new(reqwest::Client::new(), &server.url);
+        let error = update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.starts_with("not enough space in "), "{error}");
+        assert!(
+            !server
+                .requested_paths()
+                .iter()
+                .any(|path| path.starts_with("/chunks/"))
+        );
+        let state = StatePaths::new(root.path(), None);
+        assert!(!state.transaction.exists());
+        assert!(!state.pending_manifest.exists());
+    }
+
+    #[tokio::test]
+    async fn test_unmodified_pointer_skips_update() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+        let publish = |content: &str| {
+            let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+            fs::write(repo.child("chunks").join(&hash), content).unwrap();
+            let manifest = format!("Hasher: blake3\n---\n{};0;{hash};bin/tool\n", 0o100644);
+            publish_manifest_to(repo.path(), &manifest);
+        };
+
+        let server = TestServer::serve_dir(repo.path().to_path_buf()).await;
+        let source = HttpSource::new(reqwest::Client::new(), &server.url);
+        let options = UpdateOptions::default();
+        let state = StatePaths::new(root.path(), None);
+
+        publish("v1");
+        update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(read_pointer_etag(&state.manifests).is_some());
+
+        // Even with the record of the latest hash gone, a 304 is enough to skip
+        fs::remove_file(state.manifests.join("latest_hash")).unwrap();
+        let before = server.requested_paths().len();
+        assert!(
+            update(&source, root.path(), &options)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(server.requested_paths()[before..], ["/manifest"]);
+        assert_eq!(server.not_modified_paths(), ["/manifest"]);
+
+        publish("v2");
+        update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+            "v2"
+        );
+
+        // Installing a pinned manifest forgets the ETag, so the pointer is read in full next time
+        let pinned = UpdateOptions {
+            manifest: Some("Hasher: blake3\n---\n".into()),
+            ..Default::default()
+        };
+        update(&source, root.path(), &pinned).await.unwrap();
+        assert_eq!(read_pointer_etag(&state.manifests), None);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_manifest_hash() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+
+        let mut manifest_hashes = Vec::new();
+        for release in ["1.0", "2.0"] {
+            let hash = blake3::hash(release.as_bytes()).to_hex().to_string();
+            fs::write(repo.child("chunks").join(&hash), release).unwrap();
+
+            let manifest = format!("Hasher: blake3\n---\n{};0;{hash};share/release\n", 0o100644);
+            let manifest_hash = publish_manifest_to(repo.path(), &manifest);
+            manifest_hashes.push(manifest_hash);
+        }
+
+        let source = FileSource::new(repo.path());
+        let options = UpdateOptions {
+            manifest_hash: Some(manifest_hashes[0].clone()),
+            ..Default::default()
+        };
+        assert!(
+            update(&source, root.path(), &options)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(
+            fs::read_to_string(root.child("usr/share/release")).unwrap(),
+            "1.0"
+        );
+
+        // A manifest that doesn't match the pinned hash is refused
+        fs::write(repo.child(&manifest_hashes[0]), "---\n").unwrap();
+        let fresh_root = temp_dir::TempDir::new().unwrap();
+        assert!(update(&source, fresh_root.path(), &options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pre_swap_hook() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+
+        let hash = blake3::hash(b"hooked").to_hex().to_string();
+        fs::write(repo.child("chunks").join(&hash), "hooked").unwrap();
+        let manifest = format!("Hasher: blake3\n---\n{};0;{hash};share/hooked\n", 0o100644);
+        let manifest_hash = publish_manifest_to(repo.path(), &manifest);
+        let source = FileSource::new(repo.path());
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let options = UpdateOptions {
+            pre_swap_hook: Some("exit 1".into()),
+            ..Default::default()
+        };
+        assert!(update(&source, root.path(), &options).await.is_err());
+        assert!(!root.child("usr/share/hooked").exists());
+
+        let root = temp_dir::TempDir::new().unwrap();
+        let options = UpdateOptions {
+            pre_swap_hook: Some(format!(
+                "test \"$PKGSMGR_NEW_MANIFEST\" = {manifest_hash} && test -z \"$PKGSMGR_OLD_MANIFEST\""
+            )),
+            post_swap_hook: Some("touch \"$PKGSMGR_ROOT/post-swap\"".into()),
+            ..Default::default()
+        };
+        assert!(
+            update(&source, root.path(), &options)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(root.child("usr/share/hooked").exists());
+        assert!(root.child("post-swap").exists());
+
+        // Too late to refuse, so a failing post-swap hook still reports the update
+        let root = temp_dir::TempDir::new().unwrap();
+        let options = UpdateOptions {
+            post_swap_hook: Some("exit 1".into()),
+            ..Default::default()
+        };
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.manifest_hash, manifest_hash);
+        assert!(root.child("usr/share/hooked").exists());
+    }
+
+    #[tokio::test]
+    async fn test_update_with_state_dir() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+
+        let hash = blake3::hash(b"relocated").to_hex().to_string();
+        fs::write(repo.child("chunks").join(&hash), "relocated").unwrap();
+        let manifest = format!(
+            "Hasher: blake3\n---\n{};0;{hash};share/relocated\n",
+            0o100644
+        );
+        publish_manifest_to(repo.path(), &manifest);
+
+        let options = UpdateOptions {
+            state_dir: Some("var/lib/pkgsmgr".into()),
+            ..Default::default()
+        };
+        let source = FileSource::new(repo.path());
+        assert!(
+            update(&source, root.path(), &options)
+                .await
+                .unwrap()
+                .is_some()
+        );
+
+        let state = StatePaths::new(root.path(), options.state_dir.as_deref());
+        assert_eq!(state.state, root.child("var/lib/pkgsmgr"));
+        assert!(state.manifests.join("current").exists());
+        assert!(!root.child(".pkgsmgr").exists());
+        assert!(root.child("usr/share/relocated").exists());
+    }
+
+    #[tokio::test]
+    async fn test_update_summary() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+
+        let publish = |files: &[(&str, &str)]| {
+            let mut manifest = String::from("Hasher: blake3\n---\n");
+            for (path, content) in files {
+                let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+                fs::write(repo.child("chunks").join(&hash), content).unwrap();
+                manifest += &format!("{};0;{hash};{path}\n", 0o100644);
+            }
+            publish_manifest_to(repo.path(), &manifest)
+        };
+
+        let tree_hash_of = |manifest_hash: &str| {
+            let manifest = fs::read_to_string(repo.child(manifest_hash)).unwrap();
+            tree_hash(&parse_manifest(&manifest).unwrap().1)
+        };
+        let source = FileSource::new(repo.path());
+        let options = UpdateOptions {
+            keep_generations: 0,
+            ..Default::default()
+        };
+
+        let first_hash = publish(&[("share/a", "unchanged"), ("share/b", "first")]);
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            summary,
+            UpdateSummary {
+                tree_hash: tree_hash_of(&first_hash),
+                manifest_hash: first_hash.clone(),
+                previous_manifest_hash: None,
+                chunks_downloaded: 2,
+                bytes_downloaded: 14,
+                chunks_patched: 0,
+                delta_bytes: 0,
+                chunks_freed: 0,
+                bytes_freed: 0,
+                files_changed: 2,
+                changes: ManifestDiff {
+                    added: ["share/a".to_string(), "share/b".to_string()].into(),
+                    ..Default::default()
+                },
+            }
+        );
+
+        let second_hash = publish(&[
+            ("share/a", "unchanged"),
+            ("share/b", "second"),
+            ("share/c", "added"),
+        ]);
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            summary,
+            UpdateSummary {
+                tree_hash: tree_hash_of(&second_hash),
+                manifest_hash: second_hash,
+                previous_manifest_hash: Some(first_hash),
+                chunks_downloaded: 2,
+                bytes_downloaded: 11,
+                chunks_patched: 0,
+                delta_bytes: 0,
+                chunks_freed: 1,
+                bytes_freed: 5,
+                files_changed: 2,
+                changes: ManifestDiff {
+                    added: ["share/c".to_string()].into(),
+                    modified: ["share/b".to_string()].into(),
+                    ..Default::default()
+                },
+            }
+        );
+        let state = StatePaths::new(root.path(), None);
+        assert_eq!(
+            fs::read_to_string(state.tree_hash).unwrap(),
+            summary.tree_hash
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_files() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let manifest = "Hasher: blake3\n---\n33261;2;bbb;usr/bin/tool\n33188;0;aaa;etc/config\n";
+        publish_manifest_to(repo.path(), manifest);
+
+        let source = FileSource::new(repo.path());
+        let files = list_files(&source, &UpdateOptions::default())
+            .await
+            .unwrap();
+
+        let (_, mut expected) = parse_manifest(manifest).unwrap();
+        expected.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(files, expected);
+        assert_eq!(files[0].path, "etc/config");
+        assert_eq!(files[1].permissions, 0o100755);
+
+        // A manifest file is listed without touching the repo
+        let manifest_file = repo.child("local");
+        fs::write(&manifest_file, manifest).unwrap();
+        let options = UpdateOptions {
+            manifest_file: Some(manifest_file),
+            ..Default::default()
+        };
+        let empty = temp_dir::TempDir::new().unwrap();
+        let files = list_files(&FileSource::new(empty.path()), &options)
+            .await
+            .unwrap();
+        assert_eq!(files, expected);
+    }
+
+    #[test]
+    fn test_check_tree_hash() {
+        let (headers, chunklist) = parse_manifest("---\n420;1;aaaa;file\n").unwrap();
+        assert_eq!(
+            check_tree_hash(&headers, &chunklist),
+            Ok(tree_hash(&chunklist))
+        );
+
+        let tampered = format!(
+            "TreeHash: {}\n---\n420;1;bbbb;file\n",
+            tree_hash(&chunklist)
+        );
+        let (headers, chunklist) = parse_manifest(&tampered).unwrap();
+        assert!(check_tree_hash(&headers, &chunklist).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_manifest_age() {
+        let generated = "2023-11-14T22:13:20Z";
+        let headers = HashMap::from([("Generated", generated)]);
+        let at = parse_generated(generated).unwrap();
+        assert!(check_manifest_age(&headers, 3600, at + 3600).is_ok());
+        let err = check_manifest_age(&headers, 3600, at + 3601).unwrap_err();
+        assert!(err.contains("3601 seconds ago"), "{err}");
+        assert!(check_manifest_age(&HashMap::new(), 3600, at).is_err());
+
+        // Stale manifests are refused before anything is downloaded
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        let manifest =
+            format!("Generated: {generated}\nHasher: blake3\n---\n33188;0;aaa;etc/config\n");
+        publish_manifest_to(repo.path(), &manifest);
+
+        let options = UpdateOptions {
+            max_manifest_age: Some(3600),
+            ..Default::default()
+        };
+        let err = update(&FileSource::new(repo.path()), root.path(), &options)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("generated"), "{err}");
+        assert!(!root.child("usr").exists());
+    }
+
+    #[tokio::test]
+    async fn test_shared_chunk_cache() {
+        let shared = temp_dir::TempDir::new().unwrap();
+        let first = temp_dir::TempDir::new().unwrap();
+        let second = temp_dir::TempDir::new().unwrap();
+        let options = UpdateOptions {
+            shared_chunk_cache: Some(shared.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "shared between roots")]);
+
+        let summary = update(&source, first.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.chunks_downloaded, 1);
+        let summary = update(&source, second.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.chunks_downloaded, 0);
+
+        for root in [&first, &second] {
+            let installed = fs::read_to_string(root.child("usr/bin/tool")).unwrap();
+            assert_eq!(installed, "shared between roots");
+        }
+
+        // Cleaning one root's chunkstore leaves the shared copy for the other
+        source.publish(&[("bin/tool", "v2")]);
+        let options = UpdateOptions {
+            keep_generations: 0,
+            ..options
+        };
+        update(&source, first.path(), &options).await.unwrap();
+        assert_eq!(fs::read_dir(shared.path()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted_download() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+
+        let mut manifest = String::from("Hasher: blake3\n---\n");
+        let mut hashes = Vec::new();
+        for (name, content) in [("first", "first"), ("second", "second")] {
+            let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+            fs::write(repo.child("chunks").join(&hash), content).unwrap();
+            manifest += &format!("{};0;{hash};share/{name}\n", 0o100644);
+            hashes.push(hash);
+        }
+        publish_manifest_to(repo.path(), &manifest);
+
+        let source = FileSource::new(repo.path());
+        let options = UpdateOptions::default();
+
+        // The repo loses a chunk partway through, so the download phase fails
+        fs::remove_file(repo.child("chunks").join(&hashes[1])).unwrap();
+        assert!(update(&source, root.path(), &options).await.is_err());
+        assert_eq!(
+            Transaction::load(&state.transaction)
+                .unwrap()
+                .unwrap()
+                .phase,
+            Phase::Downloading
+        );
+        assert!(!state.manifests.join("current").exists());
+        assert!(!state.manifests.join("latest_hash").exists());
+
+        // Only the missing chunk is fetched on the next run
+        fs::write(repo.child("chunks").join(&hashes[1]), "second").unwrap();
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.chunks_downloaded, 1);
+        assert_eq!(
+            fs::read_to_string(state.manifests.join("current")).unwrap(),
+            manifest
+        );
+        assert_eq!(Transaction::load(&state.transaction).unwrap(), None);
+        assert!(!state.pending_manifest.exists());
+    }
+
+    #[tokio::test]
+    async fn test_resume_interrupted_swap() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        let read = |path: &str| fs::read_to_string(root.child("usr").join(path)).unwrap();
+
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "v1")]);
+        let first = update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let first_manifest = fs::read_to_string(state.manifests.join("current")).unwrap();
+
+        // Interrupted before the swap, the manifest state stays on the installed tree
+        let second_hash = source.publish(&[("bin/tool", "v2")]);
+        let refusing = UpdateOptions {
+            pre_swap_hook: Some("exit 1".into()),
+            ..Default::default()
+        };
+        assert!(update(&source, root.path(), &refusing).await.is_err());
+        assert_eq!(read("bin/tool"), "v1");
+        assert_eq!(
+            fs::read_to_string(state.manifests.join("current")).unwrap(),
+            first_manifest
+        );
+        assert_eq!(
+            Transaction::load(&state.transaction).unwrap().unwrap(),
+            Transaction {
+                phase: Phase::Built,
+                manifest_hash: second_hash.clone(),
+            }
+        );
+
+        // A staging tree left by a crash is swapped in without fetching or building anything
+        let second_manifest = fs::read_to_string(&state.pending_manifest).unwrap();
+        let (_, chunklist) = parse_manifest(&second_manifest).unwrap();
+        build_tree(&state.staging, &state.chunkstore, &chunklist).unwrap();
+        fs::remove_dir_all(&state.chunkstore).unwrap();
+        fs::create_dir_all(&state.chunkstore).unwrap();
+        let offline = UpdateOptions {
+            offline: true,
+            ..Default::default()
+        };
+        let second = update(&source, root.path(), &offline)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.manifest_hash, second_hash);
+        assert_eq!(read("bin/tool"), "v2");
+
+        // Interrupted after the swap, the next run only records the manifest
+        fs::write(state.manifests.join("current"), &first_manifest).unwrap();
+        fs::remove_file(state.manifests.join("old")).unwrap();
+        fs::write(state.manifests.join("latest_hash"), &first.manifest_hash).unwrap();
+        fs::write(&state.pending_manifest, &second_manifest).unwrap();
+        Transaction {
+            phase: Phase::Swapped,
+            manifest_hash: second_hash.clone(),
+        }
+        .save(&state.transaction)
+        .unwrap();
+
+        assert_eq!(update(&source, root.path(), &offline).await.unwrap(), None);
+        assert_eq!(
+            fs::read_to_string(state.manifests.join("current")).unwrap(),
+            second_manifest
+        );
+        assert_eq!(
+            fs::read_to_string(state.manifests.join("old")).unwrap(),
+            first_manifest
+        );
+        assert_eq!(Transaction::load(&state.transaction).unwrap(), None);
+        assert_eq!(read("bin/tool"), "v2");
+    }
+
+    #[tokio::test]
+    async fn test_repairs_manifest_recorded_before_swap() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        let options = UpdateOptions::default();
+
+        let mut source = MemorySource::default();
+        source.publish(&[("bin/tool", "v1")]);
+        update(&source, root.path(), &options).await.unwrap();
+
+        // An older client recorded the new manifest, then crashed before swapping
+        let second_hash = source.publish(&[("bin/tool", "v2"), ("bin/added", "new in v2")]);
+        let second_manifest = source.fetch_manifest(&second_hash).await.unwrap();
+        update_manifest(&second_manifest, &state.manifests).unwrap();
+        record_manifest_hash(&state.manifests, &second_hash).unwrap();
+        assert!(!root.child("usr/bin/added").exists());
+
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.manifest_hash, second_hash);
+        assert_eq!(
+            fs::read_to_string(root.child("usr/bin/added")).unwrap(),
+            "new in v2"
+        );
+        assert_eq!(
+            fs::read_to_string(state.manifests.join("current")).unwrap(),
+            second_manifest
+        );
+
+        assert_eq!(update(&source, root.path(), &options).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_force_reinstalls_current_manifest() {
+        let root = temp_dir::TempDir::new().unwrap();
+        let state = StatePaths::new(root.path(), None);
+        let mut source = MemorySource::default();
+        let manifest_hash = source.publish(&[("bin/tool", "v1"), ("share/data", "data")]);
+        update(&source, root.path(), &UpdateOptions::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let deleted = state
+            .chunkstore
+            .join(blake3::hash(b"data").to_hex().as_str());
+        remove_readonly_file(&deleted).unwrap();
+        fs::write(root.child("usr/stray"), "not in the manifest").unwrap();
+
+        // Paths, sizes and modes still match, so a normal run has nothing to do
+        assert_eq!(
+            update(&source, root.path(), &UpdateOptions::default())
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(!deleted.exists());
+
+        let options = UpdateOptions {
+            force: true,
+            ..Default::default()
+        };
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.manifest_hash, manifest_hash);
+        assert_eq!(summary.chunks_downloaded, 1);
+        assert_eq!(fs::read_to_string(&deleted).unwrap(), "data");
+        // Swapped in afresh, so anything left in the old tree is gone
+        assert!(!root.child("usr/stray").exists());
+        assert_eq!(
+            fs::read_to_string(root.child("usr/bin/tool")).unwrap(),
+            "v1"
+        );
+        assert_eq!(generation_paths(&state.manifests).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_gzipped_manifest() {
+        let repo = temp_dir::TempDir::new().unwrap();
+        let root = temp_dir::TempDir::new().unwrap();
+        fs::create_dir_all(repo.child("chunks")).unwrap();
+        let mut manifest = String::from("Hasher: blake3\n---\n");
+        for i in 0..100 {
+            let content = format!("file {i}");
+            let hash = blake3::hash(content.as_bytes()).to_hex().to_string();
+            fs::write(repo.child(format!("chunks/{hash}")), &content).unwrap();
+            manifest += &format!("420;0;{hash};share/file-{i}\n");
+        }
+        let manifest_hash = publish_manifest_to(repo.path(), &manifest);
+        let server = TestServer::serve_dir_gzipped(repo.path().to_path_buf()).await;
+
+        let raw = reqwest::Client::builder().no_gzip().build().unwrap();
+        let res = raw
+            .get(format!("{}/{manifest_hash}", server.url))
+            .header(reqwest::header::ACCEPT_ENCODING, "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[reqwest::header::CONTENT_ENCODING], "gzip");
+        assert!(res.bytes().await.unwrap().len() < manifest.len());
+
+        // Hashed once decoded, so the pinned hash and the recorded one are the plain manifest's
+        let client = crate::utils::build_client(&Default::default()).unwrap();
+        let source = HttpSource::new(client, &server.url);
+        let options = UpdateOptions {
+            manifest_hash: Some(manifest_hash.clone()),
+            ..Default::default()
+        };
+        let summary = update(&source, root.path(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.manifest_hash, manifest_hash);
+        assert_eq!(
+            fs::read_to_string(root.child("usr/share/file-42")).unwrap(),
+            "file 42"
+        );
+        assert_eq!(
+            fs::read_to_string(root.child(".pkgsmgr/manifests/current")).unwrap(),
+            manifest
+        );
+    }
+}
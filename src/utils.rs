@@ -1,41 +1,421 @@
-use std::io::Write;
+use blake2::Digest;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::debug;
 use xxhash_rust::xxh3;
 
-pub async fn get(url: &str) -> Result<reqwest::Response, reqwest::Error> {
-    let req = reqwest::get(url).await?;
+use crate::error::Error;
+use crate::types::HashType;
+
+/// Settings for the HTTP client shared by every request in a run.
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct ClientOptions {
+    /// PEM encoded CA certificate to trust in addition to the system roots
+    #[arg(long)]
+    pub ca_cert: Option<PathBuf>,
+    /// PEM encoded client certificate for mutual TLS
+    #[arg(long, requires = "client_key")]
+    pub client_cert: Option<PathBuf>,
+    /// PEM encoded PKCS#8 private key for the client certificate
+    #[arg(long, requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+    /// Seconds to wait for a connection to the repo [default: 30]
+    #[arg(long)]
+    pub connect_timeout: Option<u64>,
+    /// Seconds a request may go without receiving anything before it's aborted [default: 60]
+    #[arg(long)]
+    pub request_timeout: Option<u64>,
+    /// Extra `Name: value` header sent with every request. Repeatable, and may replace the
+    /// default User-Agent
+    #[arg(long = "header")]
+    pub headers: Vec<String>,
+    /// Don't ask servers to compress responses in transit. Needed for servers that mark already
+    /// compressed chunks as `Content-Encoding: zstd`, `br` or `gzip`, which would otherwise be
+    /// decoded twice
+    #[arg(long)]
+    pub no_transfer_compression: bool,
+}
+
+/// Bytes read at a time when hashing, compressing, or downloading chunks. Reading 64kb rather
+/// than 8kb at a time hashes large files over twice as fast, while larger buffers gain little.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+
+pub fn build_client(
+    options: &ClientOptions,
+) -> Result<reqwest::Client, Box<dyn std::error::Error>> {
+    // A read timeout rather than a total one, so large chunks on slow links aren't cut off
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(
+            options.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        ))
+        .read_timeout(Duration::from_secs(
+            options.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+        ))
+        .user_agent(concat!("pkgsmgr/", env!("CARGO_PKG_VERSION")))
+        .default_headers(parse_headers(&options.headers)?);
+
+    // Otherwise responses are requested with `Accept-Encoding: gzip, br, zstd` and decoded as
+    // they arrive, so uncompressed repos and large manifests still save bandwidth. Chunks and
+    // manifests are hashed once decoded
+    if options.no_transfer_compression {
+        builder = builder.no_gzip().no_brotli().no_zstd();
+    }
+
+    if let Some(ca_cert) = &options.ca_cert {
+        let cert = reqwest::Certificate::from_pem(&fs::read(ca_cert)?)?;
+        builder = builder.add_root_certificate(cert);
+    }
+
+    match (&options.client_cert, &options.client_key) {
+        (Some(cert), Some(key)) => {
+            let identity = reqwest::Identity::from_pkcs8_pem(&fs::read(cert)?, &fs::read(key)?)?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => (),
+        _ => return Err("a client certificate and key must be given together".into()),
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Parses `Name: value` headers given on the command line.
+fn parse_headers(headers: &[String]) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+
+    for header in headers {
+        let parsed = header.split_once(':').and_then(|(name, value)| {
+            Some((
+                HeaderName::try_from(name.trim()).ok()?,
+                HeaderValue::try_from(value.trim()).ok()?,
+            ))
+        });
+        let Some((name, value)) = parsed else {
+            return Err(format!("invalid header {header:?}, expected `Name: value`"));
+        };
+        map.append(name, value);
+    }
+
+    Ok(map)
+}
+
+pub async fn get(client: &reqwest::Client, url: &str) -> Result<reqwest::Response, reqwest::Error> {
+    debug!(url, "Fetching {url}");
+    let req = client.get(url).send().await?;
     let req = req.error_for_status()?;
 
     Ok(req)
 }
 
-pub enum Hasher {
-    Blake3(blake3::Hasher),
-    Xxh3_128(xxh3::Xxh3Default),
+/// Paces reads to a fixed number of bytes per second.
+/// A single limiter is shared between downloads, so the cap applies to all of them together.
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    next_free: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            next_free: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Accounts for `bytes` that were just read, sleeping until they fit within the rate.
+    pub async fn consume(&self, bytes: usize) {
+        let deadline = {
+            let mut next_free = self.next_free.lock().await;
+            let start = (*next_free).max(Instant::now());
+            *next_free =
+                start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+            *next_free
+        };
+
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// A hashing algorithm chunks can be checked with. Implement it and `register_hasher` to
+/// package and install with an algorithm this crate doesn't ship.
+pub trait ContentHasher: Send {
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the digest, as it's written in manifests.
+    fn finalize(self: Box<Self>) -> String;
+}
+
+impl ContentHasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> String {
+        blake3::Hasher::finalize(&self).to_hex().to_string()
+    }
+}
+
+impl ContentHasher for xxh3::Xxh3Default {
+    fn update(&mut self, data: &[u8]) {
+        xxh3::Xxh3Default::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> String {
+        hex::encode(self.digest128().to_le_bytes())
+    }
+}
+
+impl ContentHasher for blake2::Blake2b512 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> String {
+        hex::encode(Digest::finalize(*self))
+    }
+}
+
+impl ContentHasher for blake2::Blake2s256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> String {
+        hex::encode(Digest::finalize(*self))
+    }
+}
+
+/// Creates a fresh hasher for one chunk.
+pub type HasherFactory = fn() -> Box<dyn ContentHasher>;
+
+static CUSTOM_HASHERS: RwLock<Vec<(&'static str, HasherFactory)>> = RwLock::new(Vec::new());
+
+/// Makes a custom hasher available under `name`, which manifests declare in their `Hasher`
+/// header. Returns the `HashType` to package with. Registering a name again replaces its factory.
+pub fn register_hasher(name: &'static str, factory: HasherFactory) -> Result<HashType, String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(format!(
+            "invalid hasher name {name:?}: use lowercase letters, digits and underscores"
+        ));
+    }
+    if HashType::from_header(name).is_some_and(|existing| existing != HashType::Custom(name)) {
+        return Err(format!("{name} is a built-in hasher"));
+    }
+
+    let mut hashers = CUSTOM_HASHERS.write().unwrap();
+    hashers.retain(|(registered, _)| *registered != name);
+    hashers.push((name, factory));
+
+    Ok(HashType::Custom(name))
+}
+
+pub(crate) fn registered_hasher(name: &str) -> Option<HashType> {
+    CUSTOM_HASHERS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(registered, _)| *registered == name)
+        .map(|(registered, _)| HashType::Custom(registered))
 }
 
+pub struct Hasher(Box<dyn ContentHasher>);
+
 impl Hasher {
     pub fn write(&mut self, data: &[u8]) {
-        match self {
-            Hasher::Blake3(hash) => {
-                hash.write_all(data).expect("could not use blake3");
+        self.0.update(data);
+    }
+
+    pub fn digest(self) -> String {
+        self.0.finalize()
+    }
+
+    /// Fails with `Error::Parse` if `hash_method` is a custom hasher that was never registered.
+    pub fn new(hash_method: HashType) -> Result<Self, Error> {
+        Ok(Hasher(match hash_method {
+            HashType::Blake3 => Box::new(blake3::Hasher::new()),
+            HashType::Xxh3_128 => Box::new(xxh3::Xxh3Default::new()),
+            HashType::Blake2b => Box::new(blake2::Blake2b512::new()),
+            HashType::Blake2s => Box::new(blake2::Blake2s256::new()),
+            HashType::Custom(name) => {
+                let hashers = CUSTOM_HASHERS.read().unwrap();
+                let (_, factory) = hashers
+                    .iter()
+                    .find(|(registered, _)| *registered == name)
+                    .ok_or_else(|| Error::Parse(format!("hasher {name} isn't registered")))?;
+                factory()
             }
-            Hasher::Xxh3_128(hash) => {
-                hash.write_all(data).expect("could not use blake3");
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_native_tls::{TlsAcceptor, native_tls};
+
+    /// Serves a fixed response over TLS, signed by a freshly generated CA.
+    /// Returns the server's port and the CA certificate.
+    async fn tls_server() -> (u16, String) {
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "pkgsmgr test CA");
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+        let leaf_key = KeyPair::generate().unwrap();
+        let leaf = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&leaf_key, &ca)
+            .unwrap();
+
+        let identity = native_tls::Identity::from_pkcs8(
+            leaf.pem().as_bytes(),
+            leaf_key.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                    )
+                    .await;
+                let _ = stream.shutdown().await;
             }
-        }
+        });
+
+        (port, ca.pem())
     }
 
-    pub fn digest(self) -> String {
-        match self {
-            Hasher::Blake3(hash) => hash.finalize().to_hex().to_string(),
-            Hasher::Xxh3_128(hash) => hex::encode(hash.digest128().to_le_bytes()),
-        }
+    #[test]
+    fn test_blake2_vectors() {
+        let digest = |hash_type| {
+            let mut hasher = Hasher::new(hash_type).unwrap();
+            hasher.write(b"abc");
+            hasher.digest()
+        };
+
+        assert_eq!(
+            digest(crate::types::HashType::Blake2b),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+        );
+        assert_eq!(
+            digest(crate::types::HashType::Blake2s),
+            "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982"
+        );
     }
 
-    pub fn new(hash_method: crate::types::HashType) -> Self {
-        match hash_method {
-            crate::types::HashType::Blake3 => Hasher::Blake3(blake3::Hasher::new()),
-            crate::types::HashType::Xxh3_128 => Hasher::Xxh3_128(xxh3::Xxh3Default::new()),
-        }
+    #[test]
+    fn test_unregistered_hasher() {
+        let result = Hasher::new(HashType::Custom("never_registered"));
+        assert!(matches!(result, Err(Error::Parse(_))));
+    }
+
+    #[tokio::test]
+    async fn test_custom_ca() {
+        let (port, ca_pem) = tls_server().await;
+        let url = format!("https://localhost:{port}/manifest");
+
+        let client = build_client(&ClientOptions::default()).unwrap();
+        assert!(get(&client, &url).await.is_err());
+
+        let dir = temp_dir::TempDir::new().unwrap();
+        let ca_path = dir.child("ca.pem");
+        fs::write(&ca_path, ca_pem).unwrap();
+        let client = build_client(&ClientOptions {
+            ca_cert: Some(ca_path),
+            ..Default::default()
+        })
+        .unwrap();
+        let body = get(&client, &url).await.unwrap().text().await.unwrap();
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_custom_headers() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, received) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            let _ = sender.send(String::from_utf8_lossy(&buf[..n]).to_lowercase());
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .await;
+        });
+
+        let client = build_client(&ClientOptions {
+            headers: vec!["X-Routing: eu-west".into(), "X-Token:secret".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        get(&client, &url).await.unwrap();
+
+        let request = received.await.unwrap();
+        assert!(request.contains("\r\nx-routing: eu-west\r\n"), "{request}");
+        assert!(request.contains("\r\nx-token: secret\r\n"), "{request}");
+        let user_agent = format!("\r\nuser-agent: pkgsmgr/{}\r\n", env!("CARGO_PKG_VERSION"));
+        assert!(request.contains(&user_agent), "{request}");
+
+        assert!(parse_headers(&["no colon".into()]).is_err());
+        assert!(parse_headers(&["Bad Name: value".into()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        use crate::source::{HttpSource, RepoSource};
+
+        // Accepts connections, then never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                streams.push(stream);
+            }
+        });
+
+        let client = build_client(&ClientOptions {
+            request_timeout: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+        let source = HttpSource::new(client, &url);
+
+        let start = Instant::now();
+        let error = source.fetch_pointer().await.unwrap_err();
+        let elapsed = start.elapsed();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
     }
 }
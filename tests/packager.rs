@@ -0,0 +1,38 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Packages `input` into `output` with the packager binary, returning the manifest hash it
+/// points `manifest` at.
+fn package(input: &Path, output: &Path, args: &[&str]) -> String {
+    let status = Command::new(env!("CARGO_BIN_EXE_pkgsmgr-packager"))
+        .args(["--hash", "blake3", "--compression", "zstd"])
+        .args(args)
+        .arg(input)
+        .arg(output)
+        .env_remove("SOURCE_DATE_EPOCH")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    std::fs::read_to_string(output.join("manifest")).unwrap()
+}
+
+#[test]
+fn test_repackaging_keeps_the_manifest_hash() {
+    let input = temp_dir::TempDir::new().unwrap();
+    let output = temp_dir::TempDir::new().unwrap();
+    std::fs::create_dir(input.child("bin")).unwrap();
+    std::fs::write(input.child("bin/app"), "app").unwrap();
+    std::fs::write(input.child("readme"), "readme").unwrap();
+
+    let first = package(input.path(), output.path(), &[]);
+    assert_eq!(package(input.path(), output.path(), &[]), first);
+    let manifest = std::fs::read_to_string(output.child(&first)).unwrap();
+    assert!(!manifest.contains("Generated:"));
+
+    let generated = ["--generated", "--clamp-mtime", "1700000000"];
+    let clamped = package(input.path(), output.path(), &generated);
+    assert_eq!(package(input.path(), output.path(), &generated), clamped);
+    let manifest = std::fs::read_to_string(output.child(&clamped)).unwrap();
+    assert!(manifest.contains("Generated:"));
+}